@@ -0,0 +1,331 @@
+use crate::token::tokens_match;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::ops::Deref;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
+const MAX_APP_NAME_LENGTH: usize = 64;
+
+#[derive(Error, Debug)]
+pub enum ApiTokenError {
+    #[error("Failed to read or write the API token file")]
+    IoError { source: io::Error },
+
+    #[error("The API token file is not valid JSON")]
+    SerializationError { source: serde_json::Error },
+
+    #[error("App name \"{app}\" must be 1 to 64 characters of [A-Za-z0-9-_.]")]
+    InvalidAppName { app: String },
+}
+
+/// Issues, revokes and checks the API tokens which local applications present to
+/// access the JSON API. Each app holds at most one token at a time.
+pub trait ApiTokenStore: Clone + Send + Sync {
+    /// Records the token as the one issued to the app, replacing any previous token
+    fn issue(&self, app: &str, token: &str) -> Result<(), ApiTokenError>;
+
+    /// Revokes the app's token, returning false if it had none
+    fn revoke(&self, app: &str) -> Result<bool, ApiTokenError>;
+
+    /// Lists every app holding a token
+    fn list(&self) -> Result<Vec<String>, ApiTokenError>;
+
+    /// Returns the app the token was issued to, if it has not been revoked
+    fn authorize(&self, token: &str) -> Result<Option<String>, ApiTokenError>;
+}
+
+impl<T> ApiTokenStore for Arc<T>
+where
+    T: ApiTokenStore,
+{
+    fn issue(&self, app: &str, token: &str) -> Result<(), ApiTokenError> {
+        self.deref().issue(app, token)
+    }
+
+    fn revoke(&self, app: &str) -> Result<bool, ApiTokenError> {
+        self.deref().revoke(app)
+    }
+
+    fn list(&self) -> Result<Vec<String>, ApiTokenError> {
+        self.deref().list()
+    }
+
+    fn authorize(&self, token: &str) -> Result<Option<String>, ApiTokenError> {
+        self.deref().authorize(token)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct ApiTokenRecord {
+    /// Hex-encoded SHA-256 digest of the token; tokens themselves are never stored
+    sha256: String,
+    issued_at: u64,
+}
+
+type ApiTokenRecords = BTreeMap<String, ApiTokenRecord>;
+
+/// Identifies one version of the token file. The file is replaced rather than
+/// rewritten, so a new inode also shows that it changed.
+#[derive(Debug, Clone, PartialEq)]
+struct FileVersion {
+    modified: Option<SystemTime>,
+    len: u64,
+    #[cfg(unix)]
+    inode: u64,
+}
+
+impl FileVersion {
+    fn of(metadata: &fs::Metadata) -> FileVersion {
+        FileVersion {
+            modified: metadata.modified().ok(),
+            len: metadata.len(),
+            #[cfg(unix)]
+            inode: std::os::unix::fs::MetadataExt::ino(metadata),
+        }
+    }
+}
+
+/// Keeps the digests of issued tokens in a JSON file. The digests are kept in memory
+/// and the file is only re-read once it changes, so tokens issued or revoked by the
+/// `api-token` command take effect without restarting the client.
+#[derive(Debug, Clone)]
+pub struct FileApiTokenStore {
+    path: PathBuf,
+    cached: Arc<Mutex<Option<(FileVersion, ApiTokenRecords)>>>,
+}
+
+impl FileApiTokenStore {
+    pub fn new<P: Into<PathBuf>>(path: P) -> FileApiTokenStore {
+        FileApiTokenStore {
+            path: path.into(),
+            cached: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    fn load(&self) -> Result<ApiTokenRecords, ApiTokenError> {
+        let version = match fs::metadata(&self.path) {
+            Ok(metadata) => FileVersion::of(&metadata),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+            Err(source) => return Err(ApiTokenError::IoError { source }),
+        };
+        let mut cached = self.cached.lock().unwrap();
+        if let Some((cached_version, records)) = cached.as_ref() {
+            if *cached_version == version {
+                return Ok(records.clone());
+            }
+        }
+
+        let records: ApiTokenRecords = match fs::read(&self.path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .map_err(|source| ApiTokenError::SerializationError { source })?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+            Err(source) => return Err(ApiTokenError::IoError { source }),
+        };
+        *cached = Some((version, records.clone()));
+        Ok(records)
+    }
+
+    /// Replaces the file atomically, making it readable by the current user only
+    fn save(&self, records: &ApiTokenRecords) -> Result<(), ApiTokenError> {
+        let bytes = serde_json::to_vec_pretty(records)
+            .map_err(|source| ApiTokenError::SerializationError { source })?;
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        options
+            .open(&tmp_path)
+            .and_then(|mut file| file.write_all(&bytes).and_then(|_| file.sync_all()))
+            .and_then(|_| fs::rename(&tmp_path, &self.path))
+            .map_err(|source| ApiTokenError::IoError { source })
+    }
+}
+
+fn digest(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn validate_app_name(app: &str) -> Result<(), ApiTokenError> {
+    if !app.is_empty()
+        && app.len() <= MAX_APP_NAME_LENGTH
+        && app
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+    {
+        Ok(())
+    } else {
+        Err(ApiTokenError::InvalidAppName {
+            app: app.to_owned(),
+        })
+    }
+}
+
+impl ApiTokenStore for FileApiTokenStore {
+    fn issue(&self, app: &str, token: &str) -> Result<(), ApiTokenError> {
+        validate_app_name(app)?;
+        let mut records = self.load()?;
+        records.insert(
+            app.to_owned(),
+            ApiTokenRecord {
+                sha256: digest(token),
+                issued_at: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .unwrap_or(0),
+            },
+        );
+        self.save(&records)
+    }
+
+    fn revoke(&self, app: &str) -> Result<bool, ApiTokenError> {
+        let mut records = self.load()?;
+        if records.remove(app).is_some() {
+            self.save(&records)?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    fn list(&self) -> Result<Vec<String>, ApiTokenError> {
+        Ok(self.load()?.into_keys().collect())
+    }
+
+    fn authorize(&self, token: &str) -> Result<Option<String>, ApiTokenError> {
+        let token_digest = digest(token);
+        // Every record is compared so that the time taken does not depend on which
+        // app, if any, the token belongs to
+        Ok(self
+            .load()?
+            .into_iter()
+            .fold(None, |authorized, (app, record)| {
+                if tokens_match(&record.sha256, &token_digest) {
+                    Some(app)
+                } else {
+                    authorized
+                }
+            }))
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::{ApiTokenError, ApiTokenStore, FileApiTokenStore};
+    use mockall::*;
+    use std::path::PathBuf;
+    use uuid::Uuid;
+
+    mock! {
+    pub ApiTokenStore {}
+    impl ApiTokenStore for ApiTokenStore {
+            fn issue(&self, app: &str, token: &str) -> Result<(), ApiTokenError>;
+            fn revoke(&self, app: &str) -> Result<bool, ApiTokenError>;
+            fn list(&self) -> Result<Vec<String>, ApiTokenError>;
+            fn authorize(&self, token: &str) -> Result<Option<String>, ApiTokenError>;
+    }
+    impl Clone for ApiTokenStore {
+            fn clone(&self) -> Self;
+    }
+    }
+
+    fn temp_path() -> PathBuf {
+        std::env::temp_dir().join(format!("redact-api-tokens-{}.json", Uuid::new_v4()))
+    }
+
+    #[test]
+    fn test_issue_authorize_and_revoke() {
+        let path = temp_path();
+        let store = FileApiTokenStore::new(&path);
+        assert!(store.authorize("abc").unwrap().is_none());
+
+        store.issue("desktop-app", "abc").unwrap();
+        store.issue("other.app", "def").unwrap();
+        assert_eq!(
+            store.authorize("abc").unwrap(),
+            Some("desktop-app".to_owned())
+        );
+        assert_eq!(store.list().unwrap(), vec!["desktop-app", "other.app"]);
+        // Only digests are written to disk
+        assert!(!std::fs::read_to_string(&path).unwrap().contains("\"abc\""));
+
+        // Reissuing replaces the previous token
+        store.issue("desktop-app", "ghi").unwrap();
+        assert!(store.authorize("abc").unwrap().is_none());
+
+        assert!(store.revoke("desktop-app").unwrap());
+        assert!(!store.revoke("desktop-app").unwrap());
+        assert!(store.authorize("ghi").unwrap().is_none());
+        assert_eq!(
+            store.authorize("def").unwrap(),
+            Some("other.app".to_owned())
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_token_file_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = temp_path();
+        FileApiTokenStore::new(&path).issue("app", "abc").unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_invalid_app_name() {
+        let store = FileApiTokenStore::new(temp_path());
+        for invalid in &["", "has space", &"a".repeat(65)] {
+            assert!(matches!(
+                store.issue(invalid, "abc"),
+                Err(ApiTokenError::InvalidAppName { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn test_token_file_changes_are_picked_up() {
+        let path = temp_path();
+        let store = FileApiTokenStore::new(&path);
+        store.issue("desktop-app", "abc").unwrap();
+        assert!(store.authorize("abc").unwrap().is_some());
+
+        // As the `api-token` command would, from another process
+        let command = FileApiTokenStore::new(&path);
+        command.revoke("desktop-app").unwrap();
+        command.issue("other.app", "def").unwrap();
+        assert!(store.authorize("abc").unwrap().is_none());
+        assert_eq!(
+            store.authorize("def").unwrap(),
+            Some("other.app".to_owned())
+        );
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(store.authorize("def").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_invalid_token_file() {
+        let path = temp_path();
+        std::fs::write(&path, "not json").unwrap();
+        assert!(matches!(
+            FileApiTokenStore::new(&path).authorize("abc"),
+            Err(ApiTokenError::SerializationError { .. })
+        ));
+        std::fs::remove_file(path).unwrap();
+    }
+}
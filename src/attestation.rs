@@ -0,0 +1,359 @@
+use crate::startup::{RetryPolicy, StartupError, DEFAULT_KEY_PATH};
+use redact_crypto::{
+    key::sodiumoxide::SodiumOxideSecretAsymmetricKey, ByteSource, HasBuilder, SecretAsymmetricKey,
+    States, StorageError, Storer, SymmetricKey, SymmetricSealer, VectorByteSource,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sodiumoxide::crypto::sign;
+use std::fmt;
+use std::sync::{Arc, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+/// Where the key relay notifications are signed with is stored
+pub const SIGNING_KEY_PATH: &str = ".keys.signing";
+
+/// Keeps signatures made with the derived key from ever being valid for another use
+const SIGNING_SEED_CONTEXT: &[u8] = b"redact-client relay attestation v1";
+
+/// What a relay notification reports the client did
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RelayOperation {
+    /// The value was submitted and stored straight away
+    Submit,
+    /// The value was submitted offline and stored when the write queue was synced
+    Sync,
+}
+
+/// The statement a relay notification attests to, signed by the user's key
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct AttestedClaims {
+    /// The relay URL the notification is sent to. Verifiers must check that it is
+    /// their own, as otherwise a site could pass notifications it received on to
+    /// another site as if the user had submitted to it.
+    pub audience: String,
+    pub path: String,
+    pub user_id: String,
+    pub timestamp: u64,
+    pub nonce: String,
+    pub data_type: Option<String>,
+    pub operation: RelayOperation,
+}
+
+/// Signed claims, along with the public key which verifies them. The signature covers
+/// the exact bytes of `payload`, so verifiers do not need to re-serialize the claims.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Attestation {
+    /// Base64 encoding of the claims as JSON
+    pub payload: String,
+    /// Base64 encoding of the Ed25519 signature of the decoded payload
+    pub signature: String,
+    /// Base64 encoding of the Ed25519 public key. It proves nothing on its own, so
+    /// verifiers must only trust a key the user registered with them out of band.
+    pub public_key: String,
+}
+
+struct SigningKey {
+    user_id: String,
+    secret_key: sign::SecretKey,
+    public_key: sign::PublicKey,
+}
+
+/// Signs relay notifications on behalf of the user. It holds no key until one is
+/// loaded from storage at startup, and signs nothing until then.
+#[derive(Clone, Default)]
+pub struct Attestor {
+    key: Arc<RwLock<Option<SigningKey>>>,
+}
+
+impl Attestor {
+    /// Signs with an Ed25519 key derived from the user's asymmetric key, as the
+    /// asymmetric keys redact-crypto stores can only encrypt
+    pub fn set_key(&self, user_id: &str, key: &SodiumOxideSecretAsymmetricKey) {
+        let mut seed = Sha256::new();
+        seed.update(SIGNING_SEED_CONTEXT);
+        seed.update(key.secret_key.0);
+        let seed = sign::Seed::from_slice(&seed.finalize()).expect("SHA-256 digests are seeds");
+        let (public_key, secret_key) = sign::keypair_from_seed(&seed);
+        *self.key.write().unwrap() = Some(SigningKey {
+            user_id: user_id.to_owned(),
+            secret_key,
+            public_key,
+        });
+    }
+
+    /// Base64 encoding of the public key which verifies attestations, if a key is set
+    pub fn public_key(&self) -> Option<String> {
+        self.key
+            .read()
+            .unwrap()
+            .as_ref()
+            .map(|key| base64::encode(key.public_key))
+    }
+
+    /// Signs claims about the value at the path, meant for the relay URL given as the
+    /// audience, returning `None` if no key is set
+    pub fn attest(
+        &self,
+        audience: &str,
+        path: &str,
+        data_type: Option<&str>,
+        operation: RelayOperation,
+    ) -> Option<Attestation> {
+        let key = self.key.read().unwrap();
+        let key = key.as_ref()?;
+        let claims = AttestedClaims {
+            audience: audience.to_owned(),
+            path: path.to_owned(),
+            user_id: key.user_id.clone(),
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            nonce: base64::encode(rand::random::<[u8; 16]>()),
+            data_type: data_type.map(str::to_owned),
+            operation,
+        };
+        let payload = serde_json::to_vec(&claims).ok()?;
+        let signature = sign::sign_detached(&payload, &key.secret_key);
+        Some(Attestation {
+            payload: base64::encode(&payload),
+            signature: base64::encode(signature),
+            public_key: base64::encode(key.public_key),
+        })
+    }
+
+    /// The user the attestations are made for, if a key is set
+    pub fn user_id(&self) -> Option<String> {
+        self.key
+            .read()
+            .unwrap()
+            .as_ref()
+            .map(|key| key.user_id.clone())
+    }
+}
+
+impl fmt::Debug for Attestor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Attestor")
+            .field("user_id", &self.user_id())
+            .field("public_key", &self.public_key())
+            .finish()
+    }
+}
+
+/// Loads the user's signing key into the attestor, generating and storing one sealed
+/// with the default key only if storage reports there is none yet. Storage errors
+/// while loading or storing it are retried according to the policy.
+pub async fn load_signing_key<H: Storer>(
+    storer: &H,
+    attestor: &Attestor,
+    user_id: &str,
+    policy: &RetryPolicy,
+) -> Result<(), StartupError> {
+    let mut attempt = 0;
+    let key = loop {
+        attempt += 1;
+        let result = match storer.get::<SecretAsymmetricKey>(SIGNING_KEY_PATH).await {
+            Ok(entry) => match storer.resolve::<SecretAsymmetricKey>(entry.value).await {
+                Ok(SecretAsymmetricKey::SodiumOxide(key)) => Ok(key),
+                Err(source) => Err(StartupError::SigningKeyLoadError { source }),
+            },
+            Err(StorageError::NotFound) => create_signing_key(storer).await,
+            Err(source) => Err(StartupError::SigningKeyLoadError { source }),
+        };
+
+        match result {
+            Ok(key) => break key,
+            Err(StartupError::SigningKeyLoadError { source }) if attempt >= policy.max_attempts => {
+                return Err(StartupError::StorageUnreachable {
+                    attempts: attempt,
+                    source,
+                })
+            }
+            Err(StartupError::SigningKeyLoadError { source }) => {
+                let backoff = policy.backoff(attempt);
+                warn!(
+                    error = %source,
+                    "failed to load signing key (attempt {}/{}), retrying in {:?}",
+                    attempt,
+                    policy.max_attempts,
+                    backoff
+                );
+                tokio::time::sleep(backoff).await;
+            }
+            Err(e) => return Err(e),
+        }
+    };
+    attestor.set_key(user_id, &key);
+    info!(
+        public_key = %attestor.public_key().unwrap_or_default(),
+        "relay notifications will be signed"
+    );
+    Ok(())
+}
+
+async fn create_signing_key<H: Storer>(
+    storer: &H,
+) -> Result<SodiumOxideSecretAsymmetricKey, StartupError> {
+    info!("no signing key found in storage, generating a new one");
+    let default_key_entry = storer
+        .get::<SymmetricKey>(DEFAULT_KEY_PATH)
+        .await
+        .map_err(|source| StartupError::SigningKeyLoadError { source })?;
+    let default_key: SymmetricKey = storer
+        .resolve(default_key_entry.value)
+        .await
+        .map_err(|source| StartupError::SigningKeyLoadError { source })?;
+
+    let key = SodiumOxideSecretAsymmetricKey::new();
+    let unsealable = default_key
+        .seal(
+            ByteSource::Vector(VectorByteSource::new(&key.secret_key.0)),
+            None,
+            Some(default_key_entry.path),
+        )
+        .map_err(|source| StartupError::SigningKeySealError { source })?;
+    storer
+        .create(
+            SIGNING_KEY_PATH.to_owned(),
+            States::Sealed {
+                builder: key.builder().into(),
+                unsealable,
+            },
+        )
+        .await
+        .map_err(|source| StartupError::SigningKeyLoadError { source })?;
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{load_signing_key, AttestedClaims, Attestor, RelayOperation, SIGNING_KEY_PATH};
+    use crate::startup::{load_default_key, RetryPolicy, StartupError};
+    use crate::storage::tests::CountingStorer;
+    use redact_crypto::{
+        key::sodiumoxide::SodiumOxideSecretAsymmetricKey, States, StorageError, Storer,
+    };
+    use sodiumoxide::crypto::sign;
+    use std::convert::TryFrom;
+    use std::time::Duration;
+
+    const AUDIENCE: &str = "https://example.com/relay";
+
+    #[test]
+    fn test_attestation_verifies() {
+        let attestor = Attestor::default();
+        assert!(attestor
+            .attest(
+                AUDIENCE,
+                ".profile.name.",
+                Some("string"),
+                RelayOperation::Submit
+            )
+            .is_none());
+        attestor.set_key("alice", &SodiumOxideSecretAsymmetricKey::new());
+
+        let attestation = attestor
+            .attest(
+                AUDIENCE,
+                ".profile.name.",
+                Some("string"),
+                RelayOperation::Submit,
+            )
+            .unwrap();
+        let payload = base64::decode(&attestation.payload).unwrap();
+        let signature =
+            sign::Signature::try_from(&base64::decode(&attestation.signature).unwrap()[..])
+                .unwrap();
+        let public_key =
+            sign::PublicKey::from_slice(&base64::decode(&attestation.public_key).unwrap()).unwrap();
+        assert!(sign::verify_detached(&signature, &payload, &public_key));
+
+        let claims: AttestedClaims = serde_json::from_slice(&payload).unwrap();
+        assert_eq!(claims.audience, AUDIENCE);
+        assert_eq!(claims.path, ".profile.name.");
+        assert_eq!(claims.user_id, "alice");
+        assert_eq!(claims.data_type, Some("string".to_owned()));
+        assert_eq!(claims.operation, RelayOperation::Submit);
+
+        // Every attestation is unique, so a captured one cannot be replayed unnoticed
+        let other = attestor
+            .attest(
+                AUDIENCE,
+                ".profile.name.",
+                Some("string"),
+                RelayOperation::Submit,
+            )
+            .unwrap();
+        let other: AttestedClaims =
+            serde_json::from_slice(&base64::decode(&other.payload).unwrap()).unwrap();
+        assert_ne!(claims.nonce, other.nonce);
+    }
+
+    #[tokio::test]
+    async fn test_signing_key_is_stored_sealed() {
+        let storer = CountingStorer::default();
+        load_default_key(&storer, &RetryPolicy::default())
+            .await
+            .unwrap();
+
+        let attestor = Attestor::default();
+        load_signing_key(&storer, &attestor, "alice", &RetryPolicy::default())
+            .await
+            .unwrap();
+        assert!(matches!(
+            storer
+                .get::<redact_crypto::SecretAsymmetricKey>(SIGNING_KEY_PATH)
+                .await
+                .unwrap()
+                .value,
+            States::Sealed { .. }
+        ));
+
+        // The same key is loaded again after a restart
+        let reloaded = Attestor::default();
+        load_signing_key(&storer, &reloaded, "alice", &RetryPolicy::default())
+            .await
+            .unwrap();
+        assert_eq!(reloaded.public_key(), attestor.public_key());
+        assert_eq!(reloaded.user_id(), Some("alice".to_owned()));
+    }
+
+    #[tokio::test]
+    async fn test_signing_key_is_not_generated_while_storage_is_unreachable() {
+        let storer = CountingStorer::default();
+        load_default_key(&storer, &RetryPolicy::default())
+            .await
+            .unwrap();
+        let policy = RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(0),
+            max_backoff: Duration::from_millis(0),
+        };
+
+        storer.set_unreachable(true);
+        let attestor = Attestor::default();
+        match load_signing_key(&storer, &attestor, "alice", &policy).await {
+            Err(StartupError::StorageUnreachable { attempts, .. }) => assert_eq!(attempts, 3),
+            _ => panic!("expected a StorageUnreachable error"),
+        }
+        assert_eq!(attestor.public_key(), None);
+
+        storer.set_unreachable(false);
+        assert!(matches!(
+            storer
+                .get::<redact_crypto::SecretAsymmetricKey>(SIGNING_KEY_PATH)
+                .await,
+            Err(StorageError::NotFound)
+        ));
+        load_signing_key(&storer, &attestor, "alice", &policy)
+            .await
+            .unwrap();
+        assert!(attestor.public_key().is_some());
+    }
+}
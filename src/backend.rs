@@ -0,0 +1,648 @@
+use crate::{
+    replication::ReplicatedStorer,
+    storage::{Deleter, HttpStorer, RedactDeleter},
+};
+use async_trait::async_trait;
+use redact_crypto::{Data, Entry, EntryPath, HasBuilder, HasIndex, States, StorageError, Storer};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
+
+type Document = <Data as HasIndex>::Index;
+
+fn internal_error<E: Error + Send + Sync + 'static>(source: E) -> StorageError {
+    StorageError::InternalError {
+        source: Box::new(source),
+    }
+}
+
+/// Runs file and database work on the blocking thread pool, off the async runtime
+async fn blocking<R, F>(work: F) -> Result<R, StorageError>
+where
+    R: Send + 'static,
+    F: FnOnce() -> Result<R, StorageError> + Send + 'static,
+{
+    tokio::task::spawn_blocking(work)
+        .await
+        .map_err(internal_error)?
+}
+
+/// Whether the value has everything the index asks for, the way redact-store matches
+/// the index of a listing against stored values. Without this, listing one type would
+/// also return entries of every other type under the path.
+fn matches_index(value: &States, index: &Option<Document>) -> bool {
+    let index = match index {
+        Some(index) => index,
+        None => return true,
+    };
+    match (serde_json::to_value(value), serde_json::to_value(index)) {
+        (Ok(value), Ok(index)) => contains(&value, &index),
+        _ => false,
+    }
+}
+
+fn contains(value: &Value, expected: &Value) -> bool {
+    match (value, expected) {
+        (Value::Object(value), Value::Object(expected)) => {
+            expected.iter().all(|(key, expected)| {
+                value
+                    .get(key)
+                    .is_some_and(|value| contains(value, expected))
+            })
+        }
+        _ => value == expected,
+    }
+}
+
+/// Returns one page of the entries whose path starts with the given path and whose
+/// value matches the index, ordered by path, the way redact-store pages through a
+/// listing
+fn page(
+    mut entries: Vec<Entry>,
+    path: &str,
+    index: &Option<Document>,
+    skip: i64,
+    page_size: i64,
+) -> Vec<Entry> {
+    entries.retain(|entry| entry.path.starts_with(path) && matches_index(&entry.value, index));
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    entries
+        .into_iter()
+        .skip(skip.max(0) as usize)
+        .take(page_size.max(0) as usize)
+        .collect()
+}
+
+/// Stores entries as JSON files in a local directory, one file per path, readable by
+/// the current user only. Local backends keep a single entry per path, so lookups
+/// ignore the index, while listings only return entries which match it.
+#[derive(Debug, Clone)]
+pub struct FsStorer {
+    dir: PathBuf,
+}
+
+impl FsStorer {
+    pub fn new<P: Into<PathBuf>>(dir: P) -> FsStorer {
+        FsStorer { dir: dir.into() }
+    }
+
+    fn file_path(&self, path: &str) -> PathBuf {
+        let digest: String = Sha256::digest(path.as_bytes())
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        self.dir.join(format!("{}.json", digest))
+    }
+
+    fn read(file_path: &Path) -> Result<Entry, StorageError> {
+        match fs::read(file_path) {
+            Ok(bytes) => serde_json::from_slice(&bytes).map_err(internal_error),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Err(StorageError::NotFound),
+            Err(e) => Err(internal_error(e)),
+        }
+    }
+
+    /// Replaces the file atomically, so a crash never leaves a partial entry behind.
+    /// Every write goes through its own temporary file, so concurrent writes to the
+    /// same path cannot interleave.
+    fn write(&self, entry: &Entry) -> io::Result<()> {
+        let bytes = serde_json::to_vec(entry)?;
+        let file_path = self.file_path(&entry.path);
+        let mut tmp_path = file_path.clone().into_os_string();
+        tmp_path.push(format!(".{}.tmp", Uuid::new_v4()));
+        fs::create_dir_all(&self.dir)?;
+
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(&tmp_path)?;
+        let written = file
+            .write_all(&bytes)
+            .and_then(|_| file.sync_all())
+            .and_then(|_| fs::rename(&tmp_path, &file_path));
+        if written.is_err() {
+            let _ = fs::remove_file(&tmp_path);
+        }
+        written
+    }
+}
+
+// async_trait adds its own lifetime bounds to the ones required by `Storer`
+#[allow(clippy::multiple_bound_locations)]
+#[async_trait]
+impl Storer for FsStorer {
+    async fn get_indexed<T: HasBuilder + 'static>(
+        &self,
+        path: &str,
+        _index: &Option<Document>,
+    ) -> Result<Entry, StorageError> {
+        let file_path = self.file_path(path);
+        blocking(move || FsStorer::read(&file_path)).await
+    }
+
+    async fn list_indexed<T: HasBuilder + Send + 'static>(
+        &self,
+        path: &str,
+        skip: i64,
+        page_size: i64,
+        index: &Option<Document>,
+    ) -> Result<Vec<Entry>, StorageError> {
+        let dir = self.dir.clone();
+        let entries = blocking(move || {
+            let files = match fs::read_dir(&dir) {
+                Ok(files) => files,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+                Err(e) => return Err(internal_error(e)),
+            };
+            let mut entries = Vec::new();
+            for file in files {
+                let file_path = file.map_err(internal_error)?.path();
+                if file_path.extension().is_some_and(|ext| ext == "json") {
+                    entries.push(FsStorer::read(&file_path)?);
+                }
+            }
+            Ok(entries)
+        })
+        .await?;
+        Ok(page(entries, path, index, skip, page_size))
+    }
+
+    async fn create(&self, path: EntryPath, value: States) -> Result<bool, StorageError> {
+        let storer = self.clone();
+        blocking(move || storer.write(&Entry { path, value }).map_err(internal_error)).await?;
+        Ok(true)
+    }
+}
+
+#[async_trait]
+impl Deleter for FsStorer {
+    async fn delete(&self, path: &str) -> Result<(), StorageError> {
+        let file_path = self.file_path(path);
+        blocking(move || match fs::remove_file(file_path) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Err(StorageError::NotFound),
+            Err(e) => Err(internal_error(e)),
+        })
+        .await
+    }
+}
+
+/// Stores entries as JSON in a single SQLite database file, keyed by path
+#[derive(Debug, Clone)]
+pub struct SqliteStorer {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl SqliteStorer {
+    /// Opens the database, creating it and its table if needed
+    pub fn open<P: AsRef<Path>>(file_path: P) -> Result<SqliteStorer, StorageError> {
+        let conn = Connection::open(file_path).map_err(internal_error)?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS entries (path TEXT PRIMARY KEY, entry TEXT NOT NULL)",
+            params![],
+        )
+        .map_err(internal_error)?;
+        Ok(SqliteStorer {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+}
+
+// async_trait adds its own lifetime bounds to the ones required by `Storer`
+#[allow(clippy::multiple_bound_locations)]
+#[async_trait]
+impl Storer for SqliteStorer {
+    async fn get_indexed<T: HasBuilder + 'static>(
+        &self,
+        path: &str,
+        _index: &Option<Document>,
+    ) -> Result<Entry, StorageError> {
+        let conn = self.conn.clone();
+        let path = path.to_owned();
+        let entry: Option<String> = blocking(move || {
+            conn.lock()
+                .unwrap()
+                .query_row(
+                    "SELECT entry FROM entries WHERE path = ?1",
+                    params![path],
+                    |row| row.get(0),
+                )
+                .optional()
+                .map_err(internal_error)
+        })
+        .await?;
+        match entry {
+            Some(entry) => serde_json::from_str(&entry).map_err(internal_error),
+            None => Err(StorageError::NotFound),
+        }
+    }
+
+    async fn list_indexed<T: HasBuilder + Send + 'static>(
+        &self,
+        path: &str,
+        skip: i64,
+        page_size: i64,
+        index: &Option<Document>,
+    ) -> Result<Vec<Entry>, StorageError> {
+        let conn = self.conn.clone();
+        let path = path.to_owned();
+        let index = index.clone();
+        blocking(move || {
+            let conn = conn.lock().unwrap();
+            // Matching on the prefix with substr avoids escaping LIKE wildcards in the path
+            let mut statement = conn
+                .prepare(
+                    "SELECT entry FROM entries WHERE substr(path, 1, length(?1)) = ?1 \
+                     ORDER BY path",
+                )
+                .map_err(internal_error)?;
+            let rows = statement
+                .query_map(params![path], |row| row.get::<_, String>(0))
+                .map_err(internal_error)?;
+            // The index is matched on the stored JSON, so the page is counted out here
+            // rather than with LIMIT and OFFSET
+            rows.map(|entry| {
+                serde_json::from_str::<Entry>(&entry.map_err(internal_error)?)
+                    .map_err(internal_error)
+            })
+            .filter(|entry| {
+                entry
+                    .as_ref()
+                    .map_or(true, |entry| matches_index(&entry.value, &index))
+            })
+            .skip(skip.max(0) as usize)
+            .take(page_size.max(0) as usize)
+            .collect()
+        })
+        .await
+    }
+
+    async fn create(&self, path: EntryPath, value: States) -> Result<bool, StorageError> {
+        let entry = serde_json::to_string(&Entry {
+            path: path.clone(),
+            value,
+        })
+        .map_err(internal_error)?;
+        let conn = self.conn.clone();
+        blocking(move || {
+            conn.lock()
+                .unwrap()
+                .execute(
+                    "INSERT OR REPLACE INTO entries (path, entry) VALUES (?1, ?2)",
+                    params![path, entry],
+                )
+                .map_err(internal_error)
+        })
+        .await?;
+        Ok(true)
+    }
+}
+
+#[async_trait]
+impl Deleter for SqliteStorer {
+    async fn delete(&self, path: &str) -> Result<(), StorageError> {
+        let conn = self.conn.clone();
+        let path = path.to_owned();
+        let deleted = blocking(move || {
+            conn.lock()
+                .unwrap()
+                .execute("DELETE FROM entries WHERE path = ?1", params![path])
+                .map_err(internal_error)
+        })
+        .await?;
+        if deleted == 0 {
+            Err(StorageError::NotFound)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Keeps entries in memory only, so everything is lost when the client exits
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStorer {
+    entries: Arc<Mutex<BTreeMap<String, States>>>,
+}
+
+// async_trait adds its own lifetime bounds to the ones required by `Storer`
+#[allow(clippy::multiple_bound_locations)]
+#[async_trait]
+impl Storer for MemoryStorer {
+    async fn get_indexed<T: HasBuilder + 'static>(
+        &self,
+        path: &str,
+        _index: &Option<Document>,
+    ) -> Result<Entry, StorageError> {
+        match self.entries.lock().unwrap().get(path) {
+            Some(value) => Ok(Entry {
+                path: path.to_owned(),
+                value: value.clone(),
+            }),
+            None => Err(StorageError::NotFound),
+        }
+    }
+
+    async fn list_indexed<T: HasBuilder + Send + 'static>(
+        &self,
+        path: &str,
+        skip: i64,
+        page_size: i64,
+        index: &Option<Document>,
+    ) -> Result<Vec<Entry>, StorageError> {
+        let entries = self
+            .entries
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(path, value)| Entry {
+                path: path.clone(),
+                value: value.clone(),
+            })
+            .collect();
+        Ok(page(entries, path, index, skip, page_size))
+    }
+
+    async fn create(&self, path: EntryPath, value: States) -> Result<bool, StorageError> {
+        self.entries.lock().unwrap().insert(path, value);
+        Ok(true)
+    }
+}
+
+#[async_trait]
+impl Deleter for MemoryStorer {
+    async fn delete(&self, path: &str) -> Result<(), StorageError> {
+        match self.entries.lock().unwrap().remove(path) {
+            Some(_) => Ok(()),
+            None => Err(StorageError::NotFound),
+        }
+    }
+}
+
+/// The storage backend selected by `storage.backend`, which reads, writes and deletes
+/// entries through whichever backend it holds
+#[derive(Clone)]
+pub enum StorageBackend {
+    RedactStore(HttpStorer, RedactDeleter),
+    LocalFs(FsStorer),
+    Sqlite(SqliteStorer),
+    Memory(MemoryStorer),
+    Replicated(ReplicatedStorer<StorageBackend>),
+}
+
+impl StorageBackend {
+    /// Talks to a remote redact-store over HTTP, making requests with the given client
+    pub fn redact_store(url: &str, client: reqwest::Client) -> StorageBackend {
+        StorageBackend::RedactStore(
+            HttpStorer::new(url, client.clone()),
+            RedactDeleter::new(url, client),
+        )
+    }
+
+    /// Every redact-store this backend talks to
+    pub fn http_storers(&self) -> Vec<&HttpStorer> {
+        match self {
+            StorageBackend::RedactStore(storer, _) => vec![storer],
+            StorageBackend::Replicated(storer) => storer
+                .replicas()
+                .iter()
+                .flat_map(StorageBackend::http_storers)
+                .collect(),
+            _ => vec![],
+        }
+    }
+}
+
+// async_trait adds its own lifetime bounds to the ones required by `Storer`
+#[allow(clippy::multiple_bound_locations)]
+#[async_trait]
+impl Storer for StorageBackend {
+    async fn get_indexed<T: HasBuilder + 'static>(
+        &self,
+        path: &str,
+        index: &Option<Document>,
+    ) -> Result<Entry, StorageError> {
+        match self {
+            StorageBackend::RedactStore(storer, _) => storer.get_indexed::<T>(path, index).await,
+            StorageBackend::LocalFs(storer) => storer.get_indexed::<T>(path, index).await,
+            StorageBackend::Sqlite(storer) => storer.get_indexed::<T>(path, index).await,
+            StorageBackend::Memory(storer) => storer.get_indexed::<T>(path, index).await,
+            StorageBackend::Replicated(storer) => storer.get_indexed::<T>(path, index).await,
+        }
+    }
+
+    async fn list_indexed<T: HasBuilder + Send + 'static>(
+        &self,
+        path: &str,
+        skip: i64,
+        page_size: i64,
+        index: &Option<Document>,
+    ) -> Result<Vec<Entry>, StorageError> {
+        match self {
+            StorageBackend::RedactStore(storer, _) => {
+                storer.list_indexed::<T>(path, skip, page_size, index).await
+            }
+            StorageBackend::LocalFs(storer) => {
+                storer.list_indexed::<T>(path, skip, page_size, index).await
+            }
+            StorageBackend::Sqlite(storer) => {
+                storer.list_indexed::<T>(path, skip, page_size, index).await
+            }
+            StorageBackend::Memory(storer) => {
+                storer.list_indexed::<T>(path, skip, page_size, index).await
+            }
+            StorageBackend::Replicated(storer) => {
+                storer.list_indexed::<T>(path, skip, page_size, index).await
+            }
+        }
+    }
+
+    async fn create(&self, path: EntryPath, value: States) -> Result<bool, StorageError> {
+        match self {
+            StorageBackend::RedactStore(storer, _) => storer.create(path, value).await,
+            StorageBackend::LocalFs(storer) => storer.create(path, value).await,
+            StorageBackend::Sqlite(storer) => storer.create(path, value).await,
+            StorageBackend::Memory(storer) => storer.create(path, value).await,
+            StorageBackend::Replicated(storer) => storer.create(path, value).await,
+        }
+    }
+}
+
+#[async_trait]
+impl Deleter for StorageBackend {
+    async fn delete(&self, path: &str) -> Result<(), StorageError> {
+        match self {
+            StorageBackend::RedactStore(_, deleter) => deleter.delete(path).await,
+            StorageBackend::LocalFs(storer) => storer.delete(path).await,
+            StorageBackend::Sqlite(storer) => storer.delete(path).await,
+            StorageBackend::Memory(storer) => storer.delete(path).await,
+            StorageBackend::Replicated(storer) => storer.delete(path).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FsStorer, MemoryStorer, SqliteStorer, StorageBackend};
+    use crate::routes::data::{fetch_data, seal_and_store};
+    use crate::startup::{load_default_key, RetryPolicy};
+    use crate::storage::Deleter;
+    use futures::future::join_all;
+    use redact_crypto::{
+        key::sodiumoxide::{SodiumOxideSymmetricKey, SodiumOxideSymmetricKeyBuilder},
+        ByteSource, Data, DataBuilder, KeyBuilder, States, StorageError, Storer, StringDataBuilder,
+        SymmetricKey, SymmetricKeyBuilder, TypeBuilder, VectorByteSource,
+    };
+    use std::path::PathBuf;
+    use uuid::Uuid;
+
+    fn string_value(value: &str) -> States {
+        States::Unsealed {
+            builder: TypeBuilder::Data(DataBuilder::String(StringDataBuilder {})),
+            bytes: ByteSource::Vector(VectorByteSource::new(value.as_bytes())),
+        }
+    }
+
+    fn temp_path() -> PathBuf {
+        std::env::temp_dir().join(format!("redact-backend-{}", Uuid::new_v4()))
+    }
+
+    /// Runs the same round trip against every local backend
+    async fn round_trip(backend: StorageBackend) {
+        for (path, value) in &[
+            (".profile.name.", "Alice"),
+            (".profile.email.", "alice@example.com"),
+            (".settings.theme.", "dark"),
+        ] {
+            assert!(backend
+                .create((*path).to_owned(), string_value(value))
+                .await
+                .unwrap());
+        }
+        backend
+            .create(".profile.name.".to_owned(), string_value("Bob"))
+            .await
+            .unwrap();
+        let sosk = SodiumOxideSymmetricKey::new();
+        backend
+            .create(
+                ".profile.key.".to_owned(),
+                States::Unsealed {
+                    builder: TypeBuilder::Key(KeyBuilder::Symmetric(
+                        SymmetricKeyBuilder::SodiumOxide(SodiumOxideSymmetricKeyBuilder {}),
+                    )),
+                    bytes: ByteSource::Vector(VectorByteSource::new(sosk.key.as_ref())),
+                },
+            )
+            .await
+            .unwrap();
+
+        let entry = backend.get::<Data>(".profile.name.").await.unwrap();
+        assert_eq!(
+            backend.resolve::<Data>(entry.value).await.unwrap(),
+            Data::String("Bob".to_owned())
+        );
+        assert!(matches!(
+            backend.get::<Data>(".profile.missing.").await,
+            Err(StorageError::NotFound)
+        ));
+
+        let listed = backend.list::<Data>(".profile.", 0, 10).await.unwrap();
+        let paths: Vec<&str> = listed.iter().map(|entry| entry.path.as_str()).collect();
+        assert_eq!(paths, vec![".profile.email.", ".profile.name."]);
+        let listed = backend.list::<Data>(".profile.", 1, 10).await.unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].path, ".profile.name.");
+        let listed = backend
+            .list::<SymmetricKey>(".profile.", 0, 10)
+            .await
+            .unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].path, ".profile.key.");
+
+        backend.delete(".profile.name.").await.unwrap();
+        assert!(matches!(
+            backend.get::<Data>(".profile.name.").await,
+            Err(StorageError::NotFound)
+        ));
+        assert!(matches!(
+            backend.delete(".profile.name.").await,
+            Err(StorageError::NotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_local_fs() {
+        let dir = temp_path();
+        round_trip(StorageBackend::LocalFs(FsStorer::new(&dir))).await;
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_local_fs_concurrent_writes() {
+        let dir = temp_path();
+        let storer = FsStorer::new(&dir);
+        let writes = (0..20).map(|i| {
+            storer.create(
+                ".profile.name.".to_owned(),
+                string_value(&format!("Alice {}", i)),
+            )
+        });
+        for written in join_all(writes).await {
+            assert!(written.unwrap());
+        }
+
+        assert!(storer.get::<Data>(".profile.name.").await.is_ok());
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_sqlite() {
+        let file_path = temp_path();
+        round_trip(StorageBackend::Sqlite(
+            SqliteStorer::open(&file_path).unwrap(),
+        ))
+        .await;
+        std::fs::remove_file(file_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_memory() {
+        round_trip(StorageBackend::Memory(MemoryStorer::default())).await;
+    }
+
+    #[tokio::test]
+    async fn test_local_fs_persists_sealed_data() {
+        let dir = temp_path();
+        let backend = StorageBackend::LocalFs(FsStorer::new(&dir));
+        load_default_key(&backend, &RetryPolicy::default())
+            .await
+            .unwrap();
+        seal_and_store(
+            &backend,
+            ".profile.name.".to_owned(),
+            Data::String("Alice".to_owned()),
+        )
+        .await
+        .unwrap();
+
+        let reopened = StorageBackend::LocalFs(FsStorer::new(&dir));
+        assert!(matches!(
+            reopened.get::<Data>(".profile.name.").await.unwrap().value,
+            States::Sealed { .. }
+        ));
+        assert_eq!(
+            fetch_data(&reopened, ".profile.name.").await.unwrap(),
+            Some(Data::String("Alice".to_owned()))
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
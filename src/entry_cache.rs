@@ -0,0 +1,619 @@
+use crate::{metrics::ENTRY_CACHE_LOOKUPS, storage::Deleter};
+use async_trait::async_trait;
+use redact_crypto::{Data, Entry, EntryPath, HasBuilder, HasIndex, States, StorageError, Storer};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tracing::warn;
+
+type Document = <Data as HasIndex>::Index;
+
+/// How long a cached entry is served without asking storage unless configured otherwise
+pub const DEFAULT_ENTRY_CACHE_TTL: Duration = Duration::from_secs(60);
+
+/// Largest total size of the cache unless configured otherwise
+pub const DEFAULT_ENTRY_CACHE_MAX_BYTES: u64 = 64 * 1024 * 1024;
+
+/// The size and age of every cached file. The directory is only scanned the first
+/// time the cache is written to, and kept track of in memory from then on.
+#[derive(Debug, Default)]
+struct CachedFiles {
+    files: HashMap<PathBuf, (SystemTime, u64)>,
+    total: u64,
+}
+
+impl CachedFiles {
+    fn scan(dir: &Path) -> io::Result<CachedFiles> {
+        let mut cached = CachedFiles::default();
+        let files = match fs::read_dir(dir) {
+            Ok(files) => files,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(cached),
+            Err(e) => return Err(e),
+        };
+        for file in files {
+            let file = file?;
+            let metadata = file.metadata()?;
+            cached.add(
+                file.path(),
+                metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+                metadata.len(),
+            );
+        }
+        Ok(cached)
+    }
+
+    fn add(&mut self, path: PathBuf, modified: SystemTime, len: u64) {
+        self.total += len;
+        if let Some((_, replaced)) = self.files.insert(path, (modified, len)) {
+            self.total -= replaced;
+        }
+    }
+
+    fn remove(&mut self, path: &Path) -> io::Result<()> {
+        match fs::remove_file(path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => (),
+        }
+        if let Some((_, len)) = self.files.remove(path) {
+            self.total -= len;
+        }
+        Ok(())
+    }
+
+    /// Removes the least recently cached files until the total fits the size limit
+    fn evict_to_size(&mut self, max_bytes: u64) -> io::Result<()> {
+        if self.total <= max_bytes {
+            return Ok(());
+        }
+        let mut files: Vec<_> = self
+            .files
+            .iter()
+            .map(|(path, (modified, _))| (*modified, path.clone()))
+            .collect();
+        files.sort();
+        for (_, path) in files {
+            if self.total <= max_bytes {
+                break;
+            }
+            self.remove(&path)?;
+        }
+        Ok(())
+    }
+}
+
+/// Runs filesystem work on the blocking thread pool, off the async runtime
+async fn blocking<R, F>(work: F) -> io::Result<R>
+where
+    R: Send + 'static,
+    F: FnOnce() -> io::Result<R> + Send + 'static,
+{
+    tokio::task::spawn_blocking(work)
+        .await
+        .map_err(io::Error::other)?
+}
+
+/// Keeps copies of sealed entries on disk, one file per path and index. Only sealed
+/// entries are ever written, so nothing readable without the key reaches the disk.
+/// That includes the unsealed default key itself, so unsealing cached entries while
+/// storage is unreachable relies on the key still being held by the key cache.
+/// Failing to read or write the cache is logged and otherwise treated as a miss.
+#[derive(Debug, Clone)]
+pub struct EntryCache {
+    dir: Option<PathBuf>,
+    ttl: Duration,
+    max_bytes: u64,
+    files: Arc<Mutex<Option<CachedFiles>>>,
+}
+
+impl EntryCache {
+    pub fn new<P: Into<PathBuf>>(dir: P, ttl: Duration, max_bytes: u64) -> EntryCache {
+        EntryCache {
+            dir: Some(dir.into()),
+            ttl,
+            max_bytes,
+            files: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// A cache which never holds anything
+    pub fn disabled() -> EntryCache {
+        EntryCache {
+            dir: None,
+            ttl: Duration::from_secs(0),
+            max_bytes: 0,
+            files: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.dir.is_some()
+    }
+
+    /// Entries for the same path share a file name prefix, so they can be invalidated
+    /// together without knowing which indexes they were cached under
+    fn file_prefix(path: &str) -> String {
+        hex_digest(path.as_bytes())
+    }
+
+    fn file_path(&self, path: &str, index: &Option<Document>) -> Option<PathBuf> {
+        let index = serde_json::to_vec(index).unwrap_or_default();
+        self.dir.as_ref().map(|dir| {
+            dir.join(format!(
+                "{}-{}.json",
+                EntryCache::file_prefix(path),
+                hex_digest(&index)
+            ))
+        })
+    }
+
+    /// Changes the cached files while holding the lock on their sizes, scanning the
+    /// directory first if it has not been yet
+    fn with_files<R>(
+        &self,
+        dir: &Path,
+        change: impl FnOnce(&mut CachedFiles) -> io::Result<R>,
+    ) -> io::Result<R> {
+        let mut files = self.files.lock().unwrap();
+        if files.is_none() {
+            *files = Some(CachedFiles::scan(dir)?);
+        }
+        change(files.as_mut().unwrap())
+    }
+
+    /// Returns the cached entry along with whether it is still within its ttl
+    async fn get(&self, path: &str, index: &Option<Document>) -> Option<(Entry, bool)> {
+        let file_path = self.file_path(path, index)?;
+        let ttl = self.ttl;
+        let read = blocking(move || -> io::Result<(Entry, bool)> {
+            let age = fs::metadata(&file_path)?
+                .modified()?
+                .elapsed()
+                .unwrap_or_default();
+            let entry = serde_json::from_slice(&fs::read(&file_path)?)?;
+            Ok((entry, age < ttl))
+        });
+        match read.await {
+            Ok(cached) => Some(cached),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => {
+                warn!(error = %e, "failed to read cached entry");
+                None
+            }
+        }
+    }
+
+    async fn insert(&self, path: &str, index: &Option<Document>, entry: &Entry) {
+        let file_path = match self.file_path(path, index) {
+            Some(file_path) => file_path,
+            None => return,
+        };
+        if !matches!(entry.value, States::Sealed { .. }) {
+            return;
+        }
+        let bytes = match serde_json::to_vec(entry) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                warn!(error = %e, "failed to cache entry");
+                return;
+            }
+        };
+
+        let cache = self.clone();
+        let write = blocking(move || -> io::Result<()> {
+            let dir = file_path.parent().unwrap_or(&file_path).to_owned();
+            cache.with_files(&dir, |files| {
+                fs::create_dir_all(&dir)?;
+                let mut tmp_path = file_path.clone().into_os_string();
+                tmp_path.push(".tmp");
+
+                let mut options = OpenOptions::new();
+                options.write(true).create(true).truncate(true);
+                #[cfg(unix)]
+                std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+                let mut file = options.open(&tmp_path)?;
+                file.write_all(&bytes)?;
+                fs::rename(&tmp_path, &file_path)?;
+                files.add(file_path, SystemTime::now(), bytes.len() as u64);
+                files.evict_to_size(cache.max_bytes)
+            })
+        });
+        if let Err(e) = write.await {
+            warn!(error = %e, "failed to cache entry");
+        }
+    }
+
+    /// Removes every entry cached for the path
+    pub async fn invalidate(&self, path: &str) {
+        let dir = match &self.dir {
+            Some(dir) => dir.clone(),
+            None => return,
+        };
+        let prefix = format!("{}-", EntryCache::file_prefix(path));
+        let cache = self.clone();
+        let remove = blocking(move || {
+            cache.with_files(&dir, |files| {
+                let cached: Vec<_> = files
+                    .files
+                    .keys()
+                    .filter(|file_path| {
+                        file_path
+                            .file_name()
+                            .is_some_and(|name| name.to_string_lossy().starts_with(&prefix))
+                    })
+                    .cloned()
+                    .collect();
+                for file_path in cached {
+                    files.remove(&file_path)?;
+                }
+                Ok(())
+            })
+        });
+        if let Err(e) = remove.await {
+            warn!(error = %e, "failed to invalidate cached entry")
+        }
+    }
+}
+
+fn hex_digest(bytes: &[u8]) -> String {
+    Sha256::digest(bytes)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// A `Storer` which reads through an `EntryCache`. Fresh entries are served without
+/// asking storage, and stale ones are served only if storage cannot be reached.
+/// Writes evict the path from the cache rather than updating it.
+#[derive(Clone)]
+pub struct EntryCachingStorer<H: Storer> {
+    inner: H,
+    cache: EntryCache,
+}
+
+impl<H: Storer> EntryCachingStorer<H> {
+    pub fn new(inner: H, cache: EntryCache) -> EntryCachingStorer<H> {
+        EntryCachingStorer { inner, cache }
+    }
+}
+
+// async_trait adds its own lifetime bounds to the ones required by `Storer`
+#[allow(clippy::multiple_bound_locations)]
+#[async_trait]
+impl<H: Storer> Storer for EntryCachingStorer<H> {
+    async fn get_indexed<T: HasBuilder + 'static>(
+        &self,
+        path: &str,
+        index: &Option<Document>,
+    ) -> Result<Entry, StorageError> {
+        if !self.cache.is_enabled() {
+            return self.inner.get_indexed::<T>(path, index).await;
+        }
+
+        let cached = self.cache.get(path, index).await;
+        if let Some((entry, true)) = cached {
+            ENTRY_CACHE_LOOKUPS.with_label_values(&["hit"]).inc();
+            return Ok(entry);
+        }
+
+        match self.inner.get_indexed::<T>(path, index).await {
+            Ok(entry) => {
+                ENTRY_CACHE_LOOKUPS.with_label_values(&["miss"]).inc();
+                self.cache.insert(path, index, &entry).await;
+                Ok(entry)
+            }
+            Err(StorageError::NotFound) => {
+                self.cache.invalidate(path).await;
+                Err(StorageError::NotFound)
+            }
+            Err(e) => match cached {
+                Some((entry, _)) => {
+                    warn!(error = %e, "storage is unreachable, serving a stale cached entry");
+                    ENTRY_CACHE_LOOKUPS.with_label_values(&["stale"]).inc();
+                    Ok(entry)
+                }
+                None => Err(e),
+            },
+        }
+    }
+
+    async fn list_indexed<T: HasBuilder + Send + 'static>(
+        &self,
+        path: &str,
+        skip: i64,
+        page_size: i64,
+        index: &Option<Document>,
+    ) -> Result<Vec<Entry>, StorageError> {
+        self.inner
+            .list_indexed::<T>(path, skip, page_size, index)
+            .await
+    }
+
+    async fn create(&self, path: EntryPath, value: States) -> Result<bool, StorageError> {
+        // Evicting afterwards also drops anything cached by a read made during the write
+        let result = self.inner.create(path.clone(), value).await;
+        self.cache.invalidate(&path).await;
+        result
+    }
+}
+
+/// A `Deleter` which evicts deleted entries from an `EntryCache`, so they are not
+/// served from it afterwards
+#[derive(Clone)]
+pub struct EntryCachingDeleter<D: Deleter> {
+    inner: D,
+    cache: EntryCache,
+}
+
+impl<D: Deleter> EntryCachingDeleter<D> {
+    pub fn new(inner: D, cache: EntryCache) -> EntryCachingDeleter<D> {
+        EntryCachingDeleter { inner, cache }
+    }
+}
+
+#[async_trait]
+impl<D: Deleter> Deleter for EntryCachingDeleter<D> {
+    async fn delete(&self, path: &str) -> Result<(), StorageError> {
+        let result = self.inner.delete(path).await;
+        self.cache.invalidate(path).await;
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EntryCache, EntryCachingDeleter, EntryCachingStorer};
+    use crate::key_cache::{KeyCache, KeyCachingStorer};
+    use crate::storage::{
+        tests::{CountingStorer, MockDeleter},
+        Deleter,
+    };
+    use mockall::predicate::*;
+    use redact_crypto::{
+        key::sodiumoxide::{SodiumOxideSymmetricKey, SodiumOxideSymmetricKeyBuilder},
+        ByteSource, Data, DataBuilder, HasBuilder, KeyBuilder, States, StorageError, Storer,
+        StringDataBuilder, SymmetricKey, SymmetricKeyBuilder, SymmetricSealer, TypeBuilder,
+        VectorByteSource,
+    };
+    use std::path::PathBuf;
+    use std::time::Duration;
+    use uuid::Uuid;
+
+    fn temp_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("redact-entry-cache-{}", Uuid::new_v4()))
+    }
+
+    fn sealed(value: &str) -> States {
+        let data = Data::String(value.to_owned());
+        States::Sealed {
+            builder: TypeBuilder::Data(data.builder()),
+            unsealable: SymmetricKey::SodiumOxide(SodiumOxideSymmetricKey::new())
+                .seal(data.into(), None, Some(".keys.default".to_owned()))
+                .unwrap(),
+        }
+    }
+
+    fn cached_files(dir: &PathBuf) -> usize {
+        std::fs::read_dir(dir)
+            .map(|files| files.count())
+            .unwrap_or(0)
+    }
+
+    #[tokio::test]
+    async fn test_fresh_entry_is_served_from_cache() {
+        let dir = temp_dir();
+        let inner = CountingStorer::default();
+        inner.insert(".profile.name.", sealed("Alice"));
+        let storer = EntryCachingStorer::new(
+            inner.clone(),
+            EntryCache::new(&dir, Duration::from_secs(60), 1024 * 1024),
+        );
+
+        storer.get::<Data>(".profile.name.").await.unwrap();
+        let entry = storer.get::<Data>(".profile.name.").await.unwrap();
+        assert!(matches!(entry.value, States::Sealed { .. }));
+        assert_eq!(inner.gets(".profile.name."), 1);
+        assert_eq!(cached_files(&dir), 1);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_unsealed_entries_are_never_cached() {
+        let dir = temp_dir();
+        let inner = CountingStorer::default();
+        inner.insert(
+            ".profile.name.",
+            States::Unsealed {
+                builder: TypeBuilder::Data(DataBuilder::String(StringDataBuilder {})),
+                bytes: ByteSource::Vector(VectorByteSource::new(b"Alice")),
+            },
+        );
+        let storer = EntryCachingStorer::new(
+            inner.clone(),
+            EntryCache::new(&dir, Duration::from_secs(60), 1024 * 1024),
+        );
+
+        storer.get::<Data>(".profile.name.").await.unwrap();
+        storer.get::<Data>(".profile.name.").await.unwrap();
+        assert_eq!(inner.gets(".profile.name."), 2);
+        assert_eq!(cached_files(&dir), 0);
+    }
+
+    #[tokio::test]
+    async fn test_offline_reads_need_the_key_held_in_memory() {
+        let dir = temp_dir();
+        let inner = CountingStorer::default();
+        let key = SodiumOxideSymmetricKey::new();
+        inner.insert(
+            ".keys.default",
+            States::Unsealed {
+                builder: TypeBuilder::Key(KeyBuilder::Symmetric(SymmetricKeyBuilder::SodiumOxide(
+                    SodiumOxideSymmetricKeyBuilder {},
+                ))),
+                bytes: ByteSource::Vector(VectorByteSource::new(key.key.as_ref())),
+            },
+        );
+        let data = Data::String("Alice".to_owned());
+        inner.insert(
+            ".profile.name.",
+            States::Sealed {
+                builder: TypeBuilder::Data(data.builder()),
+                unsealable: SymmetricKey::SodiumOxide(key)
+                    .seal(data.clone().into(), None, Some(".keys.default".to_owned()))
+                    .unwrap(),
+            },
+        );
+        let cached = EntryCachingStorer::new(
+            inner.clone(),
+            EntryCache::new(&dir, Duration::from_secs(0), 1024 * 1024),
+        );
+        let storer = KeyCachingStorer::new(cached.clone(), KeyCache::new(Duration::from_secs(60)));
+        let entry = storer.get::<Data>(".profile.name.").await.unwrap();
+        assert_eq!(storer.resolve::<Data>(entry.value).await.unwrap(), data);
+        // The unsealed key is not written to the cache
+        assert_eq!(cached_files(&dir), 1);
+
+        inner.set_unreachable(true);
+        let entry = storer.get::<Data>(".profile.name.").await.unwrap();
+        assert_eq!(storer.resolve::<Data>(entry.value).await.unwrap(), data);
+
+        // Once the key is no longer held in memory, the cached entry cannot be unsealed
+        let restarted = KeyCachingStorer::new(cached, KeyCache::new(Duration::from_secs(60)));
+        let entry = restarted.get::<Data>(".profile.name.").await.unwrap();
+        assert!(restarted.resolve::<Data>(entry.value).await.is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_stale_entry_is_served_only_while_unreachable() {
+        let dir = temp_dir();
+        let inner = CountingStorer::default();
+        inner.insert(".profile.name.", sealed("Alice"));
+        let storer = EntryCachingStorer::new(
+            inner.clone(),
+            EntryCache::new(&dir, Duration::from_secs(0), 1024 * 1024),
+        );
+
+        storer.get::<Data>(".profile.name.").await.unwrap();
+        storer.get::<Data>(".profile.name.").await.unwrap();
+        assert_eq!(inner.gets(".profile.name."), 2);
+
+        inner.set_unreachable(true);
+        assert!(storer.get::<Data>(".profile.name.").await.is_ok());
+        assert!(matches!(
+            storer.get::<Data>(".profile.age.").await,
+            Err(StorageError::InternalError { .. })
+        ));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_writes_invalidate_cached_entry() {
+        let dir = temp_dir();
+        let inner = CountingStorer::default();
+        inner.insert(".profile.name.", sealed("Alice"));
+        let mut deleter = MockDeleter::new();
+        deleter
+            .expect_delete()
+            .times(1)
+            .with(eq(".profile.name."))
+            .returning(|_| Ok(()));
+        let cache = EntryCache::new(&dir, Duration::from_secs(60), 1024 * 1024);
+        let storer = EntryCachingStorer::new(inner.clone(), cache.clone());
+        let deleter = EntryCachingDeleter::new(deleter, cache);
+
+        storer.get::<Data>(".profile.name.").await.unwrap();
+        storer
+            .create(".profile.name.".to_owned(), sealed("Bob"))
+            .await
+            .unwrap();
+        assert_eq!(cached_files(&dir), 0);
+        storer.get::<Data>(".profile.name.").await.unwrap();
+        assert_eq!(inner.gets(".profile.name."), 2);
+
+        assert_eq!(cached_files(&dir), 1);
+        deleter.delete(".profile.name.").await.unwrap();
+        assert_eq!(cached_files(&dir), 0);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_oldest_entries_are_evicted_over_size_limit() {
+        let dir = temp_dir();
+        let inner = CountingStorer::default();
+        for path in &[".a.", ".b.", ".c."] {
+            inner.insert(path, sealed("Alice"));
+        }
+        let unbounded = EntryCachingStorer::new(
+            inner.clone(),
+            EntryCache::new(&dir, Duration::from_secs(60), u64::MAX),
+        );
+        unbounded.get::<Data>(".a.").await.unwrap();
+        let entry_size = std::fs::read_dir(&dir)
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap()
+            .metadata()
+            .unwrap()
+            .len();
+
+        // Room for two entries but not three
+        let storer = EntryCachingStorer::new(
+            inner.clone(),
+            EntryCache::new(&dir, Duration::from_secs(60), entry_size * 5 / 2),
+        );
+        for path in &[".b.", ".c."] {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            storer.get::<Data>(path).await.unwrap();
+        }
+        assert_eq!(cached_files(&dir), 2);
+        storer.get::<Data>(".a.").await.unwrap();
+        storer.get::<Data>(".c.").await.unwrap();
+        assert_eq!(inner.gets(".a."), 2);
+        assert_eq!(inner.gets(".c."), 1);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_invalidated_entries_free_their_space() {
+        let dir = temp_dir();
+        let inner = CountingStorer::default();
+        for path in &[".a.", ".b.", ".c."] {
+            inner.insert(path, sealed("Alice"));
+        }
+        let storer = EntryCachingStorer::new(
+            inner.clone(),
+            EntryCache::new(&dir, Duration::from_secs(60), u64::MAX),
+        );
+        storer.get::<Data>(".a.").await.unwrap();
+        let entry_size = std::fs::read_dir(&dir)
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap()
+            .metadata()
+            .unwrap()
+            .len();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let storer = EntryCachingStorer::new(
+            inner.clone(),
+            EntryCache::new(&dir, Duration::from_secs(60), entry_size * 5 / 2),
+        );
+        storer.get::<Data>(".a.").await.unwrap();
+        storer.get::<Data>(".b.").await.unwrap();
+        storer
+            .create(".a.".to_owned(), sealed("Bob"))
+            .await
+            .unwrap();
+        storer.get::<Data>(".c.").await.unwrap();
+        assert_eq!(cached_files(&dir), 2);
+        storer.get::<Data>(".b.").await.unwrap();
+        assert_eq!(inner.gets(".b."), 1);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,49 +1,649 @@
+use crate::metrics::REJECTIONS;
+use crate::render::{
+    ErrorTemplateValues, RenderError, RenderTemplate, Rendered, Renderer, TemplateValues,
+};
+use crate::routes::error::{
+    MetricsRejection, ProxyRejection, ProxyResponseTooLargeRejection, ProxyUrlRejection,
+    RelayHistoryRejection, RelayUrlRejection,
+};
 use crate::routes::{
-    BadRequestRejection, IframeTokensDoNotMatchRejection, SessionTokenNotFoundRejection,
+    ApiTokenRejection, ApiTokenStoreRejection, CryptoErrorRejection, DataNotFoundRejection,
+    IframeTokensDoNotMatchRejection, SerializationRejection, SessionTokenNotFoundRejection,
+    StorageErrorRejection, TokenAlreadyRedeemedRejection, ValidationRejection,
 };
-use serde::Serialize;
+use crate::token::TokenGenerationError;
+use serde::{Deserialize, Serialize};
 use std::convert::Infallible;
-use warp::http::StatusCode;
-use warp::{Rejection, Reply};
-use crate::routes::error::RelayRejection;
+use tracing::{error, warn};
+use warp::http::{header::HeaderMap, Method, StatusCode};
+use warp::path::FullPath;
+use warp::reply::Response;
+use warp::{Filter, Rejection, Reply};
 
 /// An API error serializable to JSON.
 #[derive(Serialize)]
 struct ErrorMessage {
     code: u16,
+    error_code: &'static str,
     message: String,
 }
 
-// This function receives a `Rejection` and tries to return a custom
-// value, otherwise simply passes the rejection along.
-pub async fn handle_rejection(err: Rejection) -> Result<impl Reply, Infallible> {
-    let code;
-    let message;
-
-    if err.is_not_found() {
-        code = StatusCode::NOT_FOUND;
-        message = "NOT FOUND";
-    } else if err.find::<SessionTokenNotFoundRejection>().is_some() {
-        code = StatusCode::UNAUTHORIZED;
-        message = "SESSION TOKEN NOT FOUND";
-    } else if err.find::<IframeTokensDoNotMatchRejection>().is_some() {
-        code = StatusCode::UNAUTHORIZED;
-        message = "IFRAME TOKENS DO NOT MATCH";
-    } else if err.find::<BadRequestRejection>().is_some() {
-        code = StatusCode::BAD_REQUEST;
-        message = "BAD REQUEST";
-    } else if err.find::<RelayRejection>().is_some() {
-        code = StatusCode::INTERNAL_SERVER_ERROR;
-        message = "INTERNAL SERVER ERROR - Relay Error";
+/// Every kind of error the client can respond with. The string returned by `as_str`
+/// is a stable, machine-readable identifier which callers may match on, so existing
+/// values must never be renamed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorCode {
+    NotFound,
+    MethodNotAllowed,
+    BadRequest,
+    ValidationFailed,
+    SessionTokenNotFound,
+    IframeTokensDoNotMatch,
+    TokenAlreadyRedeemed,
+    ApiTokenInvalid,
+    DataNotFound,
+    StorageError,
+    RelayUrlNotAllowed,
+    RelayHistoryError,
+    ProxyError,
+    ProxyUrlNotAllowed,
+    ProxyResponseTooLarge,
+    CryptoError,
+    SerializationError,
+    RenderError,
+    TokenGenerationError,
+    MetricsError,
+    ApiTokenError,
+    InternalError,
+}
+
+impl ErrorCode {
+    pub fn from_rejection(err: &Rejection) -> ErrorCode {
+        if err.is_not_found() {
+            ErrorCode::NotFound
+        } else if err.find::<warp::reject::InvalidQuery>().is_some()
+            || err.find::<warp::body::BodyDeserializeError>().is_some()
+            || err.find::<warp::reject::UnsupportedMediaType>().is_some()
+        {
+            ErrorCode::BadRequest
+        } else if err.find::<ValidationRejection>().is_some() {
+            ErrorCode::ValidationFailed
+        } else if err.find::<SessionTokenNotFoundRejection>().is_some() {
+            ErrorCode::SessionTokenNotFound
+        } else if err.find::<IframeTokensDoNotMatchRejection>().is_some() {
+            ErrorCode::IframeTokensDoNotMatch
+        } else if err.find::<TokenAlreadyRedeemedRejection>().is_some() {
+            ErrorCode::TokenAlreadyRedeemed
+        } else if err.find::<ApiTokenRejection>().is_some() {
+            ErrorCode::ApiTokenInvalid
+        } else if err.find::<DataNotFoundRejection>().is_some() {
+            ErrorCode::DataNotFound
+        } else if err.find::<StorageErrorRejection>().is_some() {
+            ErrorCode::StorageError
+        } else if err.find::<RelayUrlRejection>().is_some() {
+            ErrorCode::RelayUrlNotAllowed
+        } else if err.find::<RelayHistoryRejection>().is_some() {
+            ErrorCode::RelayHistoryError
+        } else if err.find::<ProxyRejection>().is_some() {
+            ErrorCode::ProxyError
+        } else if err.find::<ProxyUrlRejection>().is_some() {
+            ErrorCode::ProxyUrlNotAllowed
+        } else if err.find::<ProxyResponseTooLargeRejection>().is_some() {
+            ErrorCode::ProxyResponseTooLarge
+        } else if err.find::<CryptoErrorRejection>().is_some() {
+            ErrorCode::CryptoError
+        } else if err.find::<SerializationRejection>().is_some() {
+            ErrorCode::SerializationError
+        } else if err.find::<RenderError>().is_some() {
+            ErrorCode::RenderError
+        } else if err.find::<TokenGenerationError>().is_some() {
+            ErrorCode::TokenGenerationError
+        } else if err.find::<MetricsRejection>().is_some() {
+            ErrorCode::MetricsError
+        } else if err.find::<ApiTokenStoreRejection>().is_some() {
+            ErrorCode::ApiTokenError
+        } else if err.find::<warp::reject::MethodNotAllowed>().is_some() {
+            // Every route matching on a method other than the request's rejects it
+            // this way, so it is only reported if no route gave a more specific reason
+            ErrorCode::MethodNotAllowed
+        } else {
+            ErrorCode::InternalError
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorCode::NotFound => "not_found",
+            ErrorCode::MethodNotAllowed => "method_not_allowed",
+            ErrorCode::BadRequest => "bad_request",
+            ErrorCode::ValidationFailed => "validation_failed",
+            ErrorCode::SessionTokenNotFound => "session_token_not_found",
+            ErrorCode::IframeTokensDoNotMatch => "iframe_tokens_do_not_match",
+            ErrorCode::TokenAlreadyRedeemed => "token_already_redeemed",
+            ErrorCode::ApiTokenInvalid => "api_token_invalid",
+            ErrorCode::DataNotFound => "data_not_found",
+            ErrorCode::StorageError => "storage_error",
+            ErrorCode::RelayUrlNotAllowed => "relay_url_not_allowed",
+            ErrorCode::RelayHistoryError => "relay_history_error",
+            ErrorCode::ProxyError => "proxy_error",
+            ErrorCode::ProxyUrlNotAllowed => "proxy_url_not_allowed",
+            ErrorCode::ProxyResponseTooLarge => "proxy_response_too_large",
+            ErrorCode::CryptoError => "crypto_error",
+            ErrorCode::SerializationError => "serialization_error",
+            ErrorCode::RenderError => "render_error",
+            ErrorCode::TokenGenerationError => "token_generation_error",
+            ErrorCode::MetricsError => "metrics_error",
+            ErrorCode::ApiTokenError => "api_token_error",
+            ErrorCode::InternalError => "internal_error",
+        }
+    }
+
+    /// Storage, relay and proxy failures are the fault of an upstream service
+    /// rather than of the client itself, and are reported as bad gateways
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ErrorCode::NotFound | ErrorCode::DataNotFound => StatusCode::NOT_FOUND,
+            ErrorCode::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+            ErrorCode::BadRequest => StatusCode::BAD_REQUEST,
+            ErrorCode::ValidationFailed => StatusCode::UNPROCESSABLE_ENTITY,
+            ErrorCode::SessionTokenNotFound
+            | ErrorCode::IframeTokensDoNotMatch
+            | ErrorCode::TokenAlreadyRedeemed
+            | ErrorCode::ApiTokenInvalid => StatusCode::UNAUTHORIZED,
+            ErrorCode::RelayUrlNotAllowed | ErrorCode::ProxyUrlNotAllowed => StatusCode::FORBIDDEN,
+            ErrorCode::StorageError | ErrorCode::ProxyError | ErrorCode::ProxyResponseTooLarge => {
+                StatusCode::BAD_GATEWAY
+            }
+            ErrorCode::CryptoError
+            | ErrorCode::SerializationError
+            | ErrorCode::RenderError
+            | ErrorCode::TokenGenerationError
+            | ErrorCode::MetricsError
+            | ErrorCode::ApiTokenError
+            | ErrorCode::RelayHistoryError
+            | ErrorCode::InternalError => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    pub fn message(&self) -> &'static str {
+        match self {
+            ErrorCode::NotFound => "NOT FOUND",
+            ErrorCode::MethodNotAllowed => "METHOD NOT ALLOWED",
+            ErrorCode::BadRequest => "BAD REQUEST",
+            ErrorCode::ValidationFailed => "VALIDATION FAILED",
+            ErrorCode::SessionTokenNotFound => "SESSION TOKEN NOT FOUND",
+            ErrorCode::IframeTokensDoNotMatch => "IFRAME TOKENS DO NOT MATCH",
+            ErrorCode::TokenAlreadyRedeemed => "TOKEN ALREADY REDEEMED",
+            ErrorCode::ApiTokenInvalid => "API TOKEN INVALID",
+            ErrorCode::DataNotFound => "DATA NOT FOUND",
+            ErrorCode::StorageError => "BAD GATEWAY - Storage Error",
+            ErrorCode::RelayUrlNotAllowed => "FORBIDDEN - Relay URL Not Allowed",
+            ErrorCode::RelayHistoryError => "INTERNAL SERVER ERROR - Relay History Error",
+            ErrorCode::ProxyError => "BAD GATEWAY - Proxy Error",
+            ErrorCode::ProxyUrlNotAllowed => "FORBIDDEN - Proxy URL Not Allowed",
+            ErrorCode::ProxyResponseTooLarge => "BAD GATEWAY - Proxy Response Too Large",
+            ErrorCode::CryptoError => "INTERNAL SERVER ERROR - Crypto Error",
+            ErrorCode::SerializationError => "INTERNAL SERVER ERROR - Serialization Error",
+            ErrorCode::RenderError => "INTERNAL SERVER ERROR - Render Error",
+            ErrorCode::TokenGenerationError => "INTERNAL SERVER ERROR - Token Generation Error",
+            ErrorCode::MetricsError => "INTERNAL SERVER ERROR - Metrics Error",
+            ErrorCode::ApiTokenError => "INTERNAL SERVER ERROR - API Token Error",
+            ErrorCode::InternalError => "INTERNAL SERVER ERROR",
+        }
+    }
+}
+
+/// Whether an error should be returned as JSON for API callers, or as an HTML page
+/// which can be displayed in place of the secure iframe
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorFormat {
+    Json,
+    Html,
+}
+
+impl ErrorFormat {
+    /// Callers asking for JSON always get it. Otherwise, browsers tag navigations
+    /// inside an iframe with `Sec-Fetch-Dest: iframe`, and page loads accept `text/html`.
+    pub fn from_headers(headers: &HeaderMap) -> ErrorFormat {
+        let header = |name| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or("")
+                .to_ascii_lowercase()
+        };
+        let accept = header("accept");
+        let dest = header("sec-fetch-dest");
+
+        if accept.contains("application/json") {
+            ErrorFormat::Json
+        } else if dest == "iframe" || dest == "frame" || accept.contains("text/html") {
+            ErrorFormat::Html
+        } else {
+            ErrorFormat::Json
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
+struct ErrorQueryParams {
+    css: Option<String>,
+}
+
+/// What is known about a failed request when building its error response
+#[derive(Debug, Clone, PartialEq)]
+pub struct ErrorContext {
+    pub format: ErrorFormat,
+    pub css: Option<String>,
+    pub retry_url: Option<String>,
+}
+
+impl ErrorContext {
+    fn new(
+        headers: &HeaderMap,
+        method: &Method,
+        path: &str,
+        query: &str,
+        css: Option<String>,
+    ) -> ErrorContext {
+        ErrorContext {
+            format: ErrorFormat::from_headers(headers),
+            css,
+            retry_url: retry_url(method, path, query),
+        }
+    }
+}
+
+/// Displaying data can be retried by reloading the unsecure page for the same path,
+/// which issues a new token and session
+fn retry_url(method: &Method, path: &str, query: &str) -> Option<String> {
+    if method != Method::GET {
+        return None;
+    }
+    let segments: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+    match segments.as_slice() {
+        ["data", data_path] | ["data", data_path, _] => Some(if query.is_empty() {
+            format!("/data/{}", data_path)
+        } else {
+            format!("/data/{}?{}", data_path, query)
+        }),
+        _ => None,
+    }
+}
+
+fn error_context() -> impl Filter<Extract = (ErrorContext,), Error = Infallible> + Clone {
+    warp::header::headers_cloned()
+        .and(warp::method())
+        .and(warp::path::full())
+        .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+        .and(
+            warp::query::<ErrorQueryParams>()
+                .or(warp::any().map(ErrorQueryParams::default))
+                .unify(),
+        )
+        .map(
+            |headers: HeaderMap,
+             method: Method,
+             path: FullPath,
+             query: String,
+             params: ErrorQueryParams| {
+                ErrorContext::new(&headers, &method, path.as_str(), &query, params.css)
+            },
+        )
+}
+
+/// Wraps the given filter so that any rejection it produces is turned into an error
+/// response, formatted according to where the request came from
+pub fn recover<F, R, E>(
+    filter: F,
+    render_engine: E,
+) -> impl Filter<Extract = (Response,), Error = Infallible> + Clone
+where
+    F: Filter<Extract = (R,), Error = Rejection> + Clone + Send + Sync + 'static,
+    R: Reply,
+    E: Renderer,
+{
+    error_context()
+        .and(
+            filter
+                .map(|reply: R| Ok(reply.into_response()))
+                .recover(|err: Rejection| async move { Ok::<_, Infallible>(Err(err)) })
+                .unify(),
+        )
+        .map(
+            move |context: ErrorContext, result: Result<Response, Rejection>| match result {
+                Ok(response) => response,
+                Err(err) => handle_rejection(err, context, render_engine.clone()),
+            },
+        )
+}
+
+/// Describes the error carried by a rejection, for logging
+fn rejection_detail(err: &Rejection) -> Option<String> {
+    if let Some(StorageErrorRejection(e)) = err.find() {
+        Some(e.to_string())
+    } else if let Some(CryptoErrorRejection(e)) = err.find() {
+        Some(e.to_string())
+    } else if let Some(SerializationRejection(e)) = err.find() {
+        Some(e.to_string())
+    } else if let Some(ProxyRejection(e)) = err.find() {
+        Some(e.to_string())
+    } else if let Some(ProxyUrlRejection(e)) = err.find() {
+        Some(e.to_string())
+    } else if let Some(RelayHistoryRejection(e)) = err.find() {
+        Some(e.to_string())
+    } else if let Some(MetricsRejection(e)) = err.find() {
+        Some(e.to_string())
+    } else if let Some(ApiTokenStoreRejection(e)) = err.find() {
+        Some(e.to_string())
+    } else if err.find::<RenderError>().is_some() {
+        // Render errors can quote the values being rendered, which may be decrypted
+        // data, so only their type is logged
+        None
+    } else {
+        Some(format!("{:?}", err))
+    }
+}
+
+pub fn handle_rejection<E: Renderer>(
+    err: Rejection,
+    context: ErrorContext,
+    render_engine: E,
+) -> Response {
+    let error_code = ErrorCode::from_rejection(&err);
+    let code = error_code.status();
+
+    let rejection = error_code.as_str();
+    let detail = rejection_detail(&err);
+    if code.is_server_error() {
+        error!(rejection, detail = ?detail, status = code.as_u16(), "request failed");
     } else {
-        code = StatusCode::INTERNAL_SERVER_ERROR;
-        message = "INTERNAL SERVER ERROR";
+        warn!(rejection, detail = ?detail, status = code.as_u16(), "request rejected");
+    }
+
+    REJECTIONS.with_label_values(&[rejection]).inc();
+
+    match context.format {
+        ErrorFormat::Json => {
+            let json = warp::reply::json(&ErrorMessage {
+                code: code.as_u16(),
+                error_code: error_code.as_str(),
+                message: error_code.message().into(),
+            });
+            warp::reply::with_status(json, code).into_response()
+        }
+        ErrorFormat::Html => {
+            let rendered = Rendered::new(
+                render_engine,
+                RenderTemplate {
+                    name: "error",
+                    value: TemplateValues::Error(ErrorTemplateValues {
+                        code: code.as_u16(),
+                        error_code: error_code.as_str().to_owned(),
+                        message: error_code.message().to_owned(),
+                        css: context.css,
+                        retry_url: context.retry_url,
+                    }),
+                },
+            );
+            match rendered {
+                Ok(rendered) => warp::reply::with_status(rendered, code).into_response(),
+                Err(e) => {
+                    error!(error = %e, "failed to render error page");
+                    let html = warp::reply::html(format!(
+                        "<!DOCTYPE html>\n<html>\n<head><title>{status}</title></head>\n<body>\n<p id=\"error\" data-error-code=\"{error_code}\">{message}</p>\n</body>\n</html>\n",
+                        status = code.as_u16(),
+                        error_code = error_code.as_str(),
+                        message = error_code.message(),
+                    ));
+                    warp::reply::with_status(html, code).into_response()
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{recover, retry_url, ErrorCode};
+    use crate::relayer::RelayError;
+    use crate::render::{
+        tests::MockRenderer, ErrorTemplateValues, RenderError, RenderTemplate, TemplateValues,
+    };
+    use crate::routes::error::ProxyRejection;
+    use crate::routes::{
+        DataNotFoundRejection, SessionTokenNotFoundRejection, StorageErrorRejection,
+        ValidationRejection,
+    };
+    use handlebars::RenderError as HandlebarsRenderError;
+    use redact_crypto::StorageError;
+    use serde_json::Value;
+    use std::sync::Arc;
+    use warp::http::Method;
+    use warp::{Filter, Rejection};
+
+    fn rejecting(
+        rejection: fn() -> Rejection,
+    ) -> impl Filter<Extract = (String,), Error = Rejection> + Clone {
+        warp::path("data").and_then(move || async move { Err(rejection()) })
+    }
+
+    async fn json_error(
+        filter: impl Filter<Extract = (String,), Error = Rejection> + Clone + Send + Sync + 'static,
+    ) -> (u16, Value) {
+        let res = warp::test::request()
+            .path("/data/.testKey.")
+            .reply(&recover(filter, Arc::new(MockRenderer::new())))
+            .await;
+        assert_eq!(res.headers()["content-type"], "application/json");
+        (
+            res.status().as_u16(),
+            serde_json::from_slice(res.body()).unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_data_not_found() {
+        let (status, body) =
+            json_error(rejecting(|| warp::reject::custom(DataNotFoundRejection))).await;
+        assert_eq!(status, 404);
+        assert_eq!(body["code"], 404);
+        assert_eq!(body["error_code"], "data_not_found");
+    }
+
+    #[tokio::test]
+    async fn test_validation_failed() {
+        let (status, body) =
+            json_error(rejecting(|| warp::reject::custom(ValidationRejection))).await;
+        assert_eq!(status, 422);
+        assert_eq!(body["error_code"], "validation_failed");
+    }
+
+    #[tokio::test]
+    async fn test_upstream_failures_are_bad_gateways() {
+        let (status, body) = json_error(rejecting(|| {
+            warp::reject::custom(StorageErrorRejection(StorageError::NotFound))
+        }))
+        .await;
+        assert_eq!(status, 502);
+        assert_eq!(body["error_code"], "storage_error");
+
+        let (status, body) = json_error(rejecting(|| {
+            warp::reject::custom(ProxyRejection(RelayError::AttestationUnavailable))
+        }))
+        .await;
+        assert_eq!(status, 502);
+        assert_eq!(body["error_code"], "proxy_error");
+    }
+
+    #[tokio::test]
+    async fn test_method_not_allowed_is_least_specific() {
+        // A request rejected by one route is also rejected by every route expecting
+        // another method
+        let filter = warp::get()
+            .and(rejecting(|| {
+                warp::reject::custom(SessionTokenNotFoundRejection)
+            }))
+            .or(warp::post().and(warp::path("data")).map(|| "".to_owned()))
+            .unify();
+        let (status, body) = json_error(filter).await;
+        assert_eq!(status, 401);
+        assert_eq!(body["error_code"], "session_token_not_found");
+
+        let res = warp::test::request()
+            .method("PUT")
+            .path("/data/.testKey.")
+            .reply(&recover(
+                warp::get().and(warp::path("data")).map(|| "".to_owned()),
+                Arc::new(MockRenderer::new()),
+            ))
+            .await;
+        assert_eq!(res.status(), 405);
+    }
+
+    #[tokio::test]
+    async fn test_unmatched_route() {
+        let res = warp::test::request()
+            .path("/unknown")
+            .reply(&recover(
+                rejecting(|| warp::reject::custom(DataNotFoundRejection)),
+                Arc::new(MockRenderer::new()),
+            ))
+            .await;
+        assert_eq!(res.status(), 404);
+        let body: Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["error_code"], "not_found");
+    }
+
+    #[tokio::test]
+    async fn test_passes_replies_through() {
+        let res = warp::test::request()
+            .path("/data/.testKey.")
+            .reply(&recover(
+                warp::path!("data" / String),
+                Arc::new(MockRenderer::new()),
+            ))
+            .await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.body(), ".testKey.");
+    }
+
+    #[tokio::test]
+    async fn test_html_error_inside_iframe() {
+        let mut render_engine = MockRenderer::new();
+        render_engine
+            .expect_render()
+            .withf(|template: &RenderTemplate| {
+                let expected_value = TemplateValues::Error(ErrorTemplateValues {
+                    code: 401,
+                    error_code: "iframe_tokens_do_not_match".to_owned(),
+                    message: "IFRAME TOKENS DO NOT MATCH".to_owned(),
+                    css: Some("p { color: red; }".to_owned()),
+                    retry_url: Some(
+                        "/data/.testKey.?css=p%20%7B%20color%3A%20red%3B%20%7D&edit=true"
+                            .to_owned(),
+                    ),
+                });
+                template.name == "error" && template.value == expected_value
+            })
+            .times(1)
+            .return_once(|_| Ok("<p>error</p>".to_owned()));
+
+        let res = warp::test::request()
+            .path("/data/.testKey./ABC?css=p%20%7B%20color%3A%20red%3B%20%7D&edit=true")
+            .header("sec-fetch-dest", "iframe")
+            .header("accept", "text/html,application/xhtml+xml,*/*;q=0.8")
+            .reply(&recover(
+                rejecting(|| warp::reject::custom(crate::routes::IframeTokensDoNotMatchRejection)),
+                Arc::new(render_engine),
+            ))
+            .await;
+        assert_eq!(res.status(), 401);
+        assert_eq!(res.headers()["content-type"], "text/html; charset=utf-8");
+        assert_eq!(res.body(), "<p>error</p>");
     }
 
-    let json = warp::reply::json(&ErrorMessage {
-        code: code.as_u16(),
-        message: message.into(),
-    });
+    #[tokio::test]
+    async fn test_html_error_falls_back_when_render_fails() {
+        let mut render_engine = MockRenderer::new();
+        render_engine.expect_render().times(1).return_once(|_| {
+            Err(RenderError::RenderError {
+                source: HandlebarsRenderError::new("template not found"),
+            })
+        });
 
-    Ok(warp::reply::with_status(json, code))
+        let res = warp::test::request()
+            .path("/data/.testKey./ABC")
+            .header("sec-fetch-dest", "iframe")
+            .reply(&recover(
+                rejecting(|| warp::reject::custom(DataNotFoundRejection)),
+                Arc::new(render_engine),
+            ))
+            .await;
+        assert_eq!(res.status(), 404);
+        assert_eq!(res.headers()["content-type"], "text/html; charset=utf-8");
+        let body = String::from_utf8(res.body().to_vec()).unwrap();
+        assert!(body.contains("data-error-code=\"data_not_found\""));
+    }
+
+    #[tokio::test]
+    async fn test_json_error_for_api_clients() {
+        let res = warp::test::request()
+            .path("/data/.testKey./ABC")
+            .header("sec-fetch-dest", "iframe")
+            .header("accept", "application/json")
+            .reply(&recover(
+                rejecting(|| warp::reject::custom(DataNotFoundRejection)),
+                Arc::new(MockRenderer::new()),
+            ))
+            .await;
+        assert_eq!(res.status(), 404);
+        assert_eq!(res.headers()["content-type"], "application/json");
+    }
+
+    #[test]
+    fn test_retry_url() {
+        assert_eq!(
+            retry_url(&Method::GET, "/data/.testKey./ABC", "css=a&edit=true"),
+            Some("/data/.testKey.?css=a&edit=true".to_owned())
+        );
+        assert_eq!(
+            retry_url(&Method::GET, "/data/.testKey.", ""),
+            Some("/data/.testKey.".to_owned())
+        );
+        assert_eq!(retry_url(&Method::POST, "/data/ABC", ""), None);
+        assert_eq!(retry_url(&Method::GET, "/metrics", ""), None);
+    }
+
+    #[test]
+    fn test_error_codes_are_unique() {
+        let codes = [
+            ErrorCode::NotFound,
+            ErrorCode::MethodNotAllowed,
+            ErrorCode::BadRequest,
+            ErrorCode::ValidationFailed,
+            ErrorCode::SessionTokenNotFound,
+            ErrorCode::IframeTokensDoNotMatch,
+            ErrorCode::TokenAlreadyRedeemed,
+            ErrorCode::ApiTokenInvalid,
+            ErrorCode::DataNotFound,
+            ErrorCode::StorageError,
+            ErrorCode::RelayUrlNotAllowed,
+            ErrorCode::RelayHistoryError,
+            ErrorCode::ProxyError,
+            ErrorCode::ProxyUrlNotAllowed,
+            ErrorCode::ProxyResponseTooLarge,
+            ErrorCode::CryptoError,
+            ErrorCode::SerializationError,
+            ErrorCode::RenderError,
+            ErrorCode::TokenGenerationError,
+            ErrorCode::MetricsError,
+            ErrorCode::ApiTokenError,
+            ErrorCode::InternalError,
+        ];
+        let mut names: Vec<&str> = codes.iter().map(ErrorCode::as_str).collect();
+        names.sort_unstable();
+        names.dedup();
+        assert_eq!(names.len(), codes.len());
+    }
 }
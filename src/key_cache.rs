@@ -0,0 +1,367 @@
+use crate::metrics::KEY_CACHE_LOOKUPS;
+use crate::storage::resolve_state;
+use async_trait::async_trait;
+use redact_crypto::{
+    key::sodiumoxide::SodiumOxideSymmetricKey, Data, Entry, EntryPath, HasBuilder, HasIndex,
+    KeyBuilder, States, StorageError, Storer, SymmetricKey, TypeBuilder,
+};
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+type Document = <Data as HasIndex>::Index;
+
+/// How long a resolved key is kept unless configured otherwise
+pub const DEFAULT_KEY_CACHE_TTL: Duration = Duration::from_secs(300);
+
+struct CachedKey {
+    key: Arc<SymmetricKey>,
+    cached_at: Instant,
+}
+
+/// Keeps resolved symmetric keys in memory for a limited time, by the path they are
+/// stored at. Key material is zeroed once the last reference to an evicted key is
+/// dropped, as the underlying sodiumoxide key zeroes itself on drop.
+#[derive(Clone)]
+pub struct KeyCache {
+    keys: Arc<Mutex<HashMap<String, CachedKey>>>,
+    ttl: Duration,
+}
+
+impl KeyCache {
+    /// A ttl of zero disables caching
+    pub fn new(ttl: Duration) -> KeyCache {
+        KeyCache {
+            keys: Arc::new(Mutex::new(HashMap::new())),
+            ttl,
+        }
+    }
+
+    /// Returns the key cached for the path, evicting it if it has expired
+    pub fn get(&self, path: &str) -> Option<Arc<SymmetricKey>> {
+        let mut keys = self.keys.lock().unwrap();
+        match keys.get(path) {
+            Some(cached) if cached.cached_at.elapsed() < self.ttl => Some(cached.key.clone()),
+            Some(_) => {
+                keys.remove(path);
+                None
+            }
+            None => None,
+        }
+    }
+
+    pub fn insert(&self, path: &str, key: SymmetricKey) {
+        if self.ttl.is_zero() {
+            return;
+        }
+        let mut keys = self.keys.lock().unwrap();
+        let ttl = self.ttl;
+        keys.retain(|_, cached| cached.cached_at.elapsed() < ttl);
+        keys.insert(
+            path.to_owned(),
+            CachedKey {
+                key: Arc::new(key),
+                cached_at: Instant::now(),
+            },
+        );
+    }
+
+    /// Evicts the key cached for the path, returning false if there was none
+    pub fn invalidate(&self, path: &str) -> bool {
+        self.keys.lock().unwrap().remove(path).is_some()
+    }
+}
+
+impl Default for KeyCache {
+    fn default() -> Self {
+        KeyCache::new(DEFAULT_KEY_CACHE_TTL)
+    }
+}
+
+fn is_symmetric_key(value: &States) -> bool {
+    matches!(
+        value,
+        States::Unsealed {
+            builder: TypeBuilder::Key(KeyBuilder::Symmetric(_)),
+            ..
+        } | States::Sealed {
+            builder: TypeBuilder::Key(KeyBuilder::Symmetric(_)),
+            ..
+        }
+    )
+}
+
+/// Clones the cached key as the type being resolved. Unsealing resolves the
+/// sodiumoxide key inside the `SymmetricKey` rather than the enum itself.
+fn cached_as<T: 'static>(key: &SymmetricKey) -> Option<T> {
+    let key: Box<dyn Any> = match key {
+        SymmetricKey::SodiumOxide(sosk)
+            if TypeId::of::<T>() == TypeId::of::<SodiumOxideSymmetricKey>() =>
+        {
+            Box::new(sosk.clone())
+        }
+        key => Box::new(key.clone()),
+    };
+    key.downcast::<T>().ok().map(|key| *key)
+}
+
+/// A `Storer` which serves symmetric keys from a `KeyCache`, including the lookups
+/// made while unsealing entries. Any entry found to be a symmetric key is resolved
+/// and cached; other entries pass straight through. Writing to a path evicts the key
+/// cached for it, so a rotated key is picked up immediately.
+///
+/// A cached key is never copied out as bytes: looking it up returns a reference to
+/// its own path, which only this storer resolves, to a clone of the cached key.
+#[derive(Clone)]
+pub struct KeyCachingStorer<H: Storer> {
+    inner: H,
+    cache: KeyCache,
+}
+
+impl<H: Storer> KeyCachingStorer<H> {
+    pub fn new(inner: H, cache: KeyCache) -> KeyCachingStorer<H> {
+        KeyCachingStorer { inner, cache }
+    }
+
+    /// Looks the entry up in the wrapped storer, caching it if it is a symmetric key
+    async fn fetch<T: HasBuilder + 'static>(
+        &self,
+        path: &str,
+        index: &Option<Document>,
+    ) -> Result<Entry, StorageError> {
+        let entry = self.inner.get_indexed::<T>(path, index).await?;
+        if is_symmetric_key(&entry.value) {
+            KEY_CACHE_LOOKUPS.with_label_values(&["miss"]).inc();
+            // A key which fails to resolve is left for the caller to report
+            if let Ok(key) = self.resolve::<SymmetricKey>(entry.value.clone()).await {
+                self.cache.insert(path, key);
+            }
+        }
+        Ok(entry)
+    }
+}
+
+// async_trait adds its own lifetime bounds to the ones required by `Storer`
+#[allow(clippy::multiple_bound_locations)]
+#[async_trait]
+impl<H: Storer> Storer for KeyCachingStorer<H> {
+    async fn get_indexed<T: HasBuilder + 'static>(
+        &self,
+        path: &str,
+        index: &Option<Document>,
+    ) -> Result<Entry, StorageError> {
+        match self.cache.get(path) {
+            Some(key) => Ok(Entry {
+                path: path.to_owned(),
+                value: States::Referenced {
+                    builder: TypeBuilder::Key(KeyBuilder::Symmetric(key.builder())),
+                    path: path.to_owned(),
+                },
+            }),
+            None => self.fetch::<T>(path, index).await,
+        }
+    }
+
+    async fn list_indexed<T: HasBuilder + Send + 'static>(
+        &self,
+        path: &str,
+        skip: i64,
+        page_size: i64,
+        index: &Option<Document>,
+    ) -> Result<Vec<Entry>, StorageError> {
+        self.inner
+            .list_indexed::<T>(path, skip, page_size, index)
+            .await
+    }
+
+    async fn create(&self, path: EntryPath, value: States) -> Result<bool, StorageError> {
+        self.cache.invalidate(&path);
+        self.inner.create(path, value).await
+    }
+
+    async fn resolve_indexed<T: HasBuilder + 'static>(
+        &self,
+        state: States,
+        index: &Option<Document>,
+    ) -> Result<T, StorageError> {
+        match state {
+            States::Referenced { ref path, .. } => {
+                if let Some(key) = self.cache.get(path).and_then(|key| cached_as::<T>(&key)) {
+                    KEY_CACHE_LOOKUPS.with_label_values(&["hit"]).inc();
+                    return Ok(key);
+                }
+                // Bypasses the cache, which could otherwise answer with the same reference
+                let entry = self.fetch::<T>(path, index).await?;
+                self.resolve_indexed::<T>(entry.value, index).await
+            }
+            state => resolve_state(self, state, index).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{KeyCache, KeyCachingStorer};
+    use crate::metrics::KEY_CACHE_LOOKUPS;
+    use crate::routes::data::{fetch_data, fetch_data_batch};
+    use crate::storage::tests::CountingStorer;
+    use redact_crypto::{
+        key::sodiumoxide::{SodiumOxideSymmetricKey, SodiumOxideSymmetricKeyBuilder},
+        ByteSource, Data, DataBuilder, HasBuilder, KeyBuilder, States, Storer, StringDataBuilder,
+        SymmetricKey, SymmetricKeyBuilder, SymmetricSealer, TypeBuilder, VectorByteSource,
+    };
+    use std::time::Duration;
+
+    fn unsealed_key(sosk: &SodiumOxideSymmetricKey) -> States {
+        States::Unsealed {
+            builder: TypeBuilder::Key(KeyBuilder::Symmetric(SymmetricKeyBuilder::SodiumOxide(
+                SodiumOxideSymmetricKeyBuilder {},
+            ))),
+            bytes: ByteSource::Vector(VectorByteSource::new(sosk.key.as_ref())),
+        }
+    }
+
+    fn storer_with_key(sosk: &SodiumOxideSymmetricKey) -> CountingStorer {
+        let storer = CountingStorer::default();
+        storer.insert(".keys.default", unsealed_key(sosk));
+        storer
+    }
+
+    #[tokio::test]
+    async fn test_key_is_cached() {
+        let sosk = SodiumOxideSymmetricKey::new();
+        let inner = storer_with_key(&sosk);
+        let storer = KeyCachingStorer::new(inner.clone(), KeyCache::default());
+        let hits_before = KEY_CACHE_LOOKUPS.with_label_values(&["hit"]).get();
+
+        for _ in 0..3 {
+            let entry = storer.get::<SymmetricKey>(".keys.default").await.unwrap();
+            let key: SymmetricKey = storer.resolve(entry.value).await.unwrap();
+            let SymmetricKey::SodiumOxide(cached) = key;
+            assert_eq!(cached.key.as_ref(), sosk.key.as_ref());
+        }
+        assert_eq!(inner.gets(".keys.default"), 1);
+        assert!(KEY_CACHE_LOOKUPS.with_label_values(&["hit"]).get() >= hits_before + 2);
+    }
+
+    #[tokio::test]
+    async fn test_unsealing_uses_cached_key() {
+        let sosk = SodiumOxideSymmetricKey::new();
+        let inner = storer_with_key(&sosk);
+        let data = Data::String("Alice".to_owned());
+        inner.insert(
+            ".profile.name.",
+            States::Sealed {
+                builder: TypeBuilder::Data(data.builder()),
+                unsealable: SymmetricKey::SodiumOxide(sosk)
+                    .seal(data.clone().into(), None, Some(".keys.default".to_owned()))
+                    .unwrap(),
+            },
+        );
+        let storer = KeyCachingStorer::new(inner.clone(), KeyCache::default());
+
+        for _ in 0..3 {
+            assert_eq!(
+                fetch_data(&storer, ".profile.name.").await.unwrap(),
+                Some(data.clone())
+            );
+        }
+        assert_eq!(inner.gets(".keys.default"), 1);
+        assert_eq!(inner.gets(".profile.name."), 3);
+    }
+
+    #[tokio::test]
+    async fn test_cached_key_bytes_are_not_copied() {
+        let inner = storer_with_key(&SodiumOxideSymmetricKey::new());
+        let storer = KeyCachingStorer::new(inner.clone(), KeyCache::default());
+        storer.get::<SymmetricKey>(".keys.default").await.unwrap();
+
+        let entry = storer.get::<SymmetricKey>(".keys.default").await.unwrap();
+        match entry.value {
+            States::Referenced { path, .. } => assert_eq!(path, ".keys.default"),
+            _ => panic!("expected the cached key to be returned by reference"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_batch_unsealing_uses_cached_key() {
+        let sosk = SodiumOxideSymmetricKey::new();
+        let inner = storer_with_key(&sosk);
+        let data = Data::String("Alice".to_owned());
+        inner.insert(
+            ".profile.name.",
+            States::Sealed {
+                builder: TypeBuilder::Data(data.builder()),
+                unsealable: SymmetricKey::SodiumOxide(sosk)
+                    .seal(data.clone().into(), None, Some(".keys.default".to_owned()))
+                    .unwrap(),
+            },
+        );
+        let storer = KeyCachingStorer::new(inner.clone(), KeyCache::default());
+        let paths = vec![".profile.name.".to_owned()];
+
+        for _ in 0..2 {
+            let fields = fetch_data_batch(&storer, &paths).await.unwrap();
+            assert_eq!(fields[0].data, Some(data.clone()));
+        }
+        assert_eq!(inner.gets(".keys.default"), 1);
+    }
+
+    #[tokio::test]
+    async fn test_other_entries_are_not_cached() {
+        let inner = CountingStorer::default();
+        inner.insert(
+            ".profile.name.",
+            States::Unsealed {
+                builder: TypeBuilder::Data(DataBuilder::String(StringDataBuilder {})),
+                bytes: ByteSource::Vector(VectorByteSource::new(b"Alice")),
+            },
+        );
+        let storer = KeyCachingStorer::new(inner.clone(), KeyCache::default());
+
+        storer.get::<Data>(".profile.name.").await.unwrap();
+        storer.get::<Data>(".profile.name.").await.unwrap();
+        assert_eq!(inner.gets(".profile.name."), 2);
+    }
+
+    #[tokio::test]
+    async fn test_cached_key_expires() {
+        let inner = storer_with_key(&SodiumOxideSymmetricKey::new());
+        let storer = KeyCachingStorer::new(inner.clone(), KeyCache::new(Duration::from_millis(50)));
+
+        storer.get::<SymmetricKey>(".keys.default").await.unwrap();
+        storer.get::<SymmetricKey>(".keys.default").await.unwrap();
+        assert_eq!(inner.gets(".keys.default"), 1);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        storer.get::<SymmetricKey>(".keys.default").await.unwrap();
+        assert_eq!(inner.gets(".keys.default"), 2);
+    }
+
+    #[tokio::test]
+    async fn test_zero_ttl_disables_cache() {
+        let inner = storer_with_key(&SodiumOxideSymmetricKey::new());
+        let storer = KeyCachingStorer::new(inner.clone(), KeyCache::new(Duration::from_secs(0)));
+
+        storer.get::<SymmetricKey>(".keys.default").await.unwrap();
+        storer.get::<SymmetricKey>(".keys.default").await.unwrap();
+        assert_eq!(inner.gets(".keys.default"), 2);
+    }
+
+    #[tokio::test]
+    async fn test_rotation_invalidates_cached_key() {
+        let inner = storer_with_key(&SodiumOxideSymmetricKey::new());
+        let storer = KeyCachingStorer::new(inner.clone(), KeyCache::default());
+        storer.get::<SymmetricKey>(".keys.default").await.unwrap();
+
+        let rotated = SodiumOxideSymmetricKey::new();
+        storer
+            .create(".keys.default".to_owned(), unsealed_key(&rotated))
+            .await
+            .unwrap();
+        let entry = storer.get::<SymmetricKey>(".keys.default").await.unwrap();
+        let SymmetricKey::SodiumOxide(key) = storer.resolve(entry.value).await.unwrap();
+        assert_eq!(key.key.as_ref(), rotated.key.as_ref());
+        assert_eq!(inner.gets(".keys.default"), 2);
+    }
+}
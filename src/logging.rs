@@ -0,0 +1,242 @@
+use crate::metrics::route_label;
+use hyper::{
+    header::HeaderValue,
+    service::{make_service_fn, service_fn, Service},
+    Body, Request, Response,
+};
+use std::convert::Infallible;
+use std::fmt::{self, Display, Formatter};
+use std::net::SocketAddr;
+use std::time::Instant;
+use tracing::{info, info_span, Instrument};
+use tracing_subscriber::EnvFilter;
+use uuid::Uuid;
+use warp::{Filter, Rejection, Reply};
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+const MAX_REQUEST_ID_LENGTH: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+/// Installs the global tracing subscriber. The level filter is taken from `RUST_LOG`,
+/// defaulting to `info`.
+pub fn init(format: LogFormat) {
+    let env_filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let builder = tracing_subscriber::fmt().with_env_filter(env_filter);
+    let result = match format {
+        LogFormat::Json => builder.json().try_init(),
+        LogFormat::Text => builder.try_init(),
+    };
+    if let Err(e) = result {
+        eprintln!("failed to install log subscriber: {}", e);
+    }
+}
+
+/// Identifies a single request across log lines, storage calls and relayed notifications
+#[derive(Debug, Clone, PartialEq)]
+pub struct RequestId(String);
+
+impl RequestId {
+    pub fn new() -> RequestId {
+        RequestId(Uuid::new_v4().to_string())
+    }
+
+    /// Reuses a caller-provided ID only if it is short and made of characters that are
+    /// safe to echo into logs and headers, otherwise generates a fresh one.
+    pub fn from_header(value: Option<&str>) -> RequestId {
+        match value {
+            Some(id)
+                if !id.is_empty()
+                    && id.len() <= MAX_REQUEST_ID_LENGTH
+                    && id
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.') =>
+            {
+                RequestId(id.to_owned())
+            }
+            _ => RequestId::new(),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Default for RequestId {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Display for RequestId {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Extracts the ID of the current request, as assigned by `serve`
+pub fn request_id() -> impl Filter<Extract = (RequestId,), Error = Rejection> + Clone {
+    warp::header::optional::<String>(REQUEST_ID_HEADER)
+        .map(|value: Option<String>| RequestId::from_header(value.as_deref()))
+}
+
+/// Serves the given filter, running every request inside a span tagged with its
+/// request ID. The ID is added to the request headers so handlers can read it, and
+/// echoed back in the response.
+pub async fn serve<F, R>(filter: F, addr: impl Into<SocketAddr>) -> Result<(), hyper::Error>
+where
+    F: Filter<Extract = (R,), Error = Infallible> + Clone + Send + Sync + 'static,
+    R: Reply,
+{
+    let service = warp::service(filter);
+    let make_service = make_service_fn(move |_| {
+        let service = service.clone();
+        async move { Ok::<_, Infallible>(service_fn(move |req| handle(service.clone(), req))) }
+    });
+
+    hyper::Server::bind(&addr.into()).serve(make_service).await
+}
+
+async fn handle<S>(mut service: S, mut req: Request<Body>) -> Result<Response<Body>, Infallible>
+where
+    S: Service<Request<Body>, Response = Response<Body>, Error = Infallible>,
+{
+    let request_id = RequestId::from_header(
+        req.headers()
+            .get(REQUEST_ID_HEADER)
+            .and_then(|value| value.to_str().ok()),
+    );
+    let header_value = HeaderValue::from_str(request_id.as_str())
+        .unwrap_or_else(|_| HeaderValue::from_static("invalid"));
+    req.headers_mut()
+        .insert(REQUEST_ID_HEADER, header_value.clone());
+
+    let span = info_span!(
+        "request",
+        request_id = %request_id,
+        method = %req.method(),
+        route = route_label(req.method(), req.uri().path()),
+    );
+
+    async move {
+        let start = Instant::now();
+        let mut response = service.call(req).await?;
+        info!(
+            status = response.status().as_u16(),
+            latency_ms = start.elapsed().as_millis() as u64,
+            "request completed"
+        );
+        response
+            .headers_mut()
+            .insert(REQUEST_ID_HEADER, header_value);
+        Ok(response)
+    }
+    .instrument(span)
+    .await
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::{handle, RequestId, REQUEST_ID_HEADER};
+    use hyper::{Body, Request};
+    use std::io;
+    use std::sync::{Arc, Mutex};
+    use tracing::subscriber::DefaultGuard;
+    use warp::Filter;
+
+    #[derive(Clone, Default)]
+    pub struct CapturedLogs(Arc<Mutex<Vec<u8>>>);
+
+    impl CapturedLogs {
+        pub fn contents(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+        }
+    }
+
+    impl io::Write for CapturedLogs {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Captures every event logged on the current thread, at every level, until the
+    /// returned guard is dropped
+    pub fn capture_logs() -> (CapturedLogs, DefaultGuard) {
+        let logs = CapturedLogs::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::TRACE)
+            .with_writer(move || writer.clone())
+            .finish();
+        (logs, tracing::subscriber::set_default(subscriber))
+    }
+
+    #[test]
+    fn test_request_id_from_valid_header() {
+        assert_eq!(
+            RequestId::from_header(Some("abc-123_x.y")).as_str(),
+            "abc-123_x.y"
+        );
+    }
+
+    #[test]
+    fn test_request_id_from_invalid_header() {
+        for invalid in &["", "has space", "new\nline", &"a".repeat(65)] {
+            let id = RequestId::from_header(Some(invalid));
+            assert_ne!(id.as_str(), *invalid);
+            assert_eq!(id.as_str().len(), 36);
+        }
+        assert_eq!(RequestId::from_header(None).as_str().len(), 36);
+    }
+
+    #[tokio::test]
+    async fn test_handle_assigns_request_id() {
+        let filter = super::request_id().map(|id: RequestId| id.to_string());
+        let (logs, _guard) = capture_logs();
+
+        let res = handle(
+            warp::service(filter),
+            Request::builder()
+                .uri("/data/.a.")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+        let id = res.headers()[REQUEST_ID_HEADER]
+            .to_str()
+            .unwrap()
+            .to_owned();
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        assert_eq!(body, id.as_bytes());
+        assert!(logs.contents().contains(&id));
+    }
+
+    #[tokio::test]
+    async fn test_handle_preserves_request_id() {
+        let filter = super::request_id().map(|id: RequestId| id.to_string());
+
+        let res = handle(
+            warp::service(filter),
+            Request::builder()
+                .uri("/healthz")
+                .header(REQUEST_ID_HEADER, "upstream-id")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(res.headers()[REQUEST_ID_HEADER], "upstream-id");
+    }
+}
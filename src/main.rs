@@ -1,25 +1,72 @@
+mod api_token;
+mod attestation;
+mod backend;
+mod entry_cache;
 mod error_handler;
+mod key_cache;
+mod logging;
+mod metrics;
 pub mod render;
 mod routes;
 pub mod token;
+mod relay_history;
+mod relay_outbox;
+mod relay_policy;
 mod relayer;
+mod replication;
+mod startup;
+mod storage;
+mod write_queue;
 
-use crate::error_handler::handle_rejection;
-use redact_config::Configurator;
-use redact_crypto::{
-    key::sodiumoxide::SodiumOxideSymmetricKey, ByteSource, HasBuilder, RedactStorer, States,
-    Storer, SymmetricKey, VectorByteSource,
+use api_token::{ApiTokenStore, FileApiTokenStore};
+use backend::{FsStorer, MemoryStorer, SqliteStorer, StorageBackend};
+use entry_cache::{
+    EntryCache, EntryCachingDeleter, EntryCachingStorer, DEFAULT_ENTRY_CACHE_MAX_BYTES,
+    DEFAULT_ENTRY_CACHE_TTL,
 };
+use key_cache::{KeyCache, KeyCachingStorer, DEFAULT_KEY_CACHE_TTL};
+use logging::LogFormat;
+use redact_config::Configurator;
+use replication::{ReplicatedStorer, WritePolicy};
 use render::HandlebarsRenderer;
-use serde::Serialize;
+use startup::{load_default_key, Readiness, RetryPolicy, StartupError};
 use std::collections::HashMap;
-use token::FromThreadRng;
+use std::error::Error;
+use std::process;
+use std::time::Duration;
+use storage::storage_client;
+use token::{
+    FromThreadRng, TokenGenerator, RedeemedTokens, TokenEncoding, TokenOptions, TokenSigner,
+    DEFAULT_REDEEMED_TOKEN_TTL, MIN_TOKEN_LENGTH,
+};
+use tracing::{error, info, warn};
 use warp::Filter;
+use write_queue::{ConflictPolicy, PendingWriteStorer, WriteQueue, DEFAULT_SYNC_INTERVAL};
 use warp_sessions::MemoryStore;
-use crate::relayer::MutualTLSRelayer;
+use crate::metrics::MeteredStorer;
+use crate::relayer::{MutualTLSRelayer, RelayIdentity, DEFAULT_RELAY_TIMEOUT};
+use crate::relay_policy::{AllowlistEntry, HostOrigins, RelayPolicy};
+use crate::routes::proxy::DEFAULT_PROXY_MAX_RESPONSE_BYTES;
+use crate::relay_history::{HistoryRelayer, RelayHistory, DEFAULT_RELAY_HISTORY_MAX_ENTRIES};
+use crate::relay_outbox::{
+    OutboxRelayer, RelayOutbox, DEFAULT_DELIVERY_INTERVAL, DEFAULT_RELAY_RETRY_POLICY,
+};
+use crate::attestation::{load_signing_key, Attestor};
+
+/// Signing keys shorter than this are rejected at startup
+const MIN_SIGNING_KEY_LENGTH: usize = 32;
+
+/// Where API token digests are kept unless configured otherwise
+const DEFAULT_API_TOKEN_PATH: &str = "./api-tokens.json";
+
+/// Where relays waiting to be retried are kept unless configured otherwise
+const DEFAULT_RELAY_OUTBOX_PATH: &str = "./relay-outbox";
 
-#[derive(Serialize)]
-struct Healthz {}
+/// Where relay attempts are recorded unless configured otherwise
+const DEFAULT_RELAY_HISTORY_PATH: &str = "./relay-history.jsonl";
+
+/// Signed tokens live as long as the session cookies they stand in for by default
+const DEFAULT_SIGNED_TOKEN_TTL: Duration = Duration::from_secs(60);
 
 fn get_port<T: Configurator>(config: &T) -> u16 {
     match config.get_int("server.port") {
@@ -27,7 +74,7 @@ fn get_port<T: Configurator>(config: &T) -> u16 {
             if (1..65536).contains(&port) {
                 port as u16
             } else {
-                println!(
+                warn!(
                     "listen port value '{}' is not between 1 and 65535, defaulting to 8080",
                     port
                 );
@@ -38,17 +85,429 @@ fn get_port<T: Configurator>(config: &T) -> u16 {
             match e {
                 // Suppress debug logging if server.port was simply not set
                 redact_config::ConfigError::NotFound(_) => (),
-                _ => println!("{}", e),
+                _ => warn!("{}", e),
             }
             8080
         }
     }
 }
 
+/// Reads a setting which cannot be negative, returning `None` if it is missing or
+/// invalid so that the caller falls back to its default
+fn get_u64<T: Configurator>(config: &T, key: &str) -> Option<u64> {
+    match config.get_int(key) {
+        Ok(value) if value >= 0 => Some(value as u64),
+        Ok(value) => {
+            warn!("{} value '{}' cannot be negative, using default", key, value);
+            None
+        }
+        Err(e) => {
+            match e {
+                redact_config::ConfigError::NotFound(_) => (),
+                _ => warn!("{}", e),
+            }
+            None
+        }
+    }
+}
+
+/// Reads the retry policy configured under the prefix, e.g. `storage.retry`
+fn get_retry_policy<T: Configurator>(
+    config: &T,
+    prefix: &str,
+    default: RetryPolicy,
+) -> RetryPolicy {
+    RetryPolicy {
+        max_attempts: get_u64(config, &format!("{}.maxattempts", prefix))
+            .map(|n| n.clamp(1, u32::MAX as u64) as u32)
+            .unwrap_or(default.max_attempts),
+        initial_backoff: get_u64(config, &format!("{}.initialbackoffms", prefix))
+            .map(Duration::from_millis)
+            .unwrap_or(default.initial_backoff),
+        max_backoff: get_u64(config, &format!("{}.maxbackoffms", prefix))
+            .map(Duration::from_millis)
+            .unwrap_or(default.max_backoff),
+    }
+}
+
+fn get_key_cache_ttl<T: Configurator>(config: &T) -> Duration {
+    get_u64(config, "storage.keycache.ttlsecs")
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_KEY_CACHE_TTL)
+}
+
+/// Reads a list of strings set either as a list or as a comma-separated string,
+/// dropping empty entries
+fn get_str_list<T: Configurator>(config: &T, key: &str) -> Vec<String> {
+    let values: Vec<String> = match config.get_array(key) {
+        Ok(values) => values
+            .into_iter()
+            .filter_map(|value| value.into_str().ok())
+            .collect(),
+        Err(_) => match config.get_str(key) {
+            Ok(values) => values
+                .split(',')
+                .map(|value| value.trim().to_owned())
+                .collect(),
+            Err(_) => vec![],
+        },
+    };
+    values.into_iter().filter(|value| !value.is_empty()).collect()
+}
+
+/// Reads `storage.urls` as either a list or a comma-separated string, returning
+/// `None` if no URLs are set
+fn get_storage_urls<T: Configurator>(config: &T) -> Option<Vec<String>> {
+    let urls = get_str_list(config, "storage.urls");
+    if urls.is_empty() {
+        None
+    } else {
+        Some(urls)
+    }
+}
+
+/// Relays and proxied requests are only sent to URLs under an entry of
+/// `<prefix>.allowlist` for the origin of the host page. Plain HTTP and private
+/// network targets are refused unless explicitly allowed, for development.
+fn get_relay_policy<T: Configurator>(
+    config: &T,
+    prefix: &str,
+) -> Result<RelayPolicy, StartupError> {
+    let key = format!("{}.allowlist", prefix);
+    let allowlist = match config.get::<Vec<AllowlistEntry>>(&key) {
+        Ok(allowlist) => allowlist,
+        Err(redact_config::ConfigError::NotFound(_)) => vec![],
+        Err(source) => return Err(StartupError::ConfigError { key, source }),
+    };
+    RelayPolicy::new(
+        &allowlist,
+        config
+            .get_bool(&format!("{}.allowhttp", prefix))
+            .unwrap_or(false),
+        config
+            .get_bool(&format!("{}.allowprivatenetworks", prefix))
+            .unwrap_or(false),
+    )
+    .map_err(|source| StartupError::RelayPolicyError { key, source })
+}
+
+fn get_write_policy<T: Configurator>(config: &T) -> WritePolicy {
+    match config.get_str("storage.replication.writepolicy") {
+        Ok(policy) if policy.eq_ignore_ascii_case("all") => WritePolicy::All,
+        Ok(policy) if policy.eq_ignore_ascii_case("quorum") => WritePolicy::Quorum,
+        Ok(policy) => {
+            warn!(
+                "storage.replication.writepolicy value '{}' is not all or quorum, using default",
+                policy
+            );
+            WritePolicy::Quorum
+        }
+        Err(_) => WritePolicy::Quorum,
+    }
+}
+
+/// The identity presented to relay URLs. A PKCS#12 archive takes precedence over PEM
+/// files when both are configured.
+fn get_relay_identity<T: Configurator>(config: &T) -> Result<RelayIdentity, StartupError> {
+    if let Ok(path) = config.get_str("certificate.pkcs12.filepath") {
+        return Ok(RelayIdentity::Pkcs12 {
+            path,
+            passphrase: config
+                .get_str("certificate.pkcs12.passphrase")
+                .unwrap_or_default(),
+        });
+    }
+    let path = get_str(config, "certificate.filepath")?;
+    Ok(match config.get_str("certificate.key.filepath") {
+        Ok(key_path) => RelayIdentity::PemPair {
+            cert_path: path,
+            key_path,
+        },
+        Err(_) => RelayIdentity::Pem { path },
+    })
+}
+
+/// Storage requests present a client identity and pin the store's CA once
+/// `storage.tls.ca.filepath` is set. The identity defaults to the one used for relays.
+fn get_storage_client<T: Configurator>(config: &T) -> Result<reqwest::Client, StartupError> {
+    let ca_path = config.get_str("storage.tls.ca.filepath").ok();
+    let identity = match config.get_str("storage.tls.identity.filepath") {
+        Ok(path) => Some(RelayIdentity::Pem { path }),
+        Err(_) if ca_path.is_some() => Some(get_relay_identity(config)?),
+        Err(_) => None,
+    };
+    storage_client(identity.as_ref(), ca_path.as_deref())
+        .map_err(|source| StartupError::StorageTlsError { source })
+}
+
+/// Talks to redact-store at `storage.url`, or replicates across `storage.urls`, unless
+/// another backend is selected. Local backends keep their data at `storage.path`.
+fn get_storage_backend<T: Configurator>(
+    config: &T,
+    client: reqwest::Client,
+) -> Result<StorageBackend, StartupError> {
+    let backend = match config.get_str("storage.backend") {
+        Ok(backend) => backend.to_ascii_lowercase(),
+        Err(_) => "redact-store".to_owned(),
+    };
+    match backend.as_str() {
+        "redact-store" => match get_storage_urls(config) {
+            Some(urls) => Ok(StorageBackend::Replicated(ReplicatedStorer::new(
+                urls.iter()
+                    .map(|url| StorageBackend::redact_store(url, client.clone()))
+                    .collect(),
+                get_write_policy(config),
+            ))),
+            None => Ok(StorageBackend::redact_store(
+                &get_str(config, "storage.url")?,
+                client,
+            )),
+        },
+        "local-fs" => Ok(StorageBackend::LocalFs(FsStorer::new(get_str(
+            config,
+            "storage.path",
+        )?))),
+        "sqlite" => SqliteStorer::open(get_str(config, "storage.path")?)
+            .map(StorageBackend::Sqlite)
+            .map_err(|source| StartupError::StorageBackendError { source }),
+        "memory" => {
+            warn!("using in-memory storage, all data will be lost when the client exits");
+            Ok(StorageBackend::Memory(MemoryStorer::default()))
+        }
+        _ => Err(StartupError::UnknownStorageBackend { backend }),
+    }
+}
+
+/// The entry cache is only enabled if a directory is configured for it
+fn get_entry_cache<T: Configurator>(config: &T) -> EntryCache {
+    let dir = match config.get_str("storage.cache.path") {
+        Ok(dir) => dir,
+        Err(_) => return EntryCache::disabled(),
+    };
+    EntryCache::new(
+        dir,
+        get_u64(config, "storage.cache.ttlsecs")
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_ENTRY_CACHE_TTL),
+        get_u64(config, "storage.cache.maxbytes").unwrap_or(DEFAULT_ENTRY_CACHE_MAX_BYTES),
+    )
+}
+
+/// The relay outbox is enabled unless `relay.outbox.enabled` is false, so that relays
+/// which fail after the value was stored are retried rather than given up on
+fn get_relay_outbox<T: Configurator>(config: &T) -> RelayOutbox {
+    if let Ok(false) = config.get_bool("relay.outbox.enabled") {
+        return RelayOutbox::disabled();
+    }
+    RelayOutbox::new(
+        config
+            .get_str("relay.outbox.path")
+            .unwrap_or_else(|_| DEFAULT_RELAY_OUTBOX_PATH.to_owned()),
+        get_retry_policy(config, "relay.retry", DEFAULT_RELAY_RETRY_POLICY),
+    )
+}
+
+fn get_relay_history<T: Configurator>(config: &T) -> RelayHistory {
+    RelayHistory::new(
+        config
+            .get_str("relay.history.path")
+            .unwrap_or_else(|_| DEFAULT_RELAY_HISTORY_PATH.to_owned()),
+        get_u64(config, "relay.history.maxentries")
+            .map(|entries| entries as usize)
+            .unwrap_or(DEFAULT_RELAY_HISTORY_MAX_ENTRIES),
+    )
+}
+
+/// The write queue is only enabled if a directory is configured for it
+fn get_write_queue<T: Configurator>(config: &T) -> WriteQueue {
+    let dir = match config.get_str("storage.queue.path") {
+        Ok(dir) => dir,
+        Err(_) => return WriteQueue::disabled(),
+    };
+    let on_conflict = match config.get_str("storage.queue.onconflict") {
+        Ok(policy) if policy.eq_ignore_ascii_case("keep-local") => ConflictPolicy::KeepLocal,
+        Ok(policy) if policy.eq_ignore_ascii_case("keep-remote") => ConflictPolicy::KeepRemote,
+        Ok(policy) => {
+            warn!(
+                "storage.queue.onconflict value '{}' is not keep-remote or keep-local, using default",
+                policy
+            );
+            ConflictPolicy::KeepRemote
+        }
+        Err(_) => ConflictPolicy::KeepRemote,
+    };
+    WriteQueue::new(dir, on_conflict)
+}
+
+fn get_token_options<T: Configurator>(config: &T) -> Result<TokenOptions, StartupError> {
+    let default = TokenOptions::default();
+    let warn_unless_missing = |e: redact_config::ConfigError| match e {
+        redact_config::ConfigError::NotFound(_) => (),
+        _ => warn!("{}", e),
+    };
+
+    let length = match config.get_int("token.length") {
+        Ok(length) if (MIN_TOKEN_LENGTH as i64..=1024).contains(&length) => length as usize,
+        Ok(length) => {
+            warn!(
+                "token.length value '{}' is not between {} and 1024 bytes, using default",
+                length, MIN_TOKEN_LENGTH
+            );
+            default.length
+        }
+        Err(e) => {
+            warn_unless_missing(e);
+            default.length
+        }
+    };
+
+    let encoding = match config.get_str("token.encoding") {
+        Ok(encoding) if encoding.eq_ignore_ascii_case("hex") => TokenEncoding::Hex,
+        Ok(encoding) if encoding.eq_ignore_ascii_case("base64url") => TokenEncoding::Base64Url,
+        Ok(encoding) => {
+            warn!(
+                "token.encoding value '{}' is not one of hex or base64url, using default",
+                encoding
+            );
+            default.encoding
+        }
+        Err(e) => {
+            warn_unless_missing(e);
+            default.encoding
+        }
+    };
+
+    // Path tokens are only signed if a key is configured
+    let signer = match config.get_str("token.signing.key") {
+        Ok(key) => {
+            let key = base64::decode(&key)
+                .ok()
+                .filter(|key| key.len() >= MIN_SIGNING_KEY_LENGTH)
+                .ok_or(StartupError::TokenSigningKeyError {
+                    min_length: MIN_SIGNING_KEY_LENGTH,
+                })?;
+            let ttl = match config.get_int("token.signing.ttlsecs") {
+                Ok(ttl) if ttl > 0 => Duration::from_secs(ttl as u64),
+                Ok(ttl) => {
+                    warn!(
+                        "token.signing.ttlsecs value '{}' must be positive, using default",
+                        ttl
+                    );
+                    DEFAULT_SIGNED_TOKEN_TTL
+                }
+                Err(e) => {
+                    warn_unless_missing(e);
+                    DEFAULT_SIGNED_TOKEN_TTL
+                }
+            };
+            Some(TokenSigner::new(key, ttl))
+        }
+        Err(redact_config::ConfigError::NotFound(_)) => None,
+        Err(source) => {
+            return Err(StartupError::ConfigError {
+                key: "token.signing.key".to_owned(),
+                source,
+            })
+        }
+    };
+
+    Ok(TokenOptions {
+        length,
+        encoding,
+        signer,
+    })
+}
+
+fn get_log_format<T: Configurator>(config: &T) -> LogFormat {
+    match config.get_str("logging.format") {
+        Ok(format) if format.eq_ignore_ascii_case("json") => LogFormat::Json,
+        _ => LogFormat::Text,
+    }
+}
+
+fn get_api_token_path<T: Configurator>(config: &T) -> String {
+    config
+        .get_str("api.tokens.path")
+        .unwrap_or_else(|_| DEFAULT_API_TOKEN_PATH.to_owned())
+}
+
+fn get_str<T: Configurator>(config: &T, key: &str) -> Result<String, StartupError> {
+    config
+        .get_str(key)
+        .map_err(|source| StartupError::ConfigError {
+            key: key.to_owned(),
+            source,
+        })
+}
+
 #[tokio::main]
 async fn main() {
     // Extract config with a REDACT env var prefix
-    let config = redact_config::new("REDACT").unwrap();
+    let config = redact_config::new("REDACT");
+    logging::init(
+        config
+            .as_ref()
+            .map(get_log_format)
+            .unwrap_or(LogFormat::Text),
+    );
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let result = match config {
+        Ok(config) if args.first().map(String::as_str) == Some("api-token") => {
+            run_api_token_command(config, &args[1..])
+        }
+        Ok(config) => run(config).await,
+        Err(source) => Err(StartupError::ConfigLoadError { source }),
+    };
+    if let Err(e) = result {
+        let mut causes = Vec::new();
+        let mut source = e.source();
+        while let Some(cause) = source {
+            causes.push(cause.to_string());
+            source = cause.source();
+        }
+        error!(error = %e, causes = ?causes, "failed to start");
+        process::exit(1);
+    }
+}
+
+/// Lets the user issue, revoke and list the API tokens held by local applications.
+/// A newly issued token is printed once and cannot be recovered afterwards.
+fn run_api_token_command<T: Configurator>(config: T, args: &[String]) -> Result<(), StartupError> {
+    let api_tokens = FileApiTokenStore::new(get_api_token_path(&config));
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match args.as_slice() {
+        ["issue", app] => {
+            let token = FromThreadRng::with_options(get_token_options(&config)?)
+                .generate_token()
+                .map_err(|source| StartupError::ApiTokenGenerationError { source })?;
+            api_tokens
+                .issue(app, &token)
+                .map_err(|source| StartupError::ApiTokenError { source })?;
+            println!("{}", token);
+        }
+        ["revoke", app] => {
+            if !api_tokens
+                .revoke(app)
+                .map_err(|source| StartupError::ApiTokenError { source })?
+            {
+                warn!("no API token was issued to {}", app);
+            }
+        }
+        ["list"] => {
+            for app in api_tokens
+                .list()
+                .map_err(|source| StartupError::ApiTokenError { source })?
+            {
+                println!("{}", app);
+            }
+        }
+        _ => return Err(StartupError::ApiTokenUsageError),
+    }
+    Ok(())
+}
+
+async fn run<T: Configurator>(config: T) -> Result<(), StartupError> {
 
     // Determine port to listen on
     let port = get_port(&config);
@@ -57,52 +516,94 @@ async fn main() {
     let mut template_mapping = HashMap::new();
     template_mapping.insert("unsecure", "./static/unsecure.handlebars");
     template_mapping.insert("secure", "./static/secure.handlebars");
-    let render_engine = HandlebarsRenderer::new(template_mapping).unwrap();
+    template_mapping.insert("batch", "./static/batch.handlebars");
+    template_mapping.insert("error", "./static/error.handlebars");
+    template_mapping.insert("relays", "./static/relays.handlebars");
+    let render_engine = HandlebarsRenderer::new(template_mapping)
+        .map_err(|source| StartupError::TemplateLoadError { source })?;
 
-    // Create a relay client which supports mutual TLS
-    let relayer = MutualTLSRelayer::new(config.get_str("certificate.filepath").unwrap())
-        .unwrap();
+    // Create a relay client which supports mutual TLS and signs what it relays as the
+    // configured user once their signing key has been loaded
+    let readiness = Readiness::new();
+    let user_id = get_str(&config, "user.id")?;
+    let attestor = Attestor::default();
+    let relayer = MutualTLSRelayer::new(
+        &get_relay_identity(&config)?,
+        config.get_str("certificate.ca.filepath").ok().as_deref(),
+        attestor.clone(),
+        get_relay_policy(&config, "relay")?,
+        get_u64(&config, "relay.timeoutsecs")
+            .filter(|secs| *secs > 0)
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_RELAY_TIMEOUT),
+    )
+        .map_err(|source| StartupError::RelayerError { source })?;
+    readiness.set_tls_identity_expiry(relayer.identity_expires_at());
+    // Relays sent after a value is stored are retried in the background if they fail,
+    // and every attempt is recorded for the user to review
+    let relay_outbox = get_relay_outbox(&config);
+    let relay_history = get_relay_history(&config);
+    let outbox_relayer = HistoryRelayer::new(
+        OutboxRelayer::new(relayer.clone(), relay_outbox.clone()),
+        relay_history.clone(),
+    );
 
     // Get storage handle
-    let storage_url = config.get_str("storage.url").unwrap();
-
-    // Get the bootstrap key from config
-    //let bootstrap_identity: Key = config.get::<Key>("crypto.bootstrapidentity").unwrap();
-    let storer = RedactStorer::new(&storage_url);
-    let default_sym_key_result = storer.get::<SymmetricKey>(".keys.default").await;
-    let default_sym_key = match default_sym_key_result {
-        Ok(e) => storer.resolve::<SymmetricKey>(e.value).await,
-        Err(e) => match e {
-            _ => {
-                let key = SodiumOxideSymmetricKey::new();
-                let bytes = ByteSource::Vector(VectorByteSource::new(key.key.as_ref()));
-                let builder = key.builder().into();
-
-                storer
-                    .create(
-                        ".keys.default".to_owned(),
-                        States::Unsealed { builder, bytes },
-                    )
-                    .await
-                    .unwrap();
-                Ok(SymmetricKey::SodiumOxide(key))
-            }
-        },
+    let backend = get_storage_backend(&config, get_storage_client(&config)?)?;
+    // A store whose certificate is rejected will never become reachable, so fail now
+    // rather than retrying
+    if config.get_str("storage.tls.ca.filepath").is_ok() {
+        for storer in backend.http_storers() {
+            storer
+                .verify_certificate()
+                .await
+                .map_err(|source| StartupError::StorageTlsError { source })?;
+        }
     }
-    .unwrap();
+    // Resolved keys and sealed entries are cached for all routes, while readiness
+    // checks and the versions queued writes are compared against always come from
+    // storage
+    let entry_cache = get_entry_cache(&config);
+    let write_queue = get_write_queue(&config);
+    let cached_storer = EntryCachingStorer::new(backend.clone(), entry_cache.clone());
+    let storer = MeteredStorer::new(KeyCachingStorer::new(
+        PendingWriteStorer::new(cached_storer.clone(), write_queue.clone()),
+        KeyCache::new(get_key_cache_ttl(&config)),
+    ));
+    let uncached_storer = MeteredStorer::new(backend.clone());
+    let deleter = EntryCachingDeleter::new(backend, entry_cache);
+    let retry_policy = get_retry_policy(&config, "storage.retry", RetryPolicy::default());
 
     // Create an in-memory session store
     let session_store = MemoryStore::new();
 
     // Create a token generator
-    let token_generator = FromThreadRng::new();
+    let token_options = get_token_options(&config)?;
+    // Redeemed tokens must be remembered for as long as any of them could still be valid
+    let redeemed_tokens = RedeemedTokens::new(
+        token_options
+            .signer
+            .as_ref()
+            .map_or(DEFAULT_REDEEMED_TOKEN_TTL, |signer| {
+                signer.ttl().max(DEFAULT_REDEEMED_TOKEN_TTL)
+            }),
+    );
+    let token_generator = FromThreadRng::with_options(token_options);
+    // The host page each token was issued to, whose allowlist relay URLs are held to
+    let host_origins = HostOrigins::default();
+
+    // Tokens issued to local applications by the api-token command
+    let api_tokens = FileApiTokenStore::new(get_api_token_path(&config));
 
     // Create a CORS filter for the insecure routes that allows any origin
     let unsecure_cors = warp::cors().allow_any_origin().allow_methods(vec!["GET"]);
+    // Pages can only read safelisted headers of a cross-origin response unless they
+    // are exposed, which the proxy's Range and ETag support depends on
     let unsecure_cors_post = warp::cors()
         .allow_any_origin()
         .allow_methods(vec!["GET", "POST", "OPTIONS"])
-        .allow_headers(vec!["content-type"]);
+        .allow_headers(vec!["content-type", "range", "if-range", "if-none-match"])
+        .expose_headers(vec!["etag", "content-range", "accept-ranges", "content-length"]);
 
     // Create a CORS filter for the secure route that allows only localhost origin
     let secure_cors = warp::cors()
@@ -110,46 +611,125 @@ async fn main() {
         .allow_methods(vec!["GET", "POST"]);
 
     // Build out routes
-    let health_route = warp::path!("healthz").map(|| warp::reply::json(&Healthz {}));
+    let health_route = routes::health::healthz();
+    let ready_route = routes::health::readyz(
+        session_store.clone(),
+        uncached_storer.clone(),
+        readiness.clone(),
+    );
+    let metrics_route = routes::metrics::metrics(session_store.clone());
     let post_routes = warp::post()
-        .and(routes::data::post::submit_data(
+        .and(routes::submit_data(
             session_store.clone(),
             render_engine.clone(),
             token_generator.clone(),
             storer.clone(),
-            relayer.clone()
+            outbox_relayer.clone(),
+            redeemed_tokens.clone(),
+            write_queue.clone(),
+        ))
+        .with(secure_cors.clone());
+    let handshake_route = warp::post()
+        .and(routes::handshake::post(
+            session_store.clone(),
+            token_generator.clone(),
+            host_origins.clone(),
         ))
         .with(secure_cors.clone());
     let get_routes = warp::get().and(
-        routes::data::get::with_token(
+        routes::with_token(
             session_store.clone(),
             render_engine.clone(),
             token_generator.clone(),
             storer.clone(),
+            redeemed_tokens,
+            host_origins.clone(),
         )
         .with(unsecure_cors.clone())
-        .or(routes::data::get::without_token(
+        .or(routes::without_token(
             session_store.clone(),
             render_engine.clone(),
             token_generator.clone(),
+            host_origins,
         )
         .with(secure_cors)),
     );
+    let relay_history_route = warp::get().and(routes::relays::history(
+        api_tokens.clone(),
+        relay_history.clone(),
+        render_engine.clone(),
+    ));
+
+    let api_routes = warp::get()
+        .and(routes::api::get(api_tokens.clone(), storer.clone()))
+        .or(warp::put().and(routes::api::put(api_tokens.clone(), storer.clone())))
+        .or(warp::delete().and(routes::api::delete(api_tokens, deleter)));
 
     let proxy_routes = warp::any().and(
         warp::post().and(
-            routes::proxy::post(relayer)
+            routes::proxy::post(
+                relayer.clone(),
+                get_relay_policy(&config, "proxy")?,
+                get_u64(&config, "proxy.maxresponsebytes")
+                    .map(|bytes| bytes as usize)
+                    .unwrap_or(DEFAULT_PROXY_MAX_RESPONSE_BYTES),
+            )
         ))
         .with(unsecure_cors_post.clone());
 
-    let routes = health_route
-        .or(get_routes)
-        .or(post_routes)
-        .or(proxy_routes)
-        .with(warp::log("routes"))
-        .recover(handle_rejection);
+    let routes = error_handler::recover(
+        health_route
+            .or(ready_route)
+            .or(metrics_route)
+            .or(get_routes)
+            .or(handshake_route)
+            .or(post_routes)
+            .or(api_routes)
+            .or(relay_history_route)
+            .or(proxy_routes),
+        render_engine,
+    )
+    .with(warp::log::custom(metrics::observe_request));
 
-    // Start the server
-    println!("starting server listening on ::{}", port);
-    warp::serve(routes).run(([0, 0, 0, 0], port)).await;
+    // Start the server, reporting not-ready until the default key has been loaded
+    info!("starting server listening on ::{}", port);
+    let server = tokio::spawn(logging::serve(routes, ([0, 0, 0, 0], port)));
+
+    // Loading the default key through the caching storer also warms the cache
+    load_default_key(&storer, &retry_policy).await?;
+    load_signing_key(&storer, &attestor, &user_id, &retry_policy).await?;
+    readiness.set_ready();
+    info!("default key loaded, client is ready");
+
+    // Queued writes are replayed without going through the queue again, and compared
+    // against what storage itself holds rather than what is cached
+    if write_queue.is_enabled() {
+        tokio::spawn(write_queue.sync_periodically(
+            MeteredStorer::new(cached_storer),
+            uncached_storer,
+            outbox_relayer,
+            get_u64(&config, "storage.queue.syncintervalsecs")
+                .filter(|secs| *secs > 0)
+                .map(Duration::from_secs)
+                .unwrap_or(DEFAULT_SYNC_INTERVAL),
+        ));
+    }
+
+    // Relays which failed are retried once the signing key is loaded, recording each
+    // retry as queued until the relay is delivered or given up on
+    tokio::spawn(relay_outbox.deliver_periodically(
+        relayer,
+        relay_history,
+        get_u64(&config, "relay.outbox.intervalsecs")
+            .filter(|secs| *secs > 0)
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_DELIVERY_INTERVAL),
+    ));
+
+    match server.await {
+        Ok(Err(e)) => error!(error = %e, "server exited with an error"),
+        Err(e) => error!(error = %e, "server task exited abnormally"),
+        Ok(Ok(())) => (),
+    }
+    Ok(())
 }
@@ -0,0 +1,259 @@
+use async_trait::async_trait;
+use lazy_static::lazy_static;
+use prometheus::{
+    register_histogram_vec, register_int_counter_vec, register_int_gauge, HistogramVec,
+    IntCounterVec, IntGauge,
+};
+use redact_crypto::{Data, Entry, EntryPath, HasBuilder, HasIndex, States, StorageError, Storer};
+use std::time::Instant;
+use warp::http::Method;
+
+type Document = <Data as HasIndex>::Index;
+
+lazy_static! {
+    pub static ref REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "redact_client_requests_total",
+        "Number of HTTP requests handled, by route and status code",
+        &["route", "status"]
+    )
+    .unwrap();
+    pub static ref REQUEST_DURATION: HistogramVec = register_histogram_vec!(
+        "redact_client_request_duration_seconds",
+        "Time taken to handle HTTP requests, by route",
+        &["route"]
+    )
+    .unwrap();
+    pub static ref REJECTIONS: IntCounterVec = register_int_counter_vec!(
+        "redact_client_rejections_total",
+        "Number of requests rejected, by rejection type",
+        &["rejection"]
+    )
+    .unwrap();
+    pub static ref STORAGE_DURATION: HistogramVec = register_histogram_vec!(
+        "redact_client_storage_duration_seconds",
+        "Time taken by storage operations, by operation and outcome",
+        &["operation", "outcome"]
+    )
+    .unwrap();
+    pub static ref CRYPTO_DURATION: HistogramVec = register_histogram_vec!(
+        "redact_client_crypto_duration_seconds",
+        "Time taken by seal and unseal operations",
+        &["operation"]
+    )
+    .unwrap();
+    pub static ref RELAYS: IntCounterVec = register_int_counter_vec!(
+        "redact_client_relays_total",
+        "Number of relay notifications sent, by outcome",
+        &["outcome"]
+    )
+    .unwrap();
+    pub static ref KEY_CACHE_LOOKUPS: IntCounterVec = register_int_counter_vec!(
+        "redact_client_key_cache_lookups_total",
+        "Number of symmetric key lookups, by whether they were served from the key cache",
+        &["result"]
+    )
+    .unwrap();
+    pub static ref ENTRY_CACHE_LOOKUPS: IntCounterVec = register_int_counter_vec!(
+        "redact_client_entry_cache_lookups_total",
+        "Number of entry lookups, by whether they were served from the on-disk cache",
+        &["result"]
+    )
+    .unwrap();
+    pub static ref WRITE_QUEUE_SYNCS: IntCounterVec = register_int_counter_vec!(
+        "redact_client_write_queue_syncs_total",
+        "Number of queued writes replayed to storage, by outcome",
+        &["outcome"]
+    )
+    .unwrap();
+    pub static ref WRITE_QUEUE_PENDING: IntGauge = register_int_gauge!(
+        "redact_client_write_queue_pending",
+        "Number of writes waiting in the offline write queue"
+    )
+    .unwrap();
+    pub static ref RELAY_OUTBOX_DELIVERIES: IntCounterVec = register_int_counter_vec!(
+        "redact_client_relay_outbox_deliveries_total",
+        "Number of failed relays retried from the outbox, by outcome",
+        &["outcome"]
+    )
+    .unwrap();
+    pub static ref RELAY_OUTBOX_PENDING: IntGauge = register_int_gauge!(
+        "redact_client_relay_outbox_pending",
+        "Number of relays waiting in the outbox to be retried"
+    )
+    .unwrap();
+    pub static ref REPLICA_REPAIRS: IntCounterVec = register_int_counter_vec!(
+        "redact_client_storage_replica_repairs_total",
+        "Number of storage replicas found out of date on read, by outcome",
+        &["outcome"]
+    )
+    .unwrap();
+    pub static ref SESSIONS: IntGauge = register_int_gauge!(
+        "redact_client_sessions",
+        "Number of sessions currently held in the session store"
+    )
+    .unwrap();
+}
+
+/// Maps a request onto the route it was served by, keeping label cardinality
+/// bounded by never using the data path or token as a label value
+pub fn route_label(method: &Method, path: &str) -> &'static str {
+    let segments: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+    match (method, segments.as_slice()) {
+        (&Method::GET, ["data", _]) => "unsecure",
+        (&Method::GET, ["data", _, _]) => "secure",
+        (&Method::POST, ["data", _]) => "submit",
+        (_, ["api", "v1", "data", _]) => "api",
+        (_, ["proxy"]) => "proxy",
+        (_, ["handshake"]) => "handshake",
+        (_, ["healthz"]) => "healthz",
+        (_, ["readyz"]) => "readyz",
+        (_, ["metrics"]) => "metrics",
+        _ => "other",
+    }
+}
+
+/// Records the outcome of every request; meant to be used with `warp::log::custom`
+pub fn observe_request(info: warp::log::Info) {
+    let route = route_label(info.method(), info.path());
+    REQUESTS
+        .with_label_values(&[route, info.status().as_str()])
+        .inc();
+    REQUEST_DURATION
+        .with_label_values(&[route])
+        .observe(info.elapsed().as_secs_f64());
+}
+
+pub fn observe_relay<T, E>(result: &Result<T, E>) {
+    let outcome = if result.is_ok() { "success" } else { "failure" };
+    RELAYS.with_label_values(&[outcome]).inc();
+}
+
+fn observe_storage<T>(operation: &str, start: Instant, result: &Result<T, StorageError>) {
+    let outcome = match result {
+        Ok(_) => "success",
+        Err(StorageError::NotFound) => "not_found",
+        Err(_) => "error",
+    };
+    STORAGE_DURATION
+        .with_label_values(&[operation, outcome])
+        .observe(start.elapsed().as_secs_f64());
+}
+
+/// A `Storer` which records the latency of every call to the underlying `Storer`,
+/// as well as the time taken to unseal sealed entries during resolution
+#[derive(Clone)]
+pub struct MeteredStorer<H: Storer> {
+    inner: H,
+}
+
+impl<H: Storer> MeteredStorer<H> {
+    pub fn new(inner: H) -> MeteredStorer<H> {
+        MeteredStorer { inner }
+    }
+}
+
+// async_trait adds its own lifetime bounds to the ones required by `Storer`
+#[allow(clippy::multiple_bound_locations)]
+#[async_trait]
+impl<H: Storer> Storer for MeteredStorer<H> {
+    async fn get_indexed<T: HasBuilder + 'static>(
+        &self,
+        path: &str,
+        index: &Option<Document>,
+    ) -> Result<Entry, StorageError> {
+        let start = Instant::now();
+        let result = self.inner.get_indexed::<T>(path, index).await;
+        observe_storage("get", start, &result);
+        result
+    }
+
+    async fn list_indexed<T: HasBuilder + Send + 'static>(
+        &self,
+        path: &str,
+        skip: i64,
+        page_size: i64,
+        index: &Option<Document>,
+    ) -> Result<Vec<Entry>, StorageError> {
+        let start = Instant::now();
+        let result = self
+            .inner
+            .list_indexed::<T>(path, skip, page_size, index)
+            .await;
+        observe_storage("list", start, &result);
+        result
+    }
+
+    async fn create(&self, path: EntryPath, value: States) -> Result<bool, StorageError> {
+        let start = Instant::now();
+        let result = self.inner.create(path, value).await;
+        observe_storage("create", start, &result);
+        result
+    }
+
+    async fn resolve_indexed<T: HasBuilder + 'static>(
+        &self,
+        state: States,
+        index: &Option<Document>,
+    ) -> Result<T, StorageError> {
+        let timer = match state {
+            States::Sealed { .. } => {
+                Some(CRYPTO_DURATION.with_label_values(&["unseal"]).start_timer())
+            }
+            _ => None,
+        };
+        let result = self.inner.resolve_indexed::<T>(state, index).await;
+        if let Some(timer) = timer {
+            timer.observe_duration();
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{route_label, MeteredStorer, STORAGE_DURATION};
+    use redact_crypto::{storage::tests::MockStorer, Data, StorageError, Storer};
+    use warp::http::Method;
+
+    #[test]
+    fn test_route_label() {
+        assert_eq!(
+            route_label(&Method::GET, "/data/.profile.name."),
+            "unsecure"
+        );
+        assert_eq!(
+            route_label(&Method::GET, "/data/.profile.name./ABC"),
+            "secure"
+        );
+        assert_eq!(route_label(&Method::POST, "/data/ABC"), "submit");
+        assert_eq!(route_label(&Method::POST, "/proxy"), "proxy");
+        assert_eq!(route_label(&Method::PUT, "/api/v1/data/.a."), "api");
+        assert_eq!(route_label(&Method::GET, "/metrics"), "metrics");
+        assert_eq!(route_label(&Method::GET, "/data/a/b/c"), "other");
+        assert_eq!(route_label(&Method::DELETE, "/data/a"), "other");
+    }
+
+    #[tokio::test]
+    async fn test_metered_storer_records_get() {
+        let mut storer = MockStorer::new();
+        storer
+            .expect_get_indexed::<Data>()
+            .times(1)
+            .returning(|_, _| Err(StorageError::NotFound));
+        let before = STORAGE_DURATION
+            .with_label_values(&["get", "not_found"])
+            .get_sample_count();
+
+        let metered = MeteredStorer::new(storer);
+        assert!(matches!(
+            metered.get::<Data>(".testKey.").await,
+            Err(StorageError::NotFound)
+        ));
+        assert!(
+            STORAGE_DURATION
+                .with_label_values(&["get", "not_found"])
+                .get_sample_count()
+                > before
+        );
+    }
+}
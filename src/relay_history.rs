@@ -0,0 +1,416 @@
+use crate::{
+    attestation::RelayOperation,
+    relay_policy::RelayUrlError,
+    relayer::{ProxyRequest, RelayError, RelayNotification, RelayOutcome, Relayer},
+};
+use async_trait::async_trait;
+use http::StatusCode;
+use reqwest::Response;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+use tracing::warn;
+
+/// How many relay attempts are kept unless configured otherwise
+pub const DEFAULT_RELAY_HISTORY_MAX_ENTRIES: usize = 1000;
+
+/// Response header telling the secure page what became of the relay for a submission
+pub const RELAY_STATUS_HEADER: &str = "x-relay-status";
+
+#[derive(Error, Debug)]
+pub enum RelayHistoryError {
+    #[error("Failed to read or write the relay history")]
+    IoError { source: io::Error },
+
+    #[error("The relay history is not valid JSON")]
+    SerializationError { source: serde_json::Error },
+
+    #[error("The relay history could not be read or written in the background")]
+    Interrupted { source: tokio::task::JoinError },
+}
+
+/// What became of an attempt to notify a site
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RelayStatus {
+    /// The site acknowledged the notification
+    Delivered,
+    /// The notification will be sent again later
+    Queued,
+    /// The site could not be notified, and will not be retried
+    Failed,
+}
+
+impl RelayStatus {
+    pub fn of(result: &Result<RelayOutcome, RelayError>) -> RelayStatus {
+        match result {
+            Ok(RelayOutcome::Delivered(_)) => RelayStatus::Delivered,
+            Ok(RelayOutcome::Queued) => RelayStatus::Queued,
+            Err(_) => RelayStatus::Failed,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RelayStatus::Delivered => "delivered",
+            RelayStatus::Queued => "queued",
+            RelayStatus::Failed => "failed",
+        }
+    }
+
+    /// Shown to the user after they submit a value
+    pub fn message(&self) -> &'static str {
+        match self {
+            RelayStatus::Delivered => "The website was notified",
+            RelayStatus::Queued => "The website will be notified shortly",
+            RelayStatus::Failed => "The website could not be notified",
+        }
+    }
+}
+
+/// A single attempt to notify a site about the value at a path
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RelayAttempt {
+    pub path: String,
+    pub relay_url: String,
+    pub operation: RelayOperation,
+    pub status: RelayStatus,
+    /// The status the site responded with, if it responded at all
+    pub status_code: Option<u16>,
+    /// Seconds since the Unix epoch
+    pub timestamp: u64,
+}
+
+impl RelayAttempt {
+    pub fn new(
+        notification: &RelayNotification,
+        relay_url: &str,
+        status: RelayStatus,
+        status_code: Option<StatusCode>,
+    ) -> RelayAttempt {
+        RelayAttempt {
+            path: notification.path.clone(),
+            relay_url: relay_url.to_owned(),
+            operation: notification.operation,
+            status,
+            status_code: status_code.map(|status| status.as_u16()),
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+        }
+    }
+
+    /// Records the result of a relay as it was reported to the caller
+    pub fn from_result(
+        notification: &RelayNotification,
+        relay_url: &str,
+        result: &Result<RelayOutcome, RelayError>,
+    ) -> RelayAttempt {
+        let status_code = match result {
+            Ok(RelayOutcome::Delivered(status)) => Some(*status),
+            Ok(RelayOutcome::Queued) => None,
+            Err(e) => e.status(),
+        };
+        RelayAttempt::new(
+            notification,
+            relay_url,
+            RelayStatus::of(result),
+            status_code,
+        )
+    }
+}
+
+/// Keeps the most recent relay attempts in a local file, so the user can see which
+/// sites were notified about which paths. Attempts are appended one JSON object per
+/// line, and the file is only rewritten to drop old attempts once it holds twice the
+/// maximum.
+#[derive(Debug, Clone)]
+pub struct RelayHistory {
+    file_path: PathBuf,
+    max_entries: usize,
+    /// How many attempts the file holds, once known. Only one read or write of the
+    /// file may happen at a time.
+    len: Arc<Mutex<Option<usize>>>,
+}
+
+impl RelayHistory {
+    pub fn new<P: AsRef<Path>>(file_path: P, max_entries: usize) -> RelayHistory {
+        RelayHistory {
+            file_path: file_path.as_ref().to_owned(),
+            max_entries: max_entries.max(1),
+            len: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Adds the attempt, dropping the oldest ones beyond the maximum
+    pub async fn record(&self, attempt: RelayAttempt) -> Result<(), RelayHistoryError> {
+        let history = self.clone();
+        tokio::task::spawn_blocking(move || history.record_blocking(&attempt))
+            .await
+            .map_err(|source| RelayHistoryError::Interrupted { source })?
+    }
+
+    /// Lists recorded attempts, newest first, optionally only those for one path
+    pub async fn list(&self, path: Option<&str>) -> Result<Vec<RelayAttempt>, RelayHistoryError> {
+        let history = self.clone();
+        let path = path.map(str::to_owned);
+        tokio::task::spawn_blocking(move || {
+            let _len = history.lock();
+            let mut attempts = history.read()?;
+            let excess = attempts.len().saturating_sub(history.max_entries);
+            attempts.drain(..excess);
+            if let Some(path) = path {
+                attempts.retain(|attempt| attempt.path == path);
+            }
+            attempts.reverse();
+            Ok(attempts)
+        })
+        .await
+        .map_err(|source| RelayHistoryError::Interrupted { source })?
+    }
+
+    /// A panic while the lock was held cannot leave the file half written, as
+    /// compaction replaces it atomically, so a poisoned lock is still usable
+    fn lock(&self) -> MutexGuard<'_, Option<usize>> {
+        self.len.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn record_blocking(&self, attempt: &RelayAttempt) -> Result<(), RelayHistoryError> {
+        let mut len = self.lock();
+        let known_len = match *len {
+            Some(known_len) => known_len,
+            None => self.read()?.len(),
+        };
+
+        let mut line = serde_json::to_vec(attempt)
+            .map_err(|source| RelayHistoryError::SerializationError { source })?;
+        line.push(b'\n');
+        let mut options = OpenOptions::new();
+        options.append(true).create(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        self.create_parent_dir()
+            .and_then(|_| options.open(&self.file_path))
+            .and_then(|mut file| file.write_all(&line))
+            .map_err(|source| RelayHistoryError::IoError { source })?;
+        *len = Some(known_len + 1);
+
+        if known_len + 1 >= self.max_entries * 2 {
+            let mut attempts = self.read()?;
+            let excess = attempts.len().saturating_sub(self.max_entries);
+            attempts.drain(..excess);
+            self.rewrite(&attempts)?;
+            *len = Some(attempts.len());
+        }
+        Ok(())
+    }
+
+    fn read(&self) -> Result<Vec<RelayAttempt>, RelayHistoryError> {
+        let file = match File::open(&self.file_path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(source) => return Err(RelayHistoryError::IoError { source }),
+        };
+        let mut attempts = Vec::new();
+        for line in BufReader::new(file).lines() {
+            let line = line.map_err(|source| RelayHistoryError::IoError { source })?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            attempts.push(
+                serde_json::from_str(&line)
+                    .map_err(|source| RelayHistoryError::SerializationError { source })?,
+            );
+        }
+        Ok(attempts)
+    }
+
+    /// Replaces the file atomically, making it readable by the current user only
+    fn rewrite(&self, attempts: &[RelayAttempt]) -> Result<(), RelayHistoryError> {
+        let mut bytes = Vec::new();
+        for attempt in attempts {
+            serde_json::to_writer(&mut bytes, attempt)
+                .map_err(|source| RelayHistoryError::SerializationError { source })?;
+            bytes.push(b'\n');
+        }
+        let mut tmp_path = self.file_path.clone().into_os_string();
+        tmp_path.push(".tmp");
+
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        options
+            .open(&tmp_path)
+            .and_then(|mut file| file.write_all(&bytes).and_then(|_| file.sync_all()))
+            .and_then(|_| fs::rename(&tmp_path, &self.file_path))
+            .map_err(|source| RelayHistoryError::IoError { source })
+    }
+
+    fn create_parent_dir(&self) -> io::Result<()> {
+        self.file_path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+            .map_or(Ok(()), fs::create_dir_all)
+    }
+}
+
+/// Wraps a relayer, recording the outcome of every relay made through it
+#[derive(Debug, Clone)]
+pub struct HistoryRelayer<Q> {
+    inner: Q,
+    history: RelayHistory,
+}
+
+impl<Q: Relayer> HistoryRelayer<Q> {
+    pub fn new(inner: Q, history: RelayHistory) -> HistoryRelayer<Q> {
+        HistoryRelayer { inner, history }
+    }
+}
+
+#[async_trait]
+impl<Q: Relayer> Relayer for HistoryRelayer<Q> {
+    async fn check_url(
+        &self,
+        host_origin: Option<String>,
+        relay_url: String,
+    ) -> Result<(), RelayUrlError> {
+        self.inner.check_url(host_origin, relay_url).await
+    }
+
+    async fn relay(
+        &self,
+        notification: RelayNotification,
+        relay_url: String,
+        request_id: String,
+    ) -> Result<RelayOutcome, RelayError> {
+        let attempt = notification.clone();
+        let result = self
+            .inner
+            .relay(notification, relay_url.clone(), request_id)
+            .await;
+        // The relay has already happened, so failing to record it is not its failure
+        if let Err(e) = self
+            .history
+            .record(RelayAttempt::from_result(&attempt, &relay_url, &result))
+            .await
+        {
+            warn!(error = %e, "failed to record relay attempt");
+        }
+        result
+    }
+
+    async fn proxy(&self, request: ProxyRequest) -> Result<Response, RelayError> {
+        self.inner.proxy(request).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{HistoryRelayer, RelayAttempt, RelayHistory, RelayStatus};
+    use crate::attestation::RelayOperation;
+    use crate::relayer::{
+        tests::MockRelayer, RelayError, RelayNotification, RelayOutcome, Relayer,
+    };
+    use http::StatusCode;
+    use std::path::PathBuf;
+    use uuid::Uuid;
+
+    fn temp_file() -> PathBuf {
+        std::env::temp_dir()
+            .join(format!("redact-relay-history-{}", Uuid::new_v4()))
+            .join("history.jsonl")
+    }
+
+    fn notification(path: &str) -> RelayNotification {
+        RelayNotification {
+            path: path.to_owned(),
+            data_type: Some("string".to_owned()),
+            operation: RelayOperation::Submit,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_history_is_newest_first_and_bounded() {
+        let file_path = temp_file();
+        let history = RelayHistory::new(&file_path, 2);
+        assert!(history.list(None).await.unwrap().is_empty());
+
+        let delivered = Ok(RelayOutcome::Delivered(StatusCode::OK));
+        for path in &[".a.", ".b.", ".a."] {
+            history
+                .record(RelayAttempt::from_result(
+                    &notification(path),
+                    "https://example.com/relay",
+                    &delivered,
+                ))
+                .await
+                .unwrap();
+        }
+
+        let attempts = history.list(None).await.unwrap();
+        assert_eq!(
+            attempts
+                .iter()
+                .map(|attempt| attempt.path.as_str())
+                .collect::<Vec<_>>(),
+            vec![".a.", ".b."]
+        );
+        assert_eq!(attempts[0].status_code, Some(200));
+        assert_eq!(history.list(Some(".a.")).await.unwrap().len(), 1);
+        assert!(history.list(Some(".c.")).await.unwrap().is_empty());
+
+        // Old attempts are dropped from the file once it holds twice the maximum
+        history
+            .record(RelayAttempt::from_result(
+                &notification(".c."),
+                "https://example.com/relay",
+                &delivered,
+            ))
+            .await
+            .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&file_path).unwrap().lines().count(),
+            2
+        );
+        assert_eq!(history.list(None).await.unwrap()[0].path, ".c.");
+    }
+
+    #[tokio::test]
+    async fn test_relays_are_recorded() {
+        let history = RelayHistory::new(temp_file(), 10);
+        let mut relayer = MockRelayer::new();
+        relayer
+            .expect_relay()
+            .times(1)
+            .returning(|_, _, _| Ok(RelayOutcome::Queued));
+        relayer
+            .expect_relay()
+            .times(1)
+            .returning(|_, _, _| Err(RelayError::AttestationUnavailable));
+        let relayer = HistoryRelayer::new(relayer, history.clone());
+
+        for _ in 0..2 {
+            let _ = relayer
+                .relay(
+                    notification(".profile.name."),
+                    "https://example.com/relay".to_owned(),
+                    "test-request-id".to_owned(),
+                )
+                .await;
+        }
+
+        let attempts = history.list(Some(".profile.name.")).await.unwrap();
+        assert_eq!(attempts.len(), 2);
+        assert_eq!(attempts[0].status, RelayStatus::Failed);
+        assert_eq!(attempts[0].status_code, None);
+        assert_eq!(attempts[1].status, RelayStatus::Queued);
+        assert_eq!(attempts[1].relay_url, "https://example.com/relay");
+        assert_eq!(attempts[1].operation, RelayOperation::Submit);
+    }
+}
@@ -0,0 +1,620 @@
+use crate::{
+    metrics::{RELAY_OUTBOX_DELIVERIES, RELAY_OUTBOX_PENDING},
+    relay_history::{RelayAttempt, RelayHistory, RelayStatus},
+    relay_policy::RelayUrlError,
+    relayer::{ProxyRequest, RelayError, RelayNotification, RelayOutcome, Relayer},
+    startup::RetryPolicy,
+};
+use async_trait::async_trait;
+use reqwest::Response;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+/// How often the outbox is checked for relays due to be retried unless configured
+/// otherwise
+pub const DEFAULT_DELIVERY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How relays are retried unless configured otherwise: 10 attempts over about 13
+/// minutes
+pub const DEFAULT_RELAY_RETRY_POLICY: RetryPolicy = RetryPolicy {
+    max_attempts: 10,
+    initial_backoff: Duration::from_secs(1),
+    max_backoff: Duration::from_secs(300),
+};
+
+/// Relays which were rejected or ran out of attempts are moved here
+const FAILED_DIR: &str = "failed";
+
+#[derive(Error, Debug)]
+pub enum RelayOutboxError {
+    #[error("Failed to read or write the relay outbox")]
+    IoError { source: io::Error },
+
+    #[error("A pending relay is not valid JSON")]
+    SerializationError { source: serde_json::Error },
+}
+
+/// A relay notification which could not be delivered yet
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PendingRelay {
+    pub id: String,
+    pub notification: RelayNotification,
+    pub relay_url: String,
+    pub request_id: String,
+    /// How many times delivery has been attempted
+    pub attempts: u32,
+    /// When the relay was first attempted, in milliseconds since the Unix epoch
+    pub queued_at: u64,
+    /// When the relay is next due, in milliseconds since the Unix epoch
+    pub next_attempt_at: u64,
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Keeps relays which failed after the value they report on was stored, one file per
+/// relay, so that they are retried with exponential backoff in the background and
+/// survive restarts. Relays are re-signed on every attempt, so the outbox holds no
+/// signatures and the site sees a fresh timestamp and nonce.
+#[derive(Debug, Clone)]
+pub struct RelayOutbox {
+    dir: Option<PathBuf>,
+    policy: RetryPolicy,
+    /// The files holding pending relays. The directory is only listed the first time
+    /// the outbox is changed, and kept track of in memory from then on.
+    files: Arc<Mutex<Option<HashSet<PathBuf>>>>,
+}
+
+impl RelayOutbox {
+    pub fn new<P: Into<PathBuf>>(dir: P, policy: RetryPolicy) -> RelayOutbox {
+        RelayOutbox {
+            dir: Some(dir.into()),
+            policy,
+            files: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// An outbox which never accepts relays, so failed relays are reported to the
+    /// caller instead
+    pub fn disabled() -> RelayOutbox {
+        RelayOutbox {
+            dir: None,
+            policy: DEFAULT_RELAY_RETRY_POLICY,
+            files: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.dir.is_some()
+    }
+
+    fn file_path(&self, relay: &PendingRelay) -> Option<PathBuf> {
+        self.dir
+            .as_ref()
+            .map(|dir| dir.join(format!("{}.json", relay.id)))
+    }
+
+    /// Changes the pending files on the blocking thread pool, while holding the lock
+    /// on them
+    async fn change<R, F>(&self, change: F) -> Result<R, RelayOutboxError>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut HashSet<PathBuf>) -> Result<R, RelayOutboxError> + Send + 'static,
+    {
+        let outbox = self.clone();
+        blocking(move || {
+            let mut files = outbox.files.lock().unwrap();
+            if files.is_none() {
+                *files = Some(pending_files(outbox.dir.as_deref())?);
+            }
+            let files = files.as_mut().unwrap();
+            let result = change(files);
+            RELAY_OUTBOX_PENDING.set(files.len() as i64);
+            result
+        })
+        .await
+    }
+
+    /// Queues a relay which failed on its first attempt to be retried later
+    async fn enqueue(
+        &self,
+        notification: RelayNotification,
+        relay_url: String,
+        request_id: String,
+    ) -> Result<(), RelayOutboxError> {
+        let now = now_millis();
+        self.write_pending(PendingRelay {
+            id: Uuid::new_v4().to_string(),
+            notification,
+            relay_url,
+            request_id,
+            attempts: 1,
+            queued_at: now,
+            next_attempt_at: now + self.policy.backoff(1).as_millis() as u64,
+        })
+        .await
+    }
+
+    async fn write_pending(&self, relay: PendingRelay) -> Result<(), RelayOutboxError> {
+        if let Some(file_path) = self.file_path(&relay) {
+            self.change(move |files| {
+                write(&file_path, &relay)?;
+                files.insert(file_path);
+                Ok(())
+            })
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Lists every pending relay, oldest first
+    async fn list(&self) -> Result<Vec<PendingRelay>, RelayOutboxError> {
+        let dir = match &self.dir {
+            Some(dir) => dir.clone(),
+            None => return Ok(vec![]),
+        };
+        blocking(move || {
+            let mut relays = Vec::new();
+            for file_path in pending_files(Some(&dir))? {
+                relays.extend(read(&file_path)?);
+            }
+            relays.sort_by_key(|relay| relay.queued_at);
+            Ok(relays)
+        })
+        .await
+    }
+
+    async fn remove(&self, relay: &PendingRelay) -> Result<(), RelayOutboxError> {
+        if let Some(file_path) = self.file_path(relay) {
+            self.change(move |files| {
+                match fs::remove_file(&file_path) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                        return Err(RelayOutboxError::IoError { source: e })
+                    }
+                    _ => files.remove(&file_path),
+                };
+                Ok(())
+            })
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Moves a relay which will not be retried out of the outbox, keeping it on disk
+    /// for inspection
+    async fn set_aside(&self, relay: &PendingRelay) -> Result<(), RelayOutboxError> {
+        if let (Some(dir), Some(file_path)) = (&self.dir, self.file_path(relay)) {
+            let failed_path = dir.join(FAILED_DIR).join(format!("{}.json", relay.id));
+            self.change(move |files| {
+                failed_path
+                    .parent()
+                    .map_or(Ok(()), fs::create_dir_all)
+                    .and_then(|_| fs::rename(&file_path, &failed_path))
+                    .map_err(|source| RelayOutboxError::IoError { source })?;
+                files.remove(&file_path);
+                Ok(())
+            })
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Retries every relay which is due, returning how many were delivered. Relays
+    /// which are rejected by the site, or still fail after the policy's maximum
+    /// number of attempts, are set aside. Every attempt is recorded in the history,
+    /// as queued until the relay is given up on.
+    pub async fn deliver<Q: Relayer>(
+        &self,
+        relayer: &Q,
+        history: &RelayHistory,
+    ) -> Result<usize, RelayOutboxError> {
+        let mut delivered = 0;
+        for mut relay in self.list().await? {
+            if relay.next_attempt_at > now_millis() {
+                continue;
+            }
+
+            let result = relayer
+                .relay(
+                    relay.notification.clone(),
+                    relay.relay_url.clone(),
+                    relay.request_id.clone(),
+                )
+                .await;
+            relay.attempts += 1;
+            let attempt = match result {
+                Ok(outcome) => {
+                    info!(
+                        path = %relay.notification.path,
+                        attempts = relay.attempts,
+                        "delivered pending relay"
+                    );
+                    RELAY_OUTBOX_DELIVERIES
+                        .with_label_values(&["delivered"])
+                        .inc();
+                    self.remove(&relay).await?;
+                    delivered += 1;
+                    RelayAttempt::from_result(&relay.notification, &relay.relay_url, &Ok(outcome))
+                }
+                Err(e) if e.is_permanent() || relay.attempts >= self.policy.max_attempts => {
+                    warn!(
+                        error = %e,
+                        path = %relay.notification.path,
+                        attempts = relay.attempts,
+                        "giving up on relay, setting it aside"
+                    );
+                    RELAY_OUTBOX_DELIVERIES.with_label_values(&["failed"]).inc();
+                    self.set_aside(&relay).await?;
+                    RelayAttempt::new(
+                        &relay.notification,
+                        &relay.relay_url,
+                        RelayStatus::Failed,
+                        e.status(),
+                    )
+                }
+                Err(e) => {
+                    let backoff = self.policy.backoff(relay.attempts);
+                    warn!(
+                        error = %e,
+                        path = %relay.notification.path,
+                        attempts = relay.attempts,
+                        "relay failed again, retrying in {:?}",
+                        backoff
+                    );
+                    RELAY_OUTBOX_DELIVERIES
+                        .with_label_values(&["retried"])
+                        .inc();
+                    relay.next_attempt_at = now_millis() + backoff.as_millis() as u64;
+                    self.write_pending(relay.clone()).await?;
+                    RelayAttempt::new(
+                        &relay.notification,
+                        &relay.relay_url,
+                        RelayStatus::Queued,
+                        e.status(),
+                    )
+                }
+            };
+            if let Err(e) = history.record(attempt).await {
+                warn!(error = %e, "failed to record relay attempt");
+            }
+        }
+        Ok(delivered)
+    }
+
+    /// Retries due relays every interval, for as long as the client runs
+    pub async fn deliver_periodically<Q: Relayer>(
+        self,
+        relayer: Q,
+        history: RelayHistory,
+        interval: Duration,
+    ) {
+        // Lists the outbox up front, so the number of pending relays is reported
+        if let Err(e) = self.change(|_| Ok(())).await {
+            warn!(error = %e, "failed to read the relay outbox");
+        }
+        let mut ticks = tokio::time::interval(interval);
+        loop {
+            ticks.tick().await;
+            if let Err(e) = self.deliver(&relayer, &history).await {
+                warn!(error = %e, "failed to deliver pending relays");
+            }
+        }
+    }
+}
+
+/// A `Relayer` which hands relays that fail to the outbox, and reports them as
+/// accepted. Relays the site rejected are still reported as failed, as retrying them
+/// cannot help.
+#[derive(Clone)]
+pub struct OutboxRelayer<Q: Relayer> {
+    inner: Q,
+    outbox: RelayOutbox,
+}
+
+impl<Q: Relayer> OutboxRelayer<Q> {
+    pub fn new(inner: Q, outbox: RelayOutbox) -> OutboxRelayer<Q> {
+        OutboxRelayer { inner, outbox }
+    }
+}
+
+#[async_trait]
+impl<Q: Relayer> Relayer for OutboxRelayer<Q> {
+    async fn check_url(
+        &self,
+        host_origin: Option<String>,
+        relay_url: String,
+    ) -> Result<(), RelayUrlError> {
+        self.inner.check_url(host_origin, relay_url).await
+    }
+
+    async fn relay(
+        &self,
+        notification: RelayNotification,
+        relay_url: String,
+        request_id: String,
+    ) -> Result<RelayOutcome, RelayError> {
+        let result = self
+            .inner
+            .relay(notification.clone(), relay_url.clone(), request_id.clone())
+            .await;
+        match result {
+            Err(e) if self.outbox.is_enabled() && !e.is_permanent() => {
+                warn!(
+                    error = %e,
+                    path = %notification.path,
+                    "relay failed, retrying in the background"
+                );
+                match self
+                    .outbox
+                    .enqueue(notification, relay_url, request_id)
+                    .await
+                {
+                    Ok(()) => {
+                        RELAY_OUTBOX_DELIVERIES.with_label_values(&["queued"]).inc();
+                        Ok(RelayOutcome::Queued)
+                    }
+                    Err(queue_error) => {
+                        warn!(error = %queue_error, "failed to queue relay for retry");
+                        Err(e)
+                    }
+                }
+            }
+            result => result,
+        }
+    }
+
+    async fn proxy(&self, request: ProxyRequest) -> Result<Response, RelayError> {
+        self.inner.proxy(request).await
+    }
+}
+
+/// Runs file work on the blocking thread pool, off the async runtime
+async fn blocking<R, F>(work: F) -> Result<R, RelayOutboxError>
+where
+    R: Send + 'static,
+    F: FnOnce() -> Result<R, RelayOutboxError> + Send + 'static,
+{
+    tokio::task::spawn_blocking(work)
+        .await
+        .map_err(|e| RelayOutboxError::IoError {
+            source: io::Error::other(e),
+        })?
+}
+
+/// Every file in the outbox directory holding a pending relay
+fn pending_files(dir: Option<&Path>) -> Result<HashSet<PathBuf>, RelayOutboxError> {
+    let dir = match dir {
+        Some(dir) => dir,
+        None => return Ok(HashSet::new()),
+    };
+    let files = match fs::read_dir(dir) {
+        Ok(files) => files,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(HashSet::new()),
+        Err(source) => return Err(RelayOutboxError::IoError { source }),
+    };
+
+    let mut pending = HashSet::new();
+    for file in files {
+        let file_path = file
+            .map_err(|source| RelayOutboxError::IoError { source })?
+            .path();
+        if file_path.extension().is_some_and(|ext| ext == "json") {
+            pending.insert(file_path);
+        }
+    }
+    Ok(pending)
+}
+
+fn read(file_path: &Path) -> Result<Option<PendingRelay>, RelayOutboxError> {
+    match fs::read(file_path) {
+        Ok(bytes) => serde_json::from_slice(&bytes)
+            .map(Some)
+            .map_err(|source| RelayOutboxError::SerializationError { source }),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(source) => Err(RelayOutboxError::IoError { source }),
+    }
+}
+
+/// Replaces the file atomically, making it readable by the current user only
+fn write(file_path: &Path, relay: &PendingRelay) -> Result<(), RelayOutboxError> {
+    let bytes = serde_json::to_vec(relay)
+        .map_err(|source| RelayOutboxError::SerializationError { source })?;
+    let mut tmp_path = file_path.to_owned().into_os_string();
+    tmp_path.push(".tmp");
+
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    file_path
+        .parent()
+        .map_or(Ok(()), fs::create_dir_all)
+        .and_then(|_| options.open(&tmp_path))
+        .and_then(|mut file| file.write_all(&bytes).and_then(|_| file.sync_all()))
+        .and_then(|_| fs::rename(&tmp_path, file_path))
+        .map_err(|source| RelayOutboxError::IoError { source })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{OutboxRelayer, RelayOutbox, FAILED_DIR};
+    use crate::attestation::RelayOperation;
+    use crate::relay_history::{RelayHistory, RelayStatus};
+    use crate::relayer::{
+        tests::MockRelayer, RelayError, RelayNotification, RelayOutcome, Relayer,
+    };
+    use crate::startup::RetryPolicy;
+    use http::StatusCode;
+    use mockall::predicate::*;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use uuid::Uuid;
+
+    fn temp_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("redact-relay-outbox-{}", Uuid::new_v4()))
+    }
+
+    fn history() -> RelayHistory {
+        RelayHistory::new(temp_dir().join("history.jsonl"), 10)
+    }
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(0),
+            max_backoff: Duration::from_millis(0),
+        }
+    }
+
+    fn notification() -> RelayNotification {
+        RelayNotification {
+            path: ".profile.name.".to_owned(),
+            data_type: Some("string".to_owned()),
+            operation: RelayOperation::Submit,
+        }
+    }
+
+    fn failing() -> Result<RelayOutcome, RelayError> {
+        Err(RelayError::RelayRequestError { source: None })
+    }
+
+    async fn relay<Q: Relayer>(relayer: &Q) -> Result<RelayOutcome, RelayError> {
+        relayer
+            .relay(
+                notification(),
+                "https://relay.test".to_owned(),
+                "test-request-id".to_owned(),
+            )
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_failed_relay_is_retried() {
+        let outbox = RelayOutbox::new(temp_dir(), policy());
+        let history = history();
+
+        let mut relayer = MockRelayer::new();
+        relayer
+            .expect_relay()
+            .times(1)
+            .returning(|_, _, _| failing());
+        let relayer = Arc::new(relayer);
+        assert_eq!(
+            relay(&OutboxRelayer::new(relayer.clone(), outbox.clone()))
+                .await
+                .unwrap(),
+            RelayOutcome::Queued
+        );
+        assert_eq!(outbox.list().await.unwrap().len(), 1);
+
+        let mut relayer = MockRelayer::new();
+        relayer
+            .expect_relay()
+            .times(1)
+            .with(
+                eq(notification()),
+                eq("https://relay.test".to_owned()),
+                eq("test-request-id".to_owned()),
+            )
+            .returning(|_, _, _| Ok(RelayOutcome::Delivered(StatusCode::OK)));
+        assert_eq!(outbox.deliver(&relayer, &history).await.unwrap(), 1);
+        assert!(outbox.list().await.unwrap().is_empty());
+
+        let attempts = history.list(None).await.unwrap();
+        assert_eq!(attempts.len(), 1);
+        assert_eq!(attempts[0].status, RelayStatus::Delivered);
+    }
+
+    #[tokio::test]
+    async fn test_relay_is_set_aside_after_max_attempts() {
+        let dir = temp_dir();
+        let outbox = RelayOutbox::new(&dir, policy());
+        let history = history();
+
+        let mut relayer = MockRelayer::new();
+        relayer
+            .expect_relay()
+            .times(3)
+            .returning(|_, _, _| failing());
+        let relayer = Arc::new(relayer);
+        assert_eq!(
+            relay(&OutboxRelayer::new(relayer.clone(), outbox.clone()))
+                .await
+                .unwrap(),
+            RelayOutcome::Queued
+        );
+        assert_eq!(outbox.deliver(&relayer, &history).await.unwrap(), 0);
+        assert_eq!(outbox.list().await.unwrap()[0].attempts, 2);
+        assert_eq!(outbox.deliver(&relayer, &history).await.unwrap(), 0);
+
+        assert!(outbox.list().await.unwrap().is_empty());
+        assert_eq!(dir.join(FAILED_DIR).read_dir().unwrap().count(), 1);
+
+        // Retries are only reported as failed once the relay is given up on
+        assert_eq!(
+            history
+                .list(None)
+                .await
+                .unwrap()
+                .iter()
+                .map(|attempt| attempt.status)
+                .collect::<Vec<_>>(),
+            vec![RelayStatus::Failed, RelayStatus::Queued]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_relay_waits_for_backoff() {
+        let outbox = RelayOutbox::new(
+            temp_dir(),
+            RetryPolicy {
+                max_attempts: 3,
+                initial_backoff: Duration::from_secs(60),
+                max_backoff: Duration::from_secs(60),
+            },
+        );
+        let history = history();
+
+        let mut relayer = MockRelayer::new();
+        relayer
+            .expect_relay()
+            .times(1)
+            .returning(|_, _, _| failing());
+        let relayer = Arc::new(relayer);
+        assert_eq!(
+            relay(&OutboxRelayer::new(relayer.clone(), outbox.clone()))
+                .await
+                .unwrap(),
+            RelayOutcome::Queued
+        );
+        assert_eq!(outbox.deliver(&relayer, &history).await.unwrap(), 0);
+        assert_eq!(outbox.list().await.unwrap()[0].attempts, 1);
+        assert!(history.list(None).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_failed_relay_is_reported_when_disabled() {
+        let mut relayer = MockRelayer::new();
+        relayer
+            .expect_relay()
+            .times(1)
+            .returning(|_, _, _| failing());
+        assert!(matches!(
+            relay(&OutboxRelayer::new(relayer, RelayOutbox::disabled())).await,
+            Err(RelayError::RelayRequestError { .. })
+        ));
+    }
+}
@@ -1,26 +1,150 @@
 use warp::reject::Reject;
 use http::StatusCode;
+use openssl::{asn1::Asn1Time, error::ErrorStack, pkcs12::Pkcs12, x509::X509};
 use std::sync::Arc;
 use std::ops::Deref;
-use std::fs::File;
-use std::io::Read;
-use std::collections::HashMap;
+use std::fs;
+use std::io;
 use thiserror::Error;
 use async_trait::async_trait;
-use reqwest::Response;
+use reqwest::{header::HeaderMap, Method, Response};
+use crate::logging::REQUEST_ID_HEADER;
+use crate::attestation::{Attestation, Attestor, RelayOperation};
+use crate::relay_policy::{ApprovedRelayUrl, RelayPolicy, RelayUrlError};
+use redact_crypto::{DataBuilder, States, TypeBuilder};
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How long a relay request may take unless configured otherwise
+pub const DEFAULT_RELAY_TIMEOUT: Duration = Duration::from_secs(10);
 
 #[derive(Error, Debug)]
 pub enum RelayError {
     #[error("Failure happened during relay")]
-    RelayRequestError { source: Option<reqwest::Error> }
+    RelayRequestError { source: Option<reqwest::Error> },
+
+    #[error("Failed to read {path}")]
+    IoError { path: String, source: io::Error },
+
+    #[error("The identity in {path} is not a valid PEM certificate and private key")]
+    IdentityError { path: String, source: reqwest::Error },
+
+    #[error("The identity's certificate could not be read")]
+    CertificateError { source: ErrorStack },
+
+    #[error("{path} is not a PKCS#12 archive which opens with the given passphrase")]
+    Pkcs12Error { path: String, source: ErrorStack },
+
+    #[error("{path} is not a valid PEM CA bundle")]
+    InvalidCaBundle { path: String, source: Option<ErrorStack> },
+
+    #[error("Failed to build the relay client")]
+    ClientBuildError { source: reqwest::Error },
+
+    #[error("No signing key has been loaded to attest the relay with")]
+    AttestationUnavailable,
+
+    #[error("The relay URL was rejected")]
+    UrlRejected { source: RelayUrlError },
 }
 
 impl Reject for RelayError {}
 
+impl RelayError {
+    /// Whether retrying cannot help, because the site rejected the relay itself. Sites
+    /// which are overloaded or time out are worth retrying.
+    pub fn is_permanent(&self) -> bool {
+        match self {
+            RelayError::RelayRequestError { source: Some(source) } => {
+                source.status().is_some_and(|status| {
+                    status.is_client_error()
+                        && status != StatusCode::REQUEST_TIMEOUT
+                        && status != StatusCode::TOO_MANY_REQUESTS
+                })
+            }
+            // The host may resolve once its DNS is fixed, but no other URL will pass
+            RelayError::UrlRejected { source } => {
+                !matches!(source, RelayUrlError::ResolutionFailed { .. })
+            }
+            _ => false,
+        }
+    }
+
+    /// The status the site responded with, if it responded at all
+    pub fn status(&self) -> Option<StatusCode> {
+        match self {
+            RelayError::RelayRequestError { source: Some(source) } => source.status(),
+            _ => None,
+        }
+    }
+}
+
+/// What became of a relay which did not fail
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RelayOutcome {
+    /// The site acknowledged the relay with the status
+    Delivered(StatusCode),
+    /// The relay failed, and was kept to be retried in the background
+    Queued,
+}
+
+/// What a relaying website is told about a value stored on its behalf
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RelayNotification {
+    pub path: String,
+    pub data_type: Option<String>,
+    pub operation: RelayOperation,
+}
+
+impl RelayNotification {
+    /// Describes the value about to be stored at the path. The data type is read from
+    /// the value's builder, so sealed values do not need to be unsealed.
+    pub fn new(path: String, value: &States, operation: RelayOperation) -> RelayNotification {
+        let builder = match value {
+            States::Referenced { builder, .. }
+            | States::Sealed { builder, .. }
+            | States::Unsealed { builder, .. } => builder,
+        };
+        let data_type = match builder {
+            TypeBuilder::Data(DataBuilder::Bool(_)) => Some("bool"),
+            TypeBuilder::Data(DataBuilder::U64(_)) => Some("u64"),
+            TypeBuilder::Data(DataBuilder::I64(_)) => Some("i64"),
+            TypeBuilder::Data(DataBuilder::F64(_)) => Some("f64"),
+            TypeBuilder::Data(DataBuilder::String(_)) => Some("string"),
+            TypeBuilder::Key(_) => None,
+        };
+
+        RelayNotification {
+            path,
+            data_type: data_type.map(str::to_owned),
+            operation,
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct RelayBody {
+    path: String,
+    user_id: String,
+    attestation: Attestation,
+}
+
+/// A request made through the proxy route on behalf of the host page
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProxyRequest {
+    pub method: Method,
+    pub url: ApprovedRelayUrl,
+    pub headers: HeaderMap,
+    pub body: Option<Vec<u8>>,
+}
+
 #[async_trait]
 pub trait Relayer: Clone + Send + Sync {
-    async fn relay(&self, path: String, relay_url: String) -> Result<StatusCode, RelayError>;
-    async fn get(&self, relay_url: String) -> Result<Response, RelayError>;
+    /// Checks a relay URL chosen by a host page served from `host_origin`
+    async fn check_url(&self, host_origin: Option<String>, relay_url: String) -> Result<(), RelayUrlError>;
+    async fn relay(&self, notification: RelayNotification, relay_url: String, request_id: String) -> Result<RelayOutcome, RelayError>;
+    async fn proxy(&self, request: ProxyRequest) -> Result<Response, RelayError>;
 }
 
 #[async_trait]
@@ -28,76 +152,263 @@ impl<U> Relayer for Arc<U>
     where
         U: Relayer,
 {
-    async fn relay(&self, path: String, relay_url: String) -> Result<StatusCode, RelayError> {
-        self.deref().relay(path, relay_url).await
+    async fn check_url(&self, host_origin: Option<String>, relay_url: String) -> Result<(), RelayUrlError> {
+        self.deref().check_url(host_origin, relay_url).await
     }
 
-    async fn get(&self, relay_url: String) -> Result<Response, RelayError> {
-        self.deref().get(relay_url).await
+    async fn relay(&self, notification: RelayNotification, relay_url: String, request_id: String) -> Result<RelayOutcome, RelayError> {
+        self.deref().relay(notification, relay_url, request_id).await
+    }
+
+    async fn proxy(&self, request: ProxyRequest) -> Result<Response, RelayError> {
+        self.deref().proxy(request).await
     }
 }
 
+/// Where the client certificate presented to relay URLs is loaded from
+#[derive(Debug, Clone)]
+pub enum RelayIdentity {
+    /// A PEM file holding both the certificate chain and the private key
+    Pem { path: String },
+    /// Separate PEM files for the certificate chain and the private key
+    PemPair { cert_path: String, key_path: String },
+    /// A PKCS#12 archive protected by a passphrase
+    Pkcs12 { path: String, passphrase: String },
+}
+
+fn read(path: &str) -> Result<Vec<u8>, RelayError> {
+    fs::read(path).map_err(|source| RelayError::IoError {
+        path: path.to_owned(),
+        source,
+    })
+}
+
+impl RelayIdentity {
+    /// Reads the identity as PEM, checking that it can be loaded
+    pub fn load(&self) -> Result<Vec<u8>, RelayError> {
+        let (path, pem) = match self {
+            RelayIdentity::Pem { path } => (path, read(path)?),
+            RelayIdentity::PemPair { cert_path, key_path } => {
+                let mut pem = read(cert_path)?;
+                pem.push(b'\n');
+                pem.extend(read(key_path)?);
+                (cert_path, pem)
+            }
+            RelayIdentity::Pkcs12 { path, passphrase } => {
+                (path, pkcs12_to_pem(&read(path)?, passphrase).map_err(|source| {
+                    RelayError::Pkcs12Error {
+                        path: path.to_owned(),
+                        source,
+                    }
+                })?)
+            }
+        };
+        reqwest::Identity::from_pem(&pem).map_err(|source| RelayError::IdentityError {
+            path: path.to_owned(),
+            source,
+        })?;
+        Ok(pem)
+    }
+}
+
+/// rustls can only load identities from PEM, so the archive is converted first
+fn pkcs12_to_pem(der: &[u8], passphrase: &str) -> Result<Vec<u8>, ErrorStack> {
+    let parsed = Pkcs12::from_der(der)?.parse(passphrase)?;
+    let mut pem = parsed.cert.to_pem()?;
+    for cert in parsed.chain.into_iter().flatten() {
+        pem.extend(cert.to_pem()?);
+    }
+    pem.extend(parsed.pkey.private_key_to_pem_pkcs8()?);
+    Ok(pem)
+}
+
+/// When the identity's own certificate, the first in the chain, stops being valid
+fn certificate_expiry(identity_pem: &[u8]) -> Result<SystemTime, ErrorStack> {
+    let cert = X509::from_pem(identity_pem)?;
+    let diff = Asn1Time::from_unix(0)?.diff(cert.not_after())?;
+    let secs = i64::from(diff.days) * 24 * 60 * 60 + i64::from(diff.secs);
+    Ok(UNIX_EPOCH + Duration::from_secs(secs.max(0) as u64))
+}
+
+/// Every certificate in a PEM bundle, failing if there are none
+pub(crate) fn load_ca_bundle(path: &str) -> Result<Vec<reqwest::Certificate>, RelayError> {
+    let invalid = |source| RelayError::InvalidCaBundle {
+        path: path.to_owned(),
+        source,
+    };
+    let certs = X509::stack_from_pem(&read(path)?).map_err(|source| invalid(Some(source)))?;
+    if certs.is_empty() {
+        return Err(invalid(None));
+    }
+    certs
+        .iter()
+        .map(|cert| {
+            cert.to_der()
+                .map_err(|source| invalid(Some(source)))
+                .and_then(|der| {
+                    reqwest::Certificate::from_der(&der)
+                        .map_err(|source| RelayError::ClientBuildError { source })
+                })
+        })
+        .collect()
+}
+
 #[derive(Debug, Clone)]
 pub struct MutualTLSRelayer {
-    pub client: reqwest::Client,
+    pub attestor: Attestor,
+    pub policy: RelayPolicy,
+    /// Kept as PEM, as every request builds its own client and identities cannot be cloned
+    identity_pem: Vec<u8>,
+    identity_expires_at: SystemTime,
+    ca_certs: Vec<reqwest::Certificate>,
+    timeout: Duration,
 }
 
 impl MutualTLSRelayer {
+    /// Builds a client which presents the identity to relay URLs. Certificates in the
+    /// CA bundle are trusted in addition to the built-in roots. Every notification is
+    /// signed by the attestor, only sent to URLs the policy allows, and fails if it
+    /// takes longer than the timeout.
     pub fn new(
-        pem_file_path: String,
+        identity: &RelayIdentity,
+        ca_bundle_path: Option<&str>,
+        attestor: Attestor,
+        policy: RelayPolicy,
+        timeout: Duration,
     ) -> Result<MutualTLSRelayer, RelayError> {
-        let mut buf = Vec::new();
-        File::open(pem_file_path)
-            .unwrap()
-            .read_to_end(&mut buf)
-            .unwrap();
-        let pkcs12 = reqwest::Identity::from_pem(&buf).unwrap();
+        let identity_pem = identity.load()?;
+        let identity_expires_at = certificate_expiry(&identity_pem)
+            .map_err(|source| RelayError::CertificateError { source })?;
+        let ca_certs = match ca_bundle_path {
+            Some(path) => load_ca_bundle(path)?,
+            None => vec![],
+        };
+        // Clients are built per request, but a configuration which cannot build one
+        // should fail at startup
+        client_builder(&identity_pem, &ca_certs, timeout)?
+            .build()
+            .map_err(|source| RelayError::ClientBuildError { source })?;
 
-        let client = reqwest::Client::builder()
-            .identity(pkcs12)
-            .use_rustls_tls()
+        Ok(MutualTLSRelayer {
+            attestor,
+            policy,
+            identity_pem,
+            identity_expires_at,
+            ca_certs,
+            timeout,
+        })
+    }
+
+    /// When sites will start refusing relays, as the identity's certificate expires
+    pub fn identity_expires_at(&self) -> SystemTime {
+        self.identity_expires_at
+    }
+
+    /// A client which connects to the address the URL was approved for, so that the
+    /// host cannot answer with a different one, and which does not follow redirects,
+    /// as they could lead anywhere
+    fn pinned_client(&self, approved: &ApprovedRelayUrl) -> Result<reqwest::Client, RelayError> {
+        let mut builder = client_builder(&self.identity_pem, &self.ca_certs, self.timeout)?
+            .redirect(reqwest::redirect::Policy::none());
+        if let Some(host) = approved.url.domain() {
+            builder = builder.resolve(host, approved.addr);
+        }
+        builder
             .build()
-            .unwrap();
+            .map_err(|source| RelayError::ClientBuildError { source })
+    }
+}
 
-        Ok(MutualTLSRelayer { client })
+fn client_builder(
+    identity_pem: &[u8],
+    ca_certs: &[reqwest::Certificate],
+    timeout: Duration,
+) -> Result<reqwest::ClientBuilder, RelayError> {
+    let identity = reqwest::Identity::from_pem(identity_pem)
+        .map_err(|source| RelayError::ClientBuildError { source })?;
+    let mut builder = reqwest::Client::builder()
+        .identity(identity)
+        .timeout(timeout)
+        .use_rustls_tls();
+    for cert in ca_certs {
+        builder = builder.add_root_certificate(cert.clone());
     }
+    Ok(builder)
 }
 
 #[async_trait]
 impl Relayer for MutualTLSRelayer {
-    async fn relay(&self, path: String, relay_url: String) -> Result<StatusCode, RelayError> {
-        let mut req_body = HashMap::new();
-        req_body.insert("path", path);
-        req_body.insert("userId", "abc".to_owned());
+    async fn check_url(&self, host_origin: Option<String>, relay_url: String) -> Result<(), RelayUrlError> {
+        self.policy.check_for(host_origin.as_deref(), &relay_url).await.map(|_| ())
+    }
+
+    async fn relay(&self, notification: RelayNotification, relay_url: String, request_id: String) -> Result<RelayOutcome, RelayError> {
+        let approved = self
+            .policy
+            .check(&relay_url)
+            .await
+            .map_err(|source| RelayError::UrlRejected { source })?;
+        // The attestation names the URL it is sent to, so it is only valid for that site
+        let attestation = self
+            .attestor
+            .attest(approved.url.as_str(), &notification.path, notification.data_type.as_deref(), notification.operation)
+            .ok_or(RelayError::AttestationUnavailable)?;
+        let req_body = RelayBody {
+            user_id: self.attestor.user_id().ok_or(RelayError::AttestationUnavailable)?,
+            path: notification.path,
+            attestation,
+        };
 
-        self.client
-            .post(relay_url)
+        self.pinned_client(&approved)?
+            .post(approved.url)
+            .header(REQUEST_ID_HEADER, request_id)
             .json(&req_body)
             .send()
             .await
             .and_then(|response| response.error_for_status())
-            .and_then(|response| Ok(response.status()))
+            .map(|response| RelayOutcome::Delivered(response.status()))
             .map_err(|source| RelayError::RelayRequestError { source: Some(source) })
     }
 
-    async fn get(&self, relay_url: String) -> Result<Response, RelayError> {
-        self.client
-            .get(relay_url)
+    async fn proxy(&self, request: ProxyRequest) -> Result<Response, RelayError> {
+        let mut builder = self
+            .pinned_client(&request.url)?
+            .request(request.method, request.url.url)
+            .headers(request.headers);
+        if let Some(body) = request.body {
+            builder = builder.body(body);
+        }
+        // Error statuses are passed on to the caller rather than treated as failures
+        builder
             .send()
             .await
-            .and_then(|response| response.error_for_status())
             .map_err(|source| RelayError::RelayRequestError { source: Some(source) })
     }
 }
 
 #[cfg(test)]
 pub mod tests {
-    use super::{RelayError, Relayer};
+    use super::{
+        MutualTLSRelayer, ProxyRequest, RelayError, RelayIdentity, RelayNotification, RelayOutcome,
+        Relayer,
+    };
+    use crate::relay_policy::{tests::allowlist, RelayPolicy, RelayUrlError};
+    use crate::attestation::{AttestedClaims, Attestor, RelayOperation};
+    use crate::storage::tests::tls_fixture;
     use mockall::predicate::*;
     use mockall::*;
     use http::StatusCode;
     use reqwest::Response;
     use async_trait::async_trait;
+    use redact_crypto::{
+        key::sodiumoxide::SodiumOxideSecretAsymmetricKey, ByteSource, DataBuilder, States,
+        StringDataBuilder, TypeBuilder, VectorByteSource,
+    };
+    use sodiumoxide::crypto::sign;
+    use std::convert::TryFrom;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, UNIX_EPOCH};
+    use warp::Filter;
 
     mock! {
     pub Relayer {}
@@ -107,9 +418,255 @@ pub mod tests {
 
     #[async_trait]
     impl Relayer for MockRelayer {
-        async fn relay(&self, path: String, relay_url: String) -> Result<StatusCode, RelayError>;
-        async fn get(&self, relay_url: String) -> Result<Response, RelayError>;
+        async fn check_url(&self, host_origin: Option<String>, relay_url: String) -> Result<(), RelayUrlError>;
+        async fn relay(&self, notification: RelayNotification, relay_url: String, request_id: String) -> Result<RelayOutcome, RelayError>;
+        async fn proxy(&self, request: ProxyRequest) -> Result<Response, RelayError>;
+    }
+    }
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn pem() -> RelayIdentity {
+        RelayIdentity::Pem {
+            path: tls_fixture("client.pem"),
+        }
+    }
+
+    #[test]
+    fn test_identity_formats() {
+        for identity in &[
+            pem(),
+            RelayIdentity::PemPair {
+                cert_path: tls_fixture("client-cert.pem"),
+                key_path: tls_fixture("client-key.pem"),
+            },
+            RelayIdentity::Pkcs12 {
+                path: tls_fixture("client.p12"),
+                passphrase: "redact-test".to_owned(),
+            },
+        ] {
+            let relayer = MutualTLSRelayer::new(identity, None, Attestor::default(), RelayPolicy::default(), TIMEOUT).unwrap();
+            // The fixtures' client certificate is valid until 2056-10-10T23:30:56Z
+            assert_eq!(
+                relayer.identity_expires_at(),
+                UNIX_EPOCH + Duration::from_secs(2_738_446_256)
+            );
+        }
+        MutualTLSRelayer::new(&pem(), Some(&tls_fixture("ca.pem")), Attestor::default(), RelayPolicy::default(), TIMEOUT).unwrap();
+    }
+
+    #[test]
+    fn test_missing_files() {
+        let missing = tls_fixture("missing.pem");
+        for identity in &[
+            RelayIdentity::Pem { path: missing.clone() },
+            RelayIdentity::PemPair {
+                cert_path: tls_fixture("client-cert.pem"),
+                key_path: missing.clone(),
+            },
+            RelayIdentity::Pkcs12 {
+                path: missing.clone(),
+                passphrase: "redact-test".to_owned(),
+            },
+        ] {
+            assert!(matches!(
+                MutualTLSRelayer::new(identity, None, Attestor::default(), RelayPolicy::default(), TIMEOUT),
+                Err(RelayError::IoError { path, .. }) if path == missing
+            ));
+        }
+        assert!(matches!(
+            MutualTLSRelayer::new(&pem(), Some(&missing), Attestor::default(), RelayPolicy::default(), TIMEOUT),
+            Err(RelayError::IoError { .. })
+        ));
+    }
+
+    #[test]
+    fn test_invalid_identity() {
+        for identity in &[
+            RelayIdentity::Pem { path: tls_fixture("README.md") },
+            RelayIdentity::Pem { path: tls_fixture("client-cert.pem") },
+            RelayIdentity::PemPair {
+                cert_path: tls_fixture("client-key.pem"),
+                key_path: tls_fixture("client-key.pem"),
+            },
+        ] {
+            assert!(matches!(
+                MutualTLSRelayer::new(identity, None, Attestor::default(), RelayPolicy::default(), TIMEOUT),
+                Err(RelayError::IdentityError { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn test_invalid_pkcs12() {
+        for (path, passphrase) in &[("client.p12", "wrong"), ("client.pem", "redact-test")] {
+            let identity = RelayIdentity::Pkcs12 {
+                path: tls_fixture(path),
+                passphrase: (*passphrase).to_owned(),
+            };
+            assert!(matches!(
+                MutualTLSRelayer::new(&identity, None, Attestor::default(), RelayPolicy::default(), TIMEOUT),
+                Err(RelayError::Pkcs12Error { .. })
+            ));
+        }
     }
+
+    #[test]
+    fn test_invalid_ca_bundle() {
+        for path in &["README.md", "client-key.pem"] {
+            assert!(matches!(
+                MutualTLSRelayer::new(&pem(), Some(&tls_fixture(path)), Attestor::default(), RelayPolicy::default(), TIMEOUT),
+                Err(RelayError::InvalidCaBundle { .. })
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_relay_is_attested() {
+        let received = Arc::new(Mutex::new(None));
+        let routes = {
+            let received = received.clone();
+            warp::post()
+                .and(warp::body::json())
+                .map(move |body: serde_json::Value| {
+                    *received.lock().unwrap() = Some(body);
+                    warp::reply()
+                })
+        };
+        let (addr, server) = warp::serve(routes).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let attestor = Attestor::default();
+        let policy = RelayPolicy::new(&allowlist("https://host.example", &[&format!("http://{}", addr)]), true, true).unwrap();
+        let relayer = MutualTLSRelayer::new(&pem(), None, attestor.clone(), policy, TIMEOUT).unwrap();
+        let relay_url = format!("http://{}/relay", addr);
+        let notification = RelayNotification::new(
+            ".profile.name.".to_owned(),
+            &States::Unsealed {
+                builder: TypeBuilder::Data(DataBuilder::String(StringDataBuilder {})),
+                bytes: ByteSource::Vector(VectorByteSource::new(b"Alice")),
+            },
+            RelayOperation::Submit,
+        );
+        assert_eq!(notification.data_type, Some("string".to_owned()));
+
+        // Nothing is relayed before the signing key has been loaded
+        assert!(matches!(
+            relayer.relay(notification.clone(), relay_url.clone(), "test-request-id".to_owned()).await,
+            Err(RelayError::AttestationUnavailable)
+        ));
+        assert!(received.lock().unwrap().is_none());
+
+        attestor.set_key("alice", &SodiumOxideSecretAsymmetricKey::new());
+        relayer.relay(notification, relay_url.clone(), "test-request-id".to_owned()).await.unwrap();
+
+        let body = received.lock().unwrap().take().unwrap();
+        assert_eq!(body["path"], ".profile.name.");
+        assert_eq!(body["userId"], "alice");
+        let attestation = &body["attestation"];
+        assert_eq!(attestation["publicKey"].as_str(), attestor.public_key().as_deref());
+
+        let payload = base64::decode(attestation["payload"].as_str().unwrap()).unwrap();
+        let signature = base64::decode(attestation["signature"].as_str().unwrap()).unwrap();
+        let public_key = base64::decode(attestation["publicKey"].as_str().unwrap()).unwrap();
+        assert!(sign::verify_detached(
+            &sign::Signature::try_from(&signature[..]).unwrap(),
+            &payload,
+            &sign::PublicKey::from_slice(&public_key).unwrap()
+        ));
+        let claims: AttestedClaims = serde_json::from_slice(&payload).unwrap();
+        assert_eq!(claims.audience, relay_url);
+        assert_eq!(claims.path, ".profile.name.");
+        assert_eq!(claims.user_id, "alice");
+        assert_eq!(claims.data_type, Some("string".to_owned()));
+        assert_eq!(claims.operation, RelayOperation::Submit);
     }
 
-}
\ No newline at end of file
+    #[tokio::test]
+    async fn test_relay_failures() {
+        let slow = warp::path("slow").and_then(|| async {
+            tokio::time::sleep(Duration::from_secs(2)).await;
+            Ok::<_, warp::Rejection>(warp::reply())
+        });
+        let rejected = warp::path("rejected")
+            .map(|| warp::reply::with_status(warp::reply(), StatusCode::BAD_REQUEST));
+        let unavailable = warp::path("unavailable")
+            .map(|| warp::reply::with_status(warp::reply(), StatusCode::SERVICE_UNAVAILABLE));
+        let (addr, server) =
+            warp::serve(slow.or(rejected).or(unavailable)).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let attestor = Attestor::default();
+        attestor.set_key("alice", &SodiumOxideSecretAsymmetricKey::new());
+        let policy = RelayPolicy::new(&allowlist("https://host.example", &[&format!("http://{}", addr)]), true, true).unwrap();
+        let relayer =
+            MutualTLSRelayer::new(&pem(), None, attestor, policy, Duration::from_millis(200)).unwrap();
+        let notification = RelayNotification {
+            path: ".profile.name.".to_owned(),
+            data_type: Some("string".to_owned()),
+            operation: RelayOperation::Submit,
+        };
+
+        for (route, permanent) in &[("slow", false), ("rejected", true), ("unavailable", false)] {
+            let error = relayer
+                .relay(notification.clone(), format!("http://{}/{}", addr, route), "test-request-id".to_owned())
+                .await
+                .unwrap_err();
+            assert_eq!(error.is_permanent(), *permanent, "{}", route);
+        }
+
+        let error = relayer
+            .relay(notification, "http://169.254.169.254/latest".to_owned(), "test-request-id".to_owned())
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            RelayError::UrlRejected { source: RelayUrlError::NotAllowed { .. } }
+        ));
+        assert!(error.is_permanent());
+    }
+
+    #[tokio::test]
+    async fn test_proxy_request() {
+        let echo = warp::path("echo")
+            .and(warp::method())
+            .and(warp::header::<String>("accept"))
+            .and(warp::body::bytes())
+            .map(|method: http::Method, accept: String, body: warp::hyper::body::Bytes| {
+                format!("{} {} {}", method, accept, String::from_utf8_lossy(&body))
+            });
+        let redirect = warp::path("redirect")
+            .map(|| warp::redirect::temporary(http::Uri::from_static("/echo")));
+        let (addr, server) = warp::serve(echo.or(redirect)).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let policy = RelayPolicy::new(&allowlist("https://host.example", &[&format!("http://{}", addr)]), true, true).unwrap();
+        let relayer =
+            MutualTLSRelayer::new(&pem(), None, Attestor::default(), policy.clone(), TIMEOUT).unwrap();
+        let mut headers = reqwest::header::HeaderMap::new();
+        headers.insert("accept", "text/plain".parse().unwrap());
+
+        let response = relayer
+            .proxy(ProxyRequest {
+                method: reqwest::Method::PUT,
+                url: policy.check(&format!("http://{}/echo", addr)).await.unwrap(),
+                headers,
+                body: Some(b"brr".to_vec()),
+            })
+            .await
+            .unwrap();
+        assert_eq!(response.text().await.unwrap(), "PUT text/plain brr");
+
+        // Redirects are returned rather than followed
+        let response = relayer
+            .proxy(ProxyRequest {
+                method: reqwest::Method::GET,
+                url: policy.check(&format!("http://{}/redirect", addr)).await.unwrap(),
+                headers: reqwest::header::HeaderMap::new(),
+                body: None,
+            })
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::TEMPORARY_REDIRECT);
+    }
+}
@@ -1,3 +1,4 @@
+use crate::relay_history::{RelayAttempt, RelayStatus};
 use handlebars::{
     Context, Handlebars, Helper, Output, RenderContext, RenderError as HandlebarsRenderError,
     TemplateError as HandlebarsTemplateError,
@@ -15,7 +16,7 @@ pub enum RenderError {
     #[error("Failure happened during render")]
     RenderError { source: HandlebarsRenderError },
     #[error("Failed to load template file")]
-    TemplateError { source: HandlebarsTemplateError },
+    TemplateError { source: Box<HandlebarsTemplateError> },
 }
 
 impl Reject for RenderError {}
@@ -24,6 +25,9 @@ impl Reject for RenderError {}
 pub enum TemplateValues {
     Unsecure(UnsecureTemplateValues),
     Secure(SecureTemplateValues),
+    Batch(BatchTemplateValues),
+    Error(ErrorTemplateValues),
+    Relays(RelayHistoryTemplateValues),
 }
 
 #[derive(Serialize, Debug, Default, PartialEq)]
@@ -44,11 +48,68 @@ pub struct SecureTemplateValues {
     pub css: Option<String>,
     pub edit: Option<bool>,
     pub relay_url: Option<String>,
+    /// Set when a submitted value was saved locally because storage was unreachable
+    pub pending_sync: bool,
+    /// Set after a submitted value was relayed
+    pub relay_status: Option<RelayStatusValues>,
+    /// The version of the entry the displayed value was read from, submitted back
+    /// with edits
+    pub base_version: Option<String>,
+    /// The signed origin of the host page, passed on when asking for a fresh session
+    pub host_origin_grant: Option<String>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct RelayStatusValues {
+    pub status: RelayStatus,
+    pub message: &'static str,
+}
+
+impl From<RelayStatus> for RelayStatusValues {
+    fn from(status: RelayStatus) -> Self {
+        RelayStatusValues {
+            status,
+            message: status.message(),
+        }
+    }
+}
+
+/// Values displayed together on a single secure page, in the order they were requested
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct BatchTemplateValues {
+    pub fields: Vec<BatchField>,
+    pub css: Option<String>,
+}
+
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct BatchField {
+    pub path: String,
+    /// Missing if nothing is stored at the path
+    pub data: Option<Data>,
+}
+
+/// Which sites were notified about which paths, newest first
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct RelayHistoryTemplateValues {
+    pub attempts: Vec<RelayAttempt>,
+    /// Set when only the attempts for one path are listed
+    pub path: Option<String>,
+}
+
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct ErrorTemplateValues {
+    pub code: u16,
+    pub error_code: String,
+    pub message: String,
+    pub css: Option<String>,
+    pub retry_url: Option<String>,
 }
 
 impl From<HandlebarsTemplateError> for RenderError {
     fn from(source: HandlebarsTemplateError) -> Self {
-        RenderError::TemplateError { source }
+        RenderError::TemplateError {
+            source: Box::new(source),
+        }
     }
 }
 
@@ -196,9 +257,15 @@ impl<'reg> Renderer for HandlebarsRenderer<'reg> {
 
 #[cfg(test)]
 pub mod tests {
-    use super::{RenderError, RenderTemplate, Renderer};
+    use super::{
+        BatchField, BatchTemplateValues, ErrorTemplateValues, HandlebarsRenderer,
+        RelayHistoryTemplateValues, RenderError, RenderTemplate, Renderer, TemplateValues,
+    };
+    use crate::attestation::RelayOperation;
+    use crate::relay_history::{RelayAttempt, RelayStatus};
     use mockall::predicate::*;
     use mockall::*;
+    use redact_crypto::Data;
 
     mock! {
     pub Renderer {
@@ -217,4 +284,86 @@ pub mod tests {
             })
         }
     }
+
+    #[test]
+    fn test_render_error_template() {
+        let mut template_mapping = std::collections::HashMap::new();
+        template_mapping.insert("error", "./static/error.handlebars");
+        let renderer = HandlebarsRenderer::new(template_mapping).unwrap();
+
+        let html = renderer
+            .render(RenderTemplate {
+                name: "error",
+                value: TemplateValues::Error(ErrorTemplateValues {
+                    code: 404,
+                    error_code: "data_not_found".to_owned(),
+                    message: "DATA NOT FOUND".to_owned(),
+                    css: Some("p { color: red; }".to_owned()),
+                    retry_url: Some("/data/.testKey.?edit=true".to_owned()),
+                }),
+            })
+            .unwrap();
+        assert!(html.contains("p { color: red; }"));
+        assert!(html.contains("DATA NOT FOUND"));
+        assert!(html.contains("href=\"/data/.testKey.?edit&#x3D;true\""));
+    }
+
+    #[test]
+    fn test_render_batch_template() {
+        let mut template_mapping = std::collections::HashMap::new();
+        template_mapping.insert("batch", "./static/batch.handlebars");
+        let renderer = HandlebarsRenderer::new(template_mapping).unwrap();
+
+        let html = renderer
+            .render(RenderTemplate {
+                name: "batch",
+                value: TemplateValues::Batch(BatchTemplateValues {
+                    fields: vec![
+                        BatchField {
+                            path: ".profile.firstName.".to_owned(),
+                            data: Some(Data::String("Alice".to_owned())),
+                        },
+                        BatchField {
+                            path: ".profile.age.".to_owned(),
+                            data: None,
+                        },
+                    ],
+                    css: Some(".field { display: inline; }".to_owned()),
+                }),
+            })
+            .unwrap();
+        assert!(html.contains(".field { display: inline; }"));
+        assert!(html.contains("data-path=\".profile.firstName.\""));
+        assert!(html.contains("Alice"));
+        assert!(html.contains("data-path=\".profile.age.\" data-index=\"1\" data-missing"));
+    }
+
+    #[test]
+    fn test_render_relays_template() {
+        let mut template_mapping = std::collections::HashMap::new();
+        template_mapping.insert("relays", "./static/relays.handlebars");
+        let renderer = HandlebarsRenderer::new(template_mapping).unwrap();
+
+        let html = renderer
+            .render(RenderTemplate {
+                name: "relays",
+                value: TemplateValues::Relays(RelayHistoryTemplateValues {
+                    attempts: vec![RelayAttempt {
+                        path: ".profile.name.".to_owned(),
+                        relay_url: "https://example.com/relay".to_owned(),
+                        operation: RelayOperation::Submit,
+                        status: RelayStatus::Failed,
+                        status_code: Some(400),
+                        timestamp: 1_600_000_000,
+                    }],
+                    path: None,
+                }),
+            })
+            .unwrap();
+        assert!(html.contains("class=\"relay relay-failed\""));
+        assert!(html.contains("https://example.com/relay"));
+        assert!(html.contains("data-timestamp=\"1600000000\""));
+        assert!(html.contains("<td>400</td>"));
+        assert!(!html.contains("no-relays"));
+    }
 }
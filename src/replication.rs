@@ -0,0 +1,523 @@
+use crate::{metrics::REPLICA_REPAIRS, storage::Deleter};
+use async_trait::async_trait;
+use futures::{
+    future::{join_all, BoxFuture, FutureExt},
+    stream::{FuturesUnordered, StreamExt},
+};
+use redact_crypto::{Data, Entry, EntryPath, HasBuilder, HasIndex, States, StorageError, Storer};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::RwLock;
+use tokio::task::JoinHandle;
+use tracing::warn;
+
+type Document = <Data as HasIndex>::Index;
+
+/// How many replicas must accept a write or delete for it to succeed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WritePolicy {
+    /// Every replica
+    All,
+    /// More than half of the replicas
+    Quorum,
+}
+
+impl WritePolicy {
+    fn required(&self, replicas: usize) -> usize {
+        match self {
+            WritePolicy::All => replicas,
+            WritePolicy::Quorum => replicas / 2 + 1,
+        }
+    }
+}
+
+/// Orders writes to a path against repairs of it. Writes share the lock, while a
+/// repair holds it alone, and every write moves the path to a new generation.
+#[derive(Default)]
+struct PathLock {
+    writes: RwLock<()>,
+    generation: AtomicU64,
+}
+
+/// The locks of every path written or repaired since startup
+#[derive(Default)]
+struct PathLocks {
+    locks: Mutex<HashMap<String, Arc<PathLock>>>,
+}
+
+impl PathLocks {
+    fn get(&self, path: &str) -> Arc<PathLock> {
+        self.locks
+            .lock()
+            .unwrap()
+            .entry(path.to_owned())
+            .or_default()
+            .clone()
+    }
+
+    /// How many writes to the path have started
+    fn generation(&self, path: &str) -> u64 {
+        self.locks
+            .lock()
+            .unwrap()
+            .get(path)
+            .map_or(0, |lock| lock.generation.load(Ordering::SeqCst))
+    }
+}
+
+/// A `Storer` which writes every entry to several replicas, so that data survives the
+/// loss of any replica as long as the write policy was met. Reads are served by the
+/// first replica to find the entry. The answers of the other replicas are compared
+/// in the background, and if they disagree, replicas which are missing the entry or
+/// hold a different version than most others are repaired. Answers read while the
+/// path was being written may not reflect the write yet, so no repair is made from
+/// them.
+#[derive(Clone)]
+pub struct ReplicatedStorer<H: Storer> {
+    replicas: Arc<Vec<H>>,
+    policy: WritePolicy,
+    path_locks: Arc<PathLocks>,
+    repairs: Arc<Mutex<Vec<JoinHandle<()>>>>,
+}
+
+impl<H: Storer> ReplicatedStorer<H> {
+    pub fn new(replicas: Vec<H>, policy: WritePolicy) -> ReplicatedStorer<H> {
+        ReplicatedStorer {
+            replicas: Arc::new(replicas),
+            policy,
+            path_locks: Arc::new(PathLocks::default()),
+            repairs: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    pub fn replicas(&self) -> &[H] {
+        &self.replicas
+    }
+
+    /// Waits for the answers of every read so far to be compared, and any repairs made
+    #[cfg(test)]
+    pub async fn settle(&self) {
+        let repairs: Vec<_> = self.repairs.lock().unwrap().drain(..).collect();
+        for repair in repairs {
+            repair.await.unwrap();
+        }
+    }
+
+    /// How many replicas must be missing an entry for it not to exist
+    fn read_quorum(&self) -> usize {
+        let replicas = self.replicas.len();
+        (replicas + 1).saturating_sub(self.policy.required(replicas))
+    }
+
+    /// Succeeds if enough replicas succeeded, otherwise fails with the first error
+    fn apply_policy<T>(
+        &self,
+        operation: &str,
+        path: &str,
+        results: Vec<Result<T, StorageError>>,
+    ) -> Result<usize, StorageError> {
+        let mut succeeded = 0;
+        let mut first_error = None;
+        for (replica, result) in results.into_iter().enumerate() {
+            match result {
+                Ok(_) => succeeded += 1,
+                Err(e) => {
+                    warn!(error = %e, path = %path, replica, "replica failed to {}", operation);
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+        match first_error {
+            Some(e) if succeeded < self.policy.required(self.replicas.len()) => Err(e),
+            _ => Ok(succeeded),
+        }
+    }
+}
+
+/// The version each answer holds, serialized so that answers can be compared
+fn versions(answers: &[(usize, Option<Entry>)]) -> Vec<Option<String>> {
+    answers
+        .iter()
+        .map(|(_, entry)| {
+            entry
+                .as_ref()
+                .and_then(|entry| serde_json::to_string(&entry.value).ok())
+        })
+        .collect()
+}
+
+/// Compares the answers of every replica which could be reached, and writes the
+/// version held by most of them to the others. Entries missing from most replicas
+/// are left alone, as they may have been deleted. Nothing is repaired if the path
+/// has been written since it was read at `generation`, or is being written.
+async fn repair<H: Storer>(
+    replicas: Arc<Vec<H>>,
+    path_locks: Arc<PathLocks>,
+    path: String,
+    generation: u64,
+    answers: Vec<(usize, Option<Entry>)>,
+) {
+    let versions = versions(&answers);
+    let majority = (0..versions.len()).find(|&i| {
+        versions
+            .iter()
+            .filter(|version| **version == versions[i])
+            .count()
+            * 2
+            > versions.len()
+    });
+    let (majority, entry) = match majority.map(|i| (&versions[i], &answers[i].1)) {
+        Some((majority, Some(entry))) => (majority, entry.clone()),
+        Some((_, None)) => return,
+        None => {
+            warn!(path = %path, "replicas diverge without a majority, leaving them as they are");
+            REPLICA_REPAIRS.with_label_values(&["diverged"]).inc();
+            return;
+        }
+    };
+
+    // Writes to the path wait for the repair to finish, so that it cannot overwrite them
+    let lock = path_locks.get(&path);
+    let _repairing = match lock.writes.try_write() {
+        Ok(guard) if lock.generation.load(Ordering::SeqCst) == generation => guard,
+        _ => {
+            REPLICA_REPAIRS.with_label_values(&["skipped"]).inc();
+            return;
+        }
+    };
+    for ((replica, _), version) in answers.iter().zip(&versions) {
+        if version == majority {
+            continue;
+        }
+        match replicas[*replica]
+            .create(entry.path.clone(), entry.value.clone())
+            .await
+        {
+            Ok(_) => {
+                REPLICA_REPAIRS.with_label_values(&["repaired"]).inc();
+            }
+            Err(e) => {
+                warn!(error = %e, path = %path, replica, "failed to repair replica");
+                REPLICA_REPAIRS.with_label_values(&["failed"]).inc();
+            }
+        }
+    }
+}
+
+// async_trait adds its own lifetime bounds to the ones required by `Storer`
+#[allow(clippy::multiple_bound_locations)]
+#[async_trait]
+impl<H: Storer + 'static> Storer for ReplicatedStorer<H> {
+    async fn get_indexed<T: HasBuilder + 'static>(
+        &self,
+        path: &str,
+        index: &Option<Document>,
+    ) -> Result<Entry, StorageError> {
+        let generation = self.path_locks.generation(path);
+        let mut lookups: FuturesUnordered<
+            BoxFuture<'static, (usize, Result<Entry, StorageError>)>,
+        > = self
+            .replicas
+            .iter()
+            .cloned()
+            .enumerate()
+            .map(|(replica, storer)| {
+                let path = path.to_owned();
+                let index = index.clone();
+                let lookup: BoxFuture<'static, _> =
+                    Box::pin(
+                        async move { (replica, storer.get_indexed::<T>(&path, &index).await) },
+                    );
+                lookup
+            })
+            .collect();
+
+        // Replicas which could not be reached take no part in repairs
+        let mut answers = Vec::new();
+        let mut first_error = None;
+        let mut found = None;
+        while let Some((replica, result)) = lookups.next().await {
+            match result {
+                Ok(entry) => {
+                    answers.push((replica, Some(entry.clone())));
+                    found = Some(entry);
+                    break;
+                }
+                Err(StorageError::NotFound) => answers.push((replica, None)),
+                Err(e) => {
+                    warn!(error = %e, path = %path, replica, "replica failed to get entry");
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+
+        // The other replicas are still asked, so that they can be compared in the background
+        let not_found = answers.len();
+        if self.replicas.len() > 1 {
+            let replicas = self.replicas.clone();
+            let path_locks = self.path_locks.clone();
+            let repair_path = path.to_owned();
+            let repair_task = tokio::spawn(async move {
+                while let Some((replica, result)) = lookups.next().await {
+                    match result {
+                        Ok(entry) => answers.push((replica, Some(entry))),
+                        Err(StorageError::NotFound) => answers.push((replica, None)),
+                        Err(_) => (),
+                    }
+                }
+                let versions = versions(&answers);
+                if versions.iter().any(|version| *version != versions[0]) {
+                    repair(replicas, path_locks, repair_path, generation, answers).await;
+                }
+            });
+            let mut repairs = self.repairs.lock().unwrap();
+            repairs.retain_mut(|repair| repair.now_or_never().is_none());
+            repairs.push(repair_task);
+        }
+
+        // An entry is only reported missing once enough replicas say so that any write
+        // which met the policy must have reached one of them
+        match (found, first_error) {
+            (Some(entry), _) => Ok(entry),
+            (None, Some(e)) if not_found < self.read_quorum() => Err(e),
+            (None, _) => Err(StorageError::NotFound),
+        }
+    }
+
+    async fn list_indexed<T: HasBuilder + Send + 'static>(
+        &self,
+        path: &str,
+        skip: i64,
+        page_size: i64,
+        index: &Option<Document>,
+    ) -> Result<Vec<Entry>, StorageError> {
+        let mut first_error = StorageError::NotFound;
+        for replica in self.replicas.iter() {
+            match replica
+                .list_indexed::<T>(path, skip, page_size, index)
+                .await
+            {
+                Ok(entries) => return Ok(entries),
+                Err(e) => first_error = e,
+            }
+        }
+        Err(first_error)
+    }
+
+    async fn create(&self, path: EntryPath, value: States) -> Result<bool, StorageError> {
+        let lock = self.path_locks.get(&path);
+        let _writing = lock.writes.read().await;
+        lock.generation.fetch_add(1, Ordering::SeqCst);
+        let results = join_all(
+            self.replicas
+                .iter()
+                .map(|replica| replica.create(path.clone(), value.clone())),
+        )
+        .await;
+        self.apply_policy("create entry", &path, results)
+            .map(|_| true)
+    }
+}
+
+#[async_trait]
+impl<H: Storer + Deleter + 'static> Deleter for ReplicatedStorer<H> {
+    async fn delete(&self, path: &str) -> Result<(), StorageError> {
+        let lock = self.path_locks.get(path);
+        let _writing = lock.writes.read().await;
+        lock.generation.fetch_add(1, Ordering::SeqCst);
+        let results = join_all(self.replicas.iter().map(|replica| replica.delete(path))).await;
+        if results
+            .iter()
+            .all(|result| matches!(result, Err(StorageError::NotFound)))
+        {
+            return Err(StorageError::NotFound);
+        }
+        // A replica which never held the entry already agrees with the delete
+        let results = results
+            .into_iter()
+            .map(|result| match result {
+                Err(StorageError::NotFound) => Ok(()),
+                result => result,
+            })
+            .collect();
+        self.apply_policy("delete entry", path, results).map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ReplicatedStorer, WritePolicy};
+    use crate::storage::tests::CountingStorer;
+    use redact_crypto::{
+        ByteSource, Data, DataBuilder, States, StorageError, Storer, StringDataBuilder,
+        TypeBuilder, VectorByteSource,
+    };
+    use std::sync::atomic::Ordering;
+
+    fn string_value(value: &str) -> States {
+        States::Unsealed {
+            builder: TypeBuilder::Data(DataBuilder::String(StringDataBuilder {})),
+            bytes: ByteSource::Vector(VectorByteSource::new(value.as_bytes())),
+        }
+    }
+
+    fn replicas() -> Vec<CountingStorer> {
+        vec![
+            CountingStorer::default(),
+            CountingStorer::default(),
+            CountingStorer::default(),
+        ]
+    }
+
+    async fn value_of(storer: &CountingStorer, path: &str) -> Option<Data> {
+        match storer.get::<Data>(path).await {
+            Ok(entry) => Some(storer.resolve::<Data>(entry.value).await.unwrap()),
+            Err(_) => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_write_policies() {
+        let replicas = replicas();
+        replicas[2].set_unreachable(true);
+
+        let quorum = ReplicatedStorer::new(replicas.clone(), WritePolicy::Quorum);
+        assert!(quorum
+            .create(".profile.name.".to_owned(), string_value("Alice"))
+            .await
+            .unwrap());
+        assert_eq!(
+            value_of(&replicas[1], ".profile.name.").await,
+            Some(Data::String("Alice".to_owned()))
+        );
+
+        let all = ReplicatedStorer::new(replicas.clone(), WritePolicy::All);
+        assert!(matches!(
+            all.create(".profile.name.".to_owned(), string_value("Bob"))
+                .await,
+            Err(StorageError::InternalError { .. })
+        ));
+
+        replicas[1].set_unreachable(true);
+        assert!(matches!(
+            quorum
+                .create(".profile.name.".to_owned(), string_value("Bob"))
+                .await,
+            Err(StorageError::InternalError { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_read_survives_lost_replicas() {
+        let replicas = replicas();
+        let storer = ReplicatedStorer::new(replicas.clone(), WritePolicy::All);
+        storer
+            .create(".profile.name.".to_owned(), string_value("Alice"))
+            .await
+            .unwrap();
+        replicas[0].set_unreachable(true);
+        replicas[1].set_unreachable(true);
+
+        let entry = storer.get::<Data>(".profile.name.").await.unwrap();
+        assert_eq!(
+            storer.resolve::<Data>(entry.value).await.unwrap(),
+            Data::String("Alice".to_owned())
+        );
+        assert!(matches!(
+            storer.get::<Data>(".profile.missing.").await,
+            Err(StorageError::NotFound)
+        ));
+
+        replicas[2].set_unreachable(true);
+        assert!(matches!(
+            storer.get::<Data>(".profile.name.").await,
+            Err(StorageError::InternalError { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_read_needs_quorum_to_report_missing() {
+        let replicas = replicas();
+        let storer = ReplicatedStorer::new(replicas.clone(), WritePolicy::Quorum);
+        replicas[2].set_unreachable(true);
+        storer
+            .create(".profile.name.".to_owned(), string_value("Alice"))
+            .await
+            .unwrap();
+        assert!(matches!(
+            storer.get::<Data>(".profile.missing.").await,
+            Err(StorageError::NotFound)
+        ));
+
+        // The only replica left to answer is the one which missed the write
+        replicas[0].set_unreachable(true);
+        replicas[1].set_unreachable(true);
+        replicas[2].set_unreachable(false);
+        assert!(matches!(
+            storer.get::<Data>(".profile.name.").await,
+            Err(StorageError::InternalError { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_read_repairs_divergent_replicas() {
+        let replicas = replicas();
+        replicas[0].insert(".profile.name.", string_value("Alice"));
+        replicas[1].insert(".profile.name.", string_value("Alice"));
+        replicas[2].insert(".profile.email.", string_value("alice@example.com"));
+        replicas[2].insert(".profile.name.", string_value("Bob"));
+        let storer = ReplicatedStorer::new(replicas.clone(), WritePolicy::Quorum);
+
+        storer.get::<Data>(".profile.name.").await.unwrap();
+        storer.get::<Data>(".profile.email.").await.unwrap();
+        storer.settle().await;
+
+        for replica in &replicas {
+            assert_eq!(
+                value_of(replica, ".profile.name.").await,
+                Some(Data::String("Alice".to_owned()))
+            );
+        }
+        // An entry held by a single replica may have been deleted from the others
+        assert_eq!(value_of(&replicas[0], ".profile.email.").await, None);
+    }
+
+    #[tokio::test]
+    async fn test_read_repair_leaves_writes_in_flight_alone() {
+        let replicas = replicas();
+        replicas[0].insert(".profile.name.", string_value("Alice"));
+        replicas[1].insert(".profile.name.", string_value("Alice"));
+        replicas[2].insert(".profile.name.", string_value("Bob"));
+        let storer = ReplicatedStorer::new(replicas.clone(), WritePolicy::Quorum);
+
+        // The odd replica may be the first to hold a value still being written
+        let lock = storer.path_locks.get(".profile.name.");
+        let writing = lock.writes.read().await;
+        lock.generation.fetch_add(1, Ordering::SeqCst);
+        storer.get::<Data>(".profile.name.").await.unwrap();
+        storer.settle().await;
+        drop(writing);
+        assert_eq!(
+            value_of(&replicas[2], ".profile.name.").await,
+            Some(Data::String("Bob".to_owned()))
+        );
+
+        // Answers read before a write started are not used to repair once it is done
+        storer.get::<Data>(".profile.name.").await.unwrap();
+        lock.generation.fetch_add(1, Ordering::SeqCst);
+        storer.settle().await;
+        assert_eq!(
+            value_of(&replicas[2], ".profile.name.").await,
+            Some(Data::String("Bob".to_owned()))
+        );
+
+        // Once writes have settled the replicas are repaired again
+        storer.get::<Data>(".profile.name.").await.unwrap();
+        storer.settle().await;
+        assert_eq!(
+            value_of(&replicas[2], ".profile.name.").await,
+            Some(Data::String("Alice".to_owned()))
+        );
+    }
+}
@@ -1,11 +1,16 @@
+pub mod api;
 pub mod data;
 pub mod error;
+pub mod handshake;
+pub mod health;
+pub mod metrics;
 pub(crate) mod proxy;
+pub mod relays;
 
 pub use data::get::{with_token, without_token};
 pub use data::post::submit_data;
 pub use error::{
-    BadRequestRejection, CryptoErrorRejection, DataNotFoundRejection,
-    IframeTokensDoNotMatchRejection, SerializationRejection, SessionTokenNotFoundRejection,
-    StorageErrorRejection,
+    ApiTokenRejection, ApiTokenStoreRejection, CryptoErrorRejection, DataNotFoundRejection, IframeTokensDoNotMatchRejection,
+    SerializationRejection, SessionTokenNotFoundRejection, StorageErrorRejection,
+    TokenAlreadyRedeemedRejection, ValidationRejection,
 };
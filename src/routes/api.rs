@@ -0,0 +1,349 @@
+use crate::{
+    api_token::{ApiTokenError, ApiTokenStore},
+    routes::{
+        data::{fetch_data, seal_and_store},
+        ApiTokenRejection, ApiTokenStoreRejection, DataNotFoundRejection, StorageErrorRejection,
+        ValidationRejection,
+    },
+    storage::Deleter,
+};
+use redact_crypto::{Data, StorageError, Storer};
+use serde::Serialize;
+use tracing::info;
+use warp::http::StatusCode;
+use warp::{Filter, Rejection, Reply};
+
+/// Largest JSON body accepted when writing data
+const MAX_BODY_LENGTH: u64 = 64 * 1024;
+
+/// Entries under this path hold encryption keys rather than user data
+const KEYS_PATH_PREFIX: &str = ".keys.";
+
+#[derive(Serialize)]
+struct DataResponse {
+    path: String,
+    data: Data,
+}
+
+/// Extracts the name of the app whose API token was presented in the
+/// `Authorization: Bearer <token>` header, rejecting the request if there is none
+pub fn authorize<A: ApiTokenStore + 'static>(
+    api_tokens: A,
+) -> impl Filter<Extract = (String,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and(warp::any().map(move || api_tokens.clone()))
+        .and_then(|header: Option<String>, api_tokens: A| async move {
+            let token = header
+                .as_deref()
+                .and_then(|header| header.strip_prefix("Bearer "))
+                .ok_or_else(|| warp::reject::custom(ApiTokenRejection))?;
+            let token = token.trim().to_owned();
+            // The token file may have to be read, which is left to the blocking pool
+            tokio::task::spawn_blocking(move || api_tokens.authorize(&token))
+                .await
+                .unwrap_or_else(|e| {
+                    Err(ApiTokenError::IoError {
+                        source: std::io::Error::other(e),
+                    })
+                })
+                .map_err(ApiTokenStoreRejection)?
+                .ok_or_else(|| warp::reject::custom(ApiTokenRejection))
+        })
+}
+
+fn data_path() -> impl Filter<Extract = (String,), Error = Rejection> + Clone {
+    warp::path!("api" / "v1" / "data" / String)
+}
+
+/// Keys are never exposed through the API, as overwriting or deleting one would make
+/// all data sealed with it unreadable
+fn writable_path(path: String) -> Result<String, Rejection> {
+    if path.starts_with(KEYS_PATH_PREFIX) {
+        Err(warp::reject::custom(ValidationRejection))
+    } else {
+        Ok(path)
+    }
+}
+
+pub fn get<A: ApiTokenStore + 'static, H: Storer>(
+    api_tokens: A,
+    storer: H,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::any()
+        .and(data_path())
+        .and(authorize(api_tokens))
+        .and(warp::any().map(move || storer.clone()))
+        .and_then(move |path: String, app: String, storer: H| async move {
+            info!(app = %app, path = %path, "serving data over the API");
+            match fetch_data(&storer, &path)
+                .await
+                .map_err(StorageErrorRejection)?
+            {
+                Some(data) => Ok(warp::reply::json(&DataResponse { path, data })),
+                None => Err(warp::reject::custom(DataNotFoundRejection)),
+            }
+        })
+}
+
+pub fn put<A: ApiTokenStore + 'static, H: Storer>(
+    api_tokens: A,
+    storer: H,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::any()
+        .and(data_path())
+        .and(authorize(api_tokens))
+        .and(warp::body::content_length_limit(MAX_BODY_LENGTH))
+        .and(warp::body::json::<Data>())
+        .and(warp::any().map(move || storer.clone()))
+        .and_then(
+            move |path: String, app: String, data: Data, storer: H| async move {
+                let path = writable_path(path)?;
+                info!(app = %app, path = %path, "storing data over the API");
+                seal_and_store(&storer, path.clone(), data.clone()).await?;
+                Ok::<_, Rejection>(warp::reply::json(&DataResponse { path, data }))
+            },
+        )
+}
+
+pub fn delete<A: ApiTokenStore + 'static, D: Deleter>(
+    api_tokens: A,
+    deleter: D,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::any()
+        .and(data_path())
+        .and(authorize(api_tokens))
+        .and(warp::any().map(move || deleter.clone()))
+        .and_then(move |path: String, app: String, deleter: D| async move {
+            let path = writable_path(path)?;
+            info!(app = %app, path = %path, "deleting data over the API");
+            match deleter.delete(&path).await {
+                Ok(()) => Ok(warp::reply::with_status(
+                    warp::reply(),
+                    StatusCode::NO_CONTENT,
+                )),
+                Err(StorageError::NotFound) => Err(warp::reject::custom(DataNotFoundRejection)),
+                Err(e) => Err(warp::reject::custom(StorageErrorRejection(e))),
+            }
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api_token::tests::MockApiTokenStore;
+    use crate::error_handler::recover;
+    use crate::render::tests::MockRenderer;
+    use crate::routes::api;
+    use crate::storage::tests::MockDeleter;
+    use mockall::predicate::*;
+    use redact_crypto::{
+        key::sodiumoxide::{SodiumOxideSymmetricKey, SodiumOxideSymmetricKeyBuilder},
+        storage::tests::MockStorer,
+        ByteSource, Data, DataBuilder, Entry, KeyBuilder, States, StorageError, StringDataBuilder,
+        SymmetricKey, SymmetricKeyBuilder, TypeBuilder, VectorByteSource,
+    };
+    use serde_json::Value;
+    use std::sync::Arc;
+
+    fn api_tokens() -> Arc<MockApiTokenStore> {
+        let mut api_tokens = MockApiTokenStore::new();
+        api_tokens
+            .expect_authorize()
+            .with(eq("valid-token"))
+            .returning(|_| Ok(Some("desktop-app".to_owned())));
+        api_tokens.expect_authorize().returning(|_| Ok(None));
+        Arc::new(api_tokens)
+    }
+
+    fn error_code(body: &[u8]) -> String {
+        let body: Value = serde_json::from_slice(body).unwrap();
+        body["error_code"].as_str().unwrap().to_owned()
+    }
+
+    #[tokio::test]
+    async fn test_get() {
+        let mut storer = MockStorer::new();
+        storer
+            .expect_get_indexed::<Data>()
+            .times(1)
+            .withf(|path, _| path == ".profile.name.")
+            .returning(|_, _| {
+                Ok(Entry {
+                    path: ".profile.name.".to_owned(),
+                    value: States::Unsealed {
+                        builder: TypeBuilder::Data(DataBuilder::String(StringDataBuilder {})),
+                        bytes: ByteSource::Vector(VectorByteSource::new(b"Alice")),
+                    },
+                })
+            });
+
+        let res = warp::test::request()
+            .path("/api/v1/data/.profile.name.")
+            .header("authorization", "Bearer valid-token")
+            .reply(&api::get(api_tokens(), Arc::new(storer)))
+            .await;
+        assert_eq!(res.status(), 200);
+        let body: Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["path"], ".profile.name.");
+        assert_eq!(body["data"]["t"], "String");
+        assert_eq!(body["data"]["c"], "Alice");
+    }
+
+    #[tokio::test]
+    async fn test_get_not_found() {
+        let mut storer = MockStorer::new();
+        storer
+            .expect_get_indexed::<Data>()
+            .times(1)
+            .returning(|_, _| Err(StorageError::NotFound));
+
+        let res = warp::test::request()
+            .path("/api/v1/data/.profile.name.")
+            .header("authorization", "Bearer valid-token")
+            .reply(&recover(
+                api::get(api_tokens(), Arc::new(storer)),
+                Arc::new(MockRenderer::new()),
+            ))
+            .await;
+        assert_eq!(res.status(), 404);
+        assert_eq!(error_code(res.body()), "data_not_found");
+    }
+
+    #[tokio::test]
+    async fn test_unauthorized() {
+        let get = recover(
+            api::get(api_tokens(), Arc::new(MockStorer::new())),
+            Arc::new(MockRenderer::new()),
+        );
+        for authorization in &[None, Some("Bearer revoked-token"), Some("valid-token")] {
+            let mut req = warp::test::request().path("/api/v1/data/.profile.name.");
+            if let Some(authorization) = authorization {
+                req = req.header("authorization", *authorization);
+            }
+            let res = req.reply(&get).await;
+            assert_eq!(res.status(), 401);
+            assert_eq!(error_code(res.body()), "api_token_invalid");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_put() {
+        let mut storer = MockStorer::new();
+        storer
+            .expect_get_indexed::<SymmetricKey>()
+            .times(1)
+            .withf(|path, _| path == ".keys.default")
+            .returning(|_, _| {
+                let sosk = SodiumOxideSymmetricKey::new();
+                Ok(Entry {
+                    path: ".keys.default".to_owned(),
+                    value: States::Unsealed {
+                        builder: TypeBuilder::Key(KeyBuilder::Symmetric(
+                            SymmetricKeyBuilder::SodiumOxide(SodiumOxideSymmetricKeyBuilder {}),
+                        )),
+                        bytes: ByteSource::Vector(VectorByteSource::new(sosk.key.as_ref())),
+                    },
+                })
+            });
+        storer
+            .expect_create()
+            .times(1)
+            .withf(|path, value| path == ".profile.age." && matches!(value, States::Sealed { .. }))
+            .returning(|_, _| Ok(true));
+
+        let res = warp::test::request()
+            .method("PUT")
+            .path("/api/v1/data/.profile.age.")
+            .header("authorization", "Bearer valid-token")
+            .json(&Data::U64(42))
+            .reply(&api::put(api_tokens(), Arc::new(storer)))
+            .await;
+        assert_eq!(res.status(), 200);
+        let body: Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["data"]["c"], 42);
+    }
+
+    #[tokio::test]
+    async fn test_put_invalid_body() {
+        let mut storer = MockStorer::new();
+        storer.expect_create().times(0);
+
+        let res = warp::test::request()
+            .method("PUT")
+            .path("/api/v1/data/.profile.age.")
+            .header("authorization", "Bearer valid-token")
+            .header("content-type", "application/json")
+            .body("{\"t\": \"U64\", \"c\": \"not a number\"}")
+            .reply(&recover(
+                api::put(api_tokens(), Arc::new(storer)),
+                Arc::new(MockRenderer::new()),
+            ))
+            .await;
+        assert_eq!(res.status(), 400);
+    }
+
+    #[tokio::test]
+    async fn test_keys_cannot_be_written_or_deleted() {
+        let mut storer = MockStorer::new();
+        storer.expect_create().times(0);
+        let mut deleter = MockDeleter::new();
+        deleter.expect_delete().times(0);
+
+        let res = warp::test::request()
+            .method("PUT")
+            .path("/api/v1/data/.keys.default.")
+            .header("authorization", "Bearer valid-token")
+            .json(&Data::String("overwritten".to_owned()))
+            .reply(&recover(
+                api::put(api_tokens(), Arc::new(storer)),
+                Arc::new(MockRenderer::new()),
+            ))
+            .await;
+        assert_eq!(res.status(), 422);
+
+        let res = warp::test::request()
+            .method("DELETE")
+            .path("/api/v1/data/.keys.default.")
+            .header("authorization", "Bearer valid-token")
+            .reply(&recover(
+                api::delete(api_tokens(), Arc::new(deleter)),
+                Arc::new(MockRenderer::new()),
+            ))
+            .await;
+        assert_eq!(res.status(), 422);
+    }
+
+    #[tokio::test]
+    async fn test_delete() {
+        let mut deleter = MockDeleter::new();
+        deleter
+            .expect_delete()
+            .times(1)
+            .with(eq(".profile.name."))
+            .returning(|_| Ok(()));
+        deleter
+            .expect_delete()
+            .times(1)
+            .with(eq(".profile.missing."))
+            .returning(|_| Err(StorageError::NotFound));
+        let delete = recover(
+            api::delete(api_tokens(), Arc::new(deleter)),
+            Arc::new(MockRenderer::new()),
+        );
+
+        let res = warp::test::request()
+            .method("DELETE")
+            .path("/api/v1/data/.profile.name.")
+            .header("authorization", "Bearer valid-token")
+            .reply(&delete)
+            .await;
+        assert_eq!(res.status(), 204);
+
+        let res = warp::test::request()
+            .method("DELETE")
+            .path("/api/v1/data/.profile.missing.")
+            .header("authorization", "Bearer valid-token")
+            .reply(&delete)
+            .await;
+        assert_eq!(res.status(), 404);
+    }
+}
@@ -1,2 +1,265 @@
 pub mod get;
 pub mod post;
+
+use crate::{
+    metrics::CRYPTO_DURATION,
+    render::BatchField,
+    routes::{CryptoErrorRejection, StorageErrorRejection, ValidationRejection},
+    startup::DEFAULT_KEY_PATH,
+    storage::PreloadedKeyStorer,
+    write_queue::BaseVersion,
+};
+use futures::future::try_join_all;
+use redact_crypto::{
+    Data, HasBuilder, States, StorageError, Storer, SymmetricKey, SymmetricSealer, TypeBuilder,
+};
+use std::collections::HashSet;
+use warp::Rejection;
+
+/// Separates the paths requested together in batch mode
+const BATCH_PATH_SEPARATOR: char = ',';
+
+/// Most paths which can be requested together in batch mode
+pub(crate) const MAX_BATCH_PATHS: usize = 32;
+
+/// Splits a comma-separated list of paths requested in batch mode, returning `None`
+/// if only a single path was requested. Lists which are too long, or which contain
+/// empty or repeated paths, are rejected.
+pub(crate) fn batch_paths(path: &str) -> Result<Option<Vec<String>>, Rejection> {
+    if !path.contains(BATCH_PATH_SEPARATOR) {
+        return Ok(None);
+    }
+
+    let paths: Vec<String> = path
+        .split(BATCH_PATH_SEPARATOR)
+        .map(str::to_owned)
+        .collect();
+    let unique_paths: HashSet<&String> = paths.iter().collect();
+    if paths.len() > MAX_BATCH_PATHS
+        || unique_paths.len() != paths.len()
+        || paths.iter().any(String::is_empty)
+    {
+        Err(warp::reject::custom(ValidationRejection))
+    } else {
+        Ok(Some(paths))
+    }
+}
+
+/// Fetches and unseals the data stored at the given path, returning `None` if there
+/// is none
+pub(crate) async fn fetch_data<H: Storer>(
+    storer: &H,
+    path: &str,
+) -> Result<Option<Data>, StorageError> {
+    fetch_versioned_data(storer, path)
+        .await
+        .map(|(data, _)| data)
+}
+
+/// Like `fetch_data`, also returning the version of the entry the data was read
+/// from, so that an edit which has to be queued can tell whether the entry was
+/// changed elsewhere in the meantime
+pub(crate) async fn fetch_versioned_data<H: Storer>(
+    storer: &H,
+    path: &str,
+) -> Result<(Option<Data>, BaseVersion), StorageError> {
+    let entry = storer.get::<Data>(path).await;
+    let version = BaseVersion::of(&entry);
+    match entry {
+        Ok(entry) => storer
+            .resolve::<Data>(entry.value)
+            .await
+            .map(|data| (Some(data), version)),
+        Err(StorageError::NotFound) => Ok((None, version)),
+        Err(e) => Err(e),
+    }
+}
+
+/// Fetches and unseals the data stored at each of the given paths concurrently. The
+/// default key is looked up once for the whole batch rather than once per entry.
+pub(crate) async fn fetch_data_batch<H: Storer>(
+    storer: &H,
+    paths: &[String],
+) -> Result<Vec<BatchField>, StorageError> {
+    let key_entry = match storer.get::<SymmetricKey>(DEFAULT_KEY_PATH).await {
+        Ok(key_entry) => key_entry,
+        // Without a default key only unsealed entries can be resolved anyway
+        Err(StorageError::NotFound) => return fetch_entries(storer, paths).await,
+        Err(e) => return Err(e),
+    };
+    let storer = PreloadedKeyStorer::new(storer.clone(), DEFAULT_KEY_PATH, key_entry);
+    fetch_entries(&storer, paths).await
+}
+
+async fn fetch_entries<H: Storer>(
+    storer: &H,
+    paths: &[String],
+) -> Result<Vec<BatchField>, StorageError> {
+    let data = try_join_all(paths.iter().map(|path| async move {
+        let entry = match storer.get::<Data>(path).await {
+            Ok(entry) => entry,
+            Err(StorageError::NotFound) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let unseal_timer = match entry.value {
+            States::Sealed { .. } => {
+                Some(CRYPTO_DURATION.with_label_values(&["unseal"]).start_timer())
+            }
+            _ => None,
+        };
+        let data = storer.resolve::<Data>(entry.value).await?;
+        if let Some(timer) = unseal_timer {
+            timer.observe_duration();
+        }
+        Ok(Some(data))
+    }))
+    .await?;
+
+    Ok(paths
+        .iter()
+        .cloned()
+        .zip(data)
+        .map(|(path, data)| BatchField { path, data })
+        .collect())
+}
+
+/// Seals the data with the default key
+pub(crate) async fn seal_data<H: Storer>(storer: &H, data: Data) -> Result<States, Rejection> {
+    let key_entry = storer
+        .get::<SymmetricKey>(DEFAULT_KEY_PATH)
+        .await
+        .map_err(StorageErrorRejection)?;
+    let key: SymmetricKey = storer
+        .resolve(key_entry.value.clone())
+        .await
+        .map_err(StorageErrorRejection)?;
+    let builder = TypeBuilder::Data(data.builder());
+    let seal_timer = CRYPTO_DURATION.with_label_values(&["seal"]).start_timer();
+    let unsealable = key
+        .seal(data.into(), None, Some(key_entry.path))
+        .map_err(CryptoErrorRejection)?;
+    seal_timer.observe_duration();
+
+    Ok(States::Sealed {
+        builder,
+        unsealable,
+    })
+}
+
+/// Seals the data with the default key and stores it at the given path
+pub(crate) async fn seal_and_store<H: Storer>(
+    storer: &H,
+    path: String,
+    data: Data,
+) -> Result<(), Rejection> {
+    let sealed = seal_data(storer, data).await?;
+    storer
+        .create(path, sealed)
+        .await
+        .map_err(StorageErrorRejection)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{batch_paths, fetch_data_batch, MAX_BATCH_PATHS};
+    use crate::storage::tests::CountingStorer;
+    use redact_crypto::{
+        key::sodiumoxide::{SodiumOxideSymmetricKey, SodiumOxideSymmetricKeyBuilder},
+        ByteSource, Data, DataBuilder, HasBuilder, KeyBuilder, States, StringDataBuilder,
+        SymmetricKey, SymmetricKeyBuilder, SymmetricSealer, TypeBuilder, VectorByteSource,
+    };
+
+    #[test]
+    fn test_batch_paths() {
+        assert_eq!(batch_paths(".profile.name.").unwrap(), None);
+        assert_eq!(
+            batch_paths(".profile.name.,.profile.age.").unwrap(),
+            Some(vec![
+                ".profile.name.".to_owned(),
+                ".profile.age.".to_owned()
+            ])
+        );
+
+        let too_many = (0..=MAX_BATCH_PATHS)
+            .map(|i| format!(".field{}.", i))
+            .collect::<Vec<_>>()
+            .join(",");
+        for invalid in &[".a.,", ".a.,,.b.", ".a.,.a.", &too_many] {
+            assert!(batch_paths(invalid).is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fetch_data_batch_resolves_key_once() {
+        let sosk = SodiumOxideSymmetricKey::new();
+        let storer = CountingStorer::default();
+        storer.insert(
+            ".keys.default",
+            States::Unsealed {
+                builder: TypeBuilder::Key(KeyBuilder::Symmetric(SymmetricKeyBuilder::SodiumOxide(
+                    SodiumOxideSymmetricKeyBuilder {},
+                ))),
+                bytes: ByteSource::Vector(VectorByteSource::new(sosk.key.as_ref())),
+            },
+        );
+        let key = SymmetricKey::SodiumOxide(sosk);
+        let seal = |data: Data| States::Sealed {
+            builder: TypeBuilder::Data(data.builder()),
+            unsealable: key
+                .seal(data.into(), None, Some(".keys.default".to_owned()))
+                .unwrap(),
+        };
+        storer.insert(".profile.name.", seal(Data::String("Alice".to_owned())));
+        storer.insert(".profile.age.", seal(Data::U64(42)));
+        storer.insert(
+            ".profile.bio.",
+            States::Unsealed {
+                builder: TypeBuilder::Data(DataBuilder::String(StringDataBuilder {})),
+                bytes: ByteSource::Vector(VectorByteSource::new(b"Hello")),
+            },
+        );
+
+        let paths = [
+            ".profile.name.",
+            ".profile.age.",
+            ".profile.nickname.",
+            ".profile.bio.",
+        ]
+        .iter()
+        .map(|path| path.to_string())
+        .collect::<Vec<_>>();
+        let fields = fetch_data_batch(&storer, &paths)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|field| (field.path, field.data))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            fields,
+            vec![
+                (
+                    ".profile.name.".to_owned(),
+                    Some(Data::String("Alice".to_owned()))
+                ),
+                (".profile.age.".to_owned(), Some(Data::U64(42))),
+                (".profile.nickname.".to_owned(), None),
+                (
+                    ".profile.bio.".to_owned(),
+                    Some(Data::String("Hello".to_owned()))
+                ),
+            ]
+        );
+        assert_eq!(storer.gets(".keys.default"), 1);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_data_batch_without_key() {
+        let storer = CountingStorer::default();
+        let fields = fetch_data_batch(&storer, &[".profile.name.".to_owned()])
+            .await
+            .unwrap();
+        assert_eq!(fields.len(), 1);
+        assert_eq!(fields[0].data, None);
+    }
+}
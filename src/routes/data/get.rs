@@ -1,15 +1,20 @@
 use crate::{
+    relay_policy::{request_origin, HostOrigins},
     render::{
-        RenderTemplate, Rendered, Renderer, SecureTemplateValues, TemplateValues,
-        UnsecureTemplateValues,
+        BatchTemplateValues, RenderTemplate, Rendered, Renderer, SecureTemplateValues,
+        TemplateValues, UnsecureTemplateValues,
     },
     routes::{
-        IframeTokensDoNotMatchRejection, SessionTokenNotFoundRejection, StorageErrorRejection,
+        data::{batch_paths, fetch_data_batch, fetch_versioned_data},
+        DataNotFoundRejection, IframeTokensDoNotMatchRejection, SessionTokenNotFoundRejection,
+        StorageErrorRejection, TokenAlreadyRedeemedRejection, ValidationRejection,
     },
-    token::TokenGenerator,
+    token::{self, RedeemedTokens, TokenGenerator},
 };
-use redact_crypto::{Data, StorageError, Storer};
+use redact_crypto::{Data, Storer};
 use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tracing::debug;
 use warp::{Filter, Rejection, Reply};
 use warp_sessions::{
     self, CookieOptions, SameSiteCookieOption, Session, SessionStore, SessionWithStore,
@@ -32,6 +37,7 @@ struct WithoutTokenPathParams {
 struct WithTokenQueryParams {
     css: Option<String>,
     edit: Option<bool>,
+    create: Option<bool>,
     data_type: Option<String>,
     relay_url: Option<String>,
 }
@@ -42,14 +48,18 @@ struct WithTokenPathParams {
     token: String,
 }
 
+/// Serves the unsecure frame which the host page embeds. The origin of the host page
+/// is recorded against the token issued here, as relay URLs are only allowed for it.
 pub fn without_token<S: SessionStore, R: Renderer, T: TokenGenerator>(
     session_store: S,
     render_engine: R,
     token_generator: T,
+    host_origins: HostOrigins,
 ) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
     warp::any()
         .and(warp::path!("data" / String).map(|path| WithoutTokenPathParams { path }))
         .and(warp::query::<WithoutTokenQueryParams>())
+        .and(request_origin())
         .and(warp_sessions::request::with_session(
             session_store,
             Some(CookieOptions {
@@ -63,14 +73,25 @@ pub fn without_token<S: SessionStore, R: Renderer, T: TokenGenerator>(
                 same_site: Some(SameSiteCookieOption::None),
             }),
         ))
-        .and(warp::any().map(move || token_generator.clone().generate_token().unwrap()))
+        .and(warp::any().map(move || token_generator.clone()))
         .and(warp::any().map(move || render_engine.clone()))
+        .and(warp::any().map(move || host_origins.clone()))
         .and_then(
             move |path_params: WithoutTokenPathParams,
                   query_params: WithoutTokenQueryParams,
+                  host_origin: Option<String>,
                   session_with_store: SessionWithStore<S>,
-                  token: String,
-                  render_engine: R| async move {
+                  token_generator: T,
+                  render_engine: R,
+                  host_origins: HostOrigins| async move {
+                // Batches are display-only
+                if batch_paths(&path_params.path)?.is_some() && query_params.edit.unwrap_or(false) {
+                    return Err(warp::reject::custom(ValidationRejection));
+                }
+                let token = token_generator.generate_path_token(&path_params.path)?;
+                if let Some(host_origin) = host_origin {
+                    host_origins.insert(&token, host_origin);
+                }
                 let utv = UnsecureTemplateValues {
                     path: path_params.path.clone(),
                     token: token.clone(),
@@ -103,6 +124,9 @@ pub fn without_token<S: SessionStore, R: Renderer, T: TokenGenerator>(
                     .session
                     .insert("token", token.clone())
                     .map_err(|_| warp::reject())?;
+                session_with_store
+                    .session
+                    .expire_in(Duration::from_secs(60));
                 session_with_store.cookie_options.path =
                     Some(format!("/data/{}/{}", path_params.path, token));
 
@@ -118,7 +142,10 @@ pub fn with_token<S: SessionStore, R: Renderer, T: TokenGenerator, H: Storer>(
     render_engine: R,
     token_generator: T,
     storer: H,
+    redeemed_tokens: RedeemedTokens,
+    host_origins: HostOrigins,
 ) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let token_verifier = token_generator.clone();
     warp::any()
         .and(
             warp::path!("data" / String / String)
@@ -138,54 +165,89 @@ pub fn with_token<S: SessionStore, R: Renderer, T: TokenGenerator, H: Storer>(
                 same_site: Some(SameSiteCookieOption::None),
             }),
         ))
-        .and(warp::any().map(move || token_generator.clone().generate_token().unwrap()))
+        .and(token::new_token(token_generator))
         .and(warp::any().map(move || render_engine.clone()))
         .and(warp::any().map(move || storer.clone()))
+        .and(warp::any().map(move || token_verifier.clone()))
+        .and(warp::any().map(move || redeemed_tokens.clone()))
+        .and(warp::any().map(move || host_origins.clone()))
         .and_then(
             move |path_params: WithTokenPathParams,
                   query_params: WithTokenQueryParams,
                   session_with_store: SessionWithStore<S>,
                   token: String,
                   render_engine: R,
-                  storer: H| async move {
-                if let Some(session_token) = session_with_store.session.get::<String>("token") {
-                    if session_token != path_params.token {
-                        Err(warp::reject::custom(IframeTokensDoNotMatchRejection))
-                    } else {
-                        Ok(())
-                    }
-                } else {
-                    Err(warp::reject::custom(SessionTokenNotFoundRejection))
-                }?;
-
-                let data_entry = match storer.get::<Data>(&path_params.path).await {
-                    Ok(e) => Ok(Some(e)),
-                    Err(e) => match e {
-                        StorageError::NotFound => Ok(None),
-                        _ => Err(e),
-                    },
+                  storer: H,
+                  token_verifier: T,
+                  redeemed_tokens: RedeemedTokens,
+                  host_origins: HostOrigins| async move {
+                let batch = batch_paths(&path_params.path)?;
+                if batch.is_some() && query_params.edit.unwrap_or(false) {
+                    return Err(warp::reject::custom(ValidationRejection));
+                }
+                // Signed tokens carry their own proof of which path they were issued for
+                if !token_verifier.verify_path_token(&path_params.token, &path_params.path) {
+                    match session_with_store.session.get::<String>("token") {
+                        Some(session_token)
+                            if token::tokens_match(&session_token, &path_params.token) =>
+                        {
+                            Ok(())
+                        }
+                        Some(_) => Err(warp::reject::custom(IframeTokensDoNotMatchRejection)),
+                        None => Err(warp::reject::custom(SessionTokenNotFoundRejection)),
+                    }?;
+                }
+                // The session is only destroyed once the reply is sent, so concurrent
+                // requests presenting the same token are told apart here
+                if !redeemed_tokens.redeem(&path_params.token) {
+                    return Err(warp::reject::custom(TokenAlreadyRedeemedRejection));
                 }
-                .map_err(StorageErrorRejection)?;
 
-                let data = match data_entry {
-                    Some(data_entry) => storer
-                        .resolve::<Data>(data_entry.value)
+                if let Some(paths) = batch {
+                    debug!(paths = paths.len(), "serving data batch");
+                    let fields = fetch_data_batch(&storer, &paths)
                         .await
-                        .map_err(StorageErrorRejection)?,
-                    None => {
-                        if let Some(data_type) = query_params.data_type {
-                            match data_type.to_ascii_lowercase().as_ref() {
-                                "bool" => Data::Bool(false),
-                                "u64" => Data::U64(0),
-                                "i64" => Data::I64(0),
-                                "f64" => Data::F64(0.0),
-                                _ => Data::String("".to_owned()),
-                            }
-                        } else {
+                        .map_err(StorageErrorRejection)?;
+                    let reply = Rendered::new(
+                        render_engine,
+                        RenderTemplate {
+                            name: "batch",
+                            value: TemplateValues::Batch(BatchTemplateValues {
+                                fields,
+                                css: query_params.css,
+                            }),
+                        },
+                    )?;
+                    return Ok((reply, path_params, false, None, token, session_with_store));
+                }
+
+                debug!(path = %path_params.path, "serving data");
+                let (data, base_version) = fetch_versioned_data(&storer, &path_params.path)
+                    .await
+                    .map_err(StorageErrorRejection)?;
+                let data = match data {
+                    Some(data) => data,
+                    // Missing data can only be displayed as an empty value if the
+                    // caller intends to create it
+                    None => match query_params.data_type {
+                        Some(data_type) => match data_type.to_ascii_lowercase().as_ref() {
+                            "bool" => Data::Bool(false),
+                            "u64" => Data::U64(0),
+                            "i64" => Data::I64(0),
+                            "f64" => Data::F64(0.0),
+                            _ => Data::String("".to_owned()),
+                        },
+                        None if query_params.create.unwrap_or(false)
+                            || query_params.edit.unwrap_or(false) =>
+                        {
                             Data::String("".to_owned())
                         }
-                    }
+                        None => return Err(warp::reject::custom(DataNotFoundRejection)),
+                    },
                 };
+                // Submissions are held to the allowlist of the host page which was
+                // issued the token this frame was loaded with
+                let host_origin = host_origins.get(&path_params.token);
                 let reply = Rendered::new(
                     render_engine,
                     RenderTemplate {
@@ -197,14 +259,20 @@ pub fn with_token<S: SessionStore, R: Renderer, T: TokenGenerator, H: Storer>(
                             css: query_params.css,
                             edit: query_params.edit,
                             relay_url: query_params.relay_url,
+                            pending_sync: false,
+                            relay_status: None,
+                            base_version: Some(base_version.to_string()),
+                            host_origin_grant: host_origin
+                                .as_deref()
+                                .map(|origin| host_origins.grant(origin)),
                         }),
                     },
                 )?;
-
                 Ok::<_, Rejection>((
                     reply,
                     path_params,
                     query_params.edit.unwrap_or(false),
+                    host_origin,
                     token,
                     session_with_store,
                 ))
@@ -215,6 +283,7 @@ pub fn with_token<S: SessionStore, R: Renderer, T: TokenGenerator, H: Storer>(
             move |reply: Rendered,
                   path_params: WithTokenPathParams,
                   edit: bool,
+                  host_origin: Option<String>,
                   token: String,
                   mut session_with_store: SessionWithStore<S>| async move {
                 session_with_store.cookie_options.path = Some(format!(
@@ -244,6 +313,13 @@ pub fn with_token<S: SessionStore, R: Renderer, T: TokenGenerator, H: Storer>(
                         .session
                         .insert("token", token)
                         .map_err(|_| warp::reject())?;
+                    if let Some(host_origin) = host_origin {
+                        new_session
+                            .session
+                            .insert("host_origin", host_origin)
+                            .map_err(|_| warp::reject())?;
+                    }
+                    new_session.session.expire_in(Duration::from_secs(60));
                 }
                 Ok::<_, Rejection>((
                     warp_sessions::reply::with_session(reply, session_with_store).await?,
@@ -258,25 +334,42 @@ pub fn with_token<S: SessionStore, R: Renderer, T: TokenGenerator, H: Storer>(
 #[cfg(test)]
 mod tests {
     mod with_token {
+        use crate::error_handler::recover;
+        use crate::relay_policy::HostOrigins;
         use crate::render::{
-            tests::MockRenderer, RenderTemplate, SecureTemplateValues, TemplateValues,
+            tests::MockRenderer, BatchField, BatchTemplateValues, RenderTemplate,
+            SecureTemplateValues, TemplateValues,
         };
-        use crate::routes::data::get;
-        use crate::token::tests::MockTokenGenerator;
+        use crate::routes::data::{get, post::tests::BarrierSessionStore};
+        use crate::routes::{
+            DataNotFoundRejection, SessionTokenNotFoundRejection, TokenAlreadyRedeemedRejection,
+            ValidationRejection,
+        };
+        use crate::storage::tests::CountingStorer;
+        use crate::token::{
+            tests::{failing_rng, MockTokenGenerator},
+            FromCustomRng, RedeemedTokens, TokenGenerator, TokenOptions, TokenSigner,
+        };
+        use crate::write_queue::BaseVersion;
         use async_trait::async_trait;
         use mockall::predicate::*;
         use mockall::*;
+        use rand::SeedableRng;
+        use rand_pcg::Pcg64;
         use redact_crypto::{
             storage::tests::MockStorer, ByteSource, Data, DataBuilder, Entry, HasIndex, States,
             StorageError, StringDataBuilder, TypeBuilder, VectorByteSource,
         };
         use serde::Serialize;
+        use serde_json::Value;
+        use tokio::sync::Barrier;
 
         use std::{
             fmt::{self, Debug, Formatter},
             sync::Arc,
+            time::Duration,
         };
-        use warp_sessions::{ArcSessionStore, Session, SessionStore};
+        use warp_sessions::{ArcSessionStore, MemoryStore, Session, SessionStore};
 
         mock! {
                     pub SessionStore {}
@@ -357,6 +450,18 @@ mod tests {
                         css: None,
                         edit: None,
                         relay_url: None,
+                        pending_sync: false,
+                        relay_status: None,
+                        base_version: Some(
+                            BaseVersion::of_value(&States::Unsealed {
+                                builder: TypeBuilder::Data(DataBuilder::String(
+                                    StringDataBuilder {},
+                                )),
+                                bytes: ByteSource::Vector(VectorByteSource::new(b"someval")),
+                            })
+                            .to_string(),
+                        ),
+                        host_origin_grant: None,
                     });
                     template.value == expected_value
                 })
@@ -397,6 +502,8 @@ mod tests {
                 Arc::new(render_engine),
                 Arc::new(token_generator),
                 Arc::new(storer),
+                RedeemedTokens::default(),
+                HostOrigins::default(),
             );
 
             let res = warp::test::request()
@@ -463,6 +570,8 @@ mod tests {
                 Arc::new(render_engine),
                 Arc::new(token_generator),
                 Arc::new(storer),
+                RedeemedTokens::default(),
+                HostOrigins::default(),
             );
 
             let res = warp::test::request()
@@ -472,14 +581,392 @@ mod tests {
                 .await;
             assert_eq!(res.status(), 200);
         }
+
+        #[tokio::test]
+        async fn with_token_signed_token_without_session() {
+            let token_generator = FromCustomRng::with_options(
+                Pcg64::seed_from_u64(1),
+                TokenOptions {
+                    signer: Some(TokenSigner::new(
+                        b"0123456789abcdef0123456789abcdef".to_vec(),
+                        Duration::from_secs(60),
+                    )),
+                    ..TokenOptions::default()
+                },
+            );
+            let signed_token = token_generator.generate_path_token(".testKey.").unwrap();
+
+            let mut render_engine = MockRenderer::new();
+            render_engine
+                .expect_render()
+                .times(1)
+                .return_once(move |_| Ok("".to_string()));
+
+            let mut storer = MockStorer::new();
+            storer
+                .expect_get_indexed::<Data>()
+                .times(1)
+                .returning(|_, _| {
+                    let builder = TypeBuilder::Data(DataBuilder::String(StringDataBuilder {}));
+                    Ok(Entry {
+                        path: ".testKey.".to_owned(),
+                        value: States::Unsealed {
+                            builder,
+                            bytes: ByteSource::Vector(VectorByteSource::new(b"someval")),
+                        },
+                    })
+                });
+
+            let with_token_filter = get::with_token(
+                MemoryStore::new(),
+                Arc::new(render_engine),
+                token_generator.clone(),
+                Arc::new(storer),
+                RedeemedTokens::default(),
+                HostOrigins::default(),
+            );
+
+            let res = warp::test::request()
+                .path(&format!("/data/.testKey./{}", signed_token))
+                .reply(&with_token_filter)
+                .await;
+            assert_eq!(res.status(), 200);
+
+            // The same token cannot be used to read a different path
+            let rejection = warp::test::request()
+                .path(&format!("/data/.otherKey./{}", signed_token))
+                .filter(&with_token_filter)
+                .await
+                .err()
+                .unwrap();
+            assert!(rejection.find::<SessionTokenNotFoundRejection>().is_some());
+
+            // Nor can it be redeemed a second time
+            let rejection = warp::test::request()
+                .path(&format!("/data/.testKey./{}", signed_token))
+                .filter(&with_token_filter)
+                .await
+                .err()
+                .unwrap();
+            assert!(rejection.find::<TokenAlreadyRedeemedRejection>().is_some());
+        }
+
+        #[tokio::test]
+        async fn with_token_token_generation_failure() {
+            let with_token_filter = get::with_token(
+                MemoryStore::new(),
+                Arc::new(MockRenderer::new()),
+                FromCustomRng::new(failing_rng()),
+                Arc::new(MockStorer::new()),
+                RedeemedTokens::default(),
+                HostOrigins::default(),
+            );
+
+            let res = warp::test::request()
+                .path("/data/.testKey./E0AE2C1C9AA2DB85DFA2FF6B4AAC7A5E51FFDAA3948BECEC353561D513E59A9C")
+                .reply(&recover(with_token_filter, Arc::new(MockRenderer::new())))
+                .await;
+            assert_eq!(res.status(), 500);
+        }
+
+        #[tokio::test]
+        async fn with_token_data_not_found() {
+            let mut session = Session::new();
+            session.set_cookie_value("testSID".to_owned());
+            session
+                .insert(
+                    "token",
+                    "E0AE2C1C9AA2DB85DFA2FF6B4AAC7A5E51FFDAA3948BECEC353561D513E59A9C",
+                )
+                .unwrap();
+
+            let mut mock_store = MockSessionStore::new();
+            mock_store
+                .expect_load_session()
+                .with(predicate::eq("testSID".to_owned()))
+                .times(1)
+                .return_once(move |_| Ok(Some(session)));
+            let session_store = ArcSessionStore(Arc::new(mock_store));
+
+            let mut render_engine = MockRenderer::new();
+            render_engine.expect_render().times(0);
+
+            let mut token_generator = MockTokenGenerator::new();
+            token_generator
+                .expect_generate_token()
+                .times(1)
+                .returning(|| {
+                    Ok(
+                        "E0AE2C1C9AA2DB85DFA2FF6B4AAC7A5E51FFDAA3948BECEC353561D513E59A9D"
+                            .to_owned(),
+                    )
+                });
+
+            let mut storer = MockStorer::new();
+            storer
+                .expect_get_indexed::<Data>()
+                .times(1)
+                .returning(|_, _| Err(StorageError::NotFound));
+
+            let with_token_filter = get::with_token(
+                session_store,
+                Arc::new(render_engine),
+                Arc::new(token_generator),
+                Arc::new(storer),
+                RedeemedTokens::default(),
+                HostOrigins::default(),
+            );
+
+            let rejection = warp::test::request()
+                .path("/data/.testKey./E0AE2C1C9AA2DB85DFA2FF6B4AAC7A5E51FFDAA3948BECEC353561D513E59A9C")
+                .header("cookie", "sid=testSID")
+                .filter(&with_token_filter)
+                .await
+                .err()
+                .unwrap();
+            assert!(rejection.find::<DataNotFoundRejection>().is_some());
+        }
+
+        #[tokio::test]
+        async fn with_token_replayed_in_parallel() {
+            let token = "E0AE2C1C9AA2DB85DFA2FF6B4AAC7A5E51FFDAA3948BECEC353561D513E59A9C";
+            let session_store = BarrierSessionStore {
+                inner: MemoryStore::new(),
+                barrier: Arc::new(Barrier::new(2)),
+            };
+            let mut session = Session::new();
+            session.insert("token", token).unwrap();
+            let cookie = session_store.store_session(session).await.unwrap().unwrap();
+
+            let mut render_engine = MockRenderer::new();
+            render_engine
+                .expect_render()
+                .times(1)
+                .return_once(move |_| Ok("".to_string()));
+
+            let mut storer = MockStorer::new();
+            storer
+                .expect_get_indexed::<Data>()
+                .times(1)
+                .returning(|_, _| {
+                    let builder = TypeBuilder::Data(DataBuilder::String(StringDataBuilder {}));
+                    Ok(Entry {
+                        path: ".testKey.".to_owned(),
+                        value: States::Unsealed {
+                            builder,
+                            bytes: ByteSource::Vector(VectorByteSource::new(b"someval")),
+                        },
+                    })
+                });
+
+            let with_token_filter = recover(
+                get::with_token(
+                    session_store,
+                    Arc::new(render_engine),
+                    FromCustomRng::new(Pcg64::seed_from_u64(1)),
+                    Arc::new(storer),
+                    RedeemedTokens::default(),
+                    HostOrigins::default(),
+                ),
+                Arc::new(MockRenderer::new()),
+            );
+            let fetch = || {
+                warp::test::request()
+                    .path(&format!("/data/.testKey./{}", token))
+                    .header("cookie", format!("sid={}", cookie))
+                    .reply(&with_token_filter)
+            };
+
+            let (first, second) = tokio::join!(fetch(), fetch());
+            let (accepted, rejected) = if first.status() == 200 {
+                (first, second)
+            } else {
+                (second, first)
+            };
+            assert_eq!(accepted.status(), 200);
+            assert_eq!(rejected.status(), 401);
+            let body: Value = serde_json::from_slice(rejected.body()).unwrap();
+            assert_eq!(body["error_code"], "token_already_redeemed");
+        }
+
+        #[tokio::test]
+        async fn with_token_batch() {
+            let token = "E0AE2C1C9AA2DB85DFA2FF6B4AAC7A5E51FFDAA3948BECEC353561D513E59A9C";
+            let session_store = MemoryStore::new();
+            let mut session = Session::new();
+            session.insert("token", token).unwrap();
+            let cookie = session_store.store_session(session).await.unwrap().unwrap();
+
+            let mut render_engine = MockRenderer::new();
+            render_engine
+                .expect_render()
+                .withf(move |template: &RenderTemplate| {
+                    let expected_value = TemplateValues::Batch(BatchTemplateValues {
+                        fields: vec![
+                            BatchField {
+                                path: ".profile.name.".to_owned(),
+                                data: Some(Data::String("someval".into())),
+                            },
+                            BatchField {
+                                path: ".profile.age.".to_owned(),
+                                data: None,
+                            },
+                        ],
+                        css: Some("p { color: red; }".to_owned()),
+                    });
+                    template.name == "batch" && template.value == expected_value
+                })
+                .times(1)
+                .return_once(move |_| Ok("".to_string()));
+
+            let storer = CountingStorer::default();
+            storer.insert(
+                ".profile.name.",
+                States::Unsealed {
+                    builder: TypeBuilder::Data(DataBuilder::String(StringDataBuilder {})),
+                    bytes: ByteSource::Vector(VectorByteSource::new(b"someval")),
+                },
+            );
+
+            let with_token_filter = get::with_token(
+                session_store,
+                Arc::new(render_engine),
+                FromCustomRng::new(Pcg64::seed_from_u64(1)),
+                storer,
+                RedeemedTokens::default(),
+                HostOrigins::default(),
+            );
+
+            let res = warp::test::request()
+                .path(&format!(
+                    "/data/.profile.name.,.profile.age./{}?css=p%20%7B%20color%3A%20red%3B%20%7D",
+                    token
+                ))
+                .header("cookie", format!("sid={}", cookie))
+                .reply(&with_token_filter)
+                .await;
+            assert_eq!(res.status(), 200);
+        }
+
+        #[tokio::test]
+        async fn with_token_batch_edit() {
+            let token = "E0AE2C1C9AA2DB85DFA2FF6B4AAC7A5E51FFDAA3948BECEC353561D513E59A9C";
+            let session_store = MemoryStore::new();
+            let mut session = Session::new();
+            session.insert("token", token).unwrap();
+            let cookie = session_store.store_session(session).await.unwrap().unwrap();
+
+            let mut storer = MockStorer::new();
+            storer.expect_get_indexed::<Data>().times(0);
+            let redeemed_tokens = RedeemedTokens::default();
+
+            let with_token_filter = get::with_token(
+                session_store,
+                Arc::new(MockRenderer::new()),
+                FromCustomRng::new(Pcg64::seed_from_u64(1)),
+                Arc::new(storer),
+                redeemed_tokens.clone(),
+                HostOrigins::default(),
+            );
+
+            let rejection = warp::test::request()
+                .path(&format!(
+                    "/data/.profile.name.,.profile.age./{}?edit=true",
+                    token
+                ))
+                .header("cookie", format!("sid={}", cookie))
+                .filter(&with_token_filter)
+                .await
+                .err()
+                .unwrap();
+            assert!(rejection.find::<ValidationRejection>().is_some());
+            // Invalid requests do not use up the token
+            assert!(redeemed_tokens.redeem(token));
+        }
+
+        #[tokio::test]
+        async fn with_token_edit_keeps_host_origin() {
+            let token = "E0AE2C1C9AA2DB85DFA2FF6B4AAC7A5E51FFDAA3948BECEC353561D513E59A9C";
+            let session_store = MemoryStore::new();
+            let mut session = Session::new();
+            session.insert("token", token).unwrap();
+            let cookie = session_store.store_session(session).await.unwrap().unwrap();
+            let host_origins = HostOrigins::default();
+            host_origins.insert(token, "https://host.example".to_owned());
+
+            // The page is handed the host origin too, for when its session expires
+            let grants = host_origins.clone();
+            let mut render_engine = MockRenderer::new();
+            render_engine
+                .expect_render()
+                .withf(move |template: &RenderTemplate| match &template.value {
+                    TemplateValues::Secure(values) => {
+                        values
+                            .host_origin_grant
+                            .as_deref()
+                            .and_then(|grant| grants.verify_grant(grant))
+                            == Some("https://host.example".to_owned())
+                    }
+                    _ => false,
+                })
+                .times(1)
+                .return_once(move |_| Ok("".to_string()));
+
+            let with_token_filter = get::with_token(
+                session_store.clone(),
+                Arc::new(render_engine),
+                FromCustomRng::new(Pcg64::seed_from_u64(1)),
+                CountingStorer::default(),
+                RedeemedTokens::default(),
+                host_origins,
+            );
+
+            let res = warp::test::request()
+                .path(&format!("/data/.testKey./{}?edit=true", token))
+                .header("cookie", format!("sid={}", cookie))
+                .reply(&with_token_filter)
+                .await;
+            assert_eq!(res.status(), 200);
+
+            // The session the value is submitted with remembers the host page
+            let new_cookie = res
+                .headers()
+                .get_all("set-cookie")
+                .iter()
+                .map(|cookie| cookie.to_str().unwrap())
+                .find(|cookie| !cookie.contains(".testKey."))
+                .unwrap();
+            let cookie_value = new_cookie
+                .trim_start_matches("sid=")
+                .split(';')
+                .next()
+                .unwrap()
+                .to_owned();
+            let session = session_store
+                .load_session(cookie_value)
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(
+                session.get::<String>("host_origin").unwrap(),
+                "https://host.example"
+            );
+        }
     }
 
     mod without_token {
+        use crate::error_handler::recover;
+        use crate::relay_policy::HostOrigins;
         use crate::render::{
             tests::MockRenderer, RenderTemplate, TemplateValues, UnsecureTemplateValues,
         };
         use crate::routes::data::get;
-        use crate::token::tests::MockTokenGenerator;
+        use crate::token::{
+            tests::{failing_rng, MockTokenGenerator},
+            FromCustomRng,
+        };
+        use rand::SeedableRng;
+        use rand_pcg::Pcg64;
         use std::sync::Arc;
         use warp_sessions::MemoryStore;
 
@@ -520,6 +1007,7 @@ mod tests {
                 session_store,
                 Arc::new(render_engine),
                 Arc::new(token_generator),
+                HostOrigins::default(),
             );
 
             let res = warp::test::request()
@@ -566,6 +1054,7 @@ mod tests {
                 session_store,
                 Arc::new(render_engine),
                 Arc::new(token_generator),
+                HostOrigins::default(),
             );
 
             let res = warp::test::request()
@@ -575,6 +1064,25 @@ mod tests {
             assert_eq!(res.status(), 200);
         }
 
+        #[tokio::test]
+        async fn without_token_token_generation_failure() {
+            let without_token_filter = get::without_token(
+                MemoryStore::new(),
+                Arc::new(MockRenderer::new()),
+                FromCustomRng::new(failing_rng()),
+                HostOrigins::default(),
+            );
+
+            let res = warp::test::request()
+                .path("/data/.testKey.")
+                .reply(&recover(
+                    without_token_filter,
+                    Arc::new(MockRenderer::new()),
+                ))
+                .await;
+            assert_eq!(res.status(), 500);
+        }
+
         #[tokio::test]
         async fn test_without_token_with_edit_true() {
             let session_store = MemoryStore::new();
@@ -607,17 +1115,27 @@ mod tests {
                     )
                 });
 
+            let host_origins = HostOrigins::default();
             let without_token_filter = get::without_token(
                 session_store,
                 Arc::new(render_engine),
                 Arc::new(token_generator),
+                host_origins.clone(),
             );
 
             let res = warp::test::request()
                 .path("/data/.testKey.?edit=true")
+                .header("referer", "https://host.example/page?x=1")
                 .reply(&without_token_filter)
                 .await;
             assert_eq!(res.status(), 200);
+            // Relay URLs submitted from the frames loaded with the token are checked
+            // against the allowlist of the page which embedded it
+            assert_eq!(
+                host_origins
+                    .get("E0AE2C1C9AA2DB85DFA2FF6B4AAC7A5E51FFDAA3948BECEC353561D513E59A9C"),
+                Some("https://host.example".to_owned())
+            );
         }
 
         #[tokio::test]
@@ -656,6 +1174,7 @@ mod tests {
                 session_store,
                 Arc::new(render_engine),
                 Arc::new(token_generator),
+                HostOrigins::default(),
             );
 
             let res = warp::test::request()
@@ -664,5 +1183,26 @@ mod tests {
                 .await;
             assert_eq!(res.status(), 200);
         }
+
+        #[tokio::test]
+        async fn without_token_batch_edit() {
+            let mut render_engine = MockRenderer::new();
+            render_engine.expect_render().times(0);
+            let without_token_filter = get::without_token(
+                MemoryStore::new(),
+                Arc::new(render_engine),
+                FromCustomRng::new(Pcg64::seed_from_u64(1)),
+                HostOrigins::default(),
+            );
+
+            let res = warp::test::request()
+                .path("/data/.profile.name.,.profile.age.?edit=true")
+                .reply(&recover(
+                    without_token_filter,
+                    Arc::new(MockRenderer::new()),
+                ))
+                .await;
+            assert_eq!(res.status(), 422);
+        }
     }
 }
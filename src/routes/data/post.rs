@@ -1,44 +1,69 @@
-use crate::routes::error::RelayRejection;
+use crate::routes::error::RelayUrlRejection;
 use crate::{
+    attestation::RelayOperation,
+    logging::{self, RequestId},
+    metrics::observe_relay,
+    relay_history::{RelayStatus, RELAY_STATUS_HEADER},
     render::{RenderTemplate, Rendered, Renderer, SecureTemplateValues, TemplateValues},
     routes::{
-        BadRequestRejection, CryptoErrorRejection, IframeTokensDoNotMatchRejection,
-        SerializationRejection, SessionTokenNotFoundRejection, StorageErrorRejection,
+        data::seal_data, IframeTokensDoNotMatchRejection, SerializationRejection,
+        SessionTokenNotFoundRejection, StorageErrorRejection, TokenAlreadyRedeemedRejection,
+        ValidationRejection,
     },
-    token::TokenGenerator,
+    token::{self, RedeemedTokens, TokenGenerator},
+    write_queue::{BaseVersion, WriteQueue},
 };
-use redact_crypto::{Data, HasBuilder, States, Storer, SymmetricKey, SymmetricSealer, TypeBuilder};
+use redact_crypto::{Data, StorageError, Storer};
 use serde::{Deserialize, Serialize};
 use std::convert::TryFrom;
+use std::fmt::{self, Debug, Formatter};
+use std::time::Duration;
+use tracing::{info, warn};
+use warp::http::{HeaderValue, StatusCode};
 use warp::{Filter, Rejection, Reply};
 use warp_sessions::{CookieOptions, SameSiteCookieOption, Session, SessionStore, SessionWithStore};
-use crate::relayer::Relayer;
+use crate::relayer::{RelayNotification, Relayer};
 
 #[derive(Deserialize, Serialize)]
 struct SubmitDataPathParams {
     token: String,
 }
 
-#[derive(Deserialize, Serialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, Clone)]
 struct SubmitDataBodyParams {
     path: String,
     value: Option<String>,
     value_type: String,
     relay_url: Option<String>,
+    /// The version of the entry the edited value was read from
+    base_version: Option<String>,
+}
+
+// Submitted values are plaintext user data and must never end up in logs
+impl Debug for SubmitDataBodyParams {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("SubmitDataBodyParams")
+            .field("path", &self.path)
+            .field("value", &self.value.as_ref().map(|_| "<redacted>"))
+            .field("value_type", &self.value_type)
+            .field("relay_url", &self.relay_url)
+            .field("base_version", &self.base_version)
+            .finish()
+    }
 }
 
 impl TryFrom<SubmitDataBodyParams> for Data {
-    type Error = BadRequestRejection;
+    type Error = ValidationRejection;
 
     fn try_from(body: SubmitDataBodyParams) -> Result<Self, Self::Error> {
         if let Some(value) = body.value {
             Ok(match body.value_type.as_ref() {
-                "bool" => Data::Bool(value.parse::<bool>().or(Err(BadRequestRejection))?),
-                "u64" => Data::U64(value.parse::<u64>().or(Err(BadRequestRejection))?),
-                "i64" => Data::I64(value.parse::<i64>().or(Err(BadRequestRejection))?),
-                "f64" => Data::F64(value.parse::<f64>().or(Err(BadRequestRejection))?),
+                "bool" => Data::Bool(value.parse::<bool>().or(Err(ValidationRejection))?),
+                "u64" => Data::U64(value.parse::<u64>().or(Err(ValidationRejection))?),
+                "i64" => Data::I64(value.parse::<i64>().or(Err(ValidationRejection))?),
+                "f64" => Data::F64(value.parse::<f64>().or(Err(ValidationRejection))?),
                 "string" => Data::String(value),
-                _ => return Err(BadRequestRejection),
+                _ => return Err(ValidationRejection),
             })
         } else {
             Ok(Data::Bool(false))
@@ -59,7 +84,9 @@ pub fn submit_data<S: SessionStore, R: Renderer, T: TokenGenerator, H: Storer, Q
     render_engine: R,
     token_generator: T,
     storer: H,
-    relayer: Q
+    relayer: Q,
+    redeemed_tokens: RedeemedTokens,
+    write_queue: WriteQueue,
 ) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
     warp::any()
         .and(warp::path!("data" / String).map(|token| SubmitDataPathParams { token }))
@@ -84,10 +111,13 @@ pub fn submit_data<S: SessionStore, R: Renderer, T: TokenGenerator, H: Storer, Q
                 same_site: Some(SameSiteCookieOption::None),
             }),
         ))
-        .and(warp::any().map(move || token_generator.clone().generate_token().unwrap()))
+        .and(token::new_token(token_generator))
         .and(warp::any().map(move || render_engine.clone()))
         .and(warp::any().map(move || storer.clone()))
         .and(warp::any().map(move || relayer.clone()))
+        .and(warp::any().map(move || redeemed_tokens.clone()))
+        .and(warp::any().map(move || write_queue.clone()))
+        .and(logging::request_id())
         .and_then(
             move |path_params: SubmitDataPathParams,
                   query_params: SubmitDataQueryParams,
@@ -96,41 +126,98 @@ pub fn submit_data<S: SessionStore, R: Renderer, T: TokenGenerator, H: Storer, Q
                   token: String,
                   render_engine: R,
                   storer: H,
-                  relayer: Q| async move {
+                  relayer: Q,
+                  redeemed_tokens: RedeemedTokens,
+                  write_queue: WriteQueue,
+                  request_id: RequestId| async move {
                 match session_with_store.session.get("token") {
                     Some::<String>(session_token) => {
-                        if session_token != path_params.token {
+                        if !token::tokens_match(&session_token, &path_params.token) {
                             Err(warp::reject::custom(IframeTokensDoNotMatchRejection))
+                        } else if !redeemed_tokens.redeem(&path_params.token) {
+                            // Another request is already submitting with this session
+                            Err(warp::reject::custom(TokenAlreadyRedeemedRejection))
                         } else {
-                            let key_entry = storer
-                                .get::<SymmetricKey>(".keys.default")
-                                .await
-                                .map_err(StorageErrorRejection)?;
-                            let key: SymmetricKey = storer
-                                .resolve(key_entry.value.clone())
-                                .await
-                                .map_err(StorageErrorRejection)?;
-                            let builder = TypeBuilder::Data(data.builder());
-                            let unsealable = key
-                                .seal(data.clone().into(), None, Some(key_entry.path))
-                                .map_err(CryptoErrorRejection)?;
-
-                            storer
-                                .create(
+                            // Nothing has been stored if this fails, so the token is
+                            // released to let the submission be retried
+                            let committed = async {
+                                let base_version = body_params
+                                    .base_version
+                                    .as_deref()
+                                    .map(str::parse::<BaseVersion>)
+                                    .transpose()
+                                    .map_err(|_| warp::reject::custom(ValidationRejection))?;
+                                // The relay URL comes from the host page, so it is checked
+                                // against that page's allowlist before anything is stored
+                                if let Some(relay_url) = body_params.relay_url.clone() {
+                                    let host_origin = session_with_store.session.get("host_origin");
+                                    relayer.check_url(host_origin, relay_url).await.map_err(|e| {
+                                        warn!(error = %e, "rejected relay URL");
+                                        warp::reject::custom(RelayUrlRejection)
+                                    })?;
+                                }
+                                info!(
+                                    path = %body_params.path,
+                                    value_type = %body_params.value_type,
+                                    "storing submitted data"
+                                );
+                                let sealed = seal_data(&storer, data.clone()).await?;
+                                let notification = RelayNotification::new(
                                     body_params.path.clone(),
-                                    States::Sealed {
-                                        builder,
-                                        unsealable,
-                                    },
-                                )
-                                .await
-                                .map_err(StorageErrorRejection)?;
-
-                            if let Some(relay_url) = body_params.relay_url.clone() {
-                                relayer.relay(body_params.path.clone(), relay_url)
-                                .await
-                                .map_err(|_| warp::reject::custom(RelayRejection))?;
+                                    &sealed,
+                                    RelayOperation::Submit,
+                                );
+                                let stored_version = BaseVersion::of_value(&sealed);
+                                let (pending_sync, base_version) = match storer
+                                    .create(body_params.path.clone(), sealed.clone())
+                                    .await
+                                {
+                                    Ok(_) => (false, stored_version),
+                                    // Storage could not be reached, so the write is kept
+                                    // locally until it can be replayed
+                                    Err(e @ StorageError::InternalError { .. })
+                                        if write_queue.is_enabled() =>
+                                    {
+                                        let base_version = write_queue
+                                            .enqueue(
+                                                &storer,
+                                                &body_params.path,
+                                                sealed,
+                                                base_version,
+                                                body_params.relay_url.clone(),
+                                                request_id.to_string(),
+                                            )
+                                            .await
+                                            .map_err(|queue_error| {
+                                                warn!(error = %queue_error, "failed to queue write");
+                                                warp::reject::custom(StorageErrorRejection(e))
+                                            })?;
+                                        info!(path = %body_params.path, "queued write until storage is reachable");
+                                        (true, base_version)
+                                    }
+                                    Err(e) => return Err(warp::reject::custom(StorageErrorRejection(e))),
+                                };
+                                Ok::<_, Rejection>((notification, pending_sync, base_version))
                             }
+                            .await;
+                            let (notification, pending_sync, base_version) =
+                                committed.inspect_err(|_| redeemed_tokens.release(&path_params.token))?;
+
+                            // The value is stored by now, so a failed relay is reported
+                            // to the user rather than failing the request. Queued writes
+                            // are relayed once they reach storage.
+                            let relay_status = match body_params.relay_url.clone() {
+                                Some(_) if pending_sync => Some(RelayStatus::Queued),
+                                Some(relay_url) => {
+                                    let relay_result = relayer.relay(notification, relay_url, request_id.to_string()).await;
+                                    observe_relay(&relay_result);
+                                    if let Err(e) = &relay_result {
+                                        warn!(error = %e, "failed to relay submitted data");
+                                    }
+                                    Some(RelayStatus::of(&relay_result))
+                                }
+                                None => None,
+                            };
 
                             Ok::<_, Rejection>((
                                 Rendered::new(
@@ -144,10 +231,21 @@ pub fn submit_data<S: SessionStore, R: Renderer, T: TokenGenerator, H: Storer, Q
                                             css: query_params.css,
                                             edit: query_params.edit,
                                             relay_url: body_params.relay_url,
+                                            pending_sync,
+                                            relay_status: relay_status.map(Into::into),
+                                            // Further edits made on the same page are
+                                            // based on the value just submitted
+                                            base_version: Some(base_version)
+                                                .filter(|base| *base != BaseVersion::Unknown)
+                                                .map(|base| base.to_string()),
+                                            // The page keeps the grant it was loaded with
+                                            host_origin_grant: None,
                                         }),
                                     },
                                 )?,
                                 path_params,
+                                pending_sync,
+                                relay_status,
                                 token,
                                 session_with_store,
                             ))
@@ -161,10 +259,13 @@ pub fn submit_data<S: SessionStore, R: Renderer, T: TokenGenerator, H: Storer, Q
         .and_then(
             move |reply: Rendered,
                   path_params: SubmitDataPathParams,
+                  pending_sync: bool,
+                  relay_status: Option<RelayStatus>,
                   token: String,
                   mut session_with_store: SessionWithStore<S>| async move {
                 session_with_store.cookie_options.path =
                     Some(format!("/data/{}", path_params.token.clone()));
+                let host_origin = session_with_store.session.get::<String>("host_origin");
                 session_with_store.session.destroy();
 
                 let mut new_session = SessionWithStore::<S> {
@@ -186,8 +287,34 @@ pub fn submit_data<S: SessionStore, R: Renderer, T: TokenGenerator, H: Storer, Q
                     .session
                     .insert("token", token)
                     .map_err(SerializationRejection)?;
+                if let Some(host_origin) = host_origin {
+                    new_session
+                        .session
+                        .insert("host_origin", host_origin)
+                        .map_err(SerializationRejection)?;
+                }
+                new_session.session.expire_in(Duration::from_secs(60));
+                let mut response = warp::reply::with_status(
+                    reply,
+                    if pending_sync {
+                        StatusCode::ACCEPTED
+                    } else {
+                        StatusCode::OK
+                    },
+                )
+                .into_response();
+                if let Some(relay_status) = relay_status {
+                    response.headers_mut().insert(
+                        RELAY_STATUS_HEADER,
+                        HeaderValue::from_static(relay_status.as_str()),
+                    );
+                }
                 Ok::<_, Rejection>((
-                    warp_sessions::reply::with_session(reply, session_with_store).await?,
+                    warp_sessions::reply::with_session(
+                        response,
+                        session_with_store,
+                    )
+                    .await?,
                     new_session,
                 ))
             },
@@ -197,27 +324,47 @@ pub fn submit_data<S: SessionStore, R: Renderer, T: TokenGenerator, H: Storer, Q
 }
 
 #[cfg(test)]
-mod tests {
+pub mod tests {
+    use crate::error_handler::recover;
     use crate::render::tests::MockRenderer;
     use crate::routes::data::post;
-    use crate::token::tests::MockTokenGenerator;
-    use crate::relayer::tests::MockRelayer;
+    use crate::routes::{handshake, SessionTokenNotFoundRejection};
+    use crate::token::{
+        tests::{failing_rng, MockTokenGenerator},
+        FromCustomRng, RedeemedTokens,
+    };
+    use crate::attestation::RelayOperation;
+    use crate::relayer::{tests::MockRelayer, RelayError, RelayNotification, RelayOutcome};
+    use crate::relay_history::RelayStatus;
+    use crate::relay_policy::{HostOrigins, RelayUrlError};
+    use crate::relay_outbox::{OutboxRelayer, RelayOutbox, DEFAULT_RELAY_RETRY_POLICY};
+    use crate::key_cache::{KeyCache, KeyCachingStorer};
+    use crate::render::{RenderTemplate, TemplateValues};
+    use crate::storage::tests::CountingStorer;
+    use crate::write_queue::{BaseVersion, ConflictPolicy, WriteQueue};
+    use uuid::Uuid;
+    use crate::logging::tests::capture_logs;
     use async_trait::async_trait;
     use mockall::predicate::*;
     use mockall::*;
     use redact_crypto::{
         key::sodiumoxide::{SodiumOxideSymmetricKey, SodiumOxideSymmetricKeyBuilder},
         storage::tests::MockStorer,
-        ByteSource, Entry, HasIndex, KeyBuilder, States, SymmetricKey, SymmetricKeyBuilder,
+        ByteSource, Entry, HasIndex, KeyBuilder, States, Storer, SymmetricKey, SymmetricKeyBuilder,
         TypeBuilder, VectorByteSource,
     };
     use serde::Serialize;
+    use serde_json::Value;
 
+    use rand::SeedableRng;
+    use rand_pcg::Pcg64;
     use std::{
         fmt::{self, Debug, Formatter},
         sync::Arc,
+        time::Duration,
     };
-    use warp_sessions::{ArcSessionStore, Session, SessionStore};
+    use tokio::sync::Barrier;
+    use warp_sessions::{ArcSessionStore, MemoryStore, Session, SessionStore};
     use http::StatusCode;
 
     mock! {
@@ -261,6 +408,35 @@ mod tests {
         }
     }
 
+    /// Holds back every loaded session until as many requests as the barrier was
+    /// created for have loaded theirs, so that they all race to redeem their token
+    #[derive(Debug, Clone)]
+    pub struct BarrierSessionStore {
+        pub inner: MemoryStore,
+        pub barrier: Arc<Barrier>,
+    }
+
+    #[async_trait]
+    impl SessionStore for BarrierSessionStore {
+        async fn load_session(&self, cookie_value: String) -> async_session::Result<Option<Session>> {
+            let session = self.inner.load_session(cookie_value).await;
+            self.barrier.wait().await;
+            session
+        }
+
+        async fn store_session(&self, session: Session) -> async_session::Result<Option<String>> {
+            self.inner.store_session(session).await
+        }
+
+        async fn destroy_session(&self, session: Session) -> async_session::Result {
+            self.inner.destroy_session(session).await
+        }
+
+        async fn clear_store(&self) -> async_session::Result {
+            self.inner.clear_store().await
+        }
+    }
+
     #[tokio::test]
     async fn test_submit_data() {
         let token = "E0AE2C1C9AA2DB85DFA2FF6B4AAC7A5E51FFDAA3948BECEC353561D513E59A9D";
@@ -329,6 +505,8 @@ mod tests {
             Arc::new(token_generator),
             Arc::new(storer),
             Arc::new(relayer),
+            RedeemedTokens::default(),
+            WriteQueue::disabled(),
         );
 
         let res = warp::test::request()
@@ -407,10 +585,19 @@ mod tests {
 
         let relay_url = "http://asdfs.dsfs/relay";
         let mut relayer = MockRelayer::new();
+        relayer.expect_check_url().returning(|_, _| Ok(()));
         relayer.expect_relay()
             .times(1)
-            .with(eq(data_path.to_owned()), eq(relay_url.to_owned()))
-            .return_once(move |_, _| Ok(StatusCode::OK));
+            .with(
+                eq(RelayNotification {
+                    path: data_path.to_owned(),
+                    data_type: Some("string".to_owned()),
+                    operation: RelayOperation::Submit,
+                }),
+                eq(relay_url.to_owned()),
+                eq("test-request-id".to_owned()),
+            )
+            .return_once(move |_, _, _| Ok(RelayOutcome::Delivered(StatusCode::OK)));
 
         let submit_data = post::submit_data(
             session_store,
@@ -418,12 +605,111 @@ mod tests {
             Arc::new(token_generator),
             Arc::new(storer),
             Arc::new(relayer),
+            RedeemedTokens::default(),
+            WriteQueue::disabled(),
+        );
+
+        let res = warp::test::request()
+            .method("POST")
+            .path("/data/E0AE2C1C9AA2DB85DFA2FF6B4AAC7A5E51FFDAA3948BECEC353561D513E59A9D")
+            .header("cookie", "sid=testSID")
+            .header("x-request-id", "test-request-id")
+            .body(format!(
+                "relay_url={}&path={}&value_type=string&value=qew&submit=Submit",
+                relay_url, data_path
+            ))
+            .reply(&submit_data)
+            .await;
+
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.headers()["x-relay-status"], "delivered");
+    }
+
+    #[tokio::test]
+    async fn test_submit_data_when_relay_fails() {
+        let token = "E0AE2C1C9AA2DB85DFA2FF6B4AAC7A5E51FFDAA3948BECEC353561D513E59A9D";
+        let data_path = ".testKey.";
+
+        let mut session = Session::new();
+        session.set_cookie_value("testSID".to_owned());
+        session.insert("token", token).unwrap();
+        let expected_sid = session.id().to_owned();
+
+        let mut mock_store = MockSessionStore::new();
+        mock_store
+            .expect_load_session()
+            .with(predicate::eq("testSID".to_owned()))
+            .times(1)
+            .return_once(move |_| Ok(Some(session)));
+        mock_store
+            .expect_destroy_session()
+            .withf(move |session: &Session| session.id() == expected_sid)
+            .times(1)
+            .return_once(move |_| Ok(()));
+        mock_store
+            .expect_store_session()
+            .times(1)
+            .return_once(move |_| Ok(Some(token.to_string())));
+        let session_store = ArcSessionStore(Arc::new(mock_store));
+
+        let mut render_engine = MockRenderer::new();
+        render_engine
+            .expect_render()
+            .times(1)
+            .return_once(move |_| Ok("".to_string()));
+
+        let mut storer = MockStorer::new();
+        storer
+            .expect_get_indexed::<SymmetricKey>()
+            .times(1)
+            .withf(|path, index| {
+                path == ".keys.default" && *index == Some(SymmetricKey::get_index().unwrap())
+            })
+            .returning(|_, _| {
+                let builder = TypeBuilder::Key(KeyBuilder::Symmetric(
+                    SymmetricKeyBuilder::SodiumOxide(SodiumOxideSymmetricKeyBuilder {}),
+                ));
+                let sosk = SodiumOxideSymmetricKey::new();
+                Ok(Entry {
+                    path: ".keys.default".to_owned(),
+                    value: States::Unsealed {
+                        builder,
+                        bytes: ByteSource::Vector(VectorByteSource::new(sosk.key.as_ref())),
+                    },
+                })
+            });
+        storer.expect_create().times(1).returning(|_, _| Ok(true));
+
+        let mut token_generator = MockTokenGenerator::new();
+        token_generator.expect_generate_token().returning(|| {
+            Ok("E0AE2C1C9AA2DB85DFA2FF6B4AAC7A5E51FFDAA3948BECEC353561D513E59A9D".to_owned())
+        });
+
+        let relay_url = "http://asdfs.dsfs/relay";
+        let mut relayer = MockRelayer::new();
+        relayer.expect_check_url().returning(|_, _| Ok(()));
+        relayer
+            .expect_relay()
+            .times(1)
+            .return_once(move |_, _, _| Err(RelayError::RelayRequestError { source: None }));
+        let dir = std::env::temp_dir().join(format!("redact-relay-outbox-{}", Uuid::new_v4()));
+        let relay_outbox = RelayOutbox::new(&dir, DEFAULT_RELAY_RETRY_POLICY);
+
+        let submit_data = post::submit_data(
+            session_store,
+            Arc::new(render_engine),
+            Arc::new(token_generator),
+            Arc::new(storer),
+            OutboxRelayer::new(Arc::new(relayer), relay_outbox),
+            RedeemedTokens::default(),
+            WriteQueue::disabled(),
         );
 
         let res = warp::test::request()
             .method("POST")
             .path("/data/E0AE2C1C9AA2DB85DFA2FF6B4AAC7A5E51FFDAA3948BECEC353561D513E59A9D")
             .header("cookie", "sid=testSID")
+            .header("x-request-id", "test-request-id")
             .body(format!(
                 "relay_url={}&path={}&value_type=string&value=qew&submit=Submit",
                 relay_url, data_path
@@ -431,6 +717,552 @@ mod tests {
             .reply(&submit_data)
             .await;
 
+        // The value was stored, so the relay is retried instead of failing the request
         assert_eq!(res.status(), 200);
+        assert_eq!(res.headers()["x-relay-status"], "queued");
+        assert_eq!(dir.read_dir().unwrap().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_submit_data_when_relay_fails_without_outbox() {
+        let token = "E0AE2C1C9AA2DB85DFA2FF6B4AAC7A5E51FFDAA3948BECEC353561D513E59A9C";
+        let session_store = MemoryStore::new();
+        let mut session = Session::new();
+        session.insert("token", token).unwrap();
+        let cookie = session_store.store_session(session).await.unwrap().unwrap();
+
+        // The user is told the site was not notified
+        let mut render_engine = MockRenderer::new();
+        render_engine
+            .expect_render()
+            .withf(|template: &RenderTemplate| match &template.value {
+                TemplateValues::Secure(values) => {
+                    values.relay_status == Some(RelayStatus::Failed.into())
+                }
+                _ => false,
+            })
+            .times(1)
+            .return_once(move |_| Ok("".to_string()));
+
+        let mut storer = MockStorer::new();
+        storer
+            .expect_get_indexed::<SymmetricKey>()
+            .times(1)
+            .returning(|_, _| {
+                let builder = TypeBuilder::Key(KeyBuilder::Symmetric(
+                    SymmetricKeyBuilder::SodiumOxide(SodiumOxideSymmetricKeyBuilder {}),
+                ));
+                let sosk = SodiumOxideSymmetricKey::new();
+                Ok(Entry {
+                    path: ".keys.default".to_owned(),
+                    value: States::Unsealed {
+                        builder,
+                        bytes: ByteSource::Vector(VectorByteSource::new(sosk.key.as_ref())),
+                    },
+                })
+            });
+        storer.expect_create().times(1).returning(|_, _| Ok(true));
+
+        let mut relayer = MockRelayer::new();
+        relayer.expect_check_url().returning(|_, _| Ok(()));
+        relayer
+            .expect_relay()
+            .times(1)
+            .return_once(move |_, _, _| Err(RelayError::RelayRequestError { source: None }));
+
+        let submit_data = post::submit_data(
+            session_store,
+            Arc::new(render_engine),
+            FromCustomRng::new(Pcg64::seed_from_u64(1)),
+            Arc::new(storer),
+            OutboxRelayer::new(Arc::new(relayer), RelayOutbox::disabled()),
+            RedeemedTokens::default(),
+            WriteQueue::disabled(),
+        );
+        let res = warp::test::request()
+            .method("POST")
+            .path(&format!("/data/{}", token))
+            .header("cookie", format!("sid={}", cookie))
+            .body("path=.testKey.&value_type=string&value=qew&relay_url=https://relay.test&submit=Submit")
+            .reply(&submit_data)
+            .await;
+
+        // The value was stored, so the request still succeeds
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()["x-relay-status"], "failed");
+    }
+
+    #[tokio::test]
+    async fn test_submit_data_does_not_log_value() {
+        let (logs, _guard) = capture_logs();
+        let token = "E0AE2C1C9AA2DB85DFA2FF6B4AAC7A5E51FFDAA3948BECEC353561D513E59A9D";
+        let data_path = ".testKey.";
+
+        let mut session = Session::new();
+        session.set_cookie_value("testSID".to_owned());
+        session.insert("token", token).unwrap();
+        let expected_sid = session.id().to_owned();
+
+        let mut mock_store = MockSessionStore::new();
+        mock_store
+            .expect_load_session()
+            .with(predicate::eq("testSID".to_owned()))
+            .times(1)
+            .return_once(move |_| Ok(Some(session)));
+        mock_store
+            .expect_destroy_session()
+            .withf(move |session: &Session| session.id() == expected_sid)
+            .times(1)
+            .return_once(move |_| Ok(()));
+        mock_store
+            .expect_store_session()
+            .times(1)
+            .return_once(move |_| Ok(Some(token.to_string())));
+        let session_store = ArcSessionStore(Arc::new(mock_store));
+
+        let mut render_engine = MockRenderer::new();
+        render_engine
+            .expect_render()
+            .times(1)
+            .return_once(move |_| Ok("".to_string()));
+
+        let mut storer = MockStorer::new();
+        storer
+            .expect_get_indexed::<SymmetricKey>()
+            .times(1)
+            .withf(|path, index| {
+                path == ".keys.default" && *index == Some(SymmetricKey::get_index().unwrap())
+            })
+            .returning(|_, _| {
+                let builder = TypeBuilder::Key(KeyBuilder::Symmetric(
+                    SymmetricKeyBuilder::SodiumOxide(SodiumOxideSymmetricKeyBuilder {}),
+                ));
+                let sosk = SodiumOxideSymmetricKey::new();
+                Ok(Entry {
+                    path: ".keys.default.".to_owned(),
+                    value: States::Unsealed {
+                        builder,
+                        bytes: ByteSource::Vector(VectorByteSource::new(sosk.key.as_ref())),
+                    },
+                })
+            });
+        storer.expect_create().times(1).returning(|_, _| Ok(true));
+
+        let mut token_generator = MockTokenGenerator::new();
+        token_generator.expect_generate_token().returning(|| {
+            Ok("E0AE2C1C9AA2DB85DFA2FF6B4AAC7A5E51FFDAA3948BECEC353561D513E59A9D".to_owned())
+        });
+
+        let relayer = MockRelayer::new();
+
+        let submit_data = post::submit_data(
+            session_store,
+            Arc::new(render_engine),
+            Arc::new(token_generator),
+            Arc::new(storer),
+            Arc::new(relayer),
+            RedeemedTokens::default(),
+            WriteQueue::disabled(),
+        );
+
+        let res = warp::test::request()
+            .method("POST")
+            .path("/data/E0AE2C1C9AA2DB85DFA2FF6B4AAC7A5E51FFDAA3948BECEC353561D513E59A9D")
+            .header("cookie", "sid=testSID")
+            .body(format!(
+                "path={}&value_type=string&value=s3cr3tvalue&submit=Submit",
+                 data_path
+            ))
+            .reply(&submit_data)
+            .await;
+
+        assert_eq!(res.status(), 200);
+        let logs = logs.contents();
+        assert!(logs.contains(".testKey."));
+        assert!(!logs.contains("s3cr3tvalue"));
+    }
+
+    #[tokio::test]
+    async fn test_submit_data_after_session_expiry() {
+        let expired_token = "E0AE2C1C9AA2DB85DFA2FF6B4AAC7A5E51FFDAA3948BECEC353561D513E59A9C";
+        let fresh_token = "E0AE2C1C9AA2DB85DFA2FF6B4AAC7A5E51FFDAA3948BECEC353561D513E59A9D";
+        let session_store = MemoryStore::new();
+
+        // A session which was issued to the secure iframe but has since expired
+        let mut session = Session::new();
+        session.insert("token", expired_token).unwrap();
+        session.expire_in(Duration::from_secs(0));
+        let expired_cookie = session_store.store_session(session).await.unwrap().unwrap();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        let mut render_engine = MockRenderer::new();
+        render_engine
+            .expect_render()
+            .times(1)
+            .return_once(move |_| Ok("".to_string()));
+
+        let mut storer = MockStorer::new();
+        storer
+            .expect_get_indexed::<SymmetricKey>()
+            .times(1)
+            .returning(|_, _| {
+                let builder = TypeBuilder::Key(KeyBuilder::Symmetric(
+                    SymmetricKeyBuilder::SodiumOxide(SodiumOxideSymmetricKeyBuilder {}),
+                ));
+                let sosk = SodiumOxideSymmetricKey::new();
+                Ok(Entry {
+                    path: ".keys.default.".to_owned(),
+                    value: States::Unsealed {
+                        builder,
+                        bytes: ByteSource::Vector(VectorByteSource::new(sosk.key.as_ref())),
+                    },
+                })
+            });
+        storer
+            .expect_create()
+            .times(1)
+            .withf(|path, _| path == ".testKey.")
+            .returning(|_, _| Ok(true));
+
+        let mut token_generator = MockTokenGenerator::new();
+        token_generator
+            .expect_generate_token()
+            .returning(move || Ok(fresh_token.to_owned()));
+        let token_generator = Arc::new(token_generator);
+
+        // The relay URL is still held to the allowlist of the page the frame was issued to
+        let mut relayer = MockRelayer::new();
+        relayer
+            .expect_check_url()
+            .with(
+                eq(Some("https://host.example".to_owned())),
+                eq("https://relay.test".to_owned()),
+            )
+            .times(1)
+            .returning(|_, _| Ok(()));
+        relayer
+            .expect_relay()
+            .times(1)
+            .returning(|_, _, _| Ok(RelayOutcome::Delivered(StatusCode::OK)));
+        let host_origins = HostOrigins::default();
+        host_origins.insert("frame-token", "https://host.example".to_owned());
+
+        let submit_data = post::submit_data(
+            session_store.clone(),
+            Arc::new(render_engine),
+            token_generator.clone(),
+            Arc::new(storer),
+            Arc::new(relayer),
+            RedeemedTokens::default(),
+            WriteQueue::disabled(),
+        );
+        let handshake = handshake::post(session_store.clone(), token_generator, host_origins);
+        let body = "path=.testKey.&value_type=string&value=qew&relay_url=https://relay.test&submit=Submit";
+
+        // Submitting with the expired session is rejected without storing anything
+        let rejection = warp::test::request()
+            .method("POST")
+            .path(&format!("/data/{}", expired_token))
+            .header("cookie", format!("sid={}", expired_cookie))
+            .body(body)
+            .filter(&submit_data)
+            .await
+            .err()
+            .unwrap();
+        assert!(rejection.find::<SessionTokenNotFoundRejection>().is_some());
+
+        // The re-handshake issues a new token and session for the same store
+        let res = warp::test::request()
+            .method("POST")
+            .path("/handshake?token=frame-token")
+            .reply(&handshake)
+            .await;
+        assert_eq!(res.status(), 200);
+        let handshake_body: Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(handshake_body["token"], fresh_token);
+        let fresh_cookie = res.headers()["set-cookie"]
+            .to_str()
+            .unwrap()
+            .split(';')
+            .next()
+            .unwrap()
+            .to_owned();
+
+        // Resubmitting the pending value with the fresh token succeeds
+        let res = warp::test::request()
+            .method("POST")
+            .path(&format!("/data/{}", fresh_token))
+            .header("cookie", fresh_cookie)
+            .body(body)
+            .reply(&submit_data)
+            .await;
+        assert_eq!(res.status(), 200);
+    }
+
+    #[tokio::test]
+    async fn test_submit_data_token_generation_failure() {
+        let submit_data = post::submit_data(
+            MemoryStore::new(),
+            Arc::new(MockRenderer::new()),
+            FromCustomRng::new(failing_rng()),
+            Arc::new(MockStorer::new()),
+            Arc::new(MockRelayer::new()),
+            RedeemedTokens::default(),
+            WriteQueue::disabled(),
+        );
+
+        let res = warp::test::request()
+            .method("POST")
+            .path("/data/E0AE2C1C9AA2DB85DFA2FF6B4AAC7A5E51FFDAA3948BECEC353561D513E59A9D")
+            .body("path=.testKey.&value_type=string&value=qew&submit=Submit")
+            .reply(&recover(submit_data, Arc::new(MockRenderer::new())))
+            .await;
+        assert_eq!(res.status(), 500);
+    }
+
+    #[tokio::test]
+    async fn test_submit_data_replayed_in_parallel() {
+        let token = "E0AE2C1C9AA2DB85DFA2FF6B4AAC7A5E51FFDAA3948BECEC353561D513E59A9C";
+        let session_store = BarrierSessionStore {
+            inner: MemoryStore::new(),
+            barrier: Arc::new(Barrier::new(2)),
+        };
+        let mut session = Session::new();
+        session.insert("token", token).unwrap();
+        let cookie = session_store.store_session(session).await.unwrap().unwrap();
+
+        let mut render_engine = MockRenderer::new();
+        render_engine
+            .expect_render()
+            .times(1)
+            .return_once(move |_| Ok("".to_string()));
+
+        let mut storer = MockStorer::new();
+        storer
+            .expect_get_indexed::<SymmetricKey>()
+            .times(1)
+            .returning(|_, _| {
+                let builder = TypeBuilder::Key(KeyBuilder::Symmetric(
+                    SymmetricKeyBuilder::SodiumOxide(SodiumOxideSymmetricKeyBuilder {}),
+                ));
+                let sosk = SodiumOxideSymmetricKey::new();
+                Ok(Entry {
+                    path: ".keys.default.".to_owned(),
+                    value: States::Unsealed {
+                        builder,
+                        bytes: ByteSource::Vector(VectorByteSource::new(sosk.key.as_ref())),
+                    },
+                })
+            });
+        // The value must only be stored by whichever request redeemed the token
+        storer
+            .expect_create()
+            .times(1)
+            .returning(|_, _| Ok(true));
+
+        let submit_data = recover(
+            post::submit_data(
+                session_store,
+                Arc::new(render_engine),
+                FromCustomRng::new(Pcg64::seed_from_u64(1)),
+                Arc::new(storer),
+                Arc::new(MockRelayer::new()),
+                RedeemedTokens::default(),
+                WriteQueue::disabled(),
+            ),
+            Arc::new(MockRenderer::new()),
+        );
+        let submit = || {
+            warp::test::request()
+                .method("POST")
+                .path(&format!("/data/{}", token))
+                .header("cookie", format!("sid={}", cookie))
+                .body("path=.testKey.&value_type=string&value=qew&submit=Submit")
+                .reply(&submit_data)
+        };
+
+        let (first, second) = tokio::join!(submit(), submit());
+        let (accepted, rejected) = if first.status() == StatusCode::OK {
+            (first, second)
+        } else {
+            (second, first)
+        };
+        assert_eq!(accepted.status(), StatusCode::OK);
+        assert_eq!(rejected.status(), StatusCode::UNAUTHORIZED);
+        let body: Value = serde_json::from_slice(rejected.body()).unwrap();
+        assert_eq!(body["error_code"], "token_already_redeemed");
+    }
+
+    #[tokio::test]
+    async fn test_submit_data_while_storage_unreachable() {
+        let token = "E0AE2C1C9AA2DB85DFA2FF6B4AAC7A5E51FFDAA3948BECEC353561D513E59A9C";
+        let session_store = MemoryStore::new();
+        let mut session = Session::new();
+        session.insert("token", token).unwrap();
+        let cookie = session_store.store_session(session).await.unwrap().unwrap();
+
+        let mut render_engine = MockRenderer::new();
+        render_engine
+            .expect_render()
+            .withf(|template: &RenderTemplate| match &template.value {
+                TemplateValues::Secure(values) => {
+                    values.pending_sync && values.base_version == Some("absent".to_owned())
+                }
+                _ => false,
+            })
+            .times(1)
+            .return_once(move |_| Ok("".to_string()));
+
+        // The key is still cached from before storage became unreachable
+        let inner = CountingStorer::default();
+        let sosk = SodiumOxideSymmetricKey::new();
+        inner.insert(
+            ".keys.default",
+            States::Unsealed {
+                builder: TypeBuilder::Key(KeyBuilder::Symmetric(
+                    SymmetricKeyBuilder::SodiumOxide(SodiumOxideSymmetricKeyBuilder {}),
+                )),
+                bytes: ByteSource::Vector(VectorByteSource::new(sosk.key.as_ref())),
+            },
+        );
+        let storer = KeyCachingStorer::new(inner.clone(), KeyCache::default());
+        storer.get::<SymmetricKey>(".keys.default").await.unwrap();
+        inner.set_unreachable(true);
+
+        let mut relayer = MockRelayer::new();
+        relayer.expect_check_url().returning(|_, _| Ok(()));
+        relayer.expect_relay().times(0);
+        let dir = std::env::temp_dir().join(format!("redact-write-queue-{}", Uuid::new_v4()));
+        let write_queue = WriteQueue::new(&dir, ConflictPolicy::KeepRemote);
+
+        let submit_data = post::submit_data(
+            session_store,
+            Arc::new(render_engine),
+            FromCustomRng::new(Pcg64::seed_from_u64(1)),
+            storer,
+            Arc::new(relayer),
+            RedeemedTokens::default(),
+            write_queue.clone(),
+        );
+        let res = warp::test::request()
+            .method("POST")
+            .path(&format!("/data/{}", token))
+            .header("cookie", format!("sid={}", cookie))
+            .body("path=.testKey.&value_type=string&value=qew&relay_url=https://relay.test&base_version=absent&submit=Submit")
+            .reply(&submit_data)
+            .await;
+        assert_eq!(res.status(), StatusCode::ACCEPTED);
+        assert_eq!(res.headers()["x-relay-status"], "queued");
+
+        let queued = write_queue.pending(".testKey.").await.unwrap().unwrap();
+        assert!(matches!(queued.value, States::Sealed { .. }));
+        assert_eq!(queued.relay_url, Some("https://relay.test".to_owned()));
+        // The version the page was rendered from is kept, as storage cannot tell
+        assert_eq!(queued.base, BaseVersion::Absent);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_submit_data_with_disallowed_relay_url() {
+        let token = "E0AE2C1C9AA2DB85DFA2FF6B4AAC7A5E51FFDAA3948BECEC353561D513E59A9C";
+        let session_store = MemoryStore::new();
+        let mut session = Session::new();
+        session.insert("token", token).unwrap();
+        session.insert("host_origin", "https://host.example").unwrap();
+        let cookie = session_store.store_session(session).await.unwrap().unwrap();
+
+        // Nothing is stored or relayed for a relay URL the policy rejects for the host page
+        let mut storer = MockStorer::new();
+        storer.expect_create().times(0);
+        let mut relayer = MockRelayer::new();
+        relayer
+            .expect_check_url()
+            .with(
+                eq(Some("https://host.example".to_owned())),
+                eq("http://169.254.169.254/latest".to_owned()),
+            )
+            .times(1)
+            .returning(|_, url| Err(RelayUrlError::NotAllowed { url }));
+        relayer.expect_relay().times(0);
+
+        let submit_data = post::submit_data(
+            session_store,
+            Arc::new(MockRenderer::new()),
+            FromCustomRng::new(Pcg64::seed_from_u64(1)),
+            Arc::new(storer),
+            Arc::new(relayer),
+            RedeemedTokens::default(),
+            WriteQueue::disabled(),
+        );
+        let res = warp::test::request()
+            .method("POST")
+            .path(&format!("/data/{}", token))
+            .header("cookie", format!("sid={}", cookie))
+            .body("path=.testKey.&value_type=string&value=qew&relay_url=http://169.254.169.254/latest&submit=Submit")
+            .reply(&recover(submit_data, Arc::new(MockRenderer::new())))
+            .await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        let body: Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["error_code"], "relay_url_not_allowed");
+    }
+
+    #[tokio::test]
+    async fn test_submit_data_retried_after_storage_failure() {
+        let token = "E0AE2C1C9AA2DB85DFA2FF6B4AAC7A5E51FFDAA3948BECEC353561D513E59A9C";
+        let session_store = MemoryStore::new();
+        let mut session = Session::new();
+        session.insert("token", token).unwrap();
+        let cookie = session_store.store_session(session).await.unwrap().unwrap();
+
+        let mut render_engine = MockRenderer::new();
+        render_engine
+            .expect_render()
+            .times(1)
+            .return_once(move |_| Ok("".to_string()));
+
+        let inner = CountingStorer::default();
+        let sosk = SodiumOxideSymmetricKey::new();
+        inner.insert(
+            ".keys.default",
+            States::Unsealed {
+                builder: TypeBuilder::Key(KeyBuilder::Symmetric(
+                    SymmetricKeyBuilder::SodiumOxide(SodiumOxideSymmetricKeyBuilder {}),
+                )),
+                bytes: ByteSource::Vector(VectorByteSource::new(sosk.key.as_ref())),
+            },
+        );
+        let storer = KeyCachingStorer::new(inner.clone(), KeyCache::default());
+        storer.get::<SymmetricKey>(".keys.default").await.unwrap();
+        inner.set_unreachable(true);
+
+        let submit_data = recover(
+            post::submit_data(
+                session_store,
+                Arc::new(render_engine),
+                FromCustomRng::new(Pcg64::seed_from_u64(1)),
+                storer,
+                Arc::new(MockRelayer::new()),
+                RedeemedTokens::default(),
+                WriteQueue::disabled(),
+            ),
+            Arc::new(MockRenderer::new()),
+        );
+        let submit = || {
+            warp::test::request()
+                .method("POST")
+                .path(&format!("/data/{}", token))
+                .header("cookie", format!("sid={}", cookie))
+                .body("path=.testKey.&value_type=string&value=qew&submit=Submit")
+                .reply(&submit_data)
+        };
+
+        let res = submit().await;
+        let body: Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["error_code"], "storage_error");
+
+        // Nothing was stored, so the same token is still accepted once storage is back
+        inner.set_unreachable(false);
+        let res = submit().await;
+        assert_eq!(res.status(), StatusCode::OK);
     }
 }
@@ -1,3 +1,7 @@
+use crate::api_token::ApiTokenError;
+use crate::relay_history::RelayHistoryError;
+use crate::relay_policy::RelayUrlError;
+use crate::relayer::RelayError;
 use redact_crypto::{CryptoError, StorageError};
 use serde_json::Error as JsonSerializationError;
 use warp::reject::Reject;
@@ -14,13 +18,25 @@ impl Reject for IframeTokensDoNotMatchRejection {}
 pub struct SessionTokenNotFoundRejection;
 impl Reject for SessionTokenNotFoundRejection {}
 
+#[derive(Debug)]
+pub struct TokenAlreadyRedeemedRejection;
+impl Reject for TokenAlreadyRedeemedRejection {}
+
+#[derive(Debug)]
+pub struct ApiTokenRejection;
+impl Reject for ApiTokenRejection {}
+
+#[derive(Debug)]
+pub struct ApiTokenStoreRejection(pub ApiTokenError);
+impl Reject for ApiTokenStoreRejection {}
+
 #[derive(Debug)]
 pub struct DataNotFoundRejection;
 impl Reject for DataNotFoundRejection {}
 
 #[derive(Debug)]
-pub struct BadRequestRejection;
-impl Reject for BadRequestRejection {}
+pub struct ValidationRejection;
+impl Reject for ValidationRejection {}
 
 #[derive(Debug)]
 pub struct SerializationRejection(pub JsonSerializationError);
@@ -31,9 +47,25 @@ pub struct CryptoErrorRejection(pub CryptoError);
 impl Reject for CryptoErrorRejection {}
 
 #[derive(Debug)]
-pub struct RelayRejection;
-impl Reject for RelayRejection {}
+pub struct RelayUrlRejection;
+impl Reject for RelayUrlRejection {}
+
+#[derive(Debug)]
+pub struct RelayHistoryRejection(pub RelayHistoryError);
+impl Reject for RelayHistoryRejection {}
 
 #[derive(Debug)]
-pub struct ProxyRejection(pub reqwest::Error);
+pub struct ProxyRejection(pub RelayError);
 impl Reject for ProxyRejection {}
+
+#[derive(Debug)]
+pub struct ProxyUrlRejection(pub RelayUrlError);
+impl Reject for ProxyUrlRejection {}
+
+#[derive(Debug)]
+pub struct ProxyResponseTooLargeRejection;
+impl Reject for ProxyResponseTooLargeRejection {}
+
+#[derive(Debug)]
+pub struct MetricsRejection(pub prometheus::Error);
+impl Reject for MetricsRejection {}
@@ -0,0 +1,242 @@
+use crate::{
+    relay_policy::HostOrigins,
+    routes::SerializationRejection,
+    token::{self, TokenGenerator},
+};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use warp::{Filter, Rejection, Reply};
+use warp_sessions::{CookieOptions, SameSiteCookieOption, Session, SessionStore, SessionWithStore};
+
+#[derive(Serialize)]
+struct HandshakeResponse {
+    token: String,
+}
+
+#[derive(Deserialize)]
+struct HandshakeQueryParams {
+    /// The token the unsecure frame was loaded with
+    token: Option<String>,
+    /// The host origin grant the secure page of the expiring session was rendered with
+    host_origin: Option<String>,
+}
+
+/// Issues a fresh submission token, along with a session holding it, once the
+/// session backing the secure iframe has expired. It is called by the unsecure frame
+/// on behalf of the secure iframe so that a pending value can be resubmitted. The
+/// session keeps the origin of the host page the expiring session was held to, so
+/// that relay URLs are still checked against that page's allowlist.
+pub fn post<S: SessionStore, T: TokenGenerator>(
+    session_store: S,
+    token_generator: T,
+    host_origins: HostOrigins,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::any()
+        .and(warp::path!("handshake"))
+        .and(warp::query::<HandshakeQueryParams>())
+        .and(warp::any().map(move || session_store.clone()))
+        .and(warp::any().map(move || host_origins.clone()))
+        .and(token::new_token(token_generator))
+        .and_then(
+            move |query_params: HandshakeQueryParams,
+                  session_store: S,
+                  host_origins: HostOrigins,
+                  token: String| async move {
+                let mut session_with_store = SessionWithStore::<S> {
+                    session: Session::new(),
+                    session_store,
+                    cookie_options: CookieOptions {
+                        cookie_name: "sid",
+                        cookie_value: None,
+                        max_age: Some(60),
+                        domain: None,
+                        path: Some(format!("/data/{}", token.clone())),
+                        secure: false,
+                        http_only: true,
+                        same_site: Some(SameSiteCookieOption::None),
+                    },
+                };
+                session_with_store
+                    .session
+                    .insert("token", token.clone())
+                    .map_err(SerializationRejection)?;
+                // The grant is carried by the secure page itself, so it is still
+                // honoured once the origin recorded for the frame's token is forgotten
+                if let Some(host_origin) = query_params
+                    .host_origin
+                    .as_deref()
+                    .and_then(|grant| host_origins.verify_grant(grant))
+                    .or_else(|| {
+                        query_params
+                            .token
+                            .as_deref()
+                            .and_then(|frame_token| host_origins.get(frame_token))
+                    })
+                {
+                    session_with_store
+                        .session
+                        .insert("host_origin", host_origin)
+                        .map_err(SerializationRejection)?;
+                }
+                session_with_store
+                    .session
+                    .expire_in(Duration::from_secs(60));
+
+                Ok::<_, Rejection>((
+                    warp::reply::json(&HandshakeResponse { token }),
+                    session_with_store,
+                ))
+            },
+        )
+        .untuple_one()
+        .and_then(warp_sessions::reply::with_session)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error_handler::recover;
+    use crate::relay_policy::HostOrigins;
+    use crate::render::tests::MockRenderer;
+    use crate::routes::handshake;
+    use crate::token::{
+        tests::{failing_rng, MockTokenGenerator},
+        FromCustomRng,
+    };
+    use serde_json::Value;
+    use std::{sync::Arc, time::Duration};
+    use warp_sessions::{MemoryStore, SessionStore};
+
+    #[tokio::test]
+    async fn test_handshake() {
+        let session_store = MemoryStore::new();
+        let mut token_generator = MockTokenGenerator::new();
+        token_generator
+            .expect_generate_token()
+            .times(1)
+            .returning(|| {
+                Ok("E0AE2C1C9AA2DB85DFA2FF6B4AAC7A5E51FFDAA3948BECEC353561D513E59A9C".to_owned())
+            });
+
+        let host_origins = HostOrigins::default();
+        host_origins.insert("frame-token", "https://host.example".to_owned());
+        let handshake = handshake::post(
+            session_store.clone(),
+            Arc::new(token_generator),
+            host_origins,
+        );
+
+        let res = warp::test::request()
+            .method("POST")
+            .path("/handshake?token=frame-token")
+            .reply(&handshake)
+            .await;
+        assert_eq!(res.status(), 200);
+
+        let body: Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(
+            body["token"],
+            "E0AE2C1C9AA2DB85DFA2FF6B4AAC7A5E51FFDAA3948BECEC353561D513E59A9C"
+        );
+
+        let cookie = res.headers()["set-cookie"].to_str().unwrap();
+        assert!(cookie.contains(
+            "Path=/data/E0AE2C1C9AA2DB85DFA2FF6B4AAC7A5E51FFDAA3948BECEC353561D513E59A9C"
+        ));
+        assert!(cookie.contains("HttpOnly"));
+        let cookie_value = cookie
+            .trim_start_matches("sid=")
+            .split(';')
+            .next()
+            .unwrap()
+            .to_owned();
+        let session = session_store
+            .load_session(cookie_value)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            session.get::<String>("token").unwrap(),
+            "E0AE2C1C9AA2DB85DFA2FF6B4AAC7A5E51FFDAA3948BECEC353561D513E59A9C"
+        );
+        assert_eq!(
+            session.get::<String>("host_origin").unwrap(),
+            "https://host.example"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_handshake_after_host_origin_expired() {
+        let session_store = MemoryStore::new();
+        let mut token_generator = MockTokenGenerator::new();
+        token_generator.expect_generate_token().returning(|| {
+            Ok("E0AE2C1C9AA2DB85DFA2FF6B4AAC7A5E51FFDAA3948BECEC353561D513E59A9C".to_owned())
+        });
+
+        // The origin recorded for the frame's token is already forgotten
+        let host_origins = HostOrigins::new(Duration::from_secs(0));
+        host_origins.insert("frame-token", "https://host.example".to_owned());
+        let grant = host_origins.grant("https://host.example");
+        let forged = grant.replacen("https://host.example", "https://evil.example", 1);
+        let handshake = handshake::post(
+            session_store.clone(),
+            Arc::new(token_generator),
+            host_origins,
+        );
+
+        let handshake_origin = |query: String| {
+            let handshake = handshake.clone();
+            let session_store = session_store.clone();
+            async move {
+                let res = warp::test::request()
+                    .method("POST")
+                    .path(&format!("/handshake?{}", query))
+                    .reply(&handshake)
+                    .await;
+                assert_eq!(res.status(), 200);
+                let cookie_value = res.headers()["set-cookie"]
+                    .to_str()
+                    .unwrap()
+                    .trim_start_matches("sid=")
+                    .split(';')
+                    .next()
+                    .unwrap()
+                    .to_owned();
+                let session = session_store
+                    .load_session(cookie_value)
+                    .await
+                    .unwrap()
+                    .unwrap();
+                session.get::<String>("host_origin")
+            }
+        };
+
+        // The grant carried forward from the expiring session is honoured
+        assert_eq!(
+            handshake_origin(format!("token=frame-token&host_origin={}", grant)).await,
+            Some("https://host.example".to_owned())
+        );
+        assert_eq!(
+            handshake_origin(format!("token=frame-token&host_origin={}", forged)).await,
+            None
+        );
+        assert_eq!(handshake_origin("token=frame-token".to_owned()).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_handshake_token_generation_failure() {
+        let handshake = handshake::post(
+            MemoryStore::new(),
+            FromCustomRng::new(failing_rng()),
+            HostOrigins::default(),
+        );
+
+        let res = warp::test::request()
+            .method("POST")
+            .path("/handshake")
+            .reply(&recover(handshake, Arc::new(MockRenderer::new())))
+            .await;
+        assert_eq!(res.status(), 500);
+        let body: Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["error_code"], "token_generation_error");
+    }
+}
@@ -0,0 +1,343 @@
+use crate::startup::{Readiness, DEFAULT_KEY_PATH};
+use redact_crypto::{StorageError, Storer, SymmetricKey};
+use serde::Serialize;
+use std::future::Future;
+use std::time::Duration;
+use warp::{http::StatusCode, Filter, Rejection, Reply};
+use warp_sessions::{Session, SessionStore};
+
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Serialize)]
+struct Healthz {}
+
+#[derive(Serialize, Debug, PartialEq)]
+struct DependencyStatus {
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl DependencyStatus {
+    fn from_result<E: ToString>(result: Result<(), E>) -> Self {
+        match result {
+            Ok(()) => DependencyStatus {
+                ok: true,
+                error: None,
+            },
+            Err(e) => DependencyStatus {
+                ok: false,
+                error: Some(e.to_string()),
+            },
+        }
+    }
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+struct Readyz {
+    ready: bool,
+    bootstrap: DependencyStatus,
+    storage: DependencyStatus,
+    default_key: DependencyStatus,
+    session_store: DependencyStatus,
+    tls_identity: DependencyStatus,
+}
+
+pub fn healthz() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("healthz").map(|| warp::reply::json(&Healthz {}))
+}
+
+pub fn readyz<S: SessionStore, H: Storer>(
+    session_store: S,
+    storer: H,
+    readiness: Readiness,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::any()
+        .and(warp::path!("readyz"))
+        .and(warp::any().map(move || session_store.clone()))
+        .and(warp::any().map(move || storer.clone()))
+        .and(warp::any().map(move || readiness.clone()))
+        .and_then(
+            move |session_store: S, storer: H, readiness: Readiness| async move {
+                let (storage, default_key) = check_storage(&storer).await;
+                let session_store = check_session_store(&session_store).await;
+                let bootstrap = DependencyStatus::from_result(if readiness.is_ready() {
+                    Ok(())
+                } else {
+                    Err("default key has not been loaded yet")
+                });
+                let tls_identity = DependencyStatus::from_result(readiness.check_tls_identity());
+
+                let ready = bootstrap.ok
+                    && storage.ok
+                    && default_key.ok
+                    && session_store.ok
+                    && tls_identity.ok;
+                let code = if ready {
+                    StatusCode::OK
+                } else {
+                    StatusCode::SERVICE_UNAVAILABLE
+                };
+
+                Ok::<_, Rejection>(warp::reply::with_status(
+                    warp::reply::json(&Readyz {
+                        ready,
+                        bootstrap,
+                        storage,
+                        default_key,
+                        session_store,
+                        tls_identity,
+                    }),
+                    code,
+                ))
+            },
+        )
+}
+
+async fn with_timeout<F, E>(check: F) -> Result<(), String>
+where
+    F: Future<Output = Result<(), E>>,
+    E: ToString,
+{
+    match tokio::time::timeout(CHECK_TIMEOUT, check).await {
+        Ok(result) => result.map_err(|e| e.to_string()),
+        Err(_) => Err(format!("timed out after {:?}", CHECK_TIMEOUT)),
+    }
+}
+
+/// Storage is considered reachable if it answers for the default key at all, even
+/// with a not found; the default key check additionally requires that it resolves.
+async fn check_storage<H: Storer>(storer: &H) -> (DependencyStatus, DependencyStatus) {
+    let entry =
+        match tokio::time::timeout(CHECK_TIMEOUT, storer.get::<SymmetricKey>(DEFAULT_KEY_PATH))
+            .await
+        {
+            Ok(result) => result,
+            Err(_) => {
+                let error = format!("timed out after {:?}", CHECK_TIMEOUT);
+                return (
+                    DependencyStatus::from_result(Err(error.clone())),
+                    DependencyStatus::from_result(Err(error)),
+                );
+            }
+        };
+
+    match entry {
+        Ok(entry) => (
+            DependencyStatus::from_result(Ok::<_, String>(())),
+            DependencyStatus::from_result(
+                with_timeout(async {
+                    storer
+                        .resolve::<SymmetricKey>(entry.value)
+                        .await
+                        .map(|_| ())
+                })
+                .await,
+            ),
+        ),
+        Err(StorageError::NotFound) => (
+            DependencyStatus::from_result(Ok::<_, String>(())),
+            DependencyStatus::from_result(Err(format!("{} was not found", DEFAULT_KEY_PATH))),
+        ),
+        Err(e) => {
+            let error = e.to_string();
+            (
+                DependencyStatus::from_result(Err(error.clone())),
+                DependencyStatus::from_result(Err(error)),
+            )
+        }
+    }
+}
+
+/// Writes and then removes a throwaway session to make sure the store accepts writes
+async fn check_session_store<S: SessionStore>(session_store: &S) -> DependencyStatus {
+    DependencyStatus::from_result(
+        with_timeout(async {
+            let mut session = Session::new();
+            session.insert("readyz", true)?;
+            session_store.store_session(session.clone()).await?;
+            session_store.destroy_session(session).await
+        })
+        .await,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::routes::health;
+    use crate::startup::Readiness;
+    use async_trait::async_trait;
+    use mockall::predicate::*;
+    use mockall::*;
+    use redact_crypto::{
+        key::sodiumoxide::{SodiumOxideSymmetricKey, SodiumOxideSymmetricKeyBuilder},
+        storage::tests::MockStorer,
+        ByteSource, Entry, KeyBuilder, States, StorageError, SymmetricKey, SymmetricKeyBuilder,
+        TypeBuilder, VectorByteSource,
+    };
+    use serde_json::Value;
+    use std::{
+        fmt::{self, Debug, Formatter},
+        sync::Arc,
+        time::{Duration, SystemTime},
+    };
+    use warp_sessions::{ArcSessionStore, MemoryStore, Session, SessionStore};
+
+    mock! {
+        pub SessionStore {}
+
+        #[async_trait]
+        impl SessionStore for SessionStore {
+            async fn load_session(&self, cookie_value: String) -> async_session::Result<Option<Session>>;
+            async fn store_session(&self, session: Session) -> async_session::Result<Option<String>>;
+            async fn destroy_session(&self, session: Session) -> async_session::Result;
+            async fn clear_store(&self) -> async_session::Result;
+        }
+
+        impl Debug for SessionStore {
+            fn fmt<'a>(&self, f: &mut Formatter<'a>) -> fmt::Result;
+        }
+
+        impl Clone for SessionStore {
+            fn clone(&self) -> Self;
+        }
+    }
+
+    fn ready() -> Readiness {
+        let readiness = Readiness::new();
+        readiness.set_tls_identity_expiry(SystemTime::now() + Duration::from_secs(60 * 60));
+        readiness.set_ready();
+        readiness
+    }
+
+    fn key_storer() -> MockStorer {
+        let mut storer = MockStorer::new();
+        storer
+            .expect_get_indexed::<SymmetricKey>()
+            .times(1)
+            .withf(|path, _| path == ".keys.default")
+            .returning(|_, _| {
+                let builder = TypeBuilder::Key(KeyBuilder::Symmetric(
+                    SymmetricKeyBuilder::SodiumOxide(SodiumOxideSymmetricKeyBuilder {}),
+                ));
+                let sosk = SodiumOxideSymmetricKey::new();
+                Ok(Entry {
+                    path: ".keys.default".to_owned(),
+                    value: States::Unsealed {
+                        builder,
+                        bytes: ByteSource::Vector(VectorByteSource::new(sosk.key.as_ref())),
+                    },
+                })
+            });
+        storer
+    }
+
+    #[tokio::test]
+    async fn test_healthz() {
+        let res = warp::test::request()
+            .path("/healthz")
+            .reply(&health::healthz())
+            .await;
+        assert_eq!(res.status(), 200);
+    }
+
+    #[tokio::test]
+    async fn test_readyz_all_dependencies_ok() {
+        let readyz = health::readyz(MemoryStore::new(), Arc::new(key_storer()), ready());
+
+        let res = warp::test::request().path("/readyz").reply(&readyz).await;
+        assert_eq!(res.status(), 200);
+        let body: Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["ready"], true);
+        for dependency in &[
+            "bootstrap",
+            "storage",
+            "default_key",
+            "session_store",
+            "tls_identity",
+        ] {
+            assert_eq!(body[dependency]["ok"], true);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_readyz_before_bootstrap() {
+        let readiness = Readiness::new();
+        readiness.set_tls_identity_expiry(SystemTime::now() + Duration::from_secs(60 * 60));
+        let readyz = health::readyz(MemoryStore::new(), Arc::new(key_storer()), readiness);
+
+        let res = warp::test::request().path("/readyz").reply(&readyz).await;
+        assert_eq!(res.status(), 503);
+        let body: Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["bootstrap"]["ok"], false);
+        assert_eq!(body["storage"]["ok"], true);
+    }
+
+    #[tokio::test]
+    async fn test_readyz_tls_certificate_expired() {
+        let readiness = ready();
+        readiness.set_tls_identity_expiry(SystemTime::now() - Duration::from_secs(1));
+        let readyz = health::readyz(MemoryStore::new(), Arc::new(key_storer()), readiness);
+
+        let res = warp::test::request().path("/readyz").reply(&readyz).await;
+        assert_eq!(res.status(), 503);
+        let body: Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["tls_identity"]["ok"], false);
+        assert_eq!(body["tls_identity"]["error"], "TLS certificate has expired");
+        assert_eq!(body["storage"]["ok"], true);
+    }
+
+    #[tokio::test]
+    async fn test_readyz_default_key_not_found() {
+        let mut storer = MockStorer::new();
+        storer
+            .expect_get_indexed::<SymmetricKey>()
+            .times(1)
+            .returning(|_, _| Err(StorageError::NotFound));
+        let readyz = health::readyz(MemoryStore::new(), Arc::new(storer), ready());
+
+        let res = warp::test::request().path("/readyz").reply(&readyz).await;
+        assert_eq!(res.status(), 503);
+        let body: Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["storage"]["ok"], true);
+        assert_eq!(body["default_key"]["ok"], false);
+    }
+
+    #[tokio::test]
+    async fn test_readyz_storage_unreachable() {
+        let mut storer = MockStorer::new();
+        storer
+            .expect_get_indexed::<SymmetricKey>()
+            .times(1)
+            .returning(|_, _| {
+                Err(StorageError::InternalError {
+                    source: Box::new(StorageError::NotFound),
+                })
+            });
+        let readyz = health::readyz(MemoryStore::new(), Arc::new(storer), ready());
+
+        let res = warp::test::request().path("/readyz").reply(&readyz).await;
+        assert_eq!(res.status(), 503);
+        let body: Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["storage"]["ok"], false);
+        assert_eq!(body["default_key"]["ok"], false);
+        assert_eq!(body["session_store"]["ok"], true);
+    }
+
+    #[tokio::test]
+    async fn test_readyz_session_store_not_writable() {
+        let mut mock_store = MockSessionStore::new();
+        mock_store
+            .expect_store_session()
+            .times(1)
+            .return_once(|_| Err(async_session::Error::msg("store is read-only")));
+        let session_store = ArcSessionStore(Arc::new(mock_store));
+        let readyz = health::readyz(session_store, Arc::new(key_storer()), ready());
+
+        let res = warp::test::request().path("/readyz").reply(&readyz).await;
+        assert_eq!(res.status(), 503);
+        let body: Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["session_store"]["ok"], false);
+        assert_eq!(body["session_store"]["error"], "store is read-only");
+    }
+}
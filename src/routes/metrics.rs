@@ -0,0 +1,53 @@
+use crate::{metrics::SESSIONS, routes::error::MetricsRejection};
+use prometheus::{Encoder, TextEncoder};
+use warp::{http::Response, Filter, Rejection, Reply};
+use warp_sessions::MemoryStore;
+
+pub fn metrics(
+    session_store: MemoryStore,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::any()
+        .and(warp::path!("metrics"))
+        .and(warp::any().map(move || session_store.clone()))
+        .and_then(move |session_store: MemoryStore| async move {
+            SESSIONS.set(session_store.count().await as i64);
+
+            let encoder = TextEncoder::new();
+            let mut body = Vec::new();
+            encoder
+                .encode(&prometheus::gather(), &mut body)
+                .map_err(MetricsRejection)?;
+
+            Ok::<_, Rejection>(
+                Response::builder()
+                    .header("Content-Type", encoder.format_type())
+                    .body(body),
+            )
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::metrics::RELAYS;
+    use crate::routes::metrics;
+    use warp_sessions::{MemoryStore, Session, SessionStore};
+
+    #[tokio::test]
+    async fn test_metrics() {
+        let session_store = MemoryStore::new();
+        let mut session = Session::new();
+        session.insert("token", "abc").unwrap();
+        session_store.store_session(session).await.unwrap();
+        RELAYS.with_label_values(&["success"]).inc();
+
+        let res = warp::test::request()
+            .path("/metrics")
+            .reply(&metrics::metrics(session_store))
+            .await;
+        assert_eq!(res.status(), 200);
+
+        let body = String::from_utf8(res.body().to_vec()).unwrap();
+        assert!(body.contains("redact_client_relays_total{outcome=\"success\"}"));
+        assert!(body.contains("redact_client_sessions 1"));
+    }
+}
@@ -0,0 +1,188 @@
+use crate::api_token::ApiTokenStore;
+use crate::relay_history::RelayHistory;
+use crate::render::{
+    RelayHistoryTemplateValues, RenderTemplate, Rendered, Renderer, TemplateValues,
+};
+use crate::routes::{api::authorize, error::RelayHistoryRejection};
+use serde::Deserialize;
+use tracing::info;
+use warp::{Filter, Rejection, Reply};
+
+#[derive(Deserialize, Debug)]
+struct RelayHistoryQueryParams {
+    path: Option<String>,
+}
+
+/// Lists which sites were notified about which paths, newest first, as a page or as
+/// JSON for callers which accept it. `path` limits the list to a single path.
+///
+/// The history reveals every site the user has submitted data to, so it is only
+/// served to holders of an API token and may not be framed by another page.
+pub fn history<A: ApiTokenStore + 'static, R: Renderer>(
+    api_tokens: A,
+    relay_history: RelayHistory,
+    render_engine: R,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::any()
+        .and(warp::path!("relays"))
+        .and(authorize(api_tokens))
+        .and(warp::query::<RelayHistoryQueryParams>())
+        .and(warp::header::optional::<String>("accept"))
+        .and(warp::any().map(move || relay_history.clone()))
+        .and(warp::any().map(move || render_engine.clone()))
+        .and_then(
+            move |app: String,
+                  query_params: RelayHistoryQueryParams,
+                  accept: Option<String>,
+                  relay_history: RelayHistory,
+                  render_engine: R| async move {
+                info!(app = %app, "serving relay history");
+                let attempts = relay_history
+                    .list(query_params.path.as_deref())
+                    .await
+                    .map_err(|e| warp::reject::custom(RelayHistoryRejection(e)))?;
+                if accept.is_some_and(|accept| accept.contains("application/json")) {
+                    return Ok::<_, Rejection>(warp::reply::json(&attempts).into_response());
+                }
+                Ok(Rendered::new(
+                    render_engine,
+                    RenderTemplate {
+                        name: "relays",
+                        value: TemplateValues::Relays(RelayHistoryTemplateValues {
+                            attempts,
+                            path: query_params.path,
+                        }),
+                    },
+                )?
+                .into_response())
+            },
+        )
+        .with(warp::reply::with::header("x-frame-options", "DENY"))
+        .with(warp::reply::with::header(
+            "content-security-policy",
+            "frame-ancestors 'none'",
+        ))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api_token::tests::MockApiTokenStore;
+    use crate::attestation::RelayOperation;
+    use crate::error_handler::recover;
+    use crate::relay_history::{RelayAttempt, RelayHistory};
+    use crate::relayer::{RelayNotification, RelayOutcome};
+    use crate::render::{tests::MockRenderer, RenderTemplate, TemplateValues};
+    use crate::routes::relays;
+    use http::StatusCode;
+    use mockall::predicate::*;
+    use serde_json::Value;
+    use std::sync::Arc;
+    use uuid::Uuid;
+
+    fn api_tokens() -> Arc<MockApiTokenStore> {
+        let mut api_tokens = MockApiTokenStore::new();
+        api_tokens
+            .expect_authorize()
+            .with(eq("valid-token"))
+            .returning(|_| Ok(Some("desktop-app".to_owned())));
+        api_tokens.expect_authorize().returning(|_| Ok(None));
+        Arc::new(api_tokens)
+    }
+
+    async fn history() -> RelayHistory {
+        let history = RelayHistory::new(
+            std::env::temp_dir().join(format!("redact-relay-history-{}.jsonl", Uuid::new_v4())),
+            10,
+        );
+        for path in &[".profile.name.", ".profile.email."] {
+            history
+                .record(RelayAttempt::from_result(
+                    &RelayNotification {
+                        path: path.to_string(),
+                        data_type: Some("string".to_owned()),
+                        operation: RelayOperation::Submit,
+                    },
+                    "https://example.com/relay",
+                    &Ok(RelayOutcome::Delivered(StatusCode::OK)),
+                ))
+                .await
+                .unwrap();
+        }
+        history
+    }
+
+    #[tokio::test]
+    async fn test_history_page() {
+        let mut render_engine = MockRenderer::new();
+        render_engine
+            .expect_render()
+            .times(1)
+            .withf(|template: &RenderTemplate| match &template.value {
+                TemplateValues::Relays(values) => {
+                    template.name == "relays"
+                        && values.attempts.len() == 1
+                        && values.attempts[0].path == ".profile.name."
+                        && values.path == Some(".profile.name.".to_owned())
+                }
+                _ => false,
+            })
+            .return_once(|_| Ok("".to_string()));
+
+        let res = warp::test::request()
+            .path("/relays?path=.profile.name.")
+            .header("authorization", "Bearer valid-token")
+            .reply(&relays::history(
+                api_tokens(),
+                history().await,
+                Arc::new(render_engine),
+            ))
+            .await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.headers()["x-frame-options"], "DENY");
+        assert_eq!(
+            res.headers()["content-security-policy"],
+            "frame-ancestors 'none'"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_history_json() {
+        let res = warp::test::request()
+            .path("/relays")
+            .header("accept", "application/json")
+            .header("authorization", "Bearer valid-token")
+            .reply(&relays::history(
+                api_tokens(),
+                history().await,
+                Arc::new(MockRenderer::new()),
+            ))
+            .await;
+        assert_eq!(res.status(), 200);
+
+        let body: Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body[0]["path"], ".profile.email.");
+        assert_eq!(body[0]["relay_url"], "https://example.com/relay");
+        assert_eq!(body[0]["status"], "delivered");
+        assert_eq!(body[0]["status_code"], 200);
+        assert_eq!(body[1]["path"], ".profile.name.");
+    }
+
+    #[tokio::test]
+    async fn test_history_requires_api_token() {
+        let history = recover(
+            relays::history(api_tokens(), history().await, Arc::new(MockRenderer::new())),
+            Arc::new(MockRenderer::new()),
+        );
+        for authorization in &[None, Some("Bearer revoked-token")] {
+            let mut req = warp::test::request()
+                .path("/relays")
+                .header("accept", "application/json");
+            if let Some(authorization) = authorization {
+                req = req.header("authorization", *authorization);
+            }
+            let res = req.reply(&history).await;
+            assert_eq!(res.status(), 401);
+            assert!(!String::from_utf8_lossy(res.body()).contains(".profile.name."));
+        }
+    }
+}
@@ -0,0 +1,344 @@
+use crate::{
+    api_token::ApiTokenError, relay_policy::RelayUrlError, relayer::RelayError,
+    render::RenderError, storage::StorageTlsError, token::TokenGenerationError,
+};
+use redact_config::ConfigError;
+use redact_crypto::{
+    key::sodiumoxide::SodiumOxideSymmetricKey, ByteSource, CryptoError, HasBuilder, States, StorageError,
+    Storer, SymmetricKey, VectorByteSource,
+};
+use std::cmp;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex,
+};
+use std::time::{Duration, SystemTime};
+use thiserror::Error;
+use tracing::{info, warn};
+
+pub const DEFAULT_KEY_PATH: &str = ".keys.default";
+
+#[derive(Error, Debug)]
+pub enum StartupError {
+    #[error("Failed to load configuration")]
+    ConfigLoadError { source: ConfigError },
+
+    #[error("Configuration value \"{key}\" is missing or invalid")]
+    ConfigError { key: String, source: ConfigError },
+
+    #[error("token.signing.key must be base64 encoding at least {min_length} bytes")]
+    TokenSigningKeyError { min_length: usize },
+
+    #[error("Failed to update the API token file")]
+    ApiTokenError { source: ApiTokenError },
+
+    #[error("Failed to generate an API token")]
+    ApiTokenGenerationError { source: TokenGenerationError },
+
+    #[error("Usage: redact-client api-token issue <app> | revoke <app> | list")]
+    ApiTokenUsageError,
+
+    #[error("Failed to load HTML templates")]
+    TemplateLoadError { source: RenderError },
+
+    #[error("storage.backend value \"{backend}\" is not redact-store, local-fs, sqlite or memory")]
+    UnknownStorageBackend { backend: String },
+
+    #[error("Failed to set up TLS for storage requests")]
+    StorageTlsError { source: StorageTlsError },
+
+    #[error("Failed to open the storage backend")]
+    StorageBackendError { source: StorageError },
+
+    #[error("Failed to create the mutual TLS relayer")]
+    RelayerError { source: RelayError },
+
+    #[error("{key} contains an entry which is not a valid URL")]
+    RelayPolicyError { key: String, source: RelayUrlError },
+
+    #[error("Storage was still unreachable after {attempts} attempts")]
+    StorageUnreachable { attempts: u32, source: StorageError },
+
+    #[error("Failed to store the newly generated default key")]
+    DefaultKeyCreateError { source: StorageError },
+
+    #[error("Failed to load or store the relay signing key")]
+    SigningKeyLoadError { source: StorageError },
+
+    #[error("Failed to seal the newly generated relay signing key")]
+    SigningKeySealError { source: CryptoError },
+}
+
+/// Controls how many times, and how far apart, startup storage calls are retried
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 10,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Returns the delay to wait after the given (1-indexed) failed attempt
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        cmp::min(
+            self.initial_backoff.saturating_mul(factor),
+            self.max_backoff,
+        )
+    }
+}
+
+/// Tracks whether the client has finished bootstrapping and can serve data.
+/// This is reported on /readyz, independently of /healthz which only reports liveness.
+#[derive(Debug, Clone, Default)]
+pub struct Readiness {
+    ready: Arc<AtomicBool>,
+    tls_identity_expires_at: Arc<Mutex<Option<SystemTime>>>,
+}
+
+impl Readiness {
+    pub fn new() -> Readiness {
+        Readiness::default()
+    }
+
+    pub fn set_ready(&self) {
+        self.ready.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::SeqCst)
+    }
+
+    pub fn set_tls_identity_expiry(&self, expires_at: SystemTime) {
+        *self.tls_identity_expires_at.lock().unwrap() = Some(expires_at);
+    }
+
+    /// Fails until the relay identity has been loaded, and again once its certificate
+    /// has expired, as sites would then refuse every relay
+    pub fn check_tls_identity(&self) -> Result<(), &'static str> {
+        match *self.tls_identity_expires_at.lock().unwrap() {
+            None => Err("TLS identity has not been loaded"),
+            Some(expires_at) if expires_at <= SystemTime::now() => {
+                Err("TLS certificate has expired")
+            }
+            Some(_) => Ok(()),
+        }
+    }
+}
+
+/// Fetches and resolves the default symmetric key, creating it only if storage
+/// explicitly reports that it does not exist. Any other storage error is treated
+/// as transient and retried according to the policy; a new key is never generated
+/// in that case as it would orphan all data sealed with the existing one.
+pub async fn load_default_key<H: Storer>(
+    storer: &H,
+    policy: &RetryPolicy,
+) -> Result<SymmetricKey, StartupError> {
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        let result = match storer.get::<SymmetricKey>(DEFAULT_KEY_PATH).await {
+            Ok(entry) => storer.resolve::<SymmetricKey>(entry.value).await.map(Some),
+            Err(StorageError::NotFound) => Ok(None),
+            Err(e) => Err(e),
+        };
+
+        match result {
+            Ok(Some(key)) => return Ok(key),
+            Ok(None) => return create_default_key(storer).await,
+            Err(source) if attempt >= policy.max_attempts => {
+                return Err(StartupError::StorageUnreachable {
+                    attempts: attempt,
+                    source,
+                })
+            }
+            Err(e) => {
+                let backoff = policy.backoff(attempt);
+                warn!(
+                    error = %e,
+                    "failed to load default key (attempt {}/{}), retrying in {:?}",
+                    attempt,
+                    policy.max_attempts,
+                    backoff
+                );
+                tokio::time::sleep(backoff).await;
+            }
+        }
+    }
+}
+
+async fn create_default_key<H: Storer>(storer: &H) -> Result<SymmetricKey, StartupError> {
+    info!("no default key found in storage, generating a new one");
+    let key = SodiumOxideSymmetricKey::new();
+    let bytes = ByteSource::Vector(VectorByteSource::new(key.key.as_ref()));
+    let builder = key.builder().into();
+
+    storer
+        .create(
+            DEFAULT_KEY_PATH.to_owned(),
+            States::Unsealed { builder, bytes },
+        )
+        .await
+        .map_err(|source| StartupError::DefaultKeyCreateError { source })?;
+    Ok(SymmetricKey::SodiumOxide(key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{load_default_key, Readiness, RetryPolicy, StartupError};
+    use redact_crypto::{
+        key::sodiumoxide::{SodiumOxideSymmetricKey, SodiumOxideSymmetricKeyBuilder},
+        storage::tests::MockStorer,
+        ByteSource, Entry, KeyBuilder, States, StorageError, SymmetricKey, SymmetricKeyBuilder,
+        TypeBuilder, VectorByteSource,
+    };
+    use std::time::{Duration, SystemTime};
+
+    fn no_wait_policy(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            initial_backoff: Duration::from_millis(0),
+            max_backoff: Duration::from_millis(0),
+        }
+    }
+
+    fn key_entry() -> Entry {
+        let builder = TypeBuilder::Key(KeyBuilder::Symmetric(SymmetricKeyBuilder::SodiumOxide(
+            SodiumOxideSymmetricKeyBuilder {},
+        )));
+        let sosk = SodiumOxideSymmetricKey::new();
+        Entry {
+            path: ".keys.default".to_owned(),
+            value: States::Unsealed {
+                builder,
+                bytes: ByteSource::Vector(VectorByteSource::new(sosk.key.as_ref())),
+            },
+        }
+    }
+
+    fn internal_error() -> StorageError {
+        StorageError::InternalError {
+            source: Box::new(StorageError::NotFound),
+        }
+    }
+
+    #[test]
+    fn test_backoff_is_exponential_and_capped() {
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(500),
+        };
+        assert_eq!(policy.backoff(1), Duration::from_millis(100));
+        assert_eq!(policy.backoff(2), Duration::from_millis(200));
+        assert_eq!(policy.backoff(3), Duration::from_millis(400));
+        assert_eq!(policy.backoff(4), Duration::from_millis(500));
+        assert_eq!(policy.backoff(40), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn test_readiness() {
+        let readiness = Readiness::new();
+        assert!(!readiness.is_ready());
+        readiness.clone().set_ready();
+        assert!(readiness.is_ready());
+        assert!(readiness.check_tls_identity().is_err());
+        readiness.set_tls_identity_expiry(SystemTime::now() + Duration::from_secs(60));
+        assert!(readiness.check_tls_identity().is_ok());
+        readiness.set_tls_identity_expiry(SystemTime::now() - Duration::from_secs(60));
+        assert_eq!(
+            readiness.check_tls_identity(),
+            Err("TLS certificate has expired")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_load_default_key_existing() {
+        let mut storer = MockStorer::new();
+        storer
+            .expect_get_indexed::<SymmetricKey>()
+            .times(1)
+            .returning(|_, _| Ok(key_entry()));
+        storer.expect_create().times(0);
+
+        load_default_key(&storer, &no_wait_policy(3)).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_load_default_key_not_found_creates_key() {
+        let mut storer = MockStorer::new();
+        storer
+            .expect_get_indexed::<SymmetricKey>()
+            .times(1)
+            .returning(|_, _| Err(StorageError::NotFound));
+        storer
+            .expect_create()
+            .times(1)
+            .withf(|path, _| path == ".keys.default")
+            .returning(|_, _| Ok(true));
+
+        load_default_key(&storer, &no_wait_policy(3)).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_load_default_key_retries_transient_errors() {
+        let mut storer = MockStorer::new();
+        let mut seq = mockall::Sequence::new();
+        storer
+            .expect_get_indexed::<SymmetricKey>()
+            .times(2)
+            .in_sequence(&mut seq)
+            .returning(|_, _| Err(internal_error()));
+        storer
+            .expect_get_indexed::<SymmetricKey>()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|_, _| Ok(key_entry()));
+        storer.expect_create().times(0);
+
+        load_default_key(&storer, &no_wait_policy(3)).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_load_default_key_never_overwrites_on_transient_errors() {
+        let mut storer = MockStorer::new();
+        storer
+            .expect_get_indexed::<SymmetricKey>()
+            .times(3)
+            .returning(|_, _| Err(internal_error()));
+        storer.expect_create().times(0);
+
+        match load_default_key(&storer, &no_wait_policy(3)).await {
+            Err(StartupError::StorageUnreachable { attempts, .. }) => assert_eq!(attempts, 3),
+            _ => panic!("expected a StorageUnreachable error"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_load_default_key_create_failure() {
+        let mut storer = MockStorer::new();
+        storer
+            .expect_get_indexed::<SymmetricKey>()
+            .times(1)
+            .returning(|_, _| Err(StorageError::NotFound));
+        storer
+            .expect_create()
+            .times(1)
+            .returning(|_, _| Err(internal_error()));
+
+        match load_default_key(&storer, &no_wait_policy(3)).await {
+            Err(StartupError::DefaultKeyCreateError { .. }) => (),
+            _ => panic!("expected a DefaultKeyCreateError"),
+        }
+    }
+}
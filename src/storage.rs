@@ -0,0 +1,583 @@
+use crate::relayer::{load_ca_bundle, RelayError, RelayIdentity};
+use async_trait::async_trait;
+use redact_crypto::{
+    Builder, CryptoError, Data, Entry, EntryPath, HasBuilder, HasIndex, States, StorageError,
+    Storer, TypeBuilder, TypeBuilderContainer, Unsealable,
+};
+use std::convert::TryFrom;
+use std::error::Error;
+use std::io;
+use std::ops::Deref;
+use std::sync::Arc;
+use thiserror::Error;
+
+type Document = <Data as HasIndex>::Index;
+
+/// Removes entries from storage. This complements `Storer`, which can only read and
+/// write entries.
+#[async_trait]
+pub trait Deleter: Clone + Send + Sync {
+    /// Deletes the entry at the given path, failing with `StorageError::NotFound` if
+    /// there is none
+    async fn delete(&self, path: &str) -> Result<(), StorageError>;
+}
+
+#[async_trait]
+impl<U> Deleter for Arc<U>
+where
+    U: Deleter,
+{
+    async fn delete(&self, path: &str) -> Result<(), StorageError> {
+        self.deref().delete(path).await
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum StorageTlsError {
+    #[error("Failed to load the storage client identity")]
+    IdentityError { source: RelayError },
+
+    #[error("Failed to load the storage CA bundle")]
+    CaBundleError { source: RelayError },
+
+    #[error("Failed to build the storage client")]
+    ClientBuildError { source: reqwest::Error },
+
+    #[error("The certificate presented by {url} was not accepted")]
+    CertificateRejected { url: String, source: reqwest::Error },
+}
+
+/// Builds the client used for storage requests. It presents the given identity, if
+/// any, loaded the same way as the relay identity, and only trusts store
+/// certificates signed by one of the CAs in the PEM bundle at `ca_path`, if given,
+/// instead of the built-in roots.
+pub fn storage_client(
+    identity: Option<&RelayIdentity>,
+    ca_path: Option<&str>,
+) -> Result<reqwest::Client, StorageTlsError> {
+    let mut builder = reqwest::Client::builder().use_rustls_tls();
+    if let Some(identity) = identity {
+        let pem = identity
+            .load()
+            .map_err(|source| StorageTlsError::IdentityError { source })?;
+        // The identity was already checked while loading it
+        let identity = reqwest::Identity::from_pem(&pem)
+            .map_err(|source| StorageTlsError::ClientBuildError { source })?;
+        builder = builder.identity(identity);
+    }
+    if let Some(path) = ca_path {
+        let ca_certs =
+            load_ca_bundle(path).map_err(|source| StorageTlsError::CaBundleError { source })?;
+        builder = builder.tls_built_in_root_certs(false);
+        for ca in ca_certs {
+            builder = builder.add_root_certificate(ca);
+        }
+    }
+    builder
+        .build()
+        .map_err(|source| StorageTlsError::ClientBuildError { source })
+}
+
+/// rustls reports a failed handshake, including an untrusted certificate, as an
+/// `InvalidData` IO error, which hyper wraps in another IO error
+fn is_tls_failure(error: &reqwest::Error) -> bool {
+    let mut source = error.source();
+    while let Some(cause) = source {
+        let mut io_error = cause.downcast_ref::<io::Error>();
+        while let Some(e) = io_error {
+            if e.kind() == io::ErrorKind::InvalidData {
+                return true;
+            }
+            io_error = e
+                .get_ref()
+                .and_then(|inner| inner.downcast_ref::<io::Error>());
+        }
+        source = cause.source();
+    }
+    false
+}
+
+/// Talks to a redact-store instance the same way as `RedactStorer`, but through the
+/// given client, so that requests can be authenticated with mutual TLS
+#[derive(Debug, Clone)]
+pub struct HttpStorer {
+    url: String,
+    client: reqwest::Client,
+}
+
+impl HttpStorer {
+    pub fn new(url: &str, client: reqwest::Client) -> HttpStorer {
+        HttpStorer {
+            url: url.to_owned(),
+            client,
+        }
+    }
+
+    /// Makes a request to the store, failing only if its certificate was not
+    /// accepted. Any other failure is left for the startup retries to deal with.
+    pub async fn verify_certificate(&self) -> Result<(), StorageTlsError> {
+        match self.client.get(&self.url).send().await {
+            Err(source) if is_tls_failure(&source) => Err(StorageTlsError::CertificateRejected {
+                url: self.url.clone(),
+                source,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    async fn fetch<T: serde::de::DeserializeOwned>(
+        &self,
+        request: reqwest::RequestBuilder,
+    ) -> Result<T, StorageError> {
+        let to_storage_error = |source: reqwest::Error| {
+            if source.status() == Some(reqwest::StatusCode::NOT_FOUND) {
+                StorageError::NotFound
+            } else {
+                StorageError::InternalError {
+                    source: Box::new(source),
+                }
+            }
+        };
+        request
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(to_storage_error)?
+            .json::<T>()
+            .await
+            .map_err(to_storage_error)
+    }
+}
+
+// async_trait adds its own lifetime bounds to the ones required by `Storer`
+#[allow(clippy::multiple_bound_locations)]
+#[async_trait]
+impl Storer for HttpStorer {
+    async fn get_indexed<T: HasBuilder + 'static>(
+        &self,
+        path: &str,
+        index: &Option<Document>,
+    ) -> Result<Entry, StorageError> {
+        let mut request = self.client.get(format!("{}/{}", self.url, path));
+        if let Some(index) = index {
+            request = request.query(&[("index", index.to_string())]);
+        }
+        self.fetch(request).await
+    }
+
+    async fn list_indexed<T: HasBuilder + Send + 'static>(
+        &self,
+        path: &str,
+        skip: i64,
+        page_size: i64,
+        index: &Option<Document>,
+    ) -> Result<Vec<Entry>, StorageError> {
+        let mut request = self
+            .client
+            .get(format!("{}/{}", self.url, path))
+            .query(&[("skip", skip), ("page_size", page_size)]);
+        if let Some(index) = index {
+            request = request.query(&[("index", index.to_string())]);
+        }
+        self.fetch(request).await
+    }
+
+    async fn create(&self, path: EntryPath, value: States) -> Result<bool, StorageError> {
+        self.client
+            .post(format!("{}/", self.url))
+            .json(&Entry { path, value })
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map(|_| true)
+            .map_err(|source| StorageError::InternalError {
+                source: Box::new(source),
+            })
+    }
+}
+
+/// Deletes entries from the same redact-store instance used by `HttpStorer`
+#[derive(Debug, Clone)]
+pub struct RedactDeleter {
+    url: String,
+    client: reqwest::Client,
+}
+
+impl RedactDeleter {
+    pub fn new(url: &str, client: reqwest::Client) -> RedactDeleter {
+        RedactDeleter {
+            url: url.to_owned(),
+            client,
+        }
+    }
+}
+
+#[async_trait]
+impl Deleter for RedactDeleter {
+    async fn delete(&self, path: &str) -> Result<(), StorageError> {
+        match self
+            .client
+            .delete(format!("{}/{}", self.url, path))
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+        {
+            Ok(_) => Ok(()),
+            Err(source) if source.status() == Some(reqwest::StatusCode::NOT_FOUND) => {
+                Err(StorageError::NotFound)
+            }
+            Err(source) => Err(StorageError::InternalError {
+                source: Box::new(source),
+            }),
+        }
+    }
+}
+
+/// A `Storer` which answers lookups of a single key entry from memory. Unsealing an
+/// entry looks up the key it was sealed with, so this lets any number of entries
+/// sealed with the same key be unsealed with a single key lookup.
+#[derive(Clone)]
+pub struct PreloadedKeyStorer<H: Storer> {
+    inner: H,
+    key_path: String,
+    key_entry: Arc<Entry>,
+}
+
+impl<H: Storer> PreloadedKeyStorer<H> {
+    /// Serves the entry for lookups of either the path it was requested with, or the
+    /// path it reports for itself
+    pub fn new(inner: H, key_path: &str, key_entry: Entry) -> PreloadedKeyStorer<H> {
+        PreloadedKeyStorer {
+            inner,
+            key_path: key_path.to_owned(),
+            key_entry: Arc::new(key_entry),
+        }
+    }
+}
+
+// async_trait adds its own lifetime bounds to the ones required by `Storer`
+#[allow(clippy::multiple_bound_locations)]
+#[async_trait]
+impl<H: Storer> Storer for PreloadedKeyStorer<H> {
+    async fn get_indexed<T: HasBuilder + 'static>(
+        &self,
+        path: &str,
+        index: &Option<Document>,
+    ) -> Result<Entry, StorageError> {
+        if path == self.key_path || path == self.key_entry.path {
+            Ok(self.key_entry.deref().clone())
+        } else {
+            self.inner.get_indexed::<T>(path, index).await
+        }
+    }
+
+    async fn list_indexed<T: HasBuilder + Send + 'static>(
+        &self,
+        path: &str,
+        skip: i64,
+        page_size: i64,
+        index: &Option<Document>,
+    ) -> Result<Vec<Entry>, StorageError> {
+        self.inner
+            .list_indexed::<T>(path, skip, page_size, index)
+            .await
+    }
+
+    async fn create(&self, path: EntryPath, value: States) -> Result<bool, StorageError> {
+        self.inner.create(path, value).await
+    }
+
+    async fn resolve_indexed<T: HasBuilder + 'static>(
+        &self,
+        state: States,
+        index: &Option<Document>,
+    ) -> Result<T, StorageError> {
+        match state {
+            // The preloaded entry may itself be a reference to the key, if it was served
+            // from a key cache, so it is left to the wrapped storer to resolve
+            States::Referenced { ref path, .. }
+                if path == &self.key_path || path == &self.key_entry.path =>
+            {
+                self.inner
+                    .resolve_indexed::<T>(self.key_entry.value.clone(), index)
+                    .await
+            }
+            state => resolve_state(self, state, index).await,
+        }
+    }
+}
+
+/// Resolves a state the way `Storer::resolve_indexed` does by default, for storers
+/// which override how some references are followed. Referenced entries are looked up,
+/// and sealed entries unsealed, through the given storer.
+pub async fn resolve_state<S: Storer, T: HasBuilder + 'static>(
+    storer: &S,
+    state: States,
+    index: &Option<Document>,
+) -> Result<T, StorageError> {
+    let internal = |source: CryptoError| StorageError::InternalError {
+        source: Box::new(source),
+    };
+    match state {
+        States::Referenced { ref path, .. } => {
+            let entry = storer.get_indexed::<T>(path, index).await?;
+            storer.resolve_indexed::<T>(entry.value, index).await
+        }
+        States::Sealed {
+            builder,
+            unsealable,
+        } => {
+            let bytes = unsealable.unseal(storer.clone()).await.map_err(internal)?;
+            build::<T>(builder, bytes.get_source().get().map_err(internal)?).map_err(internal)
+        }
+        States::Unsealed { builder, bytes } => {
+            build::<T>(builder, bytes.get().map_err(internal)?).map_err(internal)
+        }
+    }
+}
+
+fn build<T: HasBuilder>(builder: TypeBuilder, bytes: &[u8]) -> Result<T, CryptoError> {
+    <T as HasBuilder>::Builder::try_from(TypeBuilderContainer(builder))?.build(bytes)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::{storage_client, Deleter, Document, HttpStorer, StorageTlsError};
+    use crate::relayer::{RelayError, RelayIdentity};
+    use async_trait::async_trait;
+    use mockall::*;
+    use redact_crypto::{
+        ByteSource, Data, DataBuilder, Entry, EntryPath, HasBuilder, States, StorageError, Storer,
+        StringDataBuilder, TypeBuilder, VectorByteSource,
+    };
+    use std::collections::HashMap;
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    };
+    use uuid::Uuid;
+    use warp::Filter;
+
+    mock! {
+    pub Deleter {}
+    #[async_trait]
+    impl Deleter for Deleter {
+            async fn delete(&self, path: &str) -> Result<(), StorageError>;
+    }
+    impl Clone for Deleter {
+            fn clone(&self) -> Self;
+    }
+    }
+
+    /// An in-memory `Storer` which counts how many times each path is looked up, and
+    /// which can be made to fail as if storage were unreachable. `MockStorer` cannot be
+    /// used where entries of more than one type are looked up, as mockall does not tell
+    /// apart expectations which differ only by type parameter.
+    #[derive(Clone, Default)]
+    pub struct CountingStorer {
+        entries: Arc<Mutex<HashMap<String, States>>>,
+        gets: Arc<Mutex<HashMap<String, usize>>>,
+        unreachable: Arc<AtomicBool>,
+    }
+
+    impl CountingStorer {
+        pub fn insert(&self, path: &str, value: States) {
+            self.entries.lock().unwrap().insert(path.to_owned(), value);
+        }
+
+        pub fn gets(&self, path: &str) -> usize {
+            self.gets.lock().unwrap().get(path).copied().unwrap_or(0)
+        }
+
+        pub fn set_unreachable(&self, unreachable: bool) {
+            self.unreachable.store(unreachable, Ordering::SeqCst);
+        }
+
+        fn check_reachable(&self) -> Result<(), StorageError> {
+            if self.unreachable.load(Ordering::SeqCst) {
+                Err(StorageError::InternalError {
+                    source: Box::new(std::io::Error::new(
+                        std::io::ErrorKind::ConnectionRefused,
+                        "storage is unreachable",
+                    )),
+                })
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    #[allow(clippy::multiple_bound_locations)]
+    #[async_trait]
+    impl Storer for CountingStorer {
+        async fn get_indexed<T: HasBuilder + 'static>(
+            &self,
+            path: &str,
+            _index: &Option<Document>,
+        ) -> Result<Entry, StorageError> {
+            *self
+                .gets
+                .lock()
+                .unwrap()
+                .entry(path.to_owned())
+                .or_insert(0) += 1;
+            self.check_reachable()?;
+            match self.entries.lock().unwrap().get(path) {
+                Some(value) => Ok(Entry {
+                    path: path.to_owned(),
+                    value: value.clone(),
+                }),
+                None => Err(StorageError::NotFound),
+            }
+        }
+
+        async fn list_indexed<T: HasBuilder + Send + 'static>(
+            &self,
+            _path: &str,
+            _skip: i64,
+            _page_size: i64,
+            _index: &Option<Document>,
+        ) -> Result<Vec<Entry>, StorageError> {
+            self.check_reachable()?;
+            Ok(vec![])
+        }
+
+        async fn create(&self, path: EntryPath, value: States) -> Result<bool, StorageError> {
+            self.check_reachable()?;
+            self.insert(&path, value);
+            Ok(true)
+        }
+    }
+
+    /// Path to one of the test certificates in tests/fixtures/tls
+    pub fn tls_fixture(name: &str) -> String {
+        format!("{}/tests/fixtures/tls/{}", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    fn pem(name: &str) -> RelayIdentity {
+        RelayIdentity::Pem {
+            path: tls_fixture(name),
+        }
+    }
+
+    /// Serves a single entry over TLS, only accepting clients with a certificate
+    /// signed by the test CA
+    fn tls_store() -> String {
+        let entry = warp::path!(String).map(|path: String| {
+            warp::reply::json(&Entry {
+                path,
+                value: States::Unsealed {
+                    builder: TypeBuilder::Data(DataBuilder::String(StringDataBuilder {})),
+                    bytes: ByteSource::Vector(VectorByteSource::new(b"Alice")),
+                },
+            })
+        });
+        let (addr, server) = warp::serve(entry)
+            .tls()
+            .cert_path(tls_fixture("server-cert.pem"))
+            .key_path(tls_fixture("server-key.pem"))
+            .client_auth_required_path(tls_fixture("ca.pem"))
+            .bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        format!("https://localhost:{}", addr.port())
+    }
+
+    #[tokio::test]
+    async fn test_mutual_tls() {
+        let url = tls_store();
+        let storer = HttpStorer::new(
+            &url,
+            storage_client(Some(&pem("client.pem")), Some(&tls_fixture("ca.pem"))).unwrap(),
+        );
+        storer.verify_certificate().await.unwrap();
+        let entry = storer.get::<Data>(".profile.name.").await.unwrap();
+        assert_eq!(entry.path, ".profile.name.");
+
+        // Identities kept as separate PEM files or as PKCS#12 are presented too
+        for identity in &[
+            RelayIdentity::PemPair {
+                cert_path: tls_fixture("client-cert.pem"),
+                key_path: tls_fixture("client-key.pem"),
+            },
+            RelayIdentity::Pkcs12 {
+                path: tls_fixture("client.p12"),
+                passphrase: "redact-test".to_owned(),
+            },
+        ] {
+            let storer = HttpStorer::new(
+                &url,
+                storage_client(Some(identity), Some(&tls_fixture("ca.pem"))).unwrap(),
+            );
+            assert!(storer.get::<Data>(".profile.name.").await.is_ok());
+        }
+
+        // The store refuses clients which do not present a certificate
+        let anonymous = HttpStorer::new(
+            &url,
+            storage_client(None, Some(&tls_fixture("ca.pem"))).unwrap(),
+        );
+        assert!(matches!(
+            anonymous.get::<Data>(".profile.name.").await,
+            Err(StorageError::InternalError { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_store_certificate_must_match_pinned_ca() {
+        let url = tls_store();
+        let storer = HttpStorer::new(
+            &url,
+            storage_client(Some(&pem("client.pem")), Some(&tls_fixture("other-ca.pem"))).unwrap(),
+        );
+        assert!(matches!(
+            storer.verify_certificate().await,
+            Err(StorageTlsError::CertificateRejected { .. })
+        ));
+
+        // Failing to connect at all is not a certificate problem
+        let unreachable = HttpStorer::new("https://localhost:1", reqwest::Client::new());
+        unreachable.verify_certificate().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_every_ca_in_bundle_is_trusted() {
+        let url = tls_store();
+        // The CA which signed the store's certificate is not the first in the bundle
+        let bundle = std::env::temp_dir().join(format!("redact-ca-bundle-{}.pem", Uuid::new_v4()));
+        let mut bundle_pem = std::fs::read(tls_fixture("other-ca.pem")).unwrap();
+        bundle_pem.extend(std::fs::read(tls_fixture("ca.pem")).unwrap());
+        std::fs::write(&bundle, bundle_pem).unwrap();
+
+        let storer = HttpStorer::new(
+            &url,
+            storage_client(Some(&pem("client.pem")), bundle.to_str()).unwrap(),
+        );
+        storer.verify_certificate().await.unwrap();
+        assert!(storer.get::<Data>(".profile.name.").await.is_ok());
+        std::fs::remove_file(bundle).unwrap();
+    }
+
+    #[test]
+    fn test_storage_client_errors() {
+        assert!(matches!(
+            storage_client(Some(&pem("missing.pem")), None),
+            Err(StorageTlsError::IdentityError { .. })
+        ));
+        assert!(matches!(
+            storage_client(Some(&pem("ca.pem")), None),
+            Err(StorageTlsError::IdentityError { .. })
+        ));
+        assert!(matches!(
+            storage_client(None, Some(&tls_fixture("missing.pem"))),
+            Err(StorageTlsError::CaBundleError {
+                source: RelayError::IoError { .. }
+            })
+        ));
+        assert!(matches!(
+            storage_client(None, Some(&tls_fixture("README.md"))),
+            Err(StorageTlsError::CaBundleError {
+                source: RelayError::InvalidCaBundle { .. }
+            })
+        ));
+    }
+}
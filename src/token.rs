@@ -1,9 +1,23 @@
+use hmac::{Hmac, Mac, NewMac};
 use rand::{thread_rng, Rng};
 use sha2::{Digest, Sha256};
+use std::collections::{hash_map::Entry, HashMap};
 use std::ops::Deref;
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use subtle::ConstantTimeEq;
 use thiserror::Error;
-use warp::reject::Reject;
+use warp::{reject::Reject, Filter, Rejection};
+
+/// Number of random bytes in a token unless configured otherwise
+pub const DEFAULT_TOKEN_LENGTH: usize = 32;
+
+/// Tokens shorter than this would be guessable and are never generated
+pub const MIN_TOKEN_LENGTH: usize = 16;
+
+/// How long a redeemed token is remembered unless configured otherwise. This matches
+/// the lifetime of the sessions that tokens are stored in.
+pub const DEFAULT_REDEEMED_TOKEN_TTL: Duration = Duration::from_secs(60);
 
 #[derive(Error, Debug)]
 pub enum TokenGenerationError {
@@ -16,8 +30,35 @@ pub enum TokenGenerationError {
 
 impl Reject for TokenGenerationError {}
 
+/// Generates a new token for each request, rejecting the request if no token
+/// could be generated
+pub fn new_token<T: TokenGenerator>(
+    token_generator: T,
+) -> impl Filter<Extract = (String,), Error = Rejection> + Clone {
+    warp::any().and_then(move || {
+        let token_generator = token_generator.clone();
+        async move {
+            token_generator
+                .generate_token()
+                .map_err(warp::reject::custom)
+        }
+    })
+}
+
 pub trait TokenGenerator: Clone + Send + Sync {
     fn generate_token(&self) -> Result<String, TokenGenerationError>;
+
+    /// Generates a token meant to access the given path. Unless tokens are signed,
+    /// this is an ordinary token which is only bound to the path by a session.
+    fn generate_path_token(&self, _path: &str) -> Result<String, TokenGenerationError> {
+        self.generate_token()
+    }
+
+    /// Returns whether the token was signed for the given path and has not expired,
+    /// in which case it can be trusted without looking up a session
+    fn verify_path_token(&self, _token: &str, _path: &str) -> bool {
+        false
+    }
 }
 
 impl<T> TokenGenerator for Arc<T>
@@ -27,82 +68,287 @@ where
     fn generate_token(&self) -> Result<String, TokenGenerationError> {
         self.deref().generate_token()
     }
+
+    fn generate_path_token(&self, path: &str) -> Result<String, TokenGenerationError> {
+        self.deref().generate_path_token(path)
+    }
+
+    fn verify_path_token(&self, token: &str, path: &str) -> bool {
+        self.deref().verify_path_token(token, path)
+    }
+}
+
+/// Compares two tokens in constant time so that a mismatch does not reveal how
+/// many leading characters of a guess were correct
+pub fn tokens_match(a: &str, b: &str) -> bool {
+    a.as_bytes().ct_eq(b.as_bytes()).into()
+}
+
+/// Remembers every token that has been redeemed so that each one is accepted at most
+/// once, even when several requests present it concurrently. Entries are kept for at
+/// least as long as the tokens could otherwise still be valid.
+#[derive(Clone)]
+pub struct RedeemedTokens {
+    redeemed: Arc<Mutex<HashMap<Vec<u8>, Instant>>>,
+    ttl: Duration,
+}
+
+impl Default for RedeemedTokens {
+    fn default() -> Self {
+        RedeemedTokens::new(DEFAULT_REDEEMED_TOKEN_TTL)
+    }
+}
+
+impl RedeemedTokens {
+    pub fn new(ttl: Duration) -> RedeemedTokens {
+        RedeemedTokens {
+            redeemed: Arc::new(Mutex::new(HashMap::new())),
+            ttl,
+        }
+    }
+
+    /// Atomically marks the token as redeemed, returning false if it already was
+    pub fn redeem(&self, token: &str) -> bool {
+        // Only a digest is kept so that redeemed tokens are not held in memory
+        let digest = Sha256::digest(token.as_bytes()).to_vec();
+        let now = Instant::now();
+        let mut redeemed = self.redeemed.lock().unwrap();
+        let ttl = self.ttl;
+        redeemed.retain(|_, redeemed_at| now.duration_since(*redeemed_at) < ttl);
+
+        match redeemed.entry(digest) {
+            Entry::Occupied(_) => false,
+            Entry::Vacant(entry) => {
+                entry.insert(now);
+                true
+            }
+        }
+    }
+
+    /// Releases a redeemed token so that a submission which failed can be retried
+    pub fn release(&self, token: &str) {
+        let digest = Sha256::digest(token.as_bytes()).to_vec();
+        self.redeemed.lock().unwrap().remove(&digest);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TokenEncoding {
+    Hex,
+    Base64Url,
+}
+
+impl TokenEncoding {
+    fn encode(&self, bytes: &[u8]) -> String {
+        match self {
+            TokenEncoding::Hex => bytes.iter().map(|b| format!("{:02X}", b)).collect(),
+            TokenEncoding::Base64Url => base64::encode_config(bytes, base64::URL_SAFE_NO_PAD),
+        }
+    }
+}
+
+/// Signs path tokens with HMAC-SHA256 so that each one is only valid for the path it
+/// was issued for, until it expires
+#[derive(Clone)]
+pub struct TokenSigner {
+    key: Arc<Vec<u8>>,
+    ttl: Duration,
+}
+
+impl TokenSigner {
+    pub fn new(key: Vec<u8>, ttl: Duration) -> TokenSigner {
+        TokenSigner {
+            key: Arc::new(key),
+            ttl,
+        }
+    }
+
+    /// How long signed tokens remain valid for
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    fn mac(&self, token: &str, expiry: u64, path: &str) -> Hmac<Sha256> {
+        // HMAC accepts keys of any length
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).unwrap();
+        mac.update(path.as_bytes());
+        mac.update(b"\n");
+        mac.update(format!("{}.{}", token, expiry).as_bytes());
+        mac
+    }
+
+    /// Produces a token of the form `<token>.<expiry>.<signature>`
+    pub fn sign(
+        &self,
+        token: &str,
+        path: &str,
+        now: SystemTime,
+    ) -> Result<String, TokenGenerationError> {
+        let expiry = now
+            .duration_since(UNIX_EPOCH)
+            .map_err(|source| TokenGenerationError::SystemTimeError { source })?
+            .saturating_add(self.ttl)
+            .as_secs();
+        let signature = self.mac(token, expiry, path).finalize().into_bytes();
+
+        Ok(format!(
+            "{}.{}.{}",
+            token,
+            expiry,
+            base64::encode_config(signature, base64::URL_SAFE_NO_PAD)
+        ))
+    }
+
+    pub fn verify(&self, signed_token: &str, path: &str, now: SystemTime) -> bool {
+        let mut parts = signed_token.rsplitn(3, '.');
+        let (signature, expiry, token) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(signature), Some(expiry), Some(token)) => (signature, expiry, token),
+            _ => return false,
+        };
+        let (expiry, signature) = match (
+            expiry.parse::<u64>(),
+            base64::decode_config(signature, base64::URL_SAFE_NO_PAD),
+        ) {
+            (Ok(expiry), Ok(signature)) => (expiry, signature),
+            _ => return false,
+        };
+        let now = match now.duration_since(UNIX_EPOCH) {
+            Ok(now) => now.as_secs(),
+            Err(_) => return false,
+        };
+
+        // The signature is compared in constant time by `verify`
+        now < expiry && self.mac(token, expiry, path).verify(&signature).is_ok()
+    }
+}
+
+/// Controls the size and format of generated tokens
+#[derive(Clone)]
+pub struct TokenOptions {
+    /// Number of random bytes in each token
+    pub length: usize,
+    pub encoding: TokenEncoding,
+    /// Signs path tokens if set
+    pub signer: Option<TokenSigner>,
+}
+
+impl Default for TokenOptions {
+    fn default() -> Self {
+        TokenOptions {
+            length: DEFAULT_TOKEN_LENGTH,
+            encoding: TokenEncoding::Hex,
+            signer: None,
+        }
+    }
+}
+
+impl TokenOptions {
+    fn generate<R: Rng + ?Sized>(&self, rng: &mut R) -> Result<String, TokenGenerationError> {
+        let mut random_bytes = vec![0; self.length.max(MIN_TOKEN_LENGTH)];
+        rng.try_fill(random_bytes.as_mut_slice())
+            .map_err(|source| TokenGenerationError::RandError { source })?;
+
+        Ok(self.encoding.encode(&random_bytes))
+    }
+
+    fn sign(&self, token: String, path: &str) -> Result<String, TokenGenerationError> {
+        match &self.signer {
+            Some(signer) => signer.sign(&token, path, SystemTime::now()),
+            None => Ok(token),
+        }
+    }
+
+    fn verify(&self, token: &str, path: &str) -> bool {
+        match &self.signer {
+            Some(signer) => signer.verify(token, path, SystemTime::now()),
+            None => false,
+        }
+    }
 }
 
 pub struct FromCustomRng<T: Rng + Send + Sync> {
     rand_source: Arc<RwLock<T>>,
+    options: TokenOptions,
 }
 
-#[derive(Clone)]
-pub struct FromThreadRng;
+#[derive(Clone, Default)]
+pub struct FromThreadRng {
+    options: TokenOptions,
+}
 
 impl<T: Rng + Send + Sync> Clone for FromCustomRng<T> {
     fn clone(&self) -> FromCustomRng<T> {
         FromCustomRng {
             rand_source: self.rand_source.clone(),
+            options: self.options.clone(),
         }
     }
 }
 
 impl<T: Rng + Send + Sync> TokenGenerator for FromCustomRng<T> {
     fn generate_token(&self) -> Result<String, TokenGenerationError> {
-        // Generate 32 cryptographically secure random bytes
-        let mut random_bytes: [u8; 32] = [0; 32];
-        self.rand_source
-            .write()
-            .unwrap()
-            .try_fill(&mut random_bytes)
-            .map_err(|source| TokenGenerationError::RandError { source })?;
+        self.options
+            .generate(&mut *self.rand_source.write().unwrap())
+    }
 
-        // Hash random bytes to create a displayable 32-byte string
-        let mut hasher = Sha256::new();
-        for b in &random_bytes {
-            hasher.update(format!("{}", b));
-        }
+    fn generate_path_token(&self, path: &str) -> Result<String, TokenGenerationError> {
+        self.options.sign(self.generate_token()?, path)
+    }
 
-        Ok(format!("{:X}", hasher.finalize()))
+    fn verify_path_token(&self, token: &str, path: &str) -> bool {
+        self.options.verify(token, path)
     }
 }
 
 impl TokenGenerator for FromThreadRng {
     fn generate_token(&self) -> Result<String, TokenGenerationError> {
-        // Generate 32 cryptographically secure random bytes
-        let mut random_bytes: [u8; 32] = [0; 32];
-        thread_rng()
-            .try_fill(&mut random_bytes)
-            .map_err(|source| TokenGenerationError::RandError { source })?;
+        // The thread-local generator is a CSPRNG seeded from the operating system
+        self.options.generate(&mut thread_rng())
+    }
 
-        // Hash random bytes to create a displayable 32-byte string
-        let mut hasher = Sha256::new();
-        for b in &random_bytes {
-            hasher.update(format!("{}", b));
-        }
+    fn generate_path_token(&self, path: &str) -> Result<String, TokenGenerationError> {
+        self.options.sign(self.generate_token()?, path)
+    }
 
-        Ok(format!("{:X}", hasher.finalize()))
+    fn verify_path_token(&self, token: &str, path: &str) -> bool {
+        self.options.verify(token, path)
     }
 }
 
 impl<T: Rng + Send + Sync> FromCustomRng<T> {
     pub fn new(rand_source: T) -> FromCustomRng<T> {
+        FromCustomRng::with_options(rand_source, TokenOptions::default())
+    }
+
+    pub fn with_options(rand_source: T, options: TokenOptions) -> FromCustomRng<T> {
         FromCustomRng {
             rand_source: Arc::new(RwLock::new(rand_source)),
+            options,
         }
     }
 }
 
 impl FromThreadRng {
     pub fn new() -> FromThreadRng {
-        FromThreadRng {}
+        FromThreadRng::with_options(TokenOptions::default())
+    }
+
+    pub fn with_options(options: TokenOptions) -> FromThreadRng {
+        FromThreadRng { options }
     }
 }
 
 #[cfg(test)]
 pub mod tests {
-    use crate::token::{FromCustomRng, FromThreadRng, TokenGenerationError, TokenGenerator};
+    use crate::token::{
+        new_token, tokens_match, FromCustomRng, FromThreadRng, RedeemedTokens, TokenEncoding,
+        TokenGenerationError, TokenGenerator, TokenOptions, TokenSigner,
+    };
     use mockall::predicate::*;
     use mockall::*;
     use rand::{prelude::*, Error, RngCore};
     use rand_pcg::Pcg64;
+    use std::time::{Duration, SystemTime};
 
     mock! {
     pub TokenGenerator {}
@@ -115,7 +361,7 @@ pub mod tests {
     }
 
     mock! {
-    FailingRng {}
+    pub FailingRng {}
     impl RngCore for FailingRng {
             fn fill_bytes(&mut self, dest: &mut [u8]);
             fn next_u32(&mut self) -> u32;
@@ -124,13 +370,28 @@ pub mod tests {
     }
     }
 
+    pub fn failing_rng() -> MockFailingRng {
+        let mut failing_rng = MockFailingRng::new();
+        failing_rng
+            .expect_try_fill_bytes()
+            .returning(|_| Err(Error::new("filling array failed".to_owned())));
+        failing_rng
+    }
+
+    fn signer() -> TokenSigner {
+        TokenSigner::new(
+            b"0123456789abcdef0123456789abcdef".to_vec(),
+            Duration::from_secs(60),
+        )
+    }
+
     #[test]
     fn test_token_generation_with_deterministic_rng() {
         let token_generator = FromCustomRng::new(Pcg64::seed_from_u64(1));
         let token = token_generator.generate_token().unwrap();
         assert_eq!(
             token,
-            "E0AE2C1C9AA2DB85DFA2FF6B4AAC7A5E51FFDAA3948BECEC353561D513E59A9C"
+            "472D0624C8F501D757AFBA8A9BAF6D04A0B50D4998A9281DD3E733ACC5EDA6FB"
         );
     }
 
@@ -139,6 +400,34 @@ pub mod tests {
         let token_generator = FromThreadRng::new();
         let token = token_generator.generate_token().unwrap();
         assert_eq!(token.chars().count(), 64);
+        assert_ne!(token, token_generator.generate_token().unwrap());
+    }
+
+    #[test]
+    fn test_token_generation_with_base64url_encoding() {
+        let token_generator = FromThreadRng::with_options(TokenOptions {
+            length: 48,
+            encoding: TokenEncoding::Base64Url,
+            signer: None,
+        });
+        let token = token_generator.generate_token().unwrap();
+        assert_eq!(token.chars().count(), 64);
+        assert!(token
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'));
+    }
+
+    #[test]
+    fn test_token_generation_enforces_minimum_length() {
+        let token_generator = FromThreadRng::with_options(TokenOptions {
+            length: 1,
+            encoding: TokenEncoding::Hex,
+            signer: None,
+        });
+        assert_eq!(
+            token_generator.generate_token().unwrap().chars().count(),
+            32
+        );
     }
 
     #[test]
@@ -153,6 +442,72 @@ pub mod tests {
         let _ = token_generator.generate_token().unwrap();
     }
 
+    #[test]
+    fn test_unsigned_path_tokens_are_not_verifiable() {
+        let token_generator = FromCustomRng::new(Pcg64::seed_from_u64(1));
+        let token = token_generator.generate_path_token(".testKey.").unwrap();
+        assert_eq!(token.chars().count(), 64);
+        assert!(!token_generator.verify_path_token(&token, ".testKey."));
+    }
+
+    #[test]
+    fn test_signed_path_tokens() {
+        let token_generator = FromCustomRng::with_options(
+            Pcg64::seed_from_u64(1),
+            TokenOptions {
+                signer: Some(signer()),
+                ..TokenOptions::default()
+            },
+        );
+        let token = token_generator.generate_path_token(".testKey.").unwrap();
+        assert!(token_generator.verify_path_token(&token, ".testKey."));
+        assert!(!token_generator.verify_path_token(&token, ".otherKey."));
+        assert!(!token_generator.verify_path_token(&token[1..], ".testKey."));
+        assert!(!token_generator.verify_path_token("not.a.token", ".testKey."));
+    }
+
+    #[test]
+    fn test_signed_tokens_expire() {
+        let signer = signer();
+        let issued_at = SystemTime::now();
+        let token = signer.sign("ABC", ".testKey.", issued_at).unwrap();
+        assert!(signer.verify(&token, ".testKey.", issued_at + Duration::from_secs(59)));
+        assert!(!signer.verify(&token, ".testKey.", issued_at + Duration::from_secs(61)));
+    }
+
+    #[test]
+    fn test_signed_tokens_cannot_be_extended() {
+        let signer = signer();
+        let now = SystemTime::now();
+        let token = signer.sign("ABC", ".testKey.", now).unwrap();
+        let mut parts: Vec<&str> = token.split('.').collect();
+        let extended_expiry = (parts[1].parse::<u64>().unwrap() + 3600).to_string();
+        parts[1] = &extended_expiry;
+        assert!(!signer.verify(&parts.join("."), ".testKey.", now));
+    }
+
+    #[tokio::test]
+    async fn test_new_token_filter() {
+        let token = warp::test::request()
+            .filter(&new_token(FromCustomRng::new(Pcg64::seed_from_u64(1))))
+            .await
+            .unwrap();
+        assert_eq!(
+            token,
+            "472D0624C8F501D757AFBA8A9BAF6D04A0B50D4998A9281DD3E733ACC5EDA6FB"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_new_token_filter_with_rng_error() {
+        let rejection = warp::test::request()
+            .filter(&new_token(FromCustomRng::new(failing_rng())))
+            .await
+            .err()
+            .unwrap();
+        assert!(rejection.find::<TokenGenerationError>().is_some());
+    }
+
     #[test]
     fn test_converting_token_generation_error_to_warp_rejection() {
         let rand_err = Error::new("some random error".to_string());
@@ -165,4 +520,44 @@ pub mod tests {
         let rng = FromCustomRng::new(Pcg64::seed_from_u64(1));
         let _ = rng.clone();
     }
+
+    #[test]
+    fn test_tokens_match() {
+        assert!(tokens_match("ABCDEF", "ABCDEF"));
+        assert!(!tokens_match("ABCDEF", "ABCDEG"));
+        assert!(!tokens_match("ABCDEF", "ABCDE"));
+        assert!(!tokens_match("", "A"));
+    }
+
+    #[test]
+    fn test_redeemed_tokens_only_redeem_once() {
+        let redeemed_tokens = RedeemedTokens::default();
+        assert!(redeemed_tokens.redeem("ABCDEF"));
+        assert!(!redeemed_tokens.clone().redeem("ABCDEF"));
+        assert!(redeemed_tokens.redeem("ABCDEG"));
+    }
+
+    #[test]
+    fn test_redeemed_tokens_concurrently() {
+        let redeemed_tokens = RedeemedTokens::default();
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let redeemed_tokens = redeemed_tokens.clone();
+                std::thread::spawn(move || redeemed_tokens.redeem("ABCDEF"))
+            })
+            .collect();
+        let redeemed = handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .filter(|redeemed| *redeemed)
+            .count();
+        assert_eq!(redeemed, 1);
+    }
+
+    #[test]
+    fn test_redeemed_tokens_are_forgotten_after_ttl() {
+        let redeemed_tokens = RedeemedTokens::new(Duration::from_secs(0));
+        assert!(redeemed_tokens.redeem("ABCDEF"));
+        assert!(redeemed_tokens.redeem("ABCDEF"));
+    }
 }
@@ -0,0 +1,970 @@
+use crate::{
+    attestation::RelayOperation,
+    metrics::{observe_relay, WRITE_QUEUE_PENDING, WRITE_QUEUE_SYNCS},
+    relayer::{RelayNotification, Relayer},
+};
+use async_trait::async_trait;
+use redact_crypto::{Data, Entry, EntryPath, HasBuilder, HasIndex, States, StorageError, Storer};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::fmt::{self, Display, Formatter};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+use tracing::{info, warn};
+
+type Document = <Data as HasIndex>::Index;
+
+/// How often queued writes are replayed unless configured otherwise
+pub const DEFAULT_SYNC_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Queued writes which conflict with a change made elsewhere are moved here
+const CONFLICTS_DIR: &str = "conflicts";
+
+#[derive(Error, Debug)]
+pub enum WriteQueueError {
+    #[error("Failed to read or write the write queue")]
+    IoError { source: io::Error },
+
+    #[error("A queued write is not valid JSON")]
+    SerializationError { source: serde_json::Error },
+}
+
+/// What to do with a queued write when the entry it replaces was changed elsewhere
+/// after it was queued
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConflictPolicy {
+    /// Keep the other change, setting the queued write aside. Writes whose base
+    /// version is unknown are set aside too.
+    KeepRemote,
+    /// Overwrite the other change with the queued write
+    KeepLocal,
+}
+
+/// The version of an entry a queued write was based on, used to tell whether the
+/// entry was changed elsewhere before the write was replayed. The version is taken
+/// when the value is read for display, and sent back with the submitted form.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "t", content = "c", rename_all = "snake_case")]
+pub enum BaseVersion {
+    /// There was no entry
+    Absent,
+    /// The entry had this SHA-256 digest
+    Known(String),
+    /// Storage could not tell, so a change made elsewhere cannot be ruled out
+    Unknown,
+}
+
+impl BaseVersion {
+    pub fn of(entry: &Result<Entry, StorageError>) -> BaseVersion {
+        match entry {
+            Ok(entry) => BaseVersion::of_value(&entry.value),
+            Err(StorageError::NotFound) => BaseVersion::Absent,
+            Err(_) => BaseVersion::Unknown,
+        }
+    }
+
+    pub fn of_value(value: &States) -> BaseVersion {
+        BaseVersion::Known(fingerprint(value))
+    }
+}
+
+impl Display for BaseVersion {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            BaseVersion::Absent => write!(f, "absent"),
+            BaseVersion::Known(digest) => write!(f, "{}", digest),
+            BaseVersion::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+impl FromStr for BaseVersion {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "absent" => Ok(BaseVersion::Absent),
+            "unknown" => Ok(BaseVersion::Unknown),
+            digest
+                if digest.len() == 64
+                    && digest
+                        .bytes()
+                        .all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')) =>
+            {
+                Ok(BaseVersion::Known(digest.to_owned()))
+            }
+            _ => Err(()),
+        }
+    }
+}
+
+fn fingerprint(value: &States) -> String {
+    Sha256::digest(&serde_json::to_vec(value).unwrap_or_default())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// A sealed value waiting to be written to storage
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct QueuedWrite {
+    pub path: EntryPath,
+    pub value: States,
+    pub base: BaseVersion,
+    pub queued_at: u64,
+    pub relay_url: Option<String>,
+    pub request_id: String,
+}
+
+impl QueuedWrite {
+    /// Whether this is the same write, rather than a newer one queued for the path
+    fn is(&self, other: &QueuedWrite) -> bool {
+        self.queued_at == other.queued_at
+            && self.request_id == other.request_id
+            && fingerprint(&self.value) == fingerprint(&other.value)
+    }
+}
+
+/// Keeps sealed writes which could not reach storage on disk, one file per path, so
+/// that they survive restarts and can be replayed once storage is reachable again.
+/// Only sealed values are ever queued, so nothing readable without the key reaches
+/// the disk.
+#[derive(Debug, Clone)]
+pub struct WriteQueue {
+    dir: Option<PathBuf>,
+    on_conflict: ConflictPolicy,
+    /// The files holding queued writes. The directory is only listed the first time
+    /// the queue is changed, and kept track of in memory from then on.
+    files: Arc<Mutex<Option<HashSet<PathBuf>>>>,
+}
+
+impl WriteQueue {
+    pub fn new<P: Into<PathBuf>>(dir: P, on_conflict: ConflictPolicy) -> WriteQueue {
+        WriteQueue {
+            dir: Some(dir.into()),
+            on_conflict,
+            files: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// A queue which never accepts writes
+    pub fn disabled() -> WriteQueue {
+        WriteQueue {
+            dir: None,
+            on_conflict: ConflictPolicy::KeepRemote,
+            files: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.dir.is_some()
+    }
+
+    fn file_path(&self, path: &str) -> Option<PathBuf> {
+        let digest: String = Sha256::digest(path.as_bytes())
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        self.dir
+            .as_ref()
+            .map(|dir| dir.join(format!("{}.json", digest)))
+    }
+
+    /// Changes the queued files on the blocking thread pool, while holding the lock
+    /// on them, so that changes to the same path never interleave
+    async fn change<R, F>(&self, change: F) -> Result<R, WriteQueueError>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut HashSet<PathBuf>) -> Result<R, WriteQueueError> + Send + 'static,
+    {
+        let queue = self.clone();
+        blocking(move || {
+            let mut files = queue.files.lock().unwrap();
+            if files.is_none() {
+                *files = Some(queued_files(queue.dir.as_deref())?);
+            }
+            let files = files.as_mut().unwrap();
+            let result = change(files);
+            WRITE_QUEUE_PENDING.set(files.len() as i64);
+            result
+        })
+        .await
+    }
+
+    /// Returns the write queued for the path, if any
+    pub async fn pending(&self, path: &str) -> Result<Option<QueuedWrite>, WriteQueueError> {
+        let file_path = match self.file_path(path) {
+            Some(file_path) => file_path,
+            None => return Ok(None),
+        };
+        // Once the queue is known, paths with nothing queued are answered from memory
+        if let Some(files) = self.files.lock().unwrap().as_ref() {
+            if !files.contains(&file_path) {
+                return Ok(None);
+            }
+        }
+        blocking(move || read(&file_path)).await
+    }
+
+    /// Queues the sealed value to be written to the path, returning the version of
+    /// the entry it is based on. A write already queued for the path is replaced, but
+    /// the version it was based on is kept, since that is the last version of the
+    /// entry seen in storage. Otherwise the version the value was read at is used if
+    /// it is known, falling back to asking storage.
+    pub async fn enqueue<H: Storer>(
+        &self,
+        storer: &H,
+        path: &str,
+        value: States,
+        base: Option<BaseVersion>,
+        relay_url: Option<String>,
+        request_id: String,
+    ) -> Result<BaseVersion, WriteQueueError> {
+        let file_path = match self.file_path(path) {
+            Some(file_path) => file_path,
+            None => return Ok(BaseVersion::Unknown),
+        };
+        let base = match (self.pending(path).await?, base) {
+            (Some(queued), _) => queued.base,
+            (None, Some(base)) => base,
+            (None, None) => BaseVersion::of(&storer.get::<Data>(path).await),
+        };
+
+        let queued = QueuedWrite {
+            path: path.to_owned(),
+            value,
+            base: base.clone(),
+            queued_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            relay_url,
+            request_id,
+        };
+        self.change(move |files| {
+            write(&file_path, &queued)?;
+            files.insert(file_path);
+            Ok(())
+        })
+        .await?;
+        Ok(base)
+    }
+
+    /// Drops the write queued for the path, if any
+    pub async fn remove(&self, path: &str) -> Result<(), WriteQueueError> {
+        if let Some(file_path) = self.file_path(path) {
+            self.change(move |files| {
+                match fs::remove_file(&file_path) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                        return Err(WriteQueueError::IoError { source: e })
+                    }
+                    _ => files.remove(&file_path),
+                };
+                Ok(())
+            })
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Drops the write queued for its path once it has been replayed, unless a newer
+    /// write was queued for the path in the meantime
+    async fn remove_replayed(&self, write: &QueuedWrite) -> Result<(), WriteQueueError> {
+        if let Some(file_path) = self.file_path(&write.path) {
+            let replayed = write.clone();
+            self.change(move |files| {
+                if !read(&file_path)?.is_some_and(|queued| queued.is(&replayed)) {
+                    return Ok(());
+                }
+                match fs::remove_file(&file_path) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                        return Err(WriteQueueError::IoError { source: e })
+                    }
+                    _ => files.remove(&file_path),
+                };
+                Ok(())
+            })
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Lists every queued write, oldest first
+    async fn list(&self) -> Result<Vec<QueuedWrite>, WriteQueueError> {
+        let dir = match &self.dir {
+            Some(dir) => dir.clone(),
+            None => return Ok(vec![]),
+        };
+        blocking(move || {
+            let mut writes = Vec::new();
+            for file_path in queued_files(Some(&dir))? {
+                writes.extend(read(&file_path)?);
+            }
+            writes.sort_by_key(|write| write.queued_at);
+            Ok(writes)
+        })
+        .await
+    }
+
+    /// Moves a conflicting write out of the queue, keeping it on disk for inspection.
+    /// A newer write queued for the path in the meantime is left to the next sync.
+    async fn set_aside(&self, write: &QueuedWrite) -> Result<(), WriteQueueError> {
+        if let (Some(dir), Some(file_path)) = (&self.dir, self.file_path(&write.path)) {
+            let conflicts_dir = dir.join(CONFLICTS_DIR);
+            let conflicting = write.clone();
+            self.change(move |files| {
+                if !read(&file_path)?.is_some_and(|queued| queued.is(&conflicting)) {
+                    return Ok(());
+                }
+                fs::create_dir_all(&conflicts_dir)
+                    .map_err(|source| WriteQueueError::IoError { source })?;
+                let file_name = file_path.file_stem().unwrap_or_default().to_string_lossy();
+                fs::rename(
+                    &file_path,
+                    conflicts_dir.join(format!("{}-{}.json", file_name, conflicting.queued_at)),
+                )
+                .map_err(|source| WriteQueueError::IoError { source })?;
+                files.remove(&file_path);
+                Ok(())
+            })
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Replays queued writes to storage, oldest first, returning how many were
+    /// written. Replaying stops at the first write storage fails to accept, so that
+    /// writes to the same storage are never reordered. The current version of each
+    /// entry is read from `remote`, which must not answer from a cache, so that a
+    /// change made elsewhere is never hidden.
+    pub async fn sync<H: Storer, B: Storer, Q: Relayer>(
+        &self,
+        storer: &H,
+        remote: &B,
+        relayer: &Q,
+    ) -> Result<usize, WriteQueueError> {
+        let mut synced = 0;
+        for write in self.list().await? {
+            let current = remote.get::<Data>(&write.path).await;
+            if let Err(StorageError::InternalError { .. }) = current {
+                WRITE_QUEUE_SYNCS.with_label_values(&["failed"]).inc();
+                break;
+            }
+
+            // Without a known base version a change made elsewhere cannot be ruled out
+            let conflicted =
+                write.base == BaseVersion::Unknown || write.base != BaseVersion::of(&current);
+            if conflicted && self.on_conflict == ConflictPolicy::KeepRemote {
+                warn!(
+                    path = %write.path,
+                    "queued write conflicts with a change made elsewhere, setting it aside"
+                );
+                WRITE_QUEUE_SYNCS.with_label_values(&["conflict"]).inc();
+                self.set_aside(&write).await?;
+                continue;
+            }
+
+            if let Err(e) = storer.create(write.path.clone(), write.value.clone()).await {
+                warn!(error = %e, path = %write.path, "failed to replay queued write");
+                WRITE_QUEUE_SYNCS.with_label_values(&["failed"]).inc();
+                break;
+            }
+            info!(path = %write.path, conflicted, "replayed queued write");
+            WRITE_QUEUE_SYNCS.with_label_values(&["synced"]).inc();
+            self.remove_replayed(&write).await?;
+            synced += 1;
+
+            if let Some(relay_url) = write.relay_url {
+                let notification =
+                    RelayNotification::new(write.path, &write.value, RelayOperation::Sync);
+                let relay_result = relayer
+                    .relay(notification, relay_url, write.request_id)
+                    .await;
+                observe_relay(&relay_result);
+                if let Err(e) = relay_result {
+                    warn!(error = %e, "failed to relay replayed write");
+                }
+            }
+        }
+        Ok(synced)
+    }
+
+    /// Replays queued writes every interval, for as long as the client runs
+    pub async fn sync_periodically<H: Storer, B: Storer, Q: Relayer>(
+        self,
+        storer: H,
+        remote: B,
+        relayer: Q,
+        interval: Duration,
+    ) {
+        // Lists the queue up front, so the number of pending writes is reported
+        if let Err(e) = self.change(|_| Ok(())).await {
+            warn!(error = %e, "failed to read the write queue");
+        }
+        let mut ticks = tokio::time::interval(interval);
+        loop {
+            ticks.tick().await;
+            if let Err(e) = self.sync(&storer, &remote, &relayer).await {
+                warn!(error = %e, "failed to sync the write queue");
+            }
+        }
+    }
+}
+
+/// Runs file work on the blocking thread pool, off the async runtime
+async fn blocking<R, F>(work: F) -> Result<R, WriteQueueError>
+where
+    R: Send + 'static,
+    F: FnOnce() -> Result<R, WriteQueueError> + Send + 'static,
+{
+    tokio::task::spawn_blocking(work)
+        .await
+        .map_err(|e| WriteQueueError::IoError {
+            source: io::Error::other(e),
+        })?
+}
+
+/// Every file in the queue directory holding a queued write
+fn queued_files(dir: Option<&Path>) -> Result<HashSet<PathBuf>, WriteQueueError> {
+    let dir = match dir {
+        Some(dir) => dir,
+        None => return Ok(HashSet::new()),
+    };
+    let files = match fs::read_dir(dir) {
+        Ok(files) => files,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(HashSet::new()),
+        Err(source) => return Err(WriteQueueError::IoError { source }),
+    };
+
+    let mut queued = HashSet::new();
+    for file in files {
+        let file_path = file
+            .map_err(|source| WriteQueueError::IoError { source })?
+            .path();
+        if file_path.extension().is_some_and(|ext| ext == "json") {
+            queued.insert(file_path);
+        }
+    }
+    Ok(queued)
+}
+
+fn read(file_path: &Path) -> Result<Option<QueuedWrite>, WriteQueueError> {
+    match fs::read(file_path) {
+        Ok(bytes) => serde_json::from_slice(&bytes)
+            .map(Some)
+            .map_err(|source| WriteQueueError::SerializationError { source }),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(source) => Err(WriteQueueError::IoError { source }),
+    }
+}
+
+/// Replaces the file atomically, making it readable by the current user only
+fn write(file_path: &Path, write: &QueuedWrite) -> Result<(), WriteQueueError> {
+    let bytes = serde_json::to_vec(write)
+        .map_err(|source| WriteQueueError::SerializationError { source })?;
+    let mut tmp_path = file_path.to_owned().into_os_string();
+    tmp_path.push(".tmp");
+
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    file_path
+        .parent()
+        .map_or(Ok(()), fs::create_dir_all)
+        .and_then(|_| options.open(&tmp_path))
+        .and_then(|mut file| file.write_all(&bytes).and_then(|_| file.sync_all()))
+        .and_then(|_| fs::rename(&tmp_path, file_path))
+        .map_err(|source| WriteQueueError::IoError { source })
+}
+
+/// A `Storer` which serves writes still waiting in a `WriteQueue` in place of what
+/// storage holds, so that a value saved offline is shown when it is next read. A
+/// successful write replaces whatever was queued for the same path.
+#[derive(Clone)]
+pub struct PendingWriteStorer<H: Storer> {
+    inner: H,
+    queue: WriteQueue,
+}
+
+impl<H: Storer> PendingWriteStorer<H> {
+    pub fn new(inner: H, queue: WriteQueue) -> PendingWriteStorer<H> {
+        PendingWriteStorer { inner, queue }
+    }
+}
+
+// async_trait adds its own lifetime bounds to the ones required by `Storer`
+#[allow(clippy::multiple_bound_locations)]
+#[async_trait]
+impl<H: Storer> Storer for PendingWriteStorer<H> {
+    async fn get_indexed<T: HasBuilder + 'static>(
+        &self,
+        path: &str,
+        index: &Option<Document>,
+    ) -> Result<Entry, StorageError> {
+        match self.queue.pending(path).await {
+            Ok(Some(queued)) => Ok(Entry {
+                path: queued.path,
+                value: queued.value,
+            }),
+            Ok(None) => self.inner.get_indexed::<T>(path, index).await,
+            Err(e) => {
+                warn!(error = %e, "failed to read the write queue");
+                self.inner.get_indexed::<T>(path, index).await
+            }
+        }
+    }
+
+    async fn list_indexed<T: HasBuilder + Send + 'static>(
+        &self,
+        path: &str,
+        skip: i64,
+        page_size: i64,
+        index: &Option<Document>,
+    ) -> Result<Vec<Entry>, StorageError> {
+        self.inner
+            .list_indexed::<T>(path, skip, page_size, index)
+            .await
+    }
+
+    async fn create(&self, path: EntryPath, value: States) -> Result<bool, StorageError> {
+        let created = self.inner.create(path.clone(), value).await?;
+        if let Err(e) = self.queue.remove(&path).await {
+            warn!(error = %e, "failed to drop a superseded queued write");
+        }
+        Ok(created)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Document;
+    use super::{BaseVersion, ConflictPolicy, PendingWriteStorer, WriteQueue, CONFLICTS_DIR};
+    use crate::attestation::RelayOperation;
+    use crate::relayer::{tests::MockRelayer, RelayNotification, RelayOutcome};
+    use crate::storage::tests::CountingStorer;
+    use async_trait::async_trait;
+    use http::StatusCode;
+    use mockall::predicate::*;
+    use redact_crypto::{
+        ByteSource, Data, DataBuilder, Entry, EntryPath, HasBuilder, States, StorageError, Storer,
+        StringDataBuilder, TypeBuilder, VectorByteSource,
+    };
+    use std::path::PathBuf;
+    use uuid::Uuid;
+
+    fn string_value(value: &str) -> States {
+        States::Unsealed {
+            builder: TypeBuilder::Data(DataBuilder::String(StringDataBuilder {})),
+            bytes: ByteSource::Vector(VectorByteSource::new(value.as_bytes())),
+        }
+    }
+
+    fn queue_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("redact-write-queue-{}", Uuid::new_v4()))
+    }
+
+    /// Queues a newer write for every path while replaying one to it, as a value
+    /// submitted during a sync would
+    #[derive(Clone)]
+    struct EnqueueingStorer {
+        inner: CountingStorer,
+        queue: WriteQueue,
+    }
+
+    // async_trait adds its own lifetime bounds to the ones required by `Storer`
+    #[allow(clippy::multiple_bound_locations)]
+    #[async_trait]
+    impl Storer for EnqueueingStorer {
+        async fn get_indexed<T: HasBuilder + 'static>(
+            &self,
+            path: &str,
+            index: &Option<Document>,
+        ) -> Result<Entry, StorageError> {
+            self.inner.get_indexed::<T>(path, index).await
+        }
+
+        async fn list_indexed<T: HasBuilder + Send + 'static>(
+            &self,
+            path: &str,
+            skip: i64,
+            page_size: i64,
+            index: &Option<Document>,
+        ) -> Result<Vec<Entry>, StorageError> {
+            self.inner
+                .list_indexed::<T>(path, skip, page_size, index)
+                .await
+        }
+
+        async fn create(&self, path: EntryPath, value: States) -> Result<bool, StorageError> {
+            self.queue
+                .enqueue(
+                    &self.inner,
+                    &path,
+                    string_value("Carol"),
+                    None,
+                    None,
+                    "newer-request-id".to_owned(),
+                )
+                .await
+                .unwrap();
+            self.inner.create(path, value).await
+        }
+    }
+
+    async fn stored(storer: &CountingStorer, path: &str) -> Data {
+        storer
+            .resolve::<Data>(storer.get::<Data>(path).await.unwrap().value)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_sync_replays_queued_write() {
+        let dir = queue_dir();
+        let queue = WriteQueue::new(&dir, ConflictPolicy::KeepRemote);
+        let storer = CountingStorer::default();
+        storer.set_unreachable(true);
+        queue
+            .enqueue(
+                &storer,
+                ".profile.name.",
+                string_value("Alice"),
+                Some(BaseVersion::Absent),
+                Some("https://relay.test".to_owned()),
+                "test-request-id".to_owned(),
+            )
+            .await
+            .unwrap();
+
+        let mut relayer = MockRelayer::new();
+        relayer
+            .expect_relay()
+            .times(1)
+            .with(
+                eq(RelayNotification {
+                    path: ".profile.name.".to_owned(),
+                    data_type: Some("string".to_owned()),
+                    operation: RelayOperation::Sync,
+                }),
+                eq("https://relay.test".to_owned()),
+                eq("test-request-id".to_owned()),
+            )
+            .return_once(|_, _, _| Ok(RelayOutcome::Delivered(StatusCode::OK)));
+
+        assert_eq!(queue.sync(&storer, &storer, &relayer).await.unwrap(), 0);
+        assert!(queue.pending(".profile.name.").await.unwrap().is_some());
+
+        storer.set_unreachable(false);
+        assert_eq!(queue.sync(&storer, &storer, &relayer).await.unwrap(), 1);
+        assert!(queue.pending(".profile.name.").await.unwrap().is_none());
+        assert_eq!(
+            stored(&storer, ".profile.name.").await,
+            Data::String("Alice".to_owned())
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_write_queued_during_sync_is_kept() {
+        let dir = queue_dir();
+        let queue = WriteQueue::new(&dir, ConflictPolicy::KeepRemote);
+        let inner = CountingStorer::default();
+        queue
+            .enqueue(
+                &inner,
+                ".profile.name.",
+                string_value("Bob"),
+                Some(BaseVersion::Absent),
+                None,
+                "test-request-id".to_owned(),
+            )
+            .await
+            .unwrap();
+        let storer = EnqueueingStorer {
+            inner: inner.clone(),
+            queue: queue.clone(),
+        };
+
+        assert_eq!(
+            queue
+                .sync(&storer, &inner, &MockRelayer::new())
+                .await
+                .unwrap(),
+            1
+        );
+        let queued = queue.pending(".profile.name.").await.unwrap().unwrap();
+        assert_eq!(queued.request_id, "newer-request-id");
+        assert_eq!(
+            storer.resolve::<Data>(queued.value).await.unwrap(),
+            Data::String("Carol".to_owned())
+        );
+        assert_eq!(
+            stored(&inner, ".profile.name.").await,
+            Data::String("Bob".to_owned())
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_conflict_keeps_remote() {
+        let dir = queue_dir();
+        let queue = WriteQueue::new(&dir, ConflictPolicy::KeepRemote);
+        let storer = CountingStorer::default();
+        storer.insert(".profile.name.", string_value("Alice"));
+        queue
+            .enqueue(
+                &storer,
+                ".profile.name.",
+                string_value("Bob"),
+                None,
+                None,
+                "test-request-id".to_owned(),
+            )
+            .await
+            .unwrap();
+        storer.insert(".profile.name.", string_value("Carol"));
+
+        let mut relayer = MockRelayer::new();
+        relayer.expect_relay().times(0);
+        assert_eq!(queue.sync(&storer, &storer, &relayer).await.unwrap(), 0);
+        assert!(queue.pending(".profile.name.").await.unwrap().is_none());
+        assert_eq!(
+            stored(&storer, ".profile.name.").await,
+            Data::String("Carol".to_owned())
+        );
+        assert_eq!(
+            std::fs::read_dir(dir.join(CONFLICTS_DIR)).unwrap().count(),
+            1
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_conflict_keeps_local() {
+        let dir = queue_dir();
+        let queue = WriteQueue::new(&dir, ConflictPolicy::KeepLocal);
+        let storer = CountingStorer::default();
+        queue
+            .enqueue(
+                &storer,
+                ".profile.name.",
+                string_value("Bob"),
+                None,
+                None,
+                "test-request-id".to_owned(),
+            )
+            .await
+            .unwrap();
+        storer.insert(".profile.name.", string_value("Carol"));
+
+        assert_eq!(
+            queue
+                .sync(&storer, &storer, &MockRelayer::new())
+                .await
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            stored(&storer, ".profile.name.").await,
+            Data::String("Bob".to_owned())
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_requeue_keeps_first_base() {
+        let dir = queue_dir();
+        let queue = WriteQueue::new(&dir, ConflictPolicy::KeepRemote);
+        let storer = CountingStorer::default();
+        storer.insert(".profile.name.", string_value("Alice"));
+        for value in &["Bob", "Carol"] {
+            queue
+                .enqueue(
+                    &storer,
+                    ".profile.name.",
+                    string_value(value),
+                    None,
+                    None,
+                    "test-request-id".to_owned(),
+                )
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(
+            queue
+                .sync(&storer, &storer, &MockRelayer::new())
+                .await
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            stored(&storer, ".profile.name.").await,
+            Data::String("Carol".to_owned())
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_pending_write_is_served() {
+        let dir = queue_dir();
+        let queue = WriteQueue::new(&dir, ConflictPolicy::KeepRemote);
+        let inner = CountingStorer::default();
+        inner.insert(".profile.name.", string_value("Alice"));
+        let storer = PendingWriteStorer::new(inner.clone(), queue.clone());
+        queue
+            .enqueue(
+                &inner,
+                ".profile.name.",
+                string_value("Bob"),
+                None,
+                None,
+                "test-request-id".to_owned(),
+            )
+            .await
+            .unwrap();
+
+        let entry = storer.get::<Data>(".profile.name.").await.unwrap();
+        assert_eq!(
+            storer.resolve::<Data>(entry.value).await.unwrap(),
+            Data::String("Bob".to_owned())
+        );
+
+        storer
+            .create(".profile.name.".to_owned(), string_value("Carol"))
+            .await
+            .unwrap();
+        assert!(queue.pending(".profile.name.").await.unwrap().is_none());
+        let entry = storer.get::<Data>(".profile.name.").await.unwrap();
+        assert_eq!(
+            storer.resolve::<Data>(entry.value).await.unwrap(),
+            Data::String("Carol".to_owned())
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_conflict_with_version_read_before_storage_failed() {
+        let dir = queue_dir();
+        let queue = WriteQueue::new(&dir, ConflictPolicy::KeepRemote);
+        let storer = CountingStorer::default();
+        storer.set_unreachable(true);
+        // The value was displayed while storage held Alice, and changed elsewhere to
+        // Carol while the edit was queued
+        queue
+            .enqueue(
+                &storer,
+                ".profile.name.",
+                string_value("Bob"),
+                Some(BaseVersion::of_value(&string_value("Alice"))),
+                None,
+                "test-request-id".to_owned(),
+            )
+            .await
+            .unwrap();
+        storer.set_unreachable(false);
+        storer.insert(".profile.name.", string_value("Carol"));
+
+        assert_eq!(
+            queue
+                .sync(&storer, &storer, &MockRelayer::new())
+                .await
+                .unwrap(),
+            0
+        );
+        assert_eq!(
+            stored(&storer, ".profile.name.").await,
+            Data::String("Carol".to_owned())
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_unknown_base_is_set_aside_when_keeping_remote() {
+        let dir = queue_dir();
+        let queue = WriteQueue::new(&dir, ConflictPolicy::KeepRemote);
+        let storer = CountingStorer::default();
+        storer.set_unreachable(true);
+        queue
+            .enqueue(
+                &storer,
+                ".profile.name.",
+                string_value("Bob"),
+                None,
+                None,
+                "test-request-id".to_owned(),
+            )
+            .await
+            .unwrap();
+        storer.set_unreachable(false);
+
+        assert_eq!(
+            queue
+                .sync(&storer, &storer, &MockRelayer::new())
+                .await
+                .unwrap(),
+            0
+        );
+        assert!(queue.pending(".profile.name.").await.unwrap().is_none());
+        assert_eq!(
+            std::fs::read_dir(dir.join(CONFLICTS_DIR)).unwrap().count(),
+            1
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_sync_compares_against_remote() {
+        let dir = queue_dir();
+        let queue = WriteQueue::new(&dir, ConflictPolicy::KeepRemote);
+        // The local storer still answers with the version the write was based on, as
+        // a cache would, while storage itself was changed elsewhere
+        let storer = CountingStorer::default();
+        storer.insert(".profile.name.", string_value("Alice"));
+        let remote = CountingStorer::default();
+        remote.insert(".profile.name.", string_value("Carol"));
+        queue
+            .enqueue(
+                &storer,
+                ".profile.name.",
+                string_value("Bob"),
+                None,
+                None,
+                "test-request-id".to_owned(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(
+            queue
+                .sync(&storer, &remote, &MockRelayer::new())
+                .await
+                .unwrap(),
+            0
+        );
+        assert_eq!(
+            stored(&storer, ".profile.name.").await,
+            Data::String("Alice".to_owned())
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_base_version_round_trips_through_forms() {
+        for base in &[
+            BaseVersion::Absent,
+            BaseVersion::Unknown,
+            BaseVersion::of_value(&string_value("Alice")),
+        ] {
+            assert_eq!(&base.to_string().parse::<BaseVersion>().unwrap(), base);
+        }
+        assert!("not-a-digest".parse::<BaseVersion>().is_err());
+    }
+}